@@ -0,0 +1 @@
+DROP TABLE `bso_deletions`;
@@ -0,0 +1,12 @@
+CREATE TABLE `bso_deletions` (
+  `userid` bigint(20)  NOT NULL,
+  `collection` int(11) NOT NULL,
+  `id` varchar(64)     NOT NULL,
+  -- deletion time in milliseconds since epoch
+  `deleted` bigint(20) NOT NULL,
+  -- expiration in milliseconds since epoch
+  `ttl` bigint(20)     NOT NULL,
+  PRIMARY KEY (`userid`, `collection`, `id`),
+  KEY `bso_deletions_usr_col_del_idx` (`userid`, `collection`, `deleted`),
+  KEY `bso_deletions_ttl_idx` (`ttl`)
+) ENGINE=InnoDB DEFAULT CHARSET=latin1;
@@ -0,0 +1,2 @@
+DROP TABLE `deleted_bso`;
+DROP TABLE `deleted_storage`;
@@ -0,0 +1,22 @@
+CREATE TABLE `deleted_storage` (
+  `userid` bigint(20)  NOT NULL,
+  -- deletion time in milliseconds since epoch
+  `deleted` bigint(20) NOT NULL,
+  -- end of the undelete grace period in milliseconds since epoch
+  `ttl` bigint(20)     NOT NULL,
+  -- 0 while copied, 1 once restorable, 2 while restored
+  `state` int(11)      NOT NULL,
+  PRIMARY KEY (`userid`),
+  KEY `deleted_storage_ttl_idx` (`ttl`)
+) ENGINE=InnoDB DEFAULT CHARSET=latin1;
+
+CREATE TABLE `deleted_bso` (
+  `userid` bigint(20)  NOT NULL,
+  `collection` int(11) NOT NULL,
+  `id` varchar(64)     NOT NULL,
+  `sortindex` int(11),
+  `payload` mediumtext NOT NULL,
+  -- the bso's expiration in milliseconds since epoch
+  `ttl` bigint(20)     NOT NULL,
+  PRIMARY KEY (`userid`, `collection`, `id`)
+) ENGINE=InnoDB DEFAULT CHARSET=latin1;
@@ -0,0 +1,14 @@
+CREATE TABLE `bso_history` (
+  `userid` bigint(20)  NOT NULL,
+  `collection` int(11) NOT NULL,
+  `id` varchar(64)     NOT NULL,
+  -- the revision's modification time in milliseconds since epoch
+  `modified` bigint(20) NOT NULL,
+  `sortindex` int(11),
+  `payload` mediumtext NOT NULL,
+  -- the revision's expiration in milliseconds since epoch
+  `ttl` bigint(20)     NOT NULL,
+  -- a tombstone: the bso was deleted at `modified` (or created just after)
+  `deleted` tinyint(1) NOT NULL DEFAULT 0,
+  PRIMARY KEY (`userid`, `collection`, `id`, `modified`)
+) ENGINE=InnoDB DEFAULT CHARSET=latin1;
@@ -0,0 +1,16 @@
+CREATE TABLE `audit_log` (
+  `id` bigint(20)          NOT NULL AUTO_INCREMENT,
+  `userid` bigint(20)      NOT NULL,
+  -- the operation's time in milliseconds since epoch
+  `recorded` bigint(20)    NOT NULL,
+  `op` varchar(32)         NOT NULL,
+  `collection` varchar(32),
+  -- the number of records deleted
+  `count` bigint(20)       NOT NULL,
+  `user_agent` text        NOT NULL,
+  `device_id` varchar(255) NOT NULL,
+  -- the entry's expiration in milliseconds since epoch
+  `ttl` bigint(20)         NOT NULL,
+  PRIMARY KEY (`id`),
+  KEY `audit_log_userid_idx` (`userid`, `recorded`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1 @@
+ALTER TABLE `deleted_bso` DROP COLUMN `payload_size`;
@@ -0,0 +1,2 @@
+-- the logical (decoded) size of the payload, kept through soft deletes
+ALTER TABLE `deleted_bso` ADD COLUMN `payload_size` bigint(20) NOT NULL DEFAULT 0 AFTER `payload`;
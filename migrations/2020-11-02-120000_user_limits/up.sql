@@ -0,0 +1,11 @@
+-- per-user overrides of the server limits, NULL when not overridden
+CREATE TABLE `user_limits` (
+  `userid` bigint(20)                   NOT NULL,
+  `max_post_bytes` bigint(20),
+  `max_post_records` bigint(20),
+  `max_record_payload_bytes` bigint(20),
+  `max_total_bytes` bigint(20),
+  `max_total_records` bigint(20),
+  `max_quota_limit` bigint(20),
+  PRIMARY KEY (`userid`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
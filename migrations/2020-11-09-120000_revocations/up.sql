@@ -0,0 +1,8 @@
+-- token revocations, matching tokens issued before issued_before (in
+-- seconds), or all matching tokens when NULL
+CREATE TABLE `revocations` (
+  `key_type` varchar(32)                NOT NULL,
+  `key_value` varchar(255)              NOT NULL,
+  `issued_before` bigint(20),
+  PRIMARY KEY (`key_type`, `key_value`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
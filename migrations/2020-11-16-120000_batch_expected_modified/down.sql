@@ -0,0 +1 @@
+ALTER TABLE `batch_upload_items` DROP COLUMN `expected_modified`;
@@ -0,0 +1,3 @@
+-- the batched BSO's expected stored timestamp (0 when it shouldn't exist
+-- yet), checked as the batch is committed
+ALTER TABLE `batch_upload_items` ADD COLUMN `expected_modified` bigint(20) DEFAULT NULL AFTER `ttl_offset`;
@@ -0,0 +1,77 @@
+//! Print a Hawk `Authorization` header for a request to a storage node.
+//!
+//! A Rust counterpart to `tools/hawk/make_hawk_token.py` that mints the
+//! token with `syncstorage::web::auth` (so no Python tokenlib is required).
+use std::error::Error;
+
+use chrono::offset::Utc;
+use docopt::Docopt;
+use hawk::{Credentials, DigestAlgorithm, Key, RequestBuilder};
+use rand::{thread_rng, Rng};
+use serde_derive::Deserialize;
+use url::{Position, Url};
+
+use syncstorage::settings::Secrets;
+use syncstorage::web::auth::{derive_token_secret, HawkPayload};
+
+const USAGE: &str = "
+Print a Hawk Authorization header for a request to URL.
+
+Usage: make_hawk_token [options] <url>
+
+Options:
+    -h, --help               Show this message.
+    --method=METHOD          The HTTP method [default: GET].
+    --uid=UID                Legacy user id [default: 1].
+    --fxa-uid=FXA_UID        FxA user id [default: DEADBEEF00004be4ae957006c0ceb620].
+    --fxa-kid=FXA_KID        FxA key id [default: DEADBEEF00004be4ae957006c0ceb620].
+    --node=NODE              Storage node URI (defaults to the origin of URL).
+    --duration=SECONDS       Token lifetime in seconds [default: 3600].
+    --secret=SECRET          The server's master secret [default: Ted_Koppel_is_a_robot].
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_url: String,
+    flag_method: String,
+    flag_uid: u64,
+    flag_fxa_uid: String,
+    flag_fxa_kid: String,
+    flag_node: Option<String>,
+    flag_duration: u64,
+    flag_secret: String,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let url = Url::parse(&args.arg_url)?;
+    let secrets = Secrets::new(&args.flag_secret).map_err(|e| e.to_string())?;
+
+    let salt: [u8; 3] = thread_rng().gen();
+    let payload = HawkPayload {
+        expires: (Utc::now().timestamp() as u64 + args.flag_duration) as f64,
+        node: args
+            .flag_node
+            .unwrap_or_else(|| url[..Position::BeforePath].to_owned()),
+        salt: salt.iter().map(|b| format!("{:02x}", b)).collect(),
+        user_id: args.flag_uid,
+        fxa_uid: args.flag_fxa_uid,
+        fxa_kid: args.flag_fxa_kid,
+        device_id: "".to_owned(),
+    };
+    let id = payload.make_token(&secrets).map_err(|e| e.to_string())?;
+    let token_secret =
+        derive_token_secret(&id, &payload.salt, &secrets).map_err(|e| e.to_string())?;
+
+    let credentials = Credentials {
+        id,
+        key: Key::new(token_secret.as_bytes(), DigestAlgorithm::Sha256)?,
+    };
+    let header = RequestBuilder::from_url(&args.flag_method, &url)?
+        .request()
+        .make_header(&credentials)?;
+    println!("Authorization: Hawk {}", header);
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! Purge the storage of inactive accounts
+//!
+//! Deletes the storage of every user whose storage hasn't been modified in
+//! `--inactive-days`, one user at a time via `Db::delete_storage`. Works
+//! against either backend, reading the database url (and statsd settings)
+//! from the syncstorage settings.
+#[macro_use]
+extern crate slog_scope;
+
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use docopt::Docopt;
+use serde_derive::Deserialize;
+use serde_json::json;
+
+use syncstorage::{
+    db::{
+        params, pool_from_settings,
+        util::{ms_since_epoch, SyncTimestamp},
+        DbPool,
+    },
+    error::ApiResult,
+    logging::{self, init_logging},
+    server::metrics::{metrics_from_opts, Metrics},
+    settings::Settings,
+    web::extractors::HawkIdentifier,
+};
+
+const USAGE: &str = "
+Usage: purge_inactive [options]
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+    --inactive-days=DAYS     Purge the users inactive for this many days [default: 730].
+    --chunk-size=USERS       Number of inactive users listed at once [default: 100].
+    --max-per-second=USERS   Most users purged per second, 0 for no limit [default: 10].
+    --max-to-delete=USERS    Most users purged per run, 0 for no limit [default: 0].
+    --dry-run                Report the inactive users without purging them.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_config: Option<String>,
+    flag_inactive_days: u32,
+    flag_chunk_size: u32,
+    flag_max_per_second: u32,
+    flag_max_to_delete: u64,
+    flag_dry_run: bool,
+}
+
+/// Whether the user was purged: they're spared when they've become active
+/// since being listed
+async fn purge_user(
+    pool: &dyn DbPool,
+    user_id: HawkIdentifier,
+    before: SyncTimestamp,
+) -> ApiResult<bool> {
+    let db = pool.get().await?;
+    db.begin(true).await?;
+    let result = async {
+        if db.get_storage_timestamp(user_id.clone()).await? >= before {
+            return Ok(false);
+        }
+        db.delete_storage(user_id).await?;
+        Ok(true)
+    }
+    .await;
+    match result {
+        Ok(_) => db.commit().await?,
+        Err(_) => db.rollback().await?,
+    }
+    result
+}
+
+/// Purge (or list) the inactive users, returning how many were found and
+/// purged
+async fn purge_inactive(args: &Args, settings: &Settings) -> ApiResult<(u64, u64)> {
+    let metrics = Metrics::from(&metrics_from_opts(settings)?);
+    let mut timer = metrics.clone();
+    timer.start_timer("purge_inactive.duration", None);
+    let pool = pool_from_settings(settings, &metrics).await?;
+
+    let inactive_ms = i64::from(args.flag_inactive_days) * 24 * 60 * 60 * 1000;
+    let before = SyncTimestamp::from_milliseconds((ms_since_epoch() - inactive_ms).max(0) as u64);
+    let pace = if args.flag_max_per_second > 0 {
+        Duration::from_secs(1) / args.flag_max_per_second
+    } else {
+        Duration::from_secs(0)
+    };
+    let since = before.as_rfc3339()?;
+    info!(
+        "Purging the users inactive since {}{}",
+        since,
+        if args.flag_dry_run { " (dry run)" } else { "" }
+    );
+
+    let mut after = None;
+    let (mut found, mut purged) = (0u64, 0u64);
+    'chunks: loop {
+        let users = {
+            let db = pool.get().await?;
+            db.begin(false).await?;
+            let users = db
+                .get_inactive_users(params::GetInactiveUsers {
+                    before,
+                    after: after.clone(),
+                    limit: args.flag_chunk_size,
+                })
+                .await?;
+            db.commit().await?;
+            users
+        };
+        after = match users.last() {
+            Some(user) => Some(user.user_id.clone()),
+            None => break,
+        };
+        for user in users {
+            if args.flag_max_to_delete > 0 && found >= args.flag_max_to_delete {
+                break 'chunks;
+            }
+            found += 1;
+            if args.flag_dry_run {
+                println!(
+                    "{}",
+                    json!({ "user_id": user.user_id, "modified": user.modified })
+                );
+                metrics.incr("purge_inactive.found");
+                continue;
+            }
+
+            let start = Instant::now();
+            match purge_user(&*pool, user.user_id.clone(), before).await {
+                Ok(true) => {
+                    purged += 1;
+                    metrics.incr("purge_inactive.purged");
+                }
+                Ok(false) => metrics.incr("purge_inactive.spared"),
+                Err(e) => {
+                    error!("⚠️ Couldn't purge {:?}: {}", user.user_id, e);
+                    metrics.incr("purge_inactive.error");
+                }
+            }
+            if let Some(wait) = pace.checked_sub(start.elapsed()) {
+                actix_rt::time::delay_for(wait).await;
+            }
+        }
+    }
+    Ok((found, purged))
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(&args.flag_config)?;
+    init_logging(!settings.human_logs).expect("Logging failed to initialize");
+
+    let (found, purged) = purge_inactive(&args, &settings)
+        .await
+        .map_err(|e| e.to_string())?;
+    info!(
+        "Completed purge_inactive: {} inactive users, {} purged",
+        found, purged
+    );
+    logging::reset_logging();
+    Ok(())
+}
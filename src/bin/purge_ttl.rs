@@ -26,6 +26,9 @@ const SPANNER_ADDRESS: &str = "spanner.googleapis.com:443";
 const RETRY_ENV_VAR: &str = "PURGE_TTL_RETRY_COUNT"; // Default value = 10
 const SLEEP_ENV_VAR: &str = "PURGE_TTL_RETRY_SLEEP_MILLIS"; // Default value = 0
 
+// Shared with syncstorage's `deleted_bso_retention` setting
+const RETENTION_ENV_VAR: &str = "SYNC_DELETED_BSO_RETENTION"; // Default value = 0
+
 use protobuf::well_known_types::Value;
 
 pub struct MetricTimer {
@@ -161,18 +164,34 @@ impl Iterator for SyncResultSet {
     }
 }
 
+/// The WHERE condition matching rows that expired more than `grace_secs`
+/// seconds ago
+fn expired_condition(grace_secs: u64) -> String {
+    format!(
+        "expiry < TIMESTAMP_SUB(CURRENT_TIMESTAMP(), INTERVAL {} SECOND)",
+        grace_secs
+    )
+}
+
 fn delete_incremental(
     client: &SpannerClient,
     session: &Session,
     table: String,
     column: String,
+    grace_secs: u64,
     chunk_size: u64,
     max_to_delete: u64,
 ) -> Result<(), Box<grpcio::Error>> {
     let mut total: u64 = 0;
     let (mut req, mut txn) = begin_transaction(&client, &session, RequestType::ReadWrite)?;
     loop {
-        let select_sql = format!("SELECT fxa_uid, fxa_kid, collection_id, {} FROM {} WHERE expiry < CURRENT_TIMESTAMP() LIMIT {}", column, table, chunk_size);
+        let select_sql = format!(
+            "SELECT fxa_uid, fxa_kid, collection_id, {} FROM {} WHERE {} LIMIT {}",
+            column,
+            table,
+            expired_condition(grace_secs),
+            chunk_size
+        );
         trace!("Selecting rows to delete: {}", select_sql);
         req.set_sql(select_sql.clone());
         let mut result = SyncResultSet {
@@ -231,11 +250,13 @@ fn delete_all(
     client: &SpannerClient,
     session: &Session,
     table: String,
+    grace_secs: u64,
 ) -> Result<(), Box<grpcio::Error>> {
     let (mut req, _txn) = begin_transaction(client, session, RequestType::PartitionedDml)?;
     req.set_sql(format!(
-        "DELETE FROM {} WHERE expiry < CURRENT_TIMESTAMP()",
-        table
+        "DELETE FROM {} WHERE {}",
+        table,
+        expired_condition(grace_secs)
     ));
     let result = client.execute_sql(&req)?;
     info!(
@@ -293,6 +314,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     let nap_time: Duration = Duration::from_millis(
         str::parse::<u64>(&env::var(SLEEP_ENV_VAR).unwrap_or_else(|_| "0".to_owned())).unwrap_or(0),
     );
+    // Expired BSOs are kept around for this long so that the deleted record
+    // feed (`?deleted_since=`) can report them
+    let retention: u64 =
+        str::parse::<u64>(&env::var(RETENTION_ENV_VAR).unwrap_or_else(|_| "0".to_owned()))
+            .unwrap_or(0);
 
     let database = db_url["spanner://".len()..].to_owned();
     info!("Retries: {}, sleep: {}ms", retries, nap_time.as_millis());
@@ -332,11 +358,12 @@ fn main() -> Result<(), Box<dyn Error>> {
                         &session,
                         "batches".to_owned(),
                         "batch_id".to_owned(),
+                        0,
                         chunk_size,
                         max_to_delete,
                     )
                 } else {
-                    delete_all(&client, &session, "batches".to_owned())
+                    delete_all(&client, &session, "batches".to_owned(), 0)
                 } {
                     Ok(_) => {
                         success = true;
@@ -370,11 +397,12 @@ fn main() -> Result<(), Box<dyn Error>> {
                         &session,
                         "bsos".to_owned(),
                         "bso_id".to_owned(),
+                        retention,
                         chunk_size,
                         max_to_delete,
                     )
                 } else {
-                    delete_all(&client, &session, "bsos".to_owned())
+                    delete_all(&client, &session, "bsos".to_owned(), retention)
                 } {
                     Ok(_) => {
                         success = true;
@@ -392,6 +420,42 @@ fn main() -> Result<(), Box<dyn Error>> {
                 panic!("Could not delete expired bsos after {} attempts", retries);
             }
         }
+        {
+            let _timer_deletions = start_timer(&statsd, "purge_ttl.bso_deletions_duration");
+            let mut success = false;
+            for i in 0..retries {
+                match if incremental {
+                    delete_incremental(
+                        &client,
+                        &session,
+                        "bso_deletions".to_owned(),
+                        "bso_id".to_owned(),
+                        0,
+                        chunk_size,
+                        max_to_delete,
+                    )
+                } else {
+                    delete_all(&client, &session, "bso_deletions".to_owned(), 0)
+                } {
+                    Ok(_) => {
+                        success = true;
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("BSO deletions transaction error {}: {:?}", i, e);
+                        if nap_time.as_millis() > 0 {
+                            thread::sleep(nap_time);
+                        }
+                    }
+                }
+            }
+            if !success {
+                panic!(
+                    "Could not delete expired bso deletions after {} attempts",
+                    retries
+                );
+            }
+        }
         info!("Completed purge_ttl");
     }
 
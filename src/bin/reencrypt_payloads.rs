@@ -0,0 +1,139 @@
+//! Re-encrypt stored payloads under the primary payload encryption key
+//!
+//! Rewrites every user's stored payloads (batches, deleted storage and BSO
+//! history included) that are either unencrypted or encrypted under one of
+//! the key file's older keys, one user at a time via
+//! `Db::reencrypt_payloads`. Rows written while it runs are skipped, so once
+//! a run has rewritten none, the older keys can be removed from the key file.
+//! Works against either backend, reading the database url and key file from
+//! the syncstorage settings.
+#[macro_use]
+extern crate slog_scope;
+
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use docopt::Docopt;
+use serde_derive::Deserialize;
+
+use syncstorage::{
+    db::{
+        params, pool_from_settings,
+        util::{ms_since_epoch, SyncTimestamp},
+        DbPool,
+    },
+    error::ApiResult,
+    logging::{self, init_logging},
+    server::metrics::{metrics_from_opts, Metrics},
+    settings::Settings,
+    web::extractors::HawkIdentifier,
+};
+
+const USAGE: &str = "
+Usage: reencrypt_payloads [options]
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+    --chunk-size=USERS       Number of users listed at once [default: 100].
+    --max-per-second=USERS   Most users re-encrypted per second, 0 for no limit [default: 10].
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_config: Option<String>,
+    flag_chunk_size: u32,
+    flag_max_per_second: u32,
+}
+
+/// The number of the user's payloads rewritten
+async fn reencrypt_user(pool: &dyn DbPool, user_id: HawkIdentifier) -> ApiResult<u64> {
+    let db = pool.get().await?;
+    db.begin(true).await?;
+    let result = db.reencrypt_payloads(user_id).await;
+    match result {
+        Ok(_) => db.commit().await?,
+        Err(_) => db.rollback().await?,
+    }
+    result
+}
+
+/// Re-encrypt every user's payloads, returning how many users were visited
+/// and how many payloads rewritten
+async fn reencrypt_payloads(args: &Args, settings: &Settings) -> ApiResult<(u64, u64)> {
+    let metrics = Metrics::from(&metrics_from_opts(settings)?);
+    let mut timer = metrics.clone();
+    timer.start_timer("reencrypt_payloads.duration", None);
+    let pool = pool_from_settings(settings, &metrics).await?;
+
+    // Every user with storage was last modified before now (give or take
+    // some clock skew)
+    let before = SyncTimestamp::from_milliseconds(ms_since_epoch() as u64 + 24 * 60 * 60 * 1000);
+    let pace = if args.flag_max_per_second > 0 {
+        Duration::from_secs(1) / args.flag_max_per_second
+    } else {
+        Duration::from_secs(0)
+    };
+
+    let mut after = None;
+    let (mut users, mut rewritten) = (0u64, 0u64);
+    loop {
+        let chunk = {
+            let db = pool.get().await?;
+            db.begin(false).await?;
+            let chunk = db
+                .get_inactive_users(params::GetInactiveUsers {
+                    before,
+                    after: after.clone(),
+                    limit: args.flag_chunk_size,
+                })
+                .await?;
+            db.commit().await?;
+            chunk
+        };
+        after = match chunk.last() {
+            Some(user) => Some(user.user_id.clone()),
+            None => break,
+        };
+        for user in chunk {
+            users += 1;
+            let start = Instant::now();
+            match reencrypt_user(&*pool, user.user_id.clone()).await {
+                Ok(count) => {
+                    rewritten += count;
+                    metrics.histogram_with_tags("reencrypt_payloads.rewritten", count, None);
+                }
+                Err(e) => {
+                    error!("⚠️ Couldn't re-encrypt {:?}: {}", user.user_id, e);
+                    metrics.incr("reencrypt_payloads.error");
+                }
+            }
+            if let Some(wait) = pace.checked_sub(start.elapsed()) {
+                actix_rt::time::delay_for(wait).await;
+            }
+        }
+    }
+    Ok((users, rewritten))
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(&args.flag_config)?;
+    init_logging(!settings.human_logs).expect("Logging failed to initialize");
+    if settings.payload_encryption_key_file.is_none() {
+        return Err("payload_encryption_key_file isn't configured".into());
+    }
+
+    let (users, rewritten) = reencrypt_payloads(&args, &settings)
+        .await
+        .map_err(|e| e.to_string())?;
+    info!(
+        "Completed reencrypt_payloads: {} users, {} payloads rewritten",
+        users, rewritten
+    );
+    logging::reset_logging();
+    Ok(())
+}
@@ -0,0 +1,212 @@
+//! Encoding of stored BSO payloads
+//!
+//! Payloads are optionally compressed and then optionally encrypted (see
+//! `encryption`) before they're written to either backend, and decoded as
+//! they're read back. Both steps mark the values they produce with a prefix,
+//! so payloads stored under another configuration (e.g. before compression
+//! was enabled) are still read correctly. Payloads stored as sent that
+//! happen to start with one of these prefixes are escaped, so they're never
+//! mistaken for an encoded payload.
+//!
+//! Quota accounting is unaffected: the backends record payloads' logical
+//! (decoded) sizes alongside them.
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+use super::{
+    encryption::{self, PayloadCipher, ENCRYPTED_PREFIX},
+    DbError, DbErrorKind,
+};
+use crate::{server::metrics::Metrics, settings::Settings};
+
+/// Prefix of compressed payloads, followed by the base64 of their deflated
+/// bytes
+const COMPRESSED_PREFIX: &str = "z1:";
+
+/// Prefix of escaped payloads, followed by the payload as sent
+const ESCAPED_PREFIX: &str = "raw:";
+
+/// Prefixes of the payloads stored as sent that need escaping
+const RESERVED_PREFIXES: &[&str] = &[COMPRESSED_PREFIX, ESCAPED_PREFIX, ENCRYPTED_PREFIX];
+
+#[derive(Debug, Default)]
+pub struct PayloadCodec {
+    /// Whether payloads are compressed
+    compression: bool,
+    /// Encrypts payloads, when payload encryption is enabled
+    cipher: Option<PayloadCipher>,
+}
+
+impl PayloadCodec {
+    pub fn from_settings(settings: &Settings) -> Result<Self, DbError> {
+        Ok(Self {
+            compression: settings.payload_compression,
+            cipher: settings
+                .payload_encryption_key_file
+                .as_ref()
+                .map(|path| PayloadCipher::from_key_file(path))
+                .transpose()?,
+        })
+    }
+
+    pub fn cipher(&self) -> Option<&PayloadCipher> {
+        self.cipher.as_ref()
+    }
+
+    /// Encode a payload for storage, for the record identified by `context`
+    /// (see `encryption::payload_context`)
+    pub fn encode(
+        &self,
+        payload: String,
+        context: &str,
+        metrics: &Metrics,
+    ) -> Result<String, DbError> {
+        let payload = if self.compression {
+            let size = payload.len();
+            metrics.count_with_tags("storage.compression.bytes_in", size as i64, None);
+            match compress(&payload)? {
+                Some(compressed) => {
+                    let saved = size - compressed.len();
+                    metrics.count_with_tags("storage.compression.bytes_saved", saved as i64, None);
+                    compressed
+                }
+                None => escape(payload),
+            }
+        } else {
+            escape(payload)
+        };
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&payload, context),
+            None => Ok(payload),
+        }
+    }
+
+    /// Decode a stored payload of the record identified by `context`
+    pub fn decode(&self, stored: String, context: &str) -> Result<String, DbError> {
+        let payload = match &self.cipher {
+            Some(cipher) => cipher.decrypt(stored, context)?,
+            // Only the server's own encrypted payloads have this prefix
+            None if encryption::is_encrypted(&stored) => {
+                return Err(DbErrorKind::Encryption(
+                    "Encrypted payload but no payload_encryption_key_file".to_owned(),
+                )
+                .into())
+            }
+            None => stored,
+        };
+        decompress(payload)
+    }
+}
+
+/// Compress a payload, unless that doesn't make it any smaller
+fn compress(payload: &str) -> Result<Option<String>, DbError> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(payload.as_bytes())
+        .and_then(|_| encoder.finish())
+        .map(|deflated| {
+            let compressed = format!("{}{}", COMPRESSED_PREFIX, base64::encode(&deflated));
+            if compressed.len() < payload.len() {
+                Some(compressed)
+            } else {
+                None
+            }
+        })
+        .map_err(|e| DbError::internal(&format!("Couldn't compress a payload: {}", e)))
+}
+
+/// Escape a payload stored as sent, when it starts with a reserved prefix
+fn escape(payload: String) -> String {
+    if RESERVED_PREFIXES
+        .iter()
+        .any(|prefix| payload.starts_with(prefix))
+    {
+        format!("{}{}", ESCAPED_PREFIX, payload)
+    } else {
+        payload
+    }
+}
+
+/// Decompress a stored payload. Uncompressed payloads are returned as is
+/// (unescaped)
+fn decompress(stored: String) -> Result<String, DbError> {
+    if let Some(payload) = stored.strip_prefix(ESCAPED_PREFIX) {
+        return Ok(payload.to_owned());
+    }
+    let data = match stored.strip_prefix(COMPRESSED_PREFIX) {
+        Some(data) => data,
+        None => return Ok(stored),
+    };
+    let invalid = || DbErrorKind::Integrity("Invalid compressed payload".to_owned());
+    let deflated = base64::decode(data).map_err(|_| invalid())?;
+    let mut payload = String::new();
+    DeflateDecoder::new(&deflated[..])
+        .read_to_string(&mut payload)
+        .map_err(|_| invalid())?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression() {
+        let codec = PayloadCodec {
+            compression: true,
+            cipher: None,
+        };
+        let metrics = Metrics::noop();
+        let payload = r#"{"ciphertext": "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"}"#;
+        let stored = codec
+            .encode(payload.to_owned(), "42:1:b0", &metrics)
+            .unwrap();
+        assert!(stored.starts_with(COMPRESSED_PREFIX));
+        assert!(stored.len() < payload.len());
+        assert_eq!(codec.decode(stored, "42:1:b0").unwrap(), payload);
+        // Not worth compressing
+        let stored = codec.encode("{}".to_owned(), "42:1:b0", &metrics).unwrap();
+        assert_eq!(stored, "{}");
+        assert_eq!(codec.decode(stored, "42:1:b0").unwrap(), "{}");
+    }
+
+    #[test]
+    fn uncompressed() {
+        let codec = PayloadCodec::default();
+        let metrics = Metrics::noop();
+        let payload = "a".repeat(100);
+        assert_eq!(
+            codec.encode(payload.clone(), "42:1:b0", &metrics).unwrap(),
+            payload
+        );
+        // Payloads compressed before compression was disabled
+        let compressed = compress(&payload).unwrap().unwrap();
+        assert_eq!(codec.decode(compressed, "42:1:b0").unwrap(), payload);
+        assert!(codec.decode("z1:AAAA".to_owned(), "42:1:b0").is_err());
+    }
+
+    #[test]
+    fn reserved_prefixes() {
+        let metrics = Metrics::noop();
+        let compressing = PayloadCodec {
+            compression: true,
+            cipher: None,
+        };
+        for codec in &[PayloadCodec::default(), compressing] {
+            for &payload in &[
+                "z1:AAAA",
+                "z1:not base64",
+                "raw:",
+                "raw:z1:AAAA",
+                "enc2:k1:AAAA",
+            ] {
+                let stored = codec
+                    .encode(payload.to_owned(), "42:1:b0", &metrics)
+                    .unwrap();
+                assert_eq!(stored, format!("raw:{}", payload));
+                assert_eq!(codec.decode(stored, "42:1:b0").unwrap(), payload);
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Restrictions on the collections users write to
+//!
+//! Writing to a collection the first time creates its row in the shared
+//! `collections` table, so the names of non-standard collections can be
+//! limited to an allowlist and the number of them each user stores capped.
+//! Both are checked by the writes that would add a collection to a user's
+//! storage (storing BSOs and creating or committing batches), so collections
+//! the user already stores and deletes are unaffected. Standard collections
+//! are always allowed and don't count towards the cap.
+//!
+//! Collections may also have a retention policy: a maximum TTL, which is also
+//! the default TTL, applied to every BSO written to them.
+use std::collections::{HashMap, HashSet};
+
+use super::{DbError, DbErrorKind, STD_COLLS};
+use crate::settings::Settings;
+
+#[derive(Debug, Default)]
+pub struct CollectionPolicy {
+    /// Most non-standard collections a user may store, unlimited when 0
+    max_custom_collections: u32,
+    /// The only non-standard collection names allowed, when set
+    allowlist: Option<HashSet<String>>,
+    /// Maximum (and default) TTLs, in seconds, of the named collections' BSOs
+    max_ttls: HashMap<String, u32>,
+}
+
+impl CollectionPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_custom_collections: settings.max_custom_collections,
+            allowlist: settings.collection_allowlist.as_ref().map(|names| {
+                names
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }),
+            max_ttls: settings.limits.max_collection_ttls.clone(),
+        }
+    }
+
+    /// Whether the number of a user's non-standard collections is capped
+    pub fn is_capped(&self) -> bool {
+        self.max_custom_collections > 0
+    }
+
+    /// Check that a collection name may be written to
+    pub fn check_name(&self, name: &str) -> Result<(), DbError> {
+        match &self.allowlist {
+            Some(allowlist) if !is_standard_collection(name) && !allowlist.contains(name) => {
+                Err(DbErrorKind::CollectionNotAllowed(name.to_owned()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that a user storing `count` non-standard collections may add
+    /// another
+    pub fn check_count(&self, count: usize) -> Result<(), DbError> {
+        if self.is_capped() && count >= self.max_custom_collections as usize {
+            return Err(DbErrorKind::CollectionLimit.into());
+        }
+        Ok(())
+    }
+
+    /// Apply the collection's retention policy, if any, to a BSO's TTL
+    pub fn ttl(&self, collection: &str, ttl: Option<u32>) -> Option<u32> {
+        match self.max_ttls.get(collection) {
+            Some(&max_ttl) => Some(ttl.map_or(max_ttl, |ttl| ttl.min(max_ttl))),
+            None => ttl,
+        }
+    }
+}
+
+/// Whether the named collection is a standard one
+pub fn is_standard_collection(name: &str) -> bool {
+    STD_COLLS.iter().any(|&(_, std_name)| std_name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist() {
+        let policy = CollectionPolicy {
+            allowlist: Some(vec!["custom".to_owned()].into_iter().collect()),
+            ..Default::default()
+        };
+        assert!(policy.check_name("bookmarks").is_ok());
+        assert!(policy.check_name("custom").is_ok());
+        assert!(policy.check_name("other").is_err());
+        assert!(CollectionPolicy::default().check_name("other").is_ok());
+    }
+
+    #[test]
+    fn cap() {
+        let policy = CollectionPolicy {
+            max_custom_collections: 2,
+            ..Default::default()
+        };
+        assert!(policy.is_capped());
+        assert!(policy.check_count(1).is_ok());
+        assert!(policy.check_count(2).is_err());
+        assert!(!CollectionPolicy::default().is_capped());
+        assert!(CollectionPolicy::default().check_count(1000).is_ok());
+    }
+
+    #[test]
+    fn ttl() {
+        let mut max_ttls = HashMap::new();
+        max_ttls.insert("tabs".to_owned(), 1000);
+        let policy = CollectionPolicy {
+            max_ttls,
+            ..Default::default()
+        };
+        // The collection's maximum TTL is also its default
+        assert_eq!(policy.ttl("tabs", None), Some(1000));
+        assert_eq!(policy.ttl("tabs", Some(2000)), Some(1000));
+        assert_eq!(policy.ttl("tabs", Some(10)), Some(10));
+        assert_eq!(policy.ttl("history", None), None);
+        assert_eq!(policy.ttl("history", Some(2000)), Some(2000));
+    }
+}
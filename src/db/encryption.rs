@@ -0,0 +1,260 @@
+//! Server side encryption of stored BSO payloads
+//!
+//! Payloads are encrypted with AES-256-GCM under the primary key of a
+//! locally configured key file before they're written to either backend, and
+//! decrypted as they're read back. Each stored payload names the key that
+//! encrypted it, so keys can be rotated: new writes use the primary key while
+//! the older keys remain in the key file until the `reencrypt_payloads` job
+//! has rewritten their payloads under the primary key.
+//!
+//! The key id and the record a payload belongs to (see `payload_context`)
+//! are authenticated along with it, so a stored payload can't be passed off
+//! as another record's.
+//!
+//! Payloads stored before encryption was enabled lack the `enc2:` prefix and
+//! are returned as is (see `codec` for how payloads sent with that prefix are
+//! escaped).
+//!
+//! Quota accounting is unaffected: the backends record payloads' logical
+//! (unencrypted) sizes alongside them.
+use std::{collections::HashMap, fmt, fs};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde_derive::Deserialize;
+
+use super::{DbError, DbErrorKind};
+
+/// Prefix of encrypted payloads, followed by `<key id>:<base64 of the nonce
+/// and ciphertext>`
+pub const ENCRYPTED_PREFIX: &str = "enc2:";
+
+/// Identifies the record a payload belongs to: the user (their legacy id, or
+/// `<fxa_uid>:<fxa_kid>`), the collection and the BSO id. It's the same for
+/// every table the payload's copied to (batches, history, deleted storage).
+pub fn payload_context(user: &str, collection_id: i32, id: &str) -> String {
+    format!("{}:{}:{}", user, collection_id, id)
+}
+
+/// The key file, e.g.:
+///
+/// ```json
+/// {"primary": "2020-10", "keys": {"2020-10": "<base64 of 32 bytes>"}}
+/// ```
+#[derive(Deserialize)]
+struct KeyFile {
+    primary: String,
+    keys: HashMap<String, String>,
+}
+
+pub struct PayloadCipher {
+    /// Id of the key new payloads are encrypted with
+    primary: String,
+    keys: HashMap<String, LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl fmt::Debug for PayloadCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut key_ids: Vec<_> = self.keys.keys().collect();
+        key_ids.sort();
+        f.debug_struct("PayloadCipher")
+            .field("primary", &self.primary)
+            .field("keys", &key_ids)
+            .finish()
+    }
+}
+
+fn encryption_error(msg: String) -> DbError {
+    DbErrorKind::Encryption(msg).into()
+}
+
+impl PayloadCipher {
+    /// Load the keys from a key file
+    pub fn from_key_file(path: &str) -> Result<Self, DbError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| encryption_error(format!("Couldn't read {}: {}", path, e)))?;
+        Self::from_json(&contents)
+            .map_err(|e| encryption_error(format!("Invalid key file {}: {}", path, e)))
+    }
+
+    fn from_json(contents: &str) -> Result<Self, String> {
+        let key_file: KeyFile = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+        let mut keys = HashMap::new();
+        for (key_id, key) in key_file.keys {
+            if key_id.is_empty() || key_id.contains(':') {
+                return Err(format!("Invalid key id {:?}", key_id));
+            }
+            let key = base64::decode(&key)
+                .ok()
+                .and_then(|key| UnboundKey::new(&AES_256_GCM, &key).ok())
+                .ok_or_else(|| format!("Key {:?} isn't 32 bytes of base64", key_id))?;
+            keys.insert(key_id, LessSafeKey::new(key));
+        }
+        if !keys.contains_key(&key_file.primary) {
+            return Err(format!("Unknown primary key {:?}", key_file.primary));
+        }
+        Ok(Self {
+            primary: key_file.primary,
+            keys,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Encrypt a payload under the primary key, for the record identified by
+    /// `context`
+    pub fn encrypt(&self, payload: &str, context: &str) -> Result<String, DbError> {
+        let key = &self.keys[&self.primary];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| encryption_error("Couldn't generate a nonce".to_owned()))?;
+        let mut sealed = payload.as_bytes().to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad(&self.primary, context)),
+            &mut sealed,
+        )
+        .map_err(|_| encryption_error("Couldn't encrypt a payload".to_owned()))?;
+        let mut data = nonce.to_vec();
+        data.append(&mut sealed);
+        Ok(format!(
+            "{}{}:{}",
+            ENCRYPTED_PREFIX,
+            self.primary,
+            base64::encode(&data)
+        ))
+    }
+
+    /// Decrypt a stored payload of the record identified by `context`
+    pub fn decrypt(&self, stored: String, context: &str) -> Result<String, DbError> {
+        let (key_id, data) = match split_encrypted(&stored) {
+            Some(parts) => parts,
+            None => return Ok(stored),
+        };
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| encryption_error(format!("Unknown payload key {:?}", key_id)))?;
+        let invalid = || encryption_error("Invalid encrypted payload".to_owned());
+        let mut data = base64::decode(data).map_err(|_| invalid())?;
+        if data.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let mut sealed = data.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&data).map_err(|_| invalid())?;
+        let payload = key
+            .open_in_place(nonce, Aad::from(aad(key_id, context)), &mut sealed)
+            .map_err(|_| invalid())?;
+        String::from_utf8(payload.to_vec()).map_err(|_| invalid())
+    }
+
+    /// Whether a stored payload isn't encrypted under the primary key, with
+    /// its record's context
+    pub fn needs_reencryption(&self, stored: &str) -> bool {
+        match split_encrypted(stored) {
+            Some((key_id, _)) => key_id != self.primary,
+            None => true,
+        }
+    }
+}
+
+/// The associated data authenticated along with a payload: its key id, then
+/// its record's context
+fn aad(key_id: &str, context: &str) -> Vec<u8> {
+    format!("{}\n{}", key_id, context).into_bytes()
+}
+
+/// Split an encrypted payload into its key id and data
+fn split_encrypted(stored: &str) -> Option<(&str, &str)> {
+    let mut parts = stored.strip_prefix(ENCRYPTED_PREFIX)?.splitn(2, ':');
+    Some((parts.next()?, parts.next()?))
+}
+
+/// Whether a stored payload is encrypted
+pub fn is_encrypted(stored: &str) -> bool {
+    split_encrypted(stored).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY1: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+    const KEY2: &str = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=";
+
+    fn cipher(primary: &str) -> PayloadCipher {
+        PayloadCipher::from_json(&format!(
+            r#"{{"primary": "{}", "keys": {{"k1": "{}", "k2": "{}"}}}}"#,
+            primary, KEY1, KEY2
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let cipher = cipher("k1");
+        let payload = r#"{"ciphertext": "x", "IV": "y", "hmac": "z"}"#;
+        let stored = cipher.encrypt(payload, "42:1:b0").unwrap();
+        assert!(stored.starts_with("enc2:k1:"));
+        assert!(!stored.contains("ciphertext"));
+        assert_ne!(stored, cipher.encrypt(payload, "42:1:b0").unwrap());
+        assert_eq!(cipher.decrypt(stored, "42:1:b0").unwrap(), payload);
+    }
+
+    #[test]
+    fn rotation() {
+        let stored = cipher("k1").encrypt("payload", "42:1:b0").unwrap();
+        let rotated = cipher("k2");
+        assert!(rotated.needs_reencryption(&stored));
+        assert_eq!(rotated.decrypt(stored, "42:1:b0").unwrap(), "payload");
+        let reencrypted = rotated.encrypt("payload", "42:1:b0").unwrap();
+        assert!(!rotated.needs_reencryption(&reencrypted));
+    }
+
+    #[test]
+    fn plaintext() {
+        let cipher = cipher("k1");
+        assert!(cipher.needs_reencryption("payload"));
+        assert_eq!(
+            cipher.decrypt("payload".to_owned(), "42:1:b0").unwrap(),
+            "payload"
+        );
+        assert!(!is_encrypted("payload"));
+        assert!(is_encrypted(&cipher.encrypt("payload", "42:1:b0").unwrap()));
+    }
+
+    #[test]
+    fn tampered() {
+        let cipher = cipher("k1");
+        let stored = cipher.encrypt("payload", "42:1:b0").unwrap();
+        // Claiming another key fails authentication
+        let swapped = stored.replacen("enc2:k1:", "enc2:k2:", 1);
+        assert!(cipher.decrypt(swapped, "42:1:b0").is_err());
+        // As does claiming it's another record's
+        assert!(cipher.decrypt(stored.clone(), "42:1:b1").is_err());
+        assert!(cipher.decrypt(stored.clone(), "43:1:b0").is_err());
+        assert!(cipher
+            .decrypt("enc2:k3:AAAA".to_owned(), "42:1:b0")
+            .is_err());
+        assert!(cipher
+            .decrypt("enc2:k1:AAAA".to_owned(), "42:1:b0")
+            .is_err());
+    }
+
+    #[test]
+    fn invalid_key_files() {
+        let invalid = |json: &str| PayloadCipher::from_json(json).is_err();
+        assert!(invalid(r#"{"primary": "k2", "keys": {"k1": "MDEy"}}"#));
+        assert!(invalid(&format!(
+            r#"{{"primary": "k2", "keys": {{"k1": "{}"}}}}"#,
+            KEY1
+        )));
+        assert!(invalid(&format!(
+            r#"{{"primary": "a:b", "keys": {{"a:b": "{}"}}}}"#,
+            KEY1
+        )));
+    }
+}
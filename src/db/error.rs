@@ -55,6 +55,15 @@ pub enum DbErrorKind {
 
     #[fail(display = "User over quota")]
     Quota,
+
+    #[fail(display = "Payload encryption error: {}", _0)]
+    Encryption(String),
+
+    #[fail(display = "Collection not allowed: {}", _0)]
+    CollectionNotAllowed(String),
+
+    #[fail(display = "User has too many collections")]
+    CollectionLimit,
 }
 
 impl DbError {
@@ -79,6 +88,8 @@ impl From<Context<DbErrorKind>> for DbError {
             //  * desktop bug: https://bugzilla.mozilla.org/show_bug.cgi?id=959034
             //  * android bug: https://bugzilla.mozilla.org/show_bug.cgi?id=959032
             DbErrorKind::Conflict => StatusCode::SERVICE_UNAVAILABLE,
+            DbErrorKind::CollectionNotAllowed(_) => StatusCode::BAD_REQUEST,
+            DbErrorKind::CollectionLimit => StatusCode::FORBIDDEN,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -5,19 +5,29 @@ use futures::future;
 
 use super::*;
 
+/// What the mock's `get_user_limits` returns: the user's overrides, or
+/// `None` to fail loading them
+type MockUserLimits = Option<results::GetUserLimits>;
+
 #[derive(Clone, Debug)]
-pub struct MockDbPool;
+pub struct MockDbPool {
+    user_limits: MockUserLimits,
+}
 
 impl MockDbPool {
     pub fn new() -> Self {
-        MockDbPool
+        Self::with_user_limits(Some(None))
+    }
+
+    pub fn with_user_limits(user_limits: MockUserLimits) -> Self {
+        MockDbPool { user_limits }
     }
 }
 
 #[async_trait(?Send)]
 impl DbPool for MockDbPool {
     async fn get<'a>(&'a self) -> ApiResult<Box<dyn Db<'a>>> {
-        Ok(Box::new(MockDb::new()) as Box<dyn Db<'a>>)
+        Ok(Box::new(MockDb::with_user_limits(self.user_limits.clone())) as Box<dyn Db<'a>>)
     }
 
     fn state(&self) -> results::PoolState {
@@ -34,11 +44,17 @@ impl DbPool for MockDbPool {
 }
 
 #[derive(Clone, Debug)]
-pub struct MockDb;
+pub struct MockDb {
+    user_limits: MockUserLimits,
+}
 
 impl MockDb {
     pub fn new() -> Self {
-        MockDb
+        Self::with_user_limits(Some(None))
+    }
+
+    pub fn with_user_limits(user_limits: MockUserLimits) -> Self {
+        MockDb { user_limits }
     }
 }
 
@@ -75,6 +91,16 @@ impl<'a> Db<'a> for MockDb {
         Box::pin(future::ok(true))
     }
 
+    fn get_user_limits(
+        &self,
+        _params: params::GetUserLimits,
+    ) -> DbFuture<'_, results::GetUserLimits> {
+        Box::pin(match self.user_limits.clone() {
+            Some(user_limits) => future::ok(user_limits),
+            None => future::err(DbError::internal("Mock user limits failure").into()),
+        })
+    }
+
     mock_db_method!(lock_for_read, LockCollection);
     mock_db_method!(lock_for_write, LockCollection);
     mock_db_method!(get_collection_timestamps, GetCollectionTimestamps);
@@ -85,10 +111,24 @@ impl<'a> Db<'a> for MockDb {
     mock_db_method!(get_storage_usage, GetStorageUsage);
     mock_db_method!(get_quota_usage, GetQuotaUsage);
     mock_db_method!(delete_storage, DeleteStorage);
+    mock_db_method!(archive_storage, ArchiveStorage);
+    mock_db_method!(restore_storage, RestoreStorage);
+    mock_db_method!(purge_deleted_storage, PurgeDeletedStorage);
+    mock_db_method!(record_audit, RecordAudit);
+    mock_db_method!(get_audit_log, GetAuditLog);
+    mock_db_method!(reencrypt_payloads, ReencryptPayloads);
+    mock_db_method!(put_user_limits, PutUserLimits);
+    mock_db_method!(delete_user_limits, DeleteUserLimits);
+    mock_db_method!(get_revocations, GetRevocations);
+    mock_db_method!(put_revocation, PutRevocation);
+    mock_db_method!(delete_revocation, DeleteRevocation);
     mock_db_method!(delete_collection, DeleteCollection);
     mock_db_method!(delete_bsos, DeleteBsos);
     mock_db_method!(get_bsos, GetBsos);
     mock_db_method!(get_bso_ids, GetBsoIds);
+    mock_db_method!(get_deleted_bsos, GetDeletedBsos);
+    mock_db_method!(get_bso_revisions, GetBsoRevisions);
+    mock_db_method!(get_inactive_users, GetInactiveUsers);
     mock_db_method!(post_bsos, PostBsos);
     mock_db_method!(delete_bso, DeleteBso);
     mock_db_method!(get_bso, GetBso, Option<results::GetBso>);
@@ -120,7 +160,6 @@ impl<'a> Db<'a> for MockDb {
     #[cfg(test)]
     fn clear_coll_cache(&self) {}
 
-    #[cfg(test)]
     fn set_quota(&mut self, _: bool, _: usize) {}
 }
 
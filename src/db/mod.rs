@@ -1,5 +1,8 @@
 //! Generic db abstration.
 
+pub mod codec;
+pub mod collections;
+pub mod encryption;
 pub mod error;
 pub mod mock;
 pub mod mysql;
@@ -11,13 +14,13 @@ mod tests;
 pub mod transaction;
 pub mod util;
 
-use std::{fmt::Debug, time::Duration};
+use std::{fmt::Debug, str::FromStr, time::Duration};
 
 use async_trait::async_trait;
-use cadence::{Gauged, StatsdClient};
+use cadence::{Counted, Gauged, StatsdClient};
 use futures::future::{self, LocalBoxFuture, TryFutureExt};
 use lazy_static::lazy_static;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
 pub use self::error::{DbError, DbErrorKind};
@@ -57,6 +60,12 @@ pub const FIRST_CUSTOM_COLLECTION_ID: i32 = 101;
 /// Rough guesstimate of the maximum reasonable life span of a batch
 pub const BATCH_LIFETIME: i64 = 2 * 60 * 60 * 1000; // 2 hours, in milliseconds
 
+/// The states of a copy of deleted storage (deleted_storage.state): while
+/// it's copied, once it can be restored and while it's restored
+pub const DELETED_STORAGE_COPYING: i32 = 0;
+pub const DELETED_STORAGE_KEPT: i32 = 1;
+pub const DELETED_STORAGE_RESTORING: i32 = 2;
+
 type DbFuture<'a, T> = LocalBoxFuture<'a, Result<T, ApiError>>;
 
 #[async_trait(?Send)]
@@ -125,6 +134,83 @@ pub trait Db<'a>: Debug + 'a {
     fn delete_storage(&self, params: params::DeleteStorage)
         -> DbFuture<'_, results::DeleteStorage>;
 
+    /// Copy up to `limit` more of the user's records for `restore_storage`
+    /// to restore for the grace period, before the storage's deleted. An
+    /// earlier copy still in its grace period is kept instead, unless the
+    /// storage was modified since it was taken: deleting again what was
+    /// already deleted (or restored) is the likelier mistake. Copying is done
+    /// once fewer than `limit` records are copied.
+    fn archive_storage(
+        &self,
+        params: params::ArchiveStorage,
+    ) -> DbFuture<'_, results::ArchiveStorage>;
+
+    /// Restore up to `limit` records of the copy kept by `archive_storage`,
+    /// replacing the user's storage on the first call, its records and
+    /// collections modified now so clients fetch them again. Restoring is
+    /// done once fewer than `limit` records are restored.
+    fn restore_storage(
+        &self,
+        params: params::RestoreStorage,
+    ) -> DbFuture<'_, results::RestoreStorage>;
+
+    /// Purge up to `limit` records and copies of deleted storage whose grace
+    /// period is over
+    fn purge_deleted_storage(
+        &self,
+        params: params::PurgeDeletedStorage,
+    ) -> DbFuture<'_, results::PurgeDeletedStorage>;
+
+    /// Record a destructive operation in the user's audit log, pruning the
+    /// entries kept long enough
+    fn record_audit(&self, params: params::RecordAudit) -> DbFuture<'_, results::RecordAudit>;
+
+    /// The user's audit log, newest first
+    fn get_audit_log(&self, params: params::GetAuditLog) -> DbFuture<'_, results::GetAuditLog>;
+
+    /// Rewrite the user's stored payloads (batches, deleted storage and BSO
+    /// history included) that aren't encrypted under the primary payload
+    /// encryption key, skipping those written concurrently
+    fn reencrypt_payloads(
+        &self,
+        params: params::ReencryptPayloads,
+    ) -> DbFuture<'_, results::ReencryptPayloads>;
+
+    /// The user's overrides of the server limits, if any
+    fn get_user_limits(
+        &self,
+        params: params::GetUserLimits,
+    ) -> DbFuture<'_, results::GetUserLimits>;
+
+    /// Replace the user's overrides of the server limits
+    fn put_user_limits(
+        &self,
+        params: params::PutUserLimits,
+    ) -> DbFuture<'_, results::PutUserLimits>;
+
+    /// Remove the user's overrides of the server limits
+    fn delete_user_limits(
+        &self,
+        params: params::DeleteUserLimits,
+    ) -> DbFuture<'_, results::DeleteUserLimits>;
+
+    /// Every user's token revocations
+    fn get_revocations(
+        &self,
+        params: params::GetRevocations,
+    ) -> DbFuture<'_, results::GetRevocations>;
+
+    /// Add a token revocation, replacing any for the same key, and prune
+    /// those that no longer match an unexpired token
+    fn put_revocation(&self, params: params::PutRevocation)
+        -> DbFuture<'_, results::PutRevocation>;
+
+    /// Remove a token revocation
+    fn delete_revocation(
+        &self,
+        params: params::DeleteRevocation,
+    ) -> DbFuture<'_, results::DeleteRevocation>;
+
     fn delete_collection(
         &self,
         params: params::DeleteCollection,
@@ -136,6 +222,19 @@ pub trait Db<'a>: Debug + 'a {
 
     fn get_bso_ids(&self, params: params::GetBsos) -> DbFuture<'_, results::GetBsoIds>;
 
+    fn get_deleted_bsos(
+        &self,
+        params: params::GetDeletedBsos,
+    ) -> DbFuture<'_, results::GetDeletedBsos>;
+
+    /// The revisions current at a given time of the collection's BSOs
+    /// modified or deleted since, for those whose revisions are kept (see
+    /// `Settings::bso_history`): `deleted` for those that didn't exist then.
+    fn get_bso_revisions(
+        &self,
+        params: params::GetBsoRevisions,
+    ) -> DbFuture<'_, results::GetBsoRevisions>;
+
     fn post_bsos(&self, params: params::PostBsos) -> DbFuture<'_, results::PostBsos>;
 
     fn delete_bso(&self, params: params::DeleteBso) -> DbFuture<'_, results::DeleteBso>;
@@ -167,6 +266,13 @@ pub trait Db<'a>: Debug + 'a {
 
     fn check(&self) -> DbFuture<'_, results::Check>;
 
+    /// List the users whose storage hasn't been modified since a given
+    /// time, for the inactive account purge
+    fn get_inactive_users(
+        &self,
+        params: params::GetInactiveUsers,
+    ) -> DbFuture<'_, results::GetInactiveUsers>;
+
     /// Retrieve the timestamp for an item/collection
     ///
     /// Modeled on the Python `get_resource_timestamp` function.
@@ -216,6 +322,10 @@ pub trait Db<'a>: Debug + 'a {
         )
     }
 
+    /// Set whether quotas are enforced, and the quota of the users this Db
+    /// is used for (the `max_quota_limit` by default)
+    fn set_quota(&mut self, enabled: bool, limit: usize);
+
     /// Internal methods used by the db tests
 
     fn get_collection_id(&self, name: String) -> DbFuture<'_, i32>;
@@ -237,9 +347,6 @@ pub trait Db<'a>: Debug + 'a {
 
     #[cfg(test)]
     fn clear_coll_cache(&self);
-
-    #[cfg(test)]
-    fn set_quota(&mut self, enabled: bool, limit: usize);
 }
 
 impl<'a> Clone for Box<dyn Db<'a>> {
@@ -263,6 +370,84 @@ impl Default for Sorting {
     }
 }
 
+/// A destructive operation recorded in the audit log
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOp {
+    DeleteStorage,
+    DeleteCollection,
+    DeleteBsos,
+    DeleteBso,
+}
+
+impl AuditOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditOp::DeleteStorage => "delete_storage",
+            AuditOp::DeleteCollection => "delete_collection",
+            AuditOp::DeleteBsos => "delete_bsos",
+            AuditOp::DeleteBso => "delete_bso",
+        }
+    }
+}
+
+impl FromStr for AuditOp {
+    type Err = DbError;
+
+    fn from_str(op: &str) -> Result<Self, Self::Err> {
+        Ok(match op {
+            "delete_storage" => AuditOp::DeleteStorage,
+            "delete_collection" => AuditOp::DeleteCollection,
+            "delete_bsos" => AuditOp::DeleteBsos,
+            "delete_bso" => AuditOp::DeleteBso,
+            _ => Err(DbErrorKind::Integrity(format!("Invalid audit op: {}", op)))?,
+        })
+    }
+}
+
+/// Encode the offset token for the page following one whose items had the
+/// given `modifieds`, when paging from `offset`/`timestamp`.
+///
+/// `newest`/`oldest` orderings page by keyset: the token is
+/// "timestamp:offset", the next page resuming at the last item's modified
+/// timestamp (inclusive) and skipping the `offset` items at that timestamp
+/// already returned. Other orderings use a simple numeric offset.
+pub fn encode_next_offset(
+    sort: Sorting,
+    offset: u64,
+    timestamp: Option<i64>,
+    modifieds: &[i64],
+) -> String {
+    let numeric_offset = || (offset + modifieds.len() as u64).to_string();
+    if sort != Sorting::Newest && sort != Sorting::Oldest {
+        return numeric_offset();
+    }
+    let bound = match modifieds.last() {
+        Some(bound) => *bound,
+        None => {
+            return match timestamp {
+                Some(timestamp) => format!("{}:{}", timestamp, offset),
+                None => offset.to_string(),
+            }
+        }
+    };
+    // Count how many of the items share that timestamp, and hence will need
+    // to be skipped over
+    let mut calc_offset = modifieds.iter().rev().take_while(|&&m| m == bound).count() as u64;
+    if calc_offset == modifieds.len() as u64 {
+        // The entire page shares the timestamp, as may the items skipped
+        // over to reach it
+        match timestamp {
+            Some(timestamp) if timestamp == bound => calc_offset += offset,
+            // A numeric offset doesn't tell us how many of the skipped items
+            // share the timestamp
+            None if offset > 0 => return numeric_offset(),
+            _ => (),
+        }
+    }
+    format!("{}:{}", bound, calc_offset)
+}
+
 /// Create/initialize a pool of managed Db connections
 pub async fn pool_from_settings(
     settings: &Settings,
@@ -309,3 +494,105 @@ pub fn spawn_pool_periodic_reporter(
     });
     Ok(())
 }
+
+/// Most records copied, restored or purged per transaction, keeping
+/// transactions within Spanner's mutation limit
+pub const DELETED_STORAGE_CHUNK: u32 = 1000;
+
+/// Copy the user's storage for `restore_storage` before it's deleted, a
+/// chunk at a time within the deletion's transaction, so a deletion that
+/// fails leaves no copy behind
+pub async fn archive_storage(
+    db: Box<dyn Db<'_> + '_>,
+    user_id: &HawkIdentifier,
+    grace_period: u32,
+) -> ApiResult<()> {
+    loop {
+        let archived = db
+            .archive_storage(params::ArchiveStorage {
+                user_id: user_id.clone(),
+                grace_period,
+                limit: DELETED_STORAGE_CHUNK,
+            })
+            .await?;
+        if archived < DELETED_STORAGE_CHUNK as usize {
+            return Ok(());
+        }
+    }
+}
+
+/// Restore the user's deleted storage, a chunk per transaction, returning
+/// the new storage timestamp if there was storage to restore. Interrupted
+/// restores are resumed by the next call
+pub async fn restore_storage(
+    pool: &dyn DbPool,
+    user_id: &HawkIdentifier,
+) -> ApiResult<Option<SyncTimestamp>> {
+    loop {
+        let db = pool.get().await?;
+        db.begin(true).await?;
+        let result = db
+            .restore_storage(params::RestoreStorage {
+                user_id: user_id.clone(),
+                limit: DELETED_STORAGE_CHUNK,
+            })
+            .await;
+        match result {
+            Ok(_) => db.commit().await?,
+            Err(_) => db.rollback().await?,
+        }
+        match result? {
+            Some(chunk) if chunk.restored < DELETED_STORAGE_CHUNK as usize => {
+                return Ok(Some(chunk.modified))
+            }
+            Some(_) => (),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Periodically purge the deleted storage whose undelete grace period is
+/// over
+pub fn spawn_deleted_storage_purger(
+    interval: Duration,
+    metrics: StatsdClient,
+    pool: Box<dyn DbPool>,
+) {
+    actix_rt::spawn(async move {
+        loop {
+            actix_rt::time::delay_for(interval).await;
+            loop {
+                match purge_deleted_storage(pool.as_ref()).await {
+                    Ok(purged) => {
+                        metrics
+                            .count("storage.deleted_storage.purged", purged as i64)
+                            .ok();
+                        // Continue while there may be more
+                        if purged < DELETED_STORAGE_CHUNK as usize {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("⚠️ Couldn't purge deleted storage: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+async fn purge_deleted_storage(pool: &dyn DbPool) -> ApiResult<usize> {
+    let db = pool.get().await?;
+    db.begin(true).await?;
+    let result = db
+        .purge_deleted_storage(params::PurgeDeletedStorage {
+            limit: DELETED_STORAGE_CHUNK,
+        })
+        .await;
+    match result {
+        Ok(_) => db.commit().await?,
+        Err(_) => db.rollback().await?,
+    }
+    result
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use diesel::{
     self,
     dsl::sql,
@@ -23,6 +25,7 @@ const MAXTTL: i32 = 2_100_000_000;
 pub fn create(db: &MysqlDb, params: params::CreateBatch) -> Result<results::CreateBatch> {
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
+    db.check_collection_policy(user_id, collection_id, &params.collection)?;
     // Careful, there's some weirdness here!
     //
     // Sync timestamps are in seconds and quantized to two decimal places, so
@@ -51,7 +54,14 @@ pub fn create(db: &MysqlDb, params: params::CreateBatch) -> Result<results::Crea
             }
         })?;
 
-    do_append(db, batch_id, params.user_id, collection_id, params.bsos)?;
+    do_append(
+        db,
+        batch_id,
+        params.user_id,
+        collection_id,
+        params.bsos,
+        &params.collection,
+    )?;
     Ok(results::CreateBatch {
         id: encode_id(batch_id),
         size: None,
@@ -94,7 +104,14 @@ pub fn append(db: &MysqlDb, params: params::AppendToBatch) -> Result<()> {
 
     let batch_id = decode_id(&params.batch.id)?;
     let collection_id = db.get_collection_id(&params.collection)?;
-    do_append(db, batch_id, params.user_id, collection_id, params.bsos)?;
+    do_append(
+        db,
+        batch_id,
+        params.user_id,
+        collection_id,
+        params.bsos,
+        &params.collection,
+    )?;
     Ok(())
 }
 
@@ -136,7 +153,21 @@ pub fn commit(db: &MysqlDb, params: params::CommitBatch) -> Result<results::Comm
     let batch_id = decode_id(&params.batch.id)?;
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
+    db.check_collection_policy(user_id, collection_id, &params.collection)?;
     let timestamp = db.timestamp();
+    let failed = if db.expected_modified {
+        drop_conflicts(db, batch_id, user_id, collection_id)?
+    } else {
+        Default::default()
+    };
+    if db.bso_history.contains_key(&params.collection) {
+        let ids = batch_upload_items::table
+            .select(batch_upload_items::id)
+            .filter(batch_upload_items::batch_id.eq(&batch_id))
+            .filter(batch_upload_items::user_id.eq(&user_id))
+            .load::<String>(&db.conn)?;
+        db.record_revisions(user_id, &params.collection, collection_id, &ids, false)?;
+    }
     sql_query(include_str!("batch_commit.sql"))
         .bind::<BigInt, _>(user_id as i64)
         .bind::<Integer, _>(&collection_id)
@@ -162,32 +193,84 @@ pub fn commit(db: &MysqlDb, params: params::CommitBatch) -> Result<results::Comm
     Ok(results::PostBsos {
         modified: timestamp,
         success: Default::default(),
-        failed: Default::default(),
+        failed,
     })
 }
 
+/// Remove the batched BSOs whose expected modified time differs from their
+/// stored one, returning them as conflicts
+fn drop_conflicts(
+    db: &MysqlDb,
+    batch_id: i64,
+    user_id: i64,
+    collection_id: i32,
+) -> Result<HashMap<String, String>> {
+    let expected = batch_upload_items::table
+        .select((
+            batch_upload_items::id,
+            batch_upload_items::expected_modified,
+        ))
+        .filter(batch_upload_items::batch_id.eq(&batch_id))
+        .filter(batch_upload_items::user_id.eq(&user_id))
+        .filter(batch_upload_items::expected_modified.is_not_null())
+        .load::<(String, Option<i64>)>(&db.conn)?;
+    let ids: Vec<_> = expected.iter().map(|(id, _)| id.as_str()).collect();
+    let stored = db.get_bso_timestamps(user_id, collection_id, &ids)?;
+    let conflicts: Vec<_> = expected
+        .into_iter()
+        .filter(|(id, modified)| stored.get(id).copied().unwrap_or(0) != modified.unwrap_or(0))
+        .map(|(id, _)| id)
+        .collect();
+    if !conflicts.is_empty() {
+        diesel::delete(batch_upload_items::table)
+            .filter(batch_upload_items::batch_id.eq(&batch_id))
+            .filter(batch_upload_items::user_id.eq(&user_id))
+            .filter(batch_upload_items::id.eq_any(&conflicts))
+            .execute(&db.conn)?;
+    }
+    Ok(conflicts
+        .into_iter()
+        .map(|id| (id, "conflict".to_owned()))
+        .collect())
+}
+
 pub fn do_append(
     db: &MysqlDb,
     batch_id: i64,
     user_id: HawkIdentifier,
-    _collection_id: i32,
+    collection_id: i32,
     bsos: Vec<params::PostCollectionBso>,
+    collection: &str,
 ) -> Result<()> {
-    let inserts: Vec<_> = bsos
+    let inserts = bsos
         .into_iter()
         .map(|bso: params::PostCollectionBso| {
             let payload_size = bso.payload.as_ref().map(|p| p.len() as i64);
-            (
+            let id = &bso.id;
+            let payload = bso
+                .payload
+                .map(|payload| {
+                    db.encode_payload(payload, user_id.legacy_id as i64, collection_id, id)
+                })
+                .transpose()?;
+            Ok((
                 batch_upload_items::batch_id.eq(&batch_id),
                 batch_upload_items::user_id.eq(user_id.legacy_id as i64),
                 batch_upload_items::id.eq(bso.id.clone()),
                 batch_upload_items::sortindex.eq(bso.sortindex),
-                batch_upload_items::payload.eq(bso.payload),
+                batch_upload_items::payload.eq(payload),
                 batch_upload_items::payload_size.eq(payload_size),
-                batch_upload_items::ttl_offset.eq(bso.ttl.map(|ttl| ttl as i32)),
-            )
+                batch_upload_items::ttl_offset.eq(db
+                    .collection_policy
+                    .ttl(collection, bso.ttl)
+                    .map(|ttl| ttl as i32)),
+                batch_upload_items::expected_modified.eq(bso
+                    .modified
+                    .filter(|_| db.expected_modified)
+                    .map(|modified| modified.as_i64())),
+            ))
         })
-        .collect();
+        .collect::<Result<Vec<_>>>()?;
 
     insert_into(batch_upload_items::table)
         .values(inserts)
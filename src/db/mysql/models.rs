@@ -2,7 +2,9 @@ use actix_web::web::block;
 
 use futures::future::TryFutureExt;
 
-use std::{self, cell::RefCell, collections::HashMap, fmt, ops::Deref, sync::Arc};
+use std::{
+    self, cell::RefCell, collections::HashMap, convert::TryFrom, fmt, mem, ops::Deref, sync::Arc,
+};
 
 use diesel::{
     connection::TransactionManager,
@@ -13,7 +15,8 @@ use diesel::{
     r2d2::{ConnectionManager, PooledConnection},
     sql_query,
     sql_types::{BigInt, Integer, Nullable, Text},
-    Connection, ExpressionMethods, GroupByDsl, OptionalExtension, QueryDsl, RunQueryDsl,
+    BoolExpressionMethods, Connection, ExpressionMethods, GroupByDsl, OptionalExtension, QueryDsl,
+    RunQueryDsl,
 };
 #[cfg(test)]
 use diesel_logger::LoggingConnection;
@@ -22,17 +25,29 @@ use super::{
     batch,
     diesel_ext::LockInShareModeDsl,
     pool::CollectionCache,
-    schema::{bso, collections, user_collections},
+    schema::{
+        audit_log, batch_upload_items, batch_uploads, bso, bso_deletions, bso_history, collections,
+        deleted_bso, deleted_storage, revocations, user_collections, user_limits,
+    },
 };
 use crate::db::{
+    codec::PayloadCodec,
+    collections::{is_standard_collection, CollectionPolicy},
+    encode_next_offset,
+    encryption::payload_context,
     error::{DbError, DbErrorKind},
     params, results,
     util::SyncTimestamp,
-    Db, DbFuture, Sorting,
+    AuditOp, Db, DbFuture, Sorting, DELETED_STORAGE_COPYING, DELETED_STORAGE_KEPT,
+    DELETED_STORAGE_RESTORING, FIRST_CUSTOM_COLLECTION_ID,
 };
 use crate::server::metrics::Metrics;
-use crate::web::extractors::{BsoQueryParams, HawkIdentifier};
+use crate::settings::{BsoHistoryPolicy, LimitOverrides};
 use crate::web::tags::Tags;
+use crate::web::{
+    auth::{Revocation, RevocationKey},
+    extractors::{BsoQueryParams, HawkIdentifier, Offset},
+};
 
 pub type Result<T> = std::result::Result<T, DbError>;
 type Conn = PooledConnection<ConnectionManager<MysqlConnection>>;
@@ -50,6 +65,9 @@ pub const EXPIRY: &str = "ttl";
 pub const LAST_MODIFIED: &str = "last_modified";
 pub const COUNT: &str = "count";
 pub const TOTAL_BYTES: &str = "total_bytes";
+/// A BSO's logical (decoded) payload size. Rows last written before it was
+/// recorded by every write path have a 0 `payload_size`
+pub const PAYLOAD_SIZE: &str = "IF(payload_size > 0, payload_size, LENGTH(payload))";
 
 #[derive(Debug)]
 pub enum CollectionLock {
@@ -88,6 +106,16 @@ pub struct MysqlDb {
     pub metrics: Metrics,
     pub quota: usize,
     pub quota_enabled: bool,
+    /// How long, in seconds, deleted BSO ids are remembered
+    pub deleted_bso_retention: u32,
+    /// Whether POSTed BSOs' expected modified timestamps are checked
+    pub expected_modified: bool,
+    /// Which collections' previous BSO revisions are kept, and for how long
+    pub bso_history: Arc<HashMap<String, BsoHistoryPolicy>>,
+    /// Compresses and/or encrypts stored payloads
+    pub payload_codec: Arc<PayloadCodec>,
+    /// Which non-standard collections users may write to, and how many
+    pub collection_policy: Arc<CollectionPolicy>,
 }
 
 /// Despite the db conn structs being !Sync (see Arc<MysqlDbInner> above) we
@@ -119,12 +147,18 @@ impl Deref for MysqlDb {
 }
 
 impl MysqlDb {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         conn: Conn,
         coll_cache: Arc<CollectionCache>,
         metrics: &Metrics,
         quota: &usize,
         quota_enabled: bool,
+        deleted_bso_retention: u32,
+        expected_modified: bool,
+        bso_history: Arc<HashMap<String, BsoHistoryPolicy>>,
+        payload_codec: Arc<PayloadCodec>,
+        collection_policy: Arc<CollectionPolicy>,
     ) -> Self {
         let inner = MysqlDbInner {
             #[cfg(not(test))]
@@ -139,6 +173,11 @@ impl MysqlDb {
             metrics: metrics.clone(),
             quota: *quota,
             quota_enabled,
+            deleted_bso_retention,
+            expected_modified,
+            bso_history,
+            payload_codec,
+            collection_policy,
         }
     }
 
@@ -286,19 +325,533 @@ impl MysqlDb {
         Ok(())
     }
 
-    pub fn delete_storage_sync(&self, user_id: HawkIdentifier) -> Result<()> {
+    pub fn delete_storage_sync(&self, user_id: HawkIdentifier) -> Result<SyncTimestamp> {
         let user_id = user_id.legacy_id as i64;
         // Delete user data.
         delete(bso::table)
             .filter(bso::user_id.eq(user_id))
             .execute(&self.conn)?;
+        delete(bso_deletions::table)
+            .filter(bso_deletions::user_id.eq(user_id))
+            .execute(&self.conn)?;
+        delete(bso_history::table)
+            .filter(bso_history::user_id.eq(user_id))
+            .execute(&self.conn)?;
         // Delete user collections.
         delete(user_collections::table)
             .filter(user_collections::user_id.eq(user_id))
             .execute(&self.conn)?;
+        Ok(self.timestamp())
+    }
+
+    pub fn archive_storage_sync(
+        &self,
+        params: params::ArchiveStorage,
+    ) -> Result<results::ArchiveStorage> {
+        let user_id = params.user_id.legacy_id as i64;
+        let limit = params.limit as usize;
+        let timestamp = self.timestamp().as_i64();
+        let kept = deleted_storage::table
+            .select((
+                deleted_storage::expiry,
+                deleted_storage::state,
+                deleted_storage::deleted,
+            ))
+            .filter(deleted_storage::user_id.eq(user_id))
+            .first::<(i64, i32, i64)>(&self.conn)
+            .optional()?;
+        let resumed = match kept {
+            Some((expiry, state, deleted)) if expiry > timestamp => match state {
+                // An interrupted copy, resumed
+                DELETED_STORAGE_COPYING => true,
+                // Replaced once the storage's written to since it was taken
+                DELETED_STORAGE_KEPT
+                    if self
+                        .get_storage_timestamp_sync(params.user_id.clone())?
+                        .as_i64()
+                        > deleted =>
+                {
+                    false
+                }
+                // Otherwise kept: the first deletion is the likelier mistake
+                _ => return Ok(0),
+            },
+            _ => false,
+        };
+        if !resumed {
+            let has_bsos = bso::table
+                .select(bso::id)
+                .filter(bso::user_id.eq(user_id))
+                .filter(bso::expiry.gt(timestamp))
+                .first::<String>(&self.conn)
+                .optional()?
+                .is_some();
+            // Nothing to restore from an empty storage
+            if !has_bsos {
+                return Ok(0);
+            }
+            if kept.is_some() {
+                // The expired or outdated copy's purged first, a chunk at a
+                // time
+                let purged = self.delete_deleted_bsos(user_id, limit)?;
+                if purged == limit {
+                    return Ok(purged);
+                }
+                self.delete_deleted_storage(user_id)?;
+            }
+            diesel::insert_into(deleted_storage::table)
+                .values((
+                    deleted_storage::user_id.eq(user_id),
+                    deleted_storage::deleted.eq(timestamp),
+                    deleted_storage::expiry.eq(timestamp + i64::from(params.grace_period) * 1000),
+                    deleted_storage::state.eq(DELETED_STORAGE_COPYING),
+                ))
+                .execute(&self.conn)?;
+        }
+
+        let archived = sql_query(
+            "INSERT INTO deleted_bso (userid, collection, id, sortindex, payload, payload_size, ttl)
+             SELECT userid, collection, id, sortindex, payload, COALESCE(payload_size, 0), ttl
+               FROM bso
+              WHERE userid = ?
+                AND ttl > ?
+                AND NOT EXISTS (
+                    SELECT 1
+                      FROM deleted_bso
+                     WHERE deleted_bso.userid = bso.userid
+                       AND deleted_bso.collection = bso.collection
+                       AND deleted_bso.id = bso.id)
+              ORDER BY collection, id
+              LIMIT ?",
+        )
+        .bind::<BigInt, _>(user_id)
+        .bind::<BigInt, _>(timestamp)
+        .bind::<BigInt, _>(i64::from(params.limit))
+        .execute(&self.conn)?;
+        if archived < limit {
+            self.set_deleted_storage_state(user_id, DELETED_STORAGE_KEPT)?;
+        }
+        Ok(archived)
+    }
+
+    pub fn restore_storage_sync(
+        &self,
+        params: params::RestoreStorage,
+    ) -> Result<results::RestoreStorage> {
+        let legacy_id = params.user_id.legacy_id as i64;
+        let limit = params.limit as usize;
+        let timestamp = self.timestamp();
+        let kept = deleted_storage::table
+            .select((deleted_storage::expiry, deleted_storage::state))
+            .filter(deleted_storage::user_id.eq(legacy_id))
+            .first::<(i64, i32)>(&self.conn)
+            .optional()?;
+        match kept {
+            // The first chunk replaces the user's storage
+            Some((expiry, DELETED_STORAGE_KEPT)) if expiry > timestamp.as_i64() => {
+                self.delete_storage_sync(params.user_id)?;
+                self.set_deleted_storage_state(legacy_id, DELETED_STORAGE_RESTORING)?;
+            }
+            Some((_, DELETED_STORAGE_RESTORING)) => (),
+            _ => return Ok(None),
+        }
+
+        // Records written by the user since are kept
+        sql_query(
+            "INSERT INTO bso (userid, collection, id, sortindex, payload, payload_size, modified, ttl)
+             SELECT userid, collection, id, sortindex, payload, payload_size, ?, ttl
+               FROM deleted_bso
+              WHERE userid = ?
+              ORDER BY collection, id
+              LIMIT ?
+                 ON DUPLICATE KEY UPDATE bso.id = bso.id",
+        )
+        .bind::<BigInt, _>(timestamp.as_i64())
+        .bind::<BigInt, _>(legacy_id)
+        .bind::<BigInt, _>(i64::from(params.limit))
+        .execute(&self.conn)?;
+        let restored = sql_query(
+            "DELETE FROM deleted_bso
+              WHERE userid = ?
+              ORDER BY collection, id
+              LIMIT ?",
+        )
+        .bind::<BigInt, _>(legacy_id)
+        .bind::<BigInt, _>(i64::from(params.limit))
+        .execute(&self.conn)?;
+
+        if restored < limit {
+            // Done: the collections are modified as of the last chunk
+            let collection_ids = bso::table
+                .select(bso::collection_id)
+                .distinct()
+                .filter(bso::user_id.eq(legacy_id))
+                .load::<i32>(&self.conn)?;
+            for collection_id in collection_ids {
+                self.update_collection(legacy_id as u32, collection_id)?;
+            }
+            self.delete_deleted_storage(legacy_id)?;
+        }
+        Ok(Some(results::RestoredStorage {
+            restored,
+            modified: timestamp,
+        }))
+    }
+
+    pub fn purge_deleted_storage_sync(
+        &self,
+        params: params::PurgeDeletedStorage,
+    ) -> Result<results::PurgeDeletedStorage> {
+        let limit = params.limit as usize;
+        let user_ids = deleted_storage::table
+            .select(deleted_storage::user_id)
+            .filter(deleted_storage::expiry.le(self.timestamp().as_i64()))
+            .limit(i64::from(params.limit))
+            .load::<i64>(&self.conn)?;
+        let mut purged = 0;
+        for user_id in user_ids {
+            purged += self.delete_deleted_bsos(user_id, limit - purged)?;
+            if purged == limit {
+                break;
+            }
+            self.delete_deleted_storage(user_id)?;
+            purged += 1;
+            if purged == limit {
+                break;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Delete up to `limit` records of the user's deleted storage
+    fn delete_deleted_bsos(&self, user_id: i64, limit: usize) -> Result<usize> {
+        Ok(
+            sql_query("DELETE FROM deleted_bso WHERE userid = ? LIMIT ?")
+                .bind::<BigInt, _>(user_id)
+                .bind::<BigInt, _>(limit as i64)
+                .execute(&self.conn)?,
+        )
+    }
+
+    fn set_deleted_storage_state(&self, user_id: i64, state: i32) -> Result<()> {
+        diesel::update(deleted_storage::table)
+            .filter(deleted_storage::user_id.eq(user_id))
+            .set(deleted_storage::state.eq(state))
+            .execute(&self.conn)?;
         Ok(())
     }
 
+    /// Delete the copy of the user's deleted storage once its records are
+    /// deleted
+    fn delete_deleted_storage(&self, user_id: i64) -> Result<()> {
+        delete(deleted_storage::table)
+            .filter(deleted_storage::user_id.eq(user_id))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    pub fn record_audit_sync(&self, params: params::RecordAudit) -> Result<()> {
+        let user_id = params.user_id.legacy_id as i64;
+        let timestamp = params.timestamp.as_i64();
+        delete(audit_log::table)
+            .filter(audit_log::user_id.eq(user_id))
+            .filter(audit_log::expiry.le(timestamp))
+            .execute(&self.conn)?;
+        diesel::insert_into(audit_log::table)
+            .values((
+                audit_log::user_id.eq(user_id),
+                audit_log::recorded.eq(timestamp),
+                audit_log::op.eq(params.op.as_str()),
+                audit_log::collection.eq(&params.collection),
+                audit_log::count.eq(params.count),
+                audit_log::user_agent.eq(&params.user_agent),
+                audit_log::device_id.eq(&params.device_id),
+                audit_log::expiry.eq(timestamp + i64::from(params.retention) * 1000),
+            ))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// Rewrite the user's payloads not encrypted under the primary key. Each
+    /// row's only rewritten if its payload's unchanged since it was read (a
+    /// concurrent write wins): such rows are left for the next run
+    pub fn reencrypt_payloads_sync(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::ReencryptPayloads> {
+        let cipher = match self.payload_codec.cipher() {
+            Some(cipher) => cipher,
+            None => return Ok(0),
+        };
+        let user_id = user_id.legacy_id as i64;
+        let reencrypt = |payload: &str, collection_id: i32, id: &str| -> Result<Option<String>> {
+            if !cipher.needs_reencryption(payload) {
+                return Ok(None);
+            }
+            let context = payload_context(&user_id.to_string(), collection_id, id);
+            let decrypted = cipher.decrypt(payload.to_owned(), &context)?;
+            Ok(Some(cipher.encrypt(&decrypted, &context)?))
+        };
+        let mut count = 0;
+
+        let rows = bso::table
+            .select((bso::collection_id, bso::id, bso::payload))
+            .filter(bso::user_id.eq(user_id))
+            .load::<(i32, String, String)>(&self.conn)?;
+        for (collection_id, id, old) in rows {
+            if let Some(payload) = reencrypt(&old, collection_id, &id)? {
+                count += diesel::update(bso::table)
+                    .filter(bso::user_id.eq(user_id))
+                    .filter(bso::collection_id.eq(collection_id))
+                    .filter(bso::id.eq(id))
+                    .filter(bso::payload.eq(old))
+                    .set(bso::payload.eq(payload))
+                    .execute(&self.conn)? as u64;
+            }
+        }
+
+        let batches: HashMap<i64, i32> = batch_uploads::table
+            .select((batch_uploads::batch_id, batch_uploads::collection_id))
+            .filter(batch_uploads::user_id.eq(user_id))
+            .load(&self.conn)?
+            .into_iter()
+            .collect();
+        let rows = batch_upload_items::table
+            .select((
+                batch_upload_items::batch_id,
+                batch_upload_items::id,
+                batch_upload_items::payload,
+            ))
+            .filter(batch_upload_items::user_id.eq(user_id))
+            .filter(batch_upload_items::payload.is_not_null())
+            .load::<(i64, String, Option<String>)>(&self.conn)?;
+        for (batch_id, id, old) in rows {
+            let collection_id = match batches.get(&batch_id) {
+                Some(&collection_id) => collection_id,
+                None => continue,
+            };
+            let old = old.unwrap_or_default();
+            if let Some(payload) = reencrypt(&old, collection_id, &id)? {
+                count += diesel::update(batch_upload_items::table)
+                    .filter(batch_upload_items::batch_id.eq(batch_id))
+                    .filter(batch_upload_items::user_id.eq(user_id))
+                    .filter(batch_upload_items::id.eq(id))
+                    .filter(batch_upload_items::payload.eq(old))
+                    .set(batch_upload_items::payload.eq(payload))
+                    .execute(&self.conn)? as u64;
+            }
+        }
+
+        let rows = deleted_bso::table
+            .select((
+                deleted_bso::collection_id,
+                deleted_bso::id,
+                deleted_bso::payload,
+            ))
+            .filter(deleted_bso::user_id.eq(user_id))
+            .load::<(i32, String, String)>(&self.conn)?;
+        for (collection_id, id, old) in rows {
+            if let Some(payload) = reencrypt(&old, collection_id, &id)? {
+                count += diesel::update(deleted_bso::table)
+                    .filter(deleted_bso::user_id.eq(user_id))
+                    .filter(deleted_bso::collection_id.eq(collection_id))
+                    .filter(deleted_bso::id.eq(id))
+                    .filter(deleted_bso::payload.eq(old))
+                    .set(deleted_bso::payload.eq(payload))
+                    .execute(&self.conn)? as u64;
+            }
+        }
+
+        let rows = bso_history::table
+            .select((
+                bso_history::collection_id,
+                bso_history::id,
+                bso_history::modified,
+                bso_history::payload,
+            ))
+            .filter(bso_history::user_id.eq(user_id))
+            .filter(bso_history::deleted.eq(false))
+            .load::<(i32, String, i64, String)>(&self.conn)?;
+        for (collection_id, id, modified, old) in rows {
+            if let Some(payload) = reencrypt(&old, collection_id, &id)? {
+                count += diesel::update(bso_history::table)
+                    .filter(bso_history::user_id.eq(user_id))
+                    .filter(bso_history::collection_id.eq(collection_id))
+                    .filter(bso_history::id.eq(id))
+                    .filter(bso_history::modified.eq(modified))
+                    .filter(bso_history::payload.eq(old))
+                    .set(bso_history::payload.eq(payload))
+                    .execute(&self.conn)? as u64;
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn get_audit_log_sync(&self, user_id: HawkIdentifier) -> Result<results::GetAuditLog> {
+        audit_log::table
+            .select((
+                audit_log::collection,
+                audit_log::op,
+                audit_log::count,
+                audit_log::recorded,
+                audit_log::user_agent,
+                audit_log::device_id,
+            ))
+            .filter(audit_log::user_id.eq(user_id.legacy_id as i64))
+            .filter(audit_log::expiry.gt(self.timestamp().as_i64()))
+            .order((audit_log::recorded.desc(), audit_log::id.desc()))
+            .load::<(Option<String>, String, i64, i64, String, String)>(&self.conn)?
+            .into_iter()
+            .map(|(collection, op, count, recorded, user_agent, device_id)| {
+                Ok(results::AuditEntry {
+                    collection,
+                    op: op.parse::<AuditOp>()?,
+                    count,
+                    timestamp: SyncTimestamp::from_i64(recorded)?,
+                    user_agent,
+                    device_id,
+                })
+            })
+            .collect()
+    }
+
+    pub fn get_user_limits_sync(&self, user_id: HawkIdentifier) -> Result<results::GetUserLimits> {
+        let limit = |value: Option<i64>| {
+            value
+                .map(|value| {
+                    u32::try_from(value)
+                        .map_err(|e| DbError::from(DbErrorKind::Integrity(e.to_string())))
+                })
+                .transpose()
+        };
+        user_limits::table
+            .select((
+                user_limits::max_post_bytes,
+                user_limits::max_post_records,
+                user_limits::max_record_payload_bytes,
+                user_limits::max_total_bytes,
+                user_limits::max_total_records,
+                user_limits::max_quota_limit,
+            ))
+            .filter(user_limits::user_id.eq(user_id.legacy_id as i64))
+            .first::<(
+                Option<i64>,
+                Option<i64>,
+                Option<i64>,
+                Option<i64>,
+                Option<i64>,
+                Option<i64>,
+            )>(&self.conn)
+            .optional()?
+            .map(
+                |(
+                    post_bytes,
+                    post_records,
+                    record_payload_bytes,
+                    total_bytes,
+                    total_records,
+                    quota_limit,
+                )| {
+                    Ok(LimitOverrides {
+                        max_post_bytes: limit(post_bytes)?,
+                        max_post_records: limit(post_records)?,
+                        max_record_payload_bytes: limit(record_payload_bytes)?,
+                        max_total_bytes: limit(total_bytes)?,
+                        max_total_records: limit(total_records)?,
+                        max_quota_limit: limit(quota_limit)?,
+                    })
+                },
+            )
+            .transpose()
+    }
+
+    pub fn put_user_limits_sync(
+        &self,
+        params: params::PutUserLimits,
+    ) -> Result<results::PutUserLimits> {
+        let limits = params.limits;
+        diesel::replace_into(user_limits::table)
+            .values((
+                user_limits::user_id.eq(params.user_id.legacy_id as i64),
+                user_limits::max_post_bytes.eq(limits.max_post_bytes.map(i64::from)),
+                user_limits::max_post_records.eq(limits.max_post_records.map(i64::from)),
+                user_limits::max_record_payload_bytes
+                    .eq(limits.max_record_payload_bytes.map(i64::from)),
+                user_limits::max_total_bytes.eq(limits.max_total_bytes.map(i64::from)),
+                user_limits::max_total_records.eq(limits.max_total_records.map(i64::from)),
+                user_limits::max_quota_limit.eq(limits.max_quota_limit.map(i64::from)),
+            ))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    pub fn delete_user_limits_sync(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::DeleteUserLimits> {
+        let count = delete(user_limits::table)
+            .filter(user_limits::user_id.eq(user_id.legacy_id as i64))
+            .execute(&self.conn)?;
+        Ok(count > 0)
+    }
+
+    pub fn get_revocations_sync(
+        &self,
+        _: params::GetRevocations,
+    ) -> Result<results::GetRevocations> {
+        revocations::table
+            .select((
+                revocations::key_type,
+                revocations::key_value,
+                revocations::issued_before,
+            ))
+            .load::<(String, String, Option<i64>)>(&self.conn)?
+            .into_iter()
+            .map(|(key_type, key_value, issued_before)| {
+                let key = RevocationKey::from_parts(&key_type, key_value).ok_or_else(|| {
+                    DbErrorKind::Integrity(format!("Invalid revocation key type {}", key_type))
+                })?;
+                Ok(Revocation {
+                    key,
+                    issued_before: issued_before.map(|issued_before| issued_before as u64),
+                })
+            })
+            .collect()
+    }
+
+    pub fn put_revocation_sync(
+        &self,
+        params: params::PutRevocation,
+    ) -> Result<results::PutRevocation> {
+        delete(revocations::table)
+            .filter(revocations::issued_before.le(params.expired_before as i64))
+            .execute(&self.conn)?;
+        let revocation = params.revocation;
+        let (key_type, key_value) = revocation.key.to_parts();
+        diesel::replace_into(revocations::table)
+            .values((
+                revocations::key_type.eq(key_type),
+                revocations::key_value.eq(key_value),
+                revocations::issued_before.eq(revocation
+                    .issued_before
+                    .map(|issued_before| issued_before as i64)),
+            ))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    pub fn delete_revocation_sync(
+        &self,
+        key: params::DeleteRevocation,
+    ) -> Result<results::DeleteRevocation> {
+        let (key_type, key_value) = key.to_parts();
+        let count = delete(revocations::table)
+            .filter(revocations::key_type.eq(key_type))
+            .filter(revocations::key_value.eq(key_value))
+            .execute(&self.conn)?;
+        Ok(count > 0)
+    }
+
     // Deleting the collection should result in:
     //  - collection does not appear in /info/collections
     //  - X-Last-Modified timestamp at the storage level changing
@@ -308,10 +861,23 @@ impl MysqlDb {
     ) -> Result<SyncTimestamp> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
+        // Its history's kept for restoring it
+        if self.bso_history.contains_key(&params.collection) {
+            let ids = bso::table
+                .select(bso::id)
+                .filter(bso::user_id.eq(user_id))
+                .filter(bso::collection_id.eq(&collection_id))
+                .load::<String>(&self.conn)?;
+            self.record_revisions(user_id, &params.collection, collection_id, &ids, true)?;
+        }
         let mut count = delete(bso::table)
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(&collection_id))
             .execute(&self.conn)?;
+        delete(bso_deletions::table)
+            .filter(bso_deletions::user_id.eq(user_id))
+            .filter(bso_deletions::collection_id.eq(&collection_id))
+            .execute(&self.conn)?;
         count += delete(user_collections::table)
             .filter(user_collections::user_id.eq(user_id))
             .filter(user_collections::collection_id.eq(&collection_id))
@@ -324,6 +890,45 @@ impl MysqlDb {
         self.get_storage_timestamp_sync(params.user_id)
     }
 
+    /// Check that the user may add the collection to their storage, before a
+    /// write creates it.
+    ///
+    /// The user's non-standard collections are read with a locking read, so
+    /// concurrent writes creating others wait on this transaction rather than
+    /// exceeding the cap.
+    pub(super) fn check_collection_policy(
+        &self,
+        user_id: i64,
+        collection_id: i32,
+        name: &str,
+    ) -> Result<()> {
+        let policy = &self.collection_policy;
+        if is_standard_collection(name) || (!policy.is_capped() && policy.check_name(name).is_ok())
+        {
+            return Ok(());
+        }
+        // Collections locked for writing are already stored
+        if self
+            .session
+            .borrow()
+            .coll_modified_cache
+            .contains_key(&(user_id as u32, collection_id))
+        {
+            return Ok(());
+        }
+        let custom_ids: Vec<i32> = user_collections::table
+            .select(user_collections::collection_id)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.ge(FIRST_CUSTOM_COLLECTION_ID))
+            .for_update()
+            .load(&self.conn)?;
+        if custom_ids.contains(&collection_id) {
+            return Ok(());
+        }
+        policy.check_name(name)?;
+        policy.check_count(custom_ids.len())
+    }
+
     pub(super) fn get_or_create_collection_id(&self, name: &str) -> Result<i32> {
         if let Some(id) = self.coll_cache.get_id(name)? {
             return Ok(id);
@@ -397,6 +1002,11 @@ impl MysqlDb {
 
         let collection_id = self.get_or_create_collection_id(&bso.collection)?;
         let user_id: u64 = bso.user_id.legacy_id;
+        self.check_collection_policy(user_id as i64, collection_id, &bso.collection)?;
+        let bso = params::PutBso {
+            ttl: self.collection_policy.ttl(&bso.collection, bso.ttl),
+            ..bso
+        };
         let timestamp = self.timestamp().as_i64();
         if self.quota_enabled {
             let usage = self.get_quota_usage_sync(params::GetQuotaUsage {
@@ -413,13 +1023,31 @@ impl MysqlDb {
             }
         }
 
+        let payload_size = bso
+            .payload
+            .as_ref()
+            .map_or(0, |payload| payload.len() as i64);
+        let payload = bso
+            .payload
+            .clone()
+            .map(|payload| self.encode_payload(payload, user_id as i64, collection_id, &bso.id))
+            .transpose()?;
         self.conn.transaction(|| {
-            let payload = bso.payload.as_deref().unwrap_or_default();
+            if bso.payload.is_some() || bso.sortindex.is_some() {
+                self.record_revisions(
+                    user_id as i64,
+                    &bso.collection,
+                    collection_id,
+                    std::slice::from_ref(&bso.id),
+                    false,
+                )?;
+            }
+            let payload = payload.as_deref().unwrap_or_default();
             let sortindex = bso.sortindex;
             let ttl = bso.ttl.map_or(DEFAULT_BSO_TTL, |ttl| ttl);
             let q = format!(r#"
-            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry})
-            VALUES (?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, payload_size, {modified}, {expiry})
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
                 ON DUPLICATE KEY UPDATE
                    {user_id} = VALUES({user_id}),
                    {collection_id} = VALUES({collection_id}),
@@ -438,7 +1066,7 @@ impl MysqlDb {
                 "{}{}",
                 q,
                 if bso.payload.is_some() {
-                    ", payload = VALUES(payload)"
+                    ", payload = VALUES(payload), payload_size = VALUES(payload_size)"
                 } else {
                     ""
                 },
@@ -467,6 +1095,7 @@ impl MysqlDb {
                 .bind::<Text, _>(&bso.id)
                 .bind::<Nullable<Integer>, _>(sortindex)
                 .bind::<Text, _>(payload)
+                .bind::<BigInt, _>(payload_size)
                 .bind::<BigInt, _>(timestamp)
                 .bind::<BigInt, _>(timestamp + (i64::from(ttl) * 1000))
                 .execute(&self.conn)?;
@@ -511,18 +1140,20 @@ impl MysqlDb {
             query = query.filter(bso::id.eq_any(ids));
         }
 
+        let Offset { offset, timestamp } = offset.unwrap_or_default();
+        // Resume keyset pagination from the offset's timestamp
+        if let Some(timestamp) = timestamp {
+            query = match sort {
+                Sorting::Newest => query.filter(bso::modified.le(timestamp.as_i64())),
+                Sorting::Oldest => query.filter(bso::modified.ge(timestamp.as_i64())),
+                _ => query,
+            };
+        }
+
         query = match sort {
-            // issue559: Revert to previous sorting
-            /*
-            Sorting::Index => query.order(bso::id.desc()).order(bso::sortindex.desc()),
-            Sorting::Newest | Sorting::None => {
-                query.order(bso::id.desc()).order(bso::modified.desc())
-            }
-            Sorting::Oldest => query.order(bso::id.asc()).order(bso::modified.asc()),
-            */
             Sorting::Index => query.order(bso::sortindex.desc()),
-            Sorting::Newest => query.order(bso::modified.desc()),
-            Sorting::Oldest => query.order(bso::modified.asc()),
+            Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
             _ => query,
         };
 
@@ -531,12 +1162,10 @@ impl MysqlDb {
         // match the query conditions
         query = query.limit(if limit >= 0 { limit + 1 } else { limit });
 
-        let numeric_offset = offset.map_or(0, |offset| offset.offset as i64);
-
-        if numeric_offset != 0 {
+        if offset != 0 {
             // XXX: copy over this optimization:
             // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
-            query = query.offset(numeric_offset);
+            query = query.offset(offset as i64);
         }
         let mut bsos = query.load::<results::GetBso>(&self.conn)?;
 
@@ -547,11 +1176,22 @@ impl MysqlDb {
 
         let next_offset = if limit >= 0 && bsos.len() > limit as usize {
             bsos.pop();
-            Some((limit + numeric_offset).to_string())
+            let modifieds: Vec<i64> = bsos.iter().map(|r| r.modified.as_i64()).collect();
+            Some(encode_next_offset(
+                sort,
+                offset,
+                timestamp.map(|t| t.as_i64()),
+                &modifieds,
+            ))
         } else {
             None
         };
 
+        for bso in &mut bsos {
+            let payload = mem::take(&mut bso.payload);
+            bso.payload = self.decode_payload(payload, user_id, collection_id, &bso.id)?;
+        }
+
         Ok(results::GetBsos {
             items: bsos,
             offset: next_offset,
@@ -572,7 +1212,7 @@ impl MysqlDb {
         } = params.params;
 
         let mut query = bso::table
-            .select(bso::id)
+            .select((bso::id, bso::modified))
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(collection_id as i32)) // XXX:
             .filter(bso::expiry.gt(self.timestamp().as_i64()))
@@ -589,10 +1229,20 @@ impl MysqlDb {
             query = query.filter(bso::id.eq_any(ids));
         }
 
+        let Offset { offset, timestamp } = offset.unwrap_or_default();
+        // Resume keyset pagination from the offset's timestamp
+        if let Some(timestamp) = timestamp {
+            query = match sort {
+                Sorting::Newest => query.filter(bso::modified.le(timestamp.as_i64())),
+                Sorting::Oldest => query.filter(bso::modified.ge(timestamp.as_i64())),
+                _ => query,
+            };
+        }
+
         query = match sort {
             Sorting::Index => query.order(bso::sortindex.desc()),
-            Sorting::Newest => query.order(bso::modified.desc()),
-            Sorting::Oldest => query.order(bso::modified.asc()),
+            Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
             _ => query,
         };
 
@@ -601,13 +1251,13 @@ impl MysqlDb {
         // match the query conditions
         query = query.limit(if limit >= 0 { limit + 1 } else { limit });
 
-        let numeric_offset = offset.map_or(0, |offset| offset.offset as i64);
-        if numeric_offset != 0 {
+        if offset != 0 {
             // XXX: copy over this optimization:
             // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
-            query = query.offset(numeric_offset);
+            query = query.offset(offset as i64);
         }
-        let mut ids = query.load::<String>(&self.conn)?;
+        let (mut ids, mut modifieds): (Vec<String>, Vec<i64>) =
+            query.load::<(String, i64)>(&self.conn)?.into_iter().unzip();
 
         // XXX: an additional get_collection_timestamp is done here in
         // python to trigger potential CollectionNotFoundErrors
@@ -616,7 +1266,13 @@ impl MysqlDb {
 
         let next_offset = if limit >= 0 && ids.len() > limit as usize {
             ids.pop();
-            Some((limit + numeric_offset).to_string())
+            modifieds.pop();
+            Some(encode_next_offset(
+                sort,
+                offset,
+                timestamp.map(|t| t.as_i64()),
+                &modifieds,
+            ))
         } else {
             None
         };
@@ -630,7 +1286,7 @@ impl MysqlDb {
     pub fn get_bso_sync(&self, params: params::GetBso) -> Result<Option<results::GetBso>> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
-        Ok(bso::table
+        bso::table
             .select((
                 bso::id,
                 bso::modified,
@@ -643,44 +1299,380 @@ impl MysqlDb {
             .filter(bso::id.eq(&params.id))
             .filter(bso::expiry.ge(self.timestamp().as_i64()))
             .get_result::<results::GetBso>(&self.conn)
-            .optional()?)
+            .optional()?
+            .map(|bso| {
+                Ok(results::GetBso {
+                    payload: self.decode_payload(bso.payload, user_id, collection_id, &bso.id)?,
+                    ..bso
+                })
+            })
+            .transpose()
     }
 
     pub fn delete_bso_sync(&self, params: params::DeleteBso) -> Result<results::DeleteBso> {
         let user_id = params.user_id.legacy_id;
         let collection_id = self.get_collection_id(&params.collection)?;
+        self.record_revisions(
+            user_id as i64,
+            &params.collection,
+            collection_id,
+            std::slice::from_ref(&params.id),
+            true,
+        )?;
         let affected_rows = delete(bso::table)
             .filter(bso::user_id.eq(user_id as i64))
             .filter(bso::collection_id.eq(&collection_id))
-            .filter(bso::id.eq(params.id))
+            .filter(bso::id.eq(&params.id))
             .filter(bso::expiry.gt(&self.timestamp().as_i64()))
             .execute(&self.conn)?;
         if affected_rows == 0 {
             Err(DbErrorKind::BsoNotFound)?
         }
+        self.record_deletions(user_id as i64, collection_id, &[params.id])?;
         self.update_collection(user_id as u32, collection_id)
     }
 
     pub fn delete_bsos_sync(&self, params: params::DeleteBsos) -> Result<results::DeleteBsos> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
-        delete(bso::table)
+        let ids = bso::table
+            .select(bso::id)
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(&collection_id))
             .filter(bso::id.eq_any(params.ids))
+            .load::<String>(&self.conn)?;
+        self.record_revisions(user_id, &params.collection, collection_id, &ids, true)?;
+        delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq_any(&ids))
             .execute(&self.conn)?;
+        self.record_deletions(user_id, collection_id, &ids)?;
         self.update_collection(user_id as u32, collection_id)
     }
 
+    /// Remember the ids of deleted BSOs for the deleted record feed, pruning
+    /// those that have been kept long enough
+    fn record_deletions(&self, user_id: i64, collection_id: i32, ids: &[String]) -> Result<()> {
+        let timestamp = self.timestamp().as_i64();
+        delete(bso_deletions::table)
+            .filter(bso_deletions::user_id.eq(user_id))
+            .filter(bso_deletions::collection_id.eq(&collection_id))
+            .filter(bso_deletions::expiry.le(timestamp))
+            .execute(&self.conn)?;
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let expiry = timestamp + i64::from(self.deleted_bso_retention) * 1000;
+        let rows: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                (
+                    bso_deletions::user_id.eq(user_id),
+                    bso_deletions::collection_id.eq(collection_id),
+                    bso_deletions::id.eq(id),
+                    bso_deletions::deleted.eq(timestamp),
+                    bso_deletions::expiry.eq(expiry),
+                )
+            })
+            .collect();
+        diesel::replace_into(bso_deletions::table)
+            .values(&rows)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// The ids of the BSOs deleted after `since`: both those deleted by
+    /// clients (unless they've since been rewritten) and those that expired
+    pub fn get_deleted_bsos_sync(
+        &self,
+        params: params::GetDeletedBsos,
+    ) -> Result<results::GetDeletedBsos> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let timestamp = self.timestamp().as_i64();
+        let limit = params.limit.map(i64::from).unwrap_or(-1);
+        let mut items = sql_query(
+            "SELECT d.id, d.deleted
+               FROM bso_deletions d
+              WHERE d.userid = ?
+                AND d.collection = ?
+                AND d.deleted > ?
+                AND d.ttl > ?
+                AND NOT EXISTS (
+                    SELECT 1
+                      FROM bso b
+                     WHERE b.userid = d.userid
+                       AND b.collection = d.collection
+                       AND b.id = d.id)
+              UNION ALL
+             SELECT id, ttl AS deleted
+               FROM bso
+              WHERE userid = ?
+                AND collection = ?
+                AND ttl > ?
+                AND ttl <= ?
+              ORDER BY deleted, id
+              LIMIT ? OFFSET ?",
+        )
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(params.since.as_i64())
+        .bind::<BigInt, _>(timestamp)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(params.since.as_i64())
+        .bind::<BigInt, _>(timestamp)
+        // fetch an extra row to detect if there are more rows
+        .bind::<BigInt, _>(if limit >= 0 { limit + 1 } else { i64::MAX })
+        .bind::<BigInt, _>(params.offset as i64)
+        .load::<results::DeletedBso>(&self.conn)?;
+        let offset = if limit >= 0 && items.len() > limit as usize {
+            items.pop();
+            Some((params.offset + items.len() as u64).to_string())
+        } else {
+            None
+        };
+        Ok(results::GetDeletedBsos { items, offset })
+    }
+
+    /// Keep the current revisions of the BSOs about to be rewritten, or
+    /// deleted (followed by a tombstone), and tombstones for those about to be
+    /// created, when the collection's revisions are kept, pruning those kept
+    /// long enough
+    pub(super) fn record_revisions(
+        &self,
+        user_id: i64,
+        collection: &str,
+        collection_id: i32,
+        ids: &[String],
+        deleted: bool,
+    ) -> Result<()> {
+        let policy = match self.bso_history.get(collection) {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        let timestamp = self.timestamp().as_i64();
+        let revisions = bso::table
+            .select((
+                bso::id,
+                bso::modified,
+                bso::sortindex,
+                bso::payload,
+                bso::expiry,
+            ))
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq_any(ids))
+            .filter(bso::expiry.gt(timestamp))
+            .load::<(String, i64, Option<i32>, String, i64)>(&self.conn)?;
+        if !revisions.is_empty() {
+            let rows: Vec<_> = revisions
+                .iter()
+                .map(|(id, modified, sortindex, payload, expiry)| {
+                    (
+                        bso_history::user_id.eq(user_id),
+                        bso_history::collection_id.eq(collection_id),
+                        bso_history::id.eq(id),
+                        bso_history::modified.eq(modified),
+                        bso_history::sortindex.eq(sortindex),
+                        bso_history::payload.eq(payload),
+                        bso_history::expiry.eq(expiry),
+                        bso_history::deleted.eq(false),
+                    )
+                })
+                .collect();
+            diesel::insert_or_ignore_into(bso_history::table)
+                .values(&rows)
+                .execute(&self.conn)?;
+        }
+        // Tombstones for the BSOs deleted now, or for those created now (as of
+        // just before), so restoring an earlier time removes them
+        let tombstones: Vec<_> = if deleted {
+            revisions
+                .iter()
+                .map(|(id, _, _, _, expiry)| (id.as_str(), timestamp, *expiry))
+                .collect()
+        } else {
+            ids.iter()
+                .filter(|id| !revisions.iter().any(|revision| &revision.0 == *id))
+                .map(|id| {
+                    let expiry = timestamp + i64::from(DEFAULT_BSO_TTL) * 1000;
+                    (id.as_str(), timestamp - 1, expiry)
+                })
+                .collect()
+        };
+        if !tombstones.is_empty() {
+            let rows: Vec<_> = tombstones
+                .into_iter()
+                .map(|(id, modified, expiry)| {
+                    (
+                        bso_history::user_id.eq(user_id),
+                        bso_history::collection_id.eq(collection_id),
+                        bso_history::id.eq(id),
+                        bso_history::modified.eq(modified),
+                        bso_history::sortindex.eq(None::<i32>),
+                        bso_history::payload.eq(""),
+                        bso_history::expiry.eq(expiry),
+                        bso_history::deleted.eq(true),
+                    )
+                })
+                .collect();
+            // Replacing any revision written earlier in this transaction
+            diesel::replace_into(bso_history::table)
+                .values(&rows)
+                .execute(&self.conn)?;
+        }
+
+        delete(bso_history::table)
+            .filter(bso_history::user_id.eq(user_id))
+            .filter(bso_history::collection_id.eq(&collection_id))
+            .filter(
+                bso_history::expiry
+                    .le(timestamp)
+                    .or(bso_history::modified.lt(policy.max_age_cutoff(timestamp).unwrap_or(0))),
+            )
+            .execute(&self.conn)?;
+        if policy.max_revisions.is_none() {
+            return Ok(());
+        }
+        let mut kept: HashMap<String, Vec<i64>> = HashMap::new();
+        for (id, modified) in bso_history::table
+            .select((bso_history::id, bso_history::modified))
+            .filter(bso_history::user_id.eq(user_id))
+            .filter(bso_history::collection_id.eq(&collection_id))
+            .filter(bso_history::id.eq_any(ids))
+            .order(bso_history::modified.desc())
+            .load::<(String, i64)>(&self.conn)?
+        {
+            kept.entry(id).or_default().push(modified);
+        }
+        for (id, revisions) in kept {
+            if let Some(modified) = policy.first_pruned(&revisions) {
+                delete(bso_history::table)
+                    .filter(bso_history::user_id.eq(user_id))
+                    .filter(bso_history::collection_id.eq(&collection_id))
+                    .filter(bso_history::id.eq(&id))
+                    .filter(bso_history::modified.le(modified))
+                    .execute(&self.conn)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// For each BSO modified or deleted after `at`, its latest kept revision
+    /// as of `at`, or a tombstone when it didn't exist then: when its earliest
+    /// kept revision since is one
+    pub fn get_bso_revisions_sync(
+        &self,
+        params: params::GetBsoRevisions,
+    ) -> Result<results::GetBsoRevisions> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let at = params.at.as_i64();
+        sql_query(
+            "SELECT c.id, h.modified, h.sortindex, h.payload, h.ttl AS expiry, h.deleted
+               FROM (
+                    SELECT u.id,
+                           COALESCE(
+                               (SELECT MAX(modified)
+                                  FROM bso_history
+                                 WHERE userid = ?
+                                   AND collection = ?
+                                   AND id = u.id
+                                   AND modified <= ?),
+                               (SELECT MIN(modified)
+                                  FROM bso_history
+                                 WHERE userid = ?
+                                   AND collection = ?
+                                   AND id = u.id
+                                   AND modified > ?)) AS at_modified
+                      FROM (SELECT id
+                              FROM bso
+                             WHERE userid = ?
+                               AND collection = ?
+                               AND modified > ?
+                             UNION
+                            SELECT id
+                              FROM bso_history
+                             WHERE userid = ?
+                               AND collection = ?
+                               AND modified > ?) u) c
+               JOIN bso_history h
+                 ON h.userid = ?
+                AND h.collection = ?
+                AND h.id = c.id
+                AND h.modified = c.at_modified
+                AND (h.modified <= ? OR h.deleted)
+                AND h.ttl > ?
+              ORDER BY c.id",
+        )
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(at)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(at)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(at)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(at)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(at)
+        .bind::<BigInt, _>(self.timestamp().as_i64())
+        .load::<results::BsoRevision>(&self.conn)?
+        .into_iter()
+        .map(|revision| {
+            if revision.deleted {
+                return Ok(revision);
+            }
+            let payload =
+                self.decode_payload(revision.payload, user_id, collection_id, &revision.id)?;
+            Ok(results::BsoRevision {
+                payload,
+                ..revision
+            })
+        })
+        .collect()
+    }
+
     pub fn post_bsos_sync(&self, input: params::PostBsos) -> Result<results::PostBsos> {
         let collection_id = self.get_or_create_collection_id(&input.collection)?;
+        self.check_collection_policy(
+            input.user_id.legacy_id as i64,
+            collection_id,
+            &input.collection,
+        )?;
         let mut result = results::PostBsos {
             modified: self.timestamp(),
             success: Default::default(),
             failed: input.failed,
         };
 
-        for pbso in input.bsos {
+        let mut bsos = input.bsos;
+        if self.expected_modified {
+            let ids: Vec<_> = bsos
+                .iter()
+                .filter(|bso| bso.modified.is_some())
+                .map(|bso| bso.id.as_str())
+                .collect();
+            let stored =
+                self.get_bso_timestamps(input.user_id.legacy_id as i64, collection_id, &ids)?;
+            bsos.retain(|bso| match bso.modified {
+                Some(expected)
+                    if stored.get(&bso.id).copied().unwrap_or(0) != expected.as_i64() =>
+                {
+                    result.failed.insert(bso.id.clone(), "conflict".to_owned());
+                    false
+                }
+                _ => true,
+            });
+        }
+
+        for pbso in bsos {
             let id = pbso.id;
             let put_result = self.put_bso_sync(params::PutBso {
                 user_id: input.user_id.clone(),
@@ -738,6 +1730,27 @@ impl MysqlDb {
             .ok_or_else(|| DbErrorKind::CollectionNotFound.into())
     }
 
+    /// The stored modified times of the (unexpired) BSOs among `ids`
+    pub(super) fn get_bso_timestamps(
+        &self,
+        user_id: i64,
+        collection_id: i32,
+        ids: &[&str],
+    ) -> Result<HashMap<String, i64>> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        Ok(bso::table
+            .select((bso::id, bso::modified))
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(collection_id))
+            .filter(bso::id.eq_any(ids))
+            .filter(bso::expiry.gt(self.timestamp().as_i64()))
+            .load::<(String, i64)>(&self.conn)?
+            .into_iter()
+            .collect())
+    }
+
     pub fn get_bso_timestamp_sync(&self, params: params::GetBsoTimestamp) -> Result<SyncTimestamp> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
@@ -774,6 +1787,35 @@ impl MysqlDb {
         self.map_collection_names(modifieds)
     }
 
+    pub fn get_inactive_users_sync(
+        &self,
+        params: params::GetInactiveUsers,
+    ) -> Result<results::GetInactiveUsers> {
+        sql_query(format!(
+            "SELECT {user_id}, MAX({modified}) AS {modified}
+               FROM user_collections
+              WHERE {user_id} > ?
+              GROUP BY {user_id}
+             HAVING MAX({modified}) < ?
+              ORDER BY {user_id}
+              LIMIT ?",
+            user_id = USER_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(params.after.map_or(-1, |user_id| user_id.legacy_id as i64))
+        .bind::<BigInt, _>(params.before.as_i64())
+        .bind::<BigInt, _>(i64::from(params.limit))
+        .load::<InactiveUserResult>(&self.conn)?
+        .into_iter()
+        .map(|r| {
+            Ok(results::InactiveUser {
+                user_id: HawkIdentifier::new_legacy(r.userid as u64),
+                modified: SyncTimestamp::from_i64(r.last_modified)?,
+            })
+        })
+        .collect()
+    }
+
     fn check_sync(&self) -> Result<results::Check> {
         // has the database been up for more than 0 seconds?
         let result = sql_query("SHOW STATUS LIKE \"Uptime\"").execute(&self.conn)?;
@@ -873,7 +1915,7 @@ impl MysqlDb {
     ) -> Result<results::GetStorageUsage> {
         let uid = user_id.legacy_id as i64;
         let total_bytes = bso::table
-            .select(sql::<Nullable<BigInt>>("SUM(LENGTH(payload))"))
+            .select(sql::<Nullable<BigInt>>(&format!("SUM({})", PAYLOAD_SIZE)))
             .filter(bso::user_id.eq(uid))
             .filter(bso::expiry.gt(&self.timestamp().as_i64()))
             .get_result::<Option<i64>>(&self.conn)?;
@@ -910,7 +1952,7 @@ impl MysqlDb {
     ) -> Result<results::GetQuotaUsage> {
         let (total_bytes, count): (i64, i32) = bso::table
             .select((
-                sql::<BigInt>(r#"COALESCE(SUM(LENGTH(COALESCE(payload, ""))),0)"#),
+                sql::<BigInt>(&format!("COALESCE(SUM({}), 0)", PAYLOAD_SIZE)),
                 sql::<Integer>("COALESCE(COUNT(*),0)"),
             ))
             .filter(bso::user_id.eq(user_id as i64))
@@ -930,7 +1972,10 @@ impl MysqlDb {
         user_id: HawkIdentifier,
     ) -> Result<results::GetCollectionUsage> {
         let counts = bso::table
-            .select((bso::collection_id, sql::<BigInt>("SUM(LENGTH(payload))")))
+            .select((
+                bso::collection_id,
+                sql::<BigInt>(&format!("SUM({})", PAYLOAD_SIZE)),
+            ))
             .filter(bso::user_id.eq(user_id.legacy_id as i64))
             .filter(bso::expiry.gt(&self.timestamp().as_i64()))
             .group_by(bso::collection_id)
@@ -975,6 +2020,30 @@ impl MysqlDb {
     pub fn timestamp(&self) -> SyncTimestamp {
         self.session.borrow().timestamp
     }
+
+    /// Encode a payload for storage
+    pub(super) fn encode_payload(
+        &self,
+        payload: String,
+        user_id: i64,
+        collection_id: i32,
+        id: &str,
+    ) -> Result<String> {
+        let context = payload_context(&user_id.to_string(), collection_id, id);
+        self.payload_codec.encode(payload, &context, &self.metrics)
+    }
+
+    /// Decode a stored payload
+    fn decode_payload(
+        &self,
+        stored: String,
+        user_id: i64,
+        collection_id: i32,
+        id: &str,
+    ) -> Result<String> {
+        let context = payload_context(&user_id.to_string(), collection_id, id);
+        self.payload_codec.decode(stored, &context)
+    }
 }
 
 macro_rules! sync_db_method {
@@ -1044,6 +2113,30 @@ impl<'a> Db<'a> for MysqlDb {
     sync_db_method!(get_storage_usage, get_storage_usage_sync, GetStorageUsage);
     sync_db_method!(get_quota_usage, get_quota_usage_sync, GetQuotaUsage);
     sync_db_method!(delete_storage, delete_storage_sync, DeleteStorage);
+    sync_db_method!(archive_storage, archive_storage_sync, ArchiveStorage);
+    sync_db_method!(restore_storage, restore_storage_sync, RestoreStorage);
+    sync_db_method!(record_audit, record_audit_sync, RecordAudit);
+    sync_db_method!(get_audit_log, get_audit_log_sync, GetAuditLog);
+    sync_db_method!(
+        reencrypt_payloads,
+        reencrypt_payloads_sync,
+        ReencryptPayloads
+    );
+    sync_db_method!(get_revocations, get_revocations_sync, GetRevocations);
+    sync_db_method!(put_revocation, put_revocation_sync, PutRevocation);
+    sync_db_method!(delete_revocation, delete_revocation_sync, DeleteRevocation);
+    sync_db_method!(get_user_limits, get_user_limits_sync, GetUserLimits);
+    sync_db_method!(put_user_limits, put_user_limits_sync, PutUserLimits);
+    sync_db_method!(
+        delete_user_limits,
+        delete_user_limits_sync,
+        DeleteUserLimits
+    );
+    sync_db_method!(
+        purge_deleted_storage,
+        purge_deleted_storage_sync,
+        PurgeDeletedStorage
+    );
     sync_db_method!(delete_collection, delete_collection_sync, DeleteCollection);
     sync_db_method!(delete_bsos, delete_bsos_sync, DeleteBsos);
     sync_db_method!(get_bsos, get_bsos_sync, GetBsos);
@@ -1057,6 +2150,13 @@ impl<'a> Db<'a> for MysqlDb {
         GetBsoTimestamp,
         results::GetBsoTimestamp
     );
+    sync_db_method!(get_deleted_bsos, get_deleted_bsos_sync, GetDeletedBsos);
+    sync_db_method!(get_bso_revisions, get_bso_revisions_sync, GetBsoRevisions);
+    sync_db_method!(
+        get_inactive_users,
+        get_inactive_users_sync,
+        GetInactiveUsers
+    );
     sync_db_method!(put_bso, put_bso_sync, PutBso);
     sync_db_method!(create_batch, create_batch_sync, CreateBatch);
     sync_db_method!(validate_batch, validate_batch_sync, ValidateBatch);
@@ -1113,7 +2213,6 @@ impl<'a> Db<'a> for MysqlDb {
         self.coll_cache.clear();
     }
 
-    #[cfg(test)]
     fn set_quota(&mut self, enabled: bool, limit: usize) {
         self.quota = limit;
         self.quota_enabled = enabled;
@@ -1141,3 +2240,11 @@ struct UserCollectionsResult {
     #[sql_type = "BigInt"]
     last_modified: i64, // LAST_MODIFIED
 }
+
+#[derive(Debug, QueryableByName)]
+struct InactiveUserResult {
+    #[sql_type = "BigInt"]
+    userid: i64, // USER_ID
+    #[sql_type = "BigInt"]
+    last_modified: i64, // LAST_MODIFIED
+}
@@ -20,13 +20,15 @@ use super::models::{MysqlDb, Result};
 #[cfg(test)]
 use super::test::TestTransactionCustomizer;
 use crate::db::{
+    codec::PayloadCodec,
+    collections::CollectionPolicy,
     error::DbError,
     results::{self, PoolState},
     Db, DbPool, STD_COLLS,
 };
 use crate::error::{ApiError, ApiResult};
 use crate::server::metrics::Metrics;
-use crate::settings::Settings;
+use crate::settings::{BsoHistoryPolicy, Settings};
 
 embed_migrations!();
 
@@ -56,6 +58,11 @@ pub struct MysqlDbPool {
     metrics: Metrics,
     quota: usize,
     quota_enabled: bool,
+    deleted_bso_retention: u32,
+    expected_modified: bool,
+    bso_history: Arc<HashMap<String, BsoHistoryPolicy>>,
+    payload_codec: Arc<PayloadCodec>,
+    collection_policy: Arc<CollectionPolicy>,
 }
 
 impl MysqlDbPool {
@@ -86,6 +93,11 @@ impl MysqlDbPool {
             metrics: metrics.clone(),
             quota: settings.limits.max_quota_limit as usize,
             quota_enabled: settings.enable_quota,
+            deleted_bso_retention: settings.deleted_bso_retention,
+            expected_modified: settings.enable_expected_modified,
+            bso_history: Arc::new(settings.bso_history.clone()),
+            payload_codec: Arc::new(PayloadCodec::from_settings(settings)?),
+            collection_policy: Arc::new(CollectionPolicy::from_settings(settings)),
         })
     }
 
@@ -96,6 +108,11 @@ impl MysqlDbPool {
             &self.metrics,
             &self.quota,
             self.quota_enabled,
+            self.deleted_bso_retention,
+            self.expected_modified,
+            Arc::clone(&self.bso_history),
+            Arc::clone(&self.payload_codec),
+            Arc::clone(&self.collection_policy),
         ))
     }
 }
@@ -1,3 +1,19 @@
+table! {
+    audit_log (id) {
+        id -> Bigint,
+        #[sql_name="userid"]
+        user_id -> BigInt,
+        recorded -> Bigint,
+        op -> Varchar,
+        collection -> Nullable<Varchar>,
+        count -> Bigint,
+        user_agent -> Text,
+        device_id -> Varchar,
+        #[sql_name="ttl"]
+        expiry -> Bigint,
+    }
+}
+
 table! {
     batch_uploads (batch_id, user_id) {
         #[sql_name="batch"]
@@ -20,6 +36,7 @@ table! {
         payload -> Nullable<Mediumtext>,
         payload_size -> Nullable<Bigint>,
         ttl_offset -> Nullable<Integer>,
+        expected_modified -> Nullable<Bigint>,
     }
 }
 
@@ -40,6 +57,62 @@ table! {
     }
 }
 
+table! {
+    bso_deletions (user_id, collection_id, id) {
+        #[sql_name="userid"]
+        user_id -> BigInt,
+        #[sql_name="collection"]
+        collection_id -> Integer,
+        id -> Varchar,
+        deleted -> Bigint,
+        #[sql_name="ttl"]
+        expiry -> Bigint,
+        state -> Integer,
+    }
+}
+
+table! {
+    bso_history (user_id, collection_id, id, modified) {
+        #[sql_name="userid"]
+        user_id -> BigInt,
+        #[sql_name="collection"]
+        collection_id -> Integer,
+        id -> Varchar,
+        modified -> Bigint,
+        sortindex -> Nullable<Integer>,
+        payload -> Mediumtext,
+        #[sql_name="ttl"]
+        expiry -> Bigint,
+        deleted -> Bool,
+    }
+}
+
+table! {
+    deleted_bso (user_id, collection_id, id) {
+        #[sql_name="userid"]
+        user_id -> BigInt,
+        #[sql_name="collection"]
+        collection_id -> Integer,
+        id -> Varchar,
+        sortindex -> Nullable<Integer>,
+        payload -> Mediumtext,
+        payload_size -> Bigint,
+        #[sql_name="ttl"]
+        expiry -> Bigint,
+    }
+}
+
+table! {
+    deleted_storage (user_id) {
+        #[sql_name="userid"]
+        user_id -> BigInt,
+        deleted -> Bigint,
+        #[sql_name="ttl"]
+        expiry -> Bigint,
+        state -> Integer,
+    }
+}
+
 table! {
     collections (id) {
         id -> Integer,
@@ -62,10 +135,38 @@ table! {
     }
 }
 
+table! {
+    user_limits (user_id) {
+        #[sql_name="userid"]
+        user_id -> BigInt,
+        max_post_bytes -> Nullable<BigInt>,
+        max_post_records -> Nullable<BigInt>,
+        max_record_payload_bytes -> Nullable<BigInt>,
+        max_total_bytes -> Nullable<BigInt>,
+        max_total_records -> Nullable<BigInt>,
+        max_quota_limit -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    revocations (key_type, key_value) {
+        key_type -> Varchar,
+        key_value -> Varchar,
+        issued_before -> Nullable<BigInt>,
+    }
+}
+
 allow_tables_to_appear_in_same_query!(
+    audit_log,
     batch_uploads,
     batch_upload_items,
     bso,
+    bso_deletions,
+    bso_history,
     collections,
+    deleted_bso,
+    deleted_storage,
+    revocations,
     user_collections,
+    user_limits,
 );
@@ -3,8 +3,12 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-use crate::db::results;
-use crate::web::extractors::{BatchBsoBody, BsoQueryParams, HawkIdentifier};
+use crate::db::{results, util::SyncTimestamp, AuditOp};
+use crate::settings::LimitOverrides;
+use crate::web::{
+    auth::{Revocation, RevocationKey},
+    extractors::{BatchBsoBody, BsoQueryParams, HawkIdentifier},
+};
 
 macro_rules! data {
     ($name:ident {$($property:ident: $type:ty,)*}) => {
@@ -53,6 +57,10 @@ uid_data! {
     GetStorageTimestamp,
     GetStorageUsage,
     DeleteStorage,
+    GetAuditLog,
+    ReencryptPayloads,
+    GetUserLimits,
+    DeleteUserLimits,
 }
 
 collection_data! {
@@ -65,6 +73,15 @@ collection_data! {
     GetBsos {
         params: BsoQueryParams,
     },
+    GetDeletedBsos {
+        since: SyncTimestamp,
+        limit: Option<u32>,
+        offset: u64,
+    },
+    GetBsoRevisions {
+        // The point in time whose revisions are wanted
+        at: SyncTimestamp,
+    },
     PostBsos {
         bsos: Vec<PostCollectionBso>,
         failed: HashMap<String, String>,
@@ -125,6 +142,10 @@ pub struct PostCollectionBso {
     pub payload: Option<String>,
     // ttl in seconds
     pub ttl: Option<u32>,
+    // expected stored timestamp (0 when it shouldn't exist yet), checked
+    // when enable_expected_modified is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<SyncTimestamp>,
 }
 
 impl From<BatchBsoBody> for PostCollectionBso {
@@ -134,12 +155,86 @@ impl From<BatchBsoBody> for PostCollectionBso {
             sortindex: b.sortindex,
             payload: b.payload,
             ttl: b.ttl,
+            // Sync timestamps have two decimal places of precision
+            modified: b.modified.map(|modified| {
+                SyncTimestamp::from_milliseconds((modified * 100.0).round() as u64 * 10)
+            }),
         }
     }
 }
 
 pub type GetCollectionId = String;
 
+data! {
+    ArchiveStorage {
+        user_id: HawkIdentifier,
+        // How long, in seconds, the storage can be restored for
+        grace_period: u32,
+        // The most records copied
+        limit: u32,
+    }
+}
+
+data! {
+    RestoreStorage {
+        user_id: HawkIdentifier,
+        // The most records restored
+        limit: u32,
+    }
+}
+
+data! {
+    PurgeDeletedStorage {
+        // The most records and storage copies purged
+        limit: u32,
+    }
+}
+
+data! {
+    RecordAudit {
+        user_id: HawkIdentifier,
+        collection: Option<String>,
+        op: AuditOp,
+        // The number of records deleted
+        count: i64,
+        timestamp: SyncTimestamp,
+        user_agent: String,
+        // The hashed device id of the client's token
+        device_id: String,
+        // How long, in seconds, the entry is kept
+        retention: u32,
+    }
+}
+
+data! {
+    GetInactiveUsers {
+        // Users whose storage was last modified before this
+        before: SyncTimestamp,
+        // Resume after this user (users are ordered by id)
+        after: Option<HawkIdentifier>,
+        limit: u32,
+    }
+}
+
+data! {
+    PutUserLimits {
+        user_id: HawkIdentifier,
+        limits: LimitOverrides,
+    }
+}
+
+pub type GetRevocations = ();
+
+data! {
+    PutRevocation {
+        revocation: Revocation,
+        // Prune the revocations whose cutoffs are before this (in seconds)
+        expired_before: u64,
+    }
+}
+
+pub type DeleteRevocation = RevocationKey;
+
 #[cfg(test)]
 pub type CreateCollection = String;
 
@@ -1,11 +1,13 @@
 //! Result types for database methods.
 use std::collections::HashMap;
 
-use diesel::sql_types::{BigInt, Integer, Nullable, Text};
+use diesel::sql_types::{BigInt, Bool, Integer, Nullable, Text};
 use serde::{Deserialize, Serialize};
 
 use super::params;
-use crate::db::util::SyncTimestamp;
+use crate::db::{util::SyncTimestamp, AuditOp};
+use crate::settings::LimitOverrides;
+use crate::web::{auth::Revocation, extractors::HawkIdentifier};
 
 pub type LockCollection = ();
 pub type GetBsoTimestamp = SyncTimestamp;
@@ -15,12 +17,26 @@ pub type GetCollectionCounts = HashMap<String, i64>;
 pub type GetCollectionUsage = HashMap<String, i64>;
 pub type GetStorageTimestamp = SyncTimestamp;
 pub type GetStorageUsage = u64;
-pub type DeleteStorage = ();
+pub type DeleteStorage = SyncTimestamp;
+/// The number of records copied
+pub type ArchiveStorage = usize;
+/// The restored chunk, if there was storage to restore
+pub type RestoreStorage = Option<RestoredStorage>;
+/// The number of records and storage copies purged
+pub type PurgeDeletedStorage = usize;
 pub type DeleteCollection = SyncTimestamp;
 pub type DeleteBsos = SyncTimestamp;
 pub type DeleteBso = SyncTimestamp;
 pub type PutBso = SyncTimestamp;
 
+#[derive(Debug, Clone)]
+pub struct RestoredStorage {
+    /// The number of records restored
+    pub restored: usize,
+    /// The storage timestamp as of this chunk
+    pub modified: SyncTimestamp,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct CreateBatch {
     pub id: String,
@@ -73,6 +89,37 @@ where
 pub type GetBsos = Paginated<GetBso>;
 pub type GetBsoIds = Paginated<String>;
 
+/// The id of a BSO that was deleted (or expired), and when
+#[derive(Debug, Default, Deserialize, QueryableByName, Serialize)]
+pub struct DeletedBso {
+    #[sql_type = "Text"]
+    pub id: String,
+    #[sql_type = "BigInt"]
+    pub deleted: SyncTimestamp,
+}
+
+pub type GetDeletedBsos = Paginated<DeletedBso>;
+
+/// The revision of a BSO that was current at an earlier point in time, or
+/// its absence then (`deleted`)
+#[derive(Debug, Default, QueryableByName)]
+pub struct BsoRevision {
+    #[sql_type = "Text"]
+    pub id: String,
+    #[sql_type = "BigInt"]
+    pub modified: SyncTimestamp,
+    #[sql_type = "Nullable<Integer>"]
+    pub sortindex: Option<i32>,
+    #[sql_type = "Text"]
+    pub payload: String,
+    #[sql_type = "BigInt"]
+    pub expiry: i64,
+    #[sql_type = "Bool"]
+    pub deleted: bool,
+}
+
+pub type GetBsoRevisions = Vec<BsoRevision>;
+
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct PostBsos {
     pub modified: SyncTimestamp,
@@ -89,6 +136,46 @@ pub struct PoolState {
 
 pub type GetCollectionId = i32;
 
+/// A user whose storage hasn't been modified since `modified`
+#[derive(Debug)]
+pub struct InactiveUser {
+    pub user_id: HawkIdentifier,
+    pub modified: SyncTimestamp,
+}
+
+pub type GetInactiveUsers = Vec<InactiveUser>;
+
+pub type RecordAudit = ();
+
+/// A destructive operation recorded in a user's audit log
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    pub op: AuditOp,
+    /// The number of records deleted
+    pub count: i64,
+    pub timestamp: SyncTimestamp,
+    pub user_agent: String,
+    /// The hashed device id of the client's token, when known
+    pub device_id: String,
+}
+
+pub type GetAuditLog = Vec<AuditEntry>;
+
+/// The number of payloads rewritten
+pub type ReencryptPayloads = u64;
+
+pub type GetUserLimits = Option<LimitOverrides>;
+pub type PutUserLimits = ();
+/// Whether the user had limit overrides
+pub type DeleteUserLimits = bool;
+
+pub type GetRevocations = Vec<Revocation>;
+pub type PutRevocation = ();
+/// Whether there was a matching revocation
+pub type DeleteRevocation = bool;
+
 #[cfg(test)]
 pub type CreateCollection = i32;
 
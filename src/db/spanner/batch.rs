@@ -7,7 +7,7 @@ use protobuf::{
 };
 use uuid::Uuid;
 
-use super::support::{null_value, struct_type_field};
+use super::support::{as_list_value, null_value, struct_type_field};
 use super::{
     models::{Result, SpannerDb, DEFAULT_BSO_TTL, PRETOUCH_TS},
     support::as_value,
@@ -23,6 +23,8 @@ pub async fn create_async(
 ) -> Result<results::CreateBatch> {
     let batch_id = Uuid::new_v4().to_simple().to_string();
     let collection_id = db.get_collection_id_async(&params.collection).await?;
+    db.check_collection_policy(&params.user_id, collection_id, &params.collection)
+        .await?;
     let timestamp = db.timestamp()?.as_i64();
 
     // Ensure a parent record exists in user_collections before writing to batches
@@ -186,6 +188,8 @@ pub async fn commit_async(
     let mut metrics = db.metrics.clone();
     metrics.start_timer("storage.spanner.apply_batch", None);
     let collection_id = db.get_collection_id_async(&params.collection).await?;
+    db.check_collection_policy(&params.user_id, collection_id, &params.collection)
+        .await?;
 
     // Ensure a parent record exists in user_collections before writing to bsos
     // (INTERLEAVE IN PARENT user_collections)
@@ -193,6 +197,44 @@ pub async fn commit_async(
         .update_collection_async(&params.user_id, collection_id, &params.collection)
         .await?;
 
+    let failed = if db.expected_modified {
+        drop_conflicts_async(db, &params, collection_id).await?
+    } else {
+        Default::default()
+    };
+
+    if db.bso_history.contains_key(&params.collection) {
+        let mut streaming = db
+            .sql(
+                "SELECT batch_bso_id
+                   FROM batch_bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND batch_id = @batch_id",
+            )?
+            .params(params! {
+                "fxa_uid" => params.user_id.fxa_uid.clone(),
+                "fxa_kid" => params.user_id.fxa_kid.clone(),
+                "collection_id" => collection_id.to_string(),
+                "batch_id" => params.batch.id.clone(),
+            })
+            .execute_async(&db.conn)?;
+        let mut ids = vec![];
+        while let Some(row) = streaming.next_async().await {
+            ids.push(row?[0].take_string_value());
+        }
+        db.record_revisions_async(
+            &params.user_id,
+            &params.collection,
+            collection_id,
+            ids,
+            timestamp,
+            false,
+        )
+        .await?;
+    }
+
     let as_rfc3339 = timestamp.as_rfc3339()?;
     {
         // First, UPDATE existing rows in the bsos table with any new values
@@ -252,10 +294,70 @@ pub async fn commit_async(
     Ok(results::PostBsos {
         modified: timestamp,
         success: Default::default(),
-        failed: Default::default(),
+        failed,
     })
 }
 
+/// Remove the batched BSOs whose expected modified time differs from their
+/// stored one, returning them as conflicts
+async fn drop_conflicts_async(
+    db: &SpannerDb,
+    params: &params::CommitBatch,
+    collection_id: i32,
+) -> Result<HashMap<String, String>> {
+    let sqlparams = params! {
+        "fxa_uid" => params.user_id.fxa_uid.clone(),
+        "fxa_kid" => params.user_id.fxa_kid.clone(),
+        "collection_id" => collection_id.to_string(),
+        "batch_id" => params.batch.id.clone(),
+    };
+    let mut streaming = db
+        .sql(
+            "SELECT batch.batch_bso_id
+               FROM batch_bsos AS batch
+               LEFT JOIN bsos
+                 ON bsos.fxa_uid = batch.fxa_uid
+                AND bsos.fxa_kid = batch.fxa_kid
+                AND bsos.collection_id = batch.collection_id
+                AND bsos.bso_id = batch.batch_bso_id
+                AND bsos.expiry > CURRENT_TIMESTAMP()
+              WHERE batch.fxa_uid = @fxa_uid
+                AND batch.fxa_kid = @fxa_kid
+                AND batch.collection_id = @collection_id
+                AND batch.batch_id = @batch_id
+                AND batch.expected_modified IS NOT NULL
+                AND batch.expected_modified != COALESCE(UNIX_MILLIS(bsos.modified), 0)",
+        )?
+        .params(sqlparams.clone())
+        .execute_async(&db.conn)?;
+    let mut conflicts = vec![];
+    while let Some(row) = streaming.next_async().await {
+        conflicts.push(row?[0].take_string_value());
+    }
+    if !conflicts.is_empty() {
+        let mut sqlparams = sqlparams;
+        sqlparams.insert(
+            "ids".to_owned(),
+            as_list_value(conflicts.clone().into_iter()),
+        );
+        db.sql(
+            "DELETE FROM batch_bsos
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND batch_id = @batch_id
+                AND batch_bso_id IN UNNEST(@ids)",
+        )?
+        .params(sqlparams)
+        .execute_dml_async(&db.conn)
+        .await?;
+    }
+    Ok(conflicts
+        .into_iter()
+        .map(|id| (id, "conflict".to_owned()))
+        .collect())
+}
+
 pub async fn do_append_async(
     db: &SpannerDb,
     user_id: HawkIdentifier,
@@ -265,28 +367,37 @@ pub async fn do_append_async(
     collection: &str,
 ) -> Result<()> {
     // Pass an array of struct objects as @values (for UNNEST), e.g.:
-    // [("<fxa_uid>", "<fxa_kid>", 101, "ba1", "bso1", NULL, "payload1", NULL),
-    //  ("<fxa_uid>", "<fxa_kid>", 101, "ba1", "bso2", NULL, "payload2", NULL)]
+    // [("<fxa_uid>", "<fxa_kid>", 101, "ba1", "bso1", NULL, "payload1", 8, NULL),
+    //  ("<fxa_uid>", "<fxa_kid>", 101, "ba1", "bso2", NULL, "payload2", 8, NULL)]
     // https://cloud.google.com/spanner/docs/structs#creating_struct_objects
     let mut running_size: usize = 0;
-    let rows: Vec<_> = bsos
+    let rows = bsos
         .into_iter()
         .map(|bso| {
             let sortindex = bso
                 .sortindex
                 .map(|sortindex| as_value(sortindex.to_string()))
                 .unwrap_or_else(null_value);
-            let payload = bso.payload.map(as_value).unwrap_or_else(null_value);
-            if payload != null_value() {
-                running_size += payload.get_string_value().len();
-            }
-            let ttl = bso
-                .ttl
+            let payload_size = match &bso.payload {
+                Some(payload) => {
+                    running_size += payload.len();
+                    as_value(payload.len().to_string())
+                }
+                None => null_value(),
+            };
+            let payload = match bso.payload {
+                Some(payload) => {
+                    as_value(db.encode_payload(payload, &user_id, collection_id, &bso.id)?)
+                }
+                None => null_value(),
+            };
+            let ttl = db
+                .collection_policy
+                .ttl(collection, bso.ttl)
                 .map(|ttl| as_value(ttl.to_string()))
                 .unwrap_or_else(null_value);
 
-            let mut row = ListValue::new();
-            row.set_values(RepeatedField::from_vec(vec![
+            let mut values = vec![
                 as_value(user_id.fxa_uid.clone()),
                 as_value(user_id.fxa_kid.clone()),
                 as_value(collection_id.to_string()),
@@ -294,13 +405,24 @@ pub async fn do_append_async(
                 as_value(bso.id),
                 sortindex,
                 payload,
+                payload_size,
                 ttl,
-            ]));
+            ];
+            // Only written when enabled: older databases lack the column
+            if db.expected_modified {
+                values.push(
+                    bso.modified
+                        .map(|modified| as_value(modified.as_i64().to_string()))
+                        .unwrap_or_else(null_value),
+                );
+            }
+            let mut row = ListValue::new();
+            row.set_values(RepeatedField::from_vec(values));
             let mut value = Value::new();
             value.set_list_value(row);
-            value
+            Ok(value)
         })
-        .collect();
+        .collect::<Result<Vec<_>>>()?;
 
     if db.quota_enabled {
         if let Some(size) = batch.size {
@@ -322,7 +444,7 @@ pub async fn do_append_async(
 
     // STRUCT requires definition of all its field types
     let mut struct_type = StructType::new();
-    let fields = vec![
+    let mut fields = vec![
         ("fxa_uid", TypeCode::STRING),
         ("fxa_kid", TypeCode::STRING),
         ("collection_id", TypeCode::INT64),
@@ -330,11 +452,16 @@ pub async fn do_append_async(
         ("batch_bso_id", TypeCode::STRING),
         ("sortindex", TypeCode::INT64),
         ("payload", TypeCode::STRING),
+        ("payload_size", TypeCode::INT64),
         ("ttl", TypeCode::INT64),
-    ]
-    .into_iter()
-    .map(|(name, field_type)| struct_type_field(name, field_type))
-    .collect();
+    ];
+    if db.expected_modified {
+        fields.push(("expected_modified", TypeCode::INT64));
+    }
+    let fields = fields
+        .into_iter()
+        .map(|(name, field_type)| struct_type_field(name, field_type))
+        .collect();
     struct_type.set_fields(RepeatedField::from_vec(fields));
     array_type.set_struct_type(struct_type);
     param_type.set_array_element_type(array_type);
@@ -343,11 +470,17 @@ pub async fn do_append_async(
     sqlparams.insert("values".to_owned(), values);
     let mut sqlparam_types = HashMap::new();
     sqlparam_types.insert("values".to_owned(), param_type);
-    db.sql(
+    let columns = if db.expected_modified {
+        ", expected_modified"
+    } else {
+        ""
+    };
+    db.sql(&format!(
         "INSERT INTO batch_bsos (fxa_uid, fxa_kid, collection_id, batch_id, batch_bso_id,
-                                 sortindex, payload, ttl)
+                                 sortindex, payload, payload_size, ttl{})
          SELECT * FROM UNNEST(@values)",
-    )?
+        columns
+    ))?
     .params(sqlparams)
     .param_types(sqlparam_types)
     .execute_dml_async(&db.conn)
@@ -1,4 +1,4 @@
-INSERT INTO bsos (fxa_uid, fxa_kid, collection_id, bso_id, sortindex, payload, modified, expiry)
+INSERT INTO bsos (fxa_uid, fxa_kid, collection_id, bso_id, sortindex, payload, payload_size, modified, expiry)
 SELECT
        batch_bsos.fxa_uid,
        batch_bsos.fxa_kid,
@@ -7,6 +7,7 @@ SELECT
 
        batch_bsos.sortindex,
        COALESCE(batch_bsos.payload, ''),
+       COALESCE(batch_bsos.payload_size, BYTE_LENGTH(batch_bsos.payload), 0),
        @timestamp,
        COALESCE(
            TIMESTAMP_ADD(@timestamp, INTERVAL batch_bsos.ttl SECOND),
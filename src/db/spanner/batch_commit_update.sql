@@ -23,6 +23,18 @@ UPDATE bsos
            bsos.payload
        ),
 
+       payload_size = COALESCE(
+           (SELECT COALESCE(payload_size, BYTE_LENGTH(payload))
+              FROM batch_bsos
+             WHERE fxa_uid = @fxa_uid
+               AND fxa_kid = @fxa_kid
+               AND collection_id = @collection_id
+               AND batch_id = @batch_id
+               AND batch_bso_id = bsos.bso_id
+           ),
+           bsos.payload_size
+       ),
+
        modified = @timestamp,
 
        expiry = COALESCE(
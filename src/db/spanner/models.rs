@@ -22,16 +22,24 @@ use protobuf::{
     well_known_types::{ListValue, Value},
     Message, RepeatedField,
 };
+use uuid::Uuid;
 
 use crate::{
     db::{
+        codec::PayloadCodec,
+        collections::{is_standard_collection, CollectionPolicy},
+        encode_next_offset,
+        encryption::PayloadCipher,
         error::{DbError, DbErrorKind},
         params, results,
-        util::SyncTimestamp,
-        Db, DbFuture, Sorting, FIRST_CUSTOM_COLLECTION_ID,
+        util::{to_rfc3339, SyncTimestamp},
+        AuditOp, Db, DbFuture, Sorting, DELETED_STORAGE_COPYING, DELETED_STORAGE_KEPT,
+        DELETED_STORAGE_RESTORING, FIRST_CUSTOM_COLLECTION_ID,
     },
     server::metrics::Metrics,
+    settings::{BsoHistoryPolicy, LimitOverrides},
     web::{
+        auth::{Revocation, RevocationKey},
         extractors::{BsoQueryParams, HawkIdentifier, Offset},
         tags::Tags,
     },
@@ -42,7 +50,7 @@ use super::{
     pool::{CollectionCache, Conn},
     support::{
         as_list_value, as_type, as_value, bso_from_row, bso_to_insert_row, bso_to_update_row,
-        ExecuteSqlRequestBuilder, StreamedResultSetAsync,
+        null_value, payload_context, ExecuteSqlRequestBuilder, StreamedResultSetAsync,
     },
 };
 
@@ -64,6 +72,10 @@ pub const PRETOUCH_TS: &str = "0001-01-01T00:00:00.00Z";
 // max load size in bytes
 pub const MAX_SPANNER_LOAD_SIZE: usize = 100_000_000;
 
+/// A BSO's logical (decoded) payload size. Rows written before it was
+/// recorded lack a `payload_size`
+pub const PAYLOAD_SIZE: &str = "COALESCE(payload_size, BYTE_LENGTH(payload))";
+
 /// Per session Db metadata
 #[derive(Debug, Default)]
 struct SpannerDbSession {
@@ -94,6 +106,16 @@ pub struct SpannerDb {
     pub metrics: Metrics,
     pub quota: usize,
     pub quota_enabled: bool,
+    /// How long, in seconds, deleted BSO ids are remembered
+    pub deleted_bso_retention: u32,
+    /// Whether POSTed BSOs' expected modified timestamps are checked
+    pub expected_modified: bool,
+    /// Which collections' previous BSO revisions are kept, and for how long
+    pub bso_history: Arc<HashMap<String, BsoHistoryPolicy>>,
+    /// Compresses and/or encrypts stored payloads
+    pub payload_codec: Arc<PayloadCodec>,
+    /// Which non-standard collections users may write to, and how many
+    pub collection_policy: Arc<CollectionPolicy>,
 }
 
 pub struct SpannerDbInner {
@@ -117,12 +139,18 @@ impl Deref for SpannerDb {
 }
 
 impl SpannerDb {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         conn: Conn,
         coll_cache: Arc<CollectionCache>,
         metrics: &Metrics,
         quota: usize,
         quota_enabled: bool,
+        deleted_bso_retention: u32,
+        expected_modified: bool,
+        bso_history: Arc<HashMap<String, BsoHistoryPolicy>>,
+        payload_codec: Arc<PayloadCodec>,
+        collection_policy: Arc<CollectionPolicy>,
     ) -> Self {
         let inner = SpannerDbInner {
             conn,
@@ -134,6 +162,11 @@ impl SpannerDb {
             metrics: metrics.clone(),
             quota,
             quota_enabled,
+            deleted_bso_retention,
+            expected_modified,
+            bso_history,
+            payload_codec,
+            collection_policy,
         }
     }
 
@@ -208,6 +241,63 @@ impl SpannerDb {
         }
     }
 
+    /// Check that the user may add the collection to their storage, before a
+    /// write creates it.
+    ///
+    /// Reads in a read-write transaction lock what they read, so concurrent
+    /// writes creating other collections conflict with this transaction
+    /// rather than exceeding the cap.
+    pub(super) async fn check_collection_policy(
+        &self,
+        user_id: &HawkIdentifier,
+        collection_id: i32,
+        name: &str,
+    ) -> Result<()> {
+        let policy = &self.collection_policy;
+        if is_standard_collection(name) || (!policy.is_capped() && policy.check_name(name).is_ok())
+        {
+            return Ok(());
+        }
+        // Collections locked for writing are already stored
+        if self
+            .session
+            .borrow()
+            .coll_modified_cache
+            .contains_key(&(user_id.clone(), collection_id))
+        {
+            return Ok(());
+        }
+        let mut streaming = self
+            .sql(
+                "SELECT collection_id
+                   FROM user_collections
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id >= @first_custom_id",
+            )?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+                "first_custom_id" => FIRST_CUSTOM_COLLECTION_ID.to_string(),
+            })
+            .execute_async(&self.conn)?;
+        let mut custom_ids = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let row = row?;
+            custom_ids.push(
+                row[0]
+                    .get_string_value()
+                    .parse::<i32>()
+                    .map_err(|e| DbErrorKind::Integrity(e.to_string()))?,
+            );
+        }
+        if custom_ids.contains(&collection_id) {
+            return Ok(());
+        }
+        policy.check_name(name)?;
+        policy.check_count(custom_ids.len())
+    }
+
     pub async fn lock_for_read_async(&self, params: params::LockCollection) -> Result<()> {
         // Begin a transaction
         self.begin_async(false).await?;
@@ -414,7 +504,6 @@ impl SpannerDb {
             .push(mutation);
     }
 
-    #[allow(unused)]
     pub(super) fn insert_or_update(&self, table: &str, columns: &[&str], values: Vec<ListValue>) {
         let mut mutation = Mutation::new();
         mutation.set_insert_or_update(self.mutation_write(table, columns, values));
@@ -709,14 +798,15 @@ impl SpannerDb {
         user_id: params::GetCollectionUsage,
     ) -> Result<results::GetCollectionUsage> {
         let mut streaming = self
-            .sql(
-                "SELECT collection_id, SUM(BYTE_LENGTH(payload))
+            .sql(&format!(
+                "SELECT collection_id, SUM({})
                    FROM bsos
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND expiry > CURRENT_TIMESTAMP()
                   GROUP BY collection_id",
-            )?
+                PAYLOAD_SIZE
+            ))?
             .params(params! {
                 "fxa_uid" => user_id.fxa_uid,
                 "fxa_kid" => user_id.fxa_kid
@@ -773,14 +863,15 @@ impl SpannerDb {
         user_id: params::GetStorageUsage,
     ) -> Result<results::GetStorageUsage> {
         let result = self
-            .sql(
-                "SELECT SUM(BYTE_LENGTH(payload))
+            .sql(&format!(
+                "SELECT SUM({})
                    FROM bsos
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND expiry > CURRENT_TIMESTAMP()
                   GROUP BY fxa_uid",
-            )?
+                PAYLOAD_SIZE
+            ))?
             .params(params! {
                 "fxa_uid" => user_id.fxa_uid,
                 "fxa_kid" => user_id.fxa_kid
@@ -864,23 +955,31 @@ impl SpannerDb {
         self.metrics
             .clone()
             .start_timer("storage.quota.update_existing_totals", None);
+        // Expired BSOs (which purge_ttl keeps around for the deleted record
+        // feed) don't count
         let calc_sql = if self.quota_enabled {
-            "SELECT SUM(BYTE_LENGTH(payload)), COUNT(*)
-            FROM bsos
-           WHERE fxa_uid = @fxa_uid
-             AND fxa_kid = @fxa_kid
-             AND collection_id = @collection_id
-           GROUP BY fxa_uid"
+            format!(
+                "SELECT SUM({}), COUNT(*)
+                FROM bsos
+               WHERE fxa_uid = @fxa_uid
+                 AND fxa_kid = @fxa_kid
+                 AND collection_id = @collection_id
+                 AND expiry > CURRENT_TIMESTAMP()
+               GROUP BY fxa_uid",
+                PAYLOAD_SIZE
+            )
         } else {
             "SELECT COUNT(*)
             FROM bsos
            WHERE fxa_uid = @fxa_uid
              AND fxa_kid = @fxa_kid
              AND collection_id = @collection_id
+             AND expiry > CURRENT_TIMESTAMP()
            GROUP BY fxa_uid"
+                .to_owned()
         };
         let result = self
-            .sql(calc_sql)?
+            .sql(&calc_sql)?
             .params(params! {
                 "fxa_uid" => user.fxa_uid.clone(),
                 "fxa_kid" => user.fxa_kid.clone(),
@@ -992,7 +1091,10 @@ impl SpannerDb {
         Ok(self.timestamp()?)
     }
 
-    pub async fn delete_storage_async(&self, user_id: params::DeleteStorage) -> Result<()> {
+    pub async fn delete_storage_async(
+        &self,
+        user_id: params::DeleteStorage,
+    ) -> Result<results::DeleteStorage> {
         // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
         // user_collections ON DELETE CASCADE)
         self.sql(
@@ -1000,196 +1102,1110 @@ impl SpannerDb {
               WHERE fxa_uid = @fxa_uid
                 AND fxa_kid = @fxa_kid",
         )?
+        .params(params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        // bso_history isn't interleaved, outliving its collections
+        self.sql(
+            "DELETE FROM bso_history
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid",
+        )?
         .params(params! {
             "fxa_uid" => user_id.fxa_uid,
             "fxa_kid" => user_id.fxa_kid,
         })
         .execute_dml_async(&self.conn)
         .await?;
-        Ok(())
-    }
-
-    pub fn timestamp(&self) -> Result<SyncTimestamp> {
-        self.session
-            .borrow()
-            .timestamp
-            .ok_or_else(|| DbError::internal("CURRENT_TIMESTAMP() not read yet"))
+        self.timestamp()
     }
 
-    pub async fn delete_collection_async(
+    pub async fn archive_storage_async(
         &self,
-        params: params::DeleteCollection,
-    ) -> Result<results::DeleteCollection> {
-        // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
-        // user_collections ON DELETE CASCADE)
-        let collection_id = self
-            .get_collection_id_async(&params.collection)
-            .await?
-            .to_string();
-        let affected_rows = self
+        params: params::ArchiveStorage,
+    ) -> Result<results::ArchiveStorage> {
+        let user_id = params.user_id;
+        let limit = params.limit as usize;
+        let kept = self
             .sql(
-                "DELETE FROM user_collections
+                "SELECT expiry > CURRENT_TIMESTAMP(), state,
+                        COALESCE((SELECT MAX(modified)
+                                    FROM user_collections u
+                                   WHERE u.fxa_uid = d.fxa_uid
+                                     AND u.fxa_kid = d.fxa_kid) > deleted, false)
+                   FROM deleted_storage d
                   WHERE fxa_uid = @fxa_uid
-                    AND fxa_kid = @fxa_kid
-                    AND collection_id = @collection_id
-                    AND modified > @pretouch_ts",
+                    AND fxa_kid = @fxa_kid",
             )?
             .params(params! {
-                "fxa_uid" => params.user_id.fxa_uid.clone(),
-                "fxa_kid" => params.user_id.fxa_kid.clone(),
-                "collection_id" => collection_id.clone(),
-                "pretouch_ts" => PRETOUCH_TS.to_owned(),
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+            })
+            .execute_async(&self.conn)?
+            .one_or_none()
+            .await?;
+        let resumed = match &kept {
+            Some(row) if row[0].get_bool_value() => {
+                let state = row[1]
+                    .get_string_value()
+                    .parse::<i32>()
+                    .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+                match state {
+                    // An interrupted copy, resumed
+                    DELETED_STORAGE_COPYING => true,
+                    // Replaced once the storage's written to since it was
+                    // taken
+                    DELETED_STORAGE_KEPT if row[2].get_bool_value() => false,
+                    // Otherwise kept: the first deletion is the likelier
+                    // mistake
+                    _ => return Ok(0),
+                }
+            }
+            _ => false,
+        };
+        if !resumed {
+            let has_bsos = self
+                .sql(
+                    "SELECT 1
+                       FROM bsos
+                      WHERE fxa_uid = @fxa_uid
+                        AND fxa_kid = @fxa_kid
+                        AND expiry > CURRENT_TIMESTAMP()
+                      LIMIT 1",
+                )?
+                .params(params! {
+                    "fxa_uid" => user_id.fxa_uid.clone(),
+                    "fxa_kid" => user_id.fxa_kid.clone(),
+                })
+                .execute_async(&self.conn)?
+                .one_or_none()
+                .await?
+                .is_some();
+            // Nothing to restore from an empty storage
+            if !has_bsos {
+                return Ok(0);
+            }
+            if kept.is_some() {
+                // The expired or outdated copy's purged first, a chunk at a
+                // time
+                let purged = self.delete_deleted_bsos_async(&user_id, limit).await?;
+                if purged == limit {
+                    return Ok(purged);
+                }
+                self.delete_deleted_storage_async(&user_id).await?;
+            }
+            self.sql(
+                "INSERT INTO deleted_storage (fxa_uid, fxa_kid, deleted, expiry, state)
+                 VALUES (@fxa_uid, @fxa_kid, CURRENT_TIMESTAMP(),
+                         TIMESTAMP_ADD(CURRENT_TIMESTAMP(), INTERVAL @grace_period SECOND),
+                         @state)",
+            )?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+                "grace_period" => params.grace_period.to_string(),
+                "state" => DELETED_STORAGE_COPYING.to_string(),
             })
             .param_types(param_types! {
-                "pretouch_ts" => TypeCode::TIMESTAMP,
+                "grace_period" => TypeCode::INT64,
+                "state" => TypeCode::INT64,
             })
             .execute_dml_async(&self.conn)
             .await?;
-        if affected_rows > 0 {
-            let mut tags = Tags::default();
-            tags.tags
-                .insert("collection".to_string(), params.collection);
-            self.metrics
-                .incr_with_tags("storage.spanner.delete_collection", Some(tags));
-            self.erect_tombstone(&params.user_id).await
-        } else {
-            self.get_storage_timestamp(params.user_id).await
         }
+
+        let archived = self
+            .sql(
+                "INSERT INTO deleted_bsos
+                        (fxa_uid, fxa_kid, collection_id, bso_id, sortindex, payload,
+                         payload_size, expiry)
+                 SELECT fxa_uid, fxa_kid, collection_id, bso_id, sortindex, payload,
+                        payload_size, expiry
+                   FROM bsos b
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND expiry > CURRENT_TIMESTAMP()
+                    AND NOT EXISTS (
+                        SELECT 1
+                          FROM deleted_bsos d
+                         WHERE d.fxa_uid = b.fxa_uid
+                           AND d.fxa_kid = b.fxa_kid
+                           AND d.collection_id = b.collection_id
+                           AND d.bso_id = b.bso_id)
+                  ORDER BY collection_id, bso_id
+                  LIMIT @limit",
+            )?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+                "limit" => params.limit.to_string(),
+            })
+            .param_types(param_types! {
+                "limit" => TypeCode::INT64,
+            })
+            .execute_dml_async(&self.conn)
+            .await? as usize;
+        if archived < limit {
+            self.set_deleted_storage_state_async(&user_id, DELETED_STORAGE_KEPT)
+                .await?;
+        }
+        Ok(archived)
     }
 
-    pub(super) async fn update_collection_async(
+    pub async fn restore_storage_async(
         &self,
-        user_id: &HawkIdentifier,
-        collection_id: i32,
-        collection: &str,
-    ) -> Result<SyncTimestamp> {
-        // NOTE: Spanner supports upserts via its InsertOrUpdate mutation but
-        // lacks a SQL equivalent. This call could be 1 InsertOrUpdate instead
-        // of 2 queries but would require put/post_bsos to also use mutations.
-        // Due to case of when no parent row exists (in user_collections)
-        // before writing to bsos. Spanner requires a parent table row exist
-        // before child table rows are written.
-        // Mutations don't run in the same order as ExecuteSql calls, they are
-        // buffered on the client side and only issued to Spanner in the final
-        // transaction Commit.
-        let timestamp = self.timestamp()?;
-        if !cfg!(test) && self.session.borrow().updated_collection {
-            // No need to touch it again (except during tests where we
-            // currently reuse Dbs for multiple requests)
-            return Ok(timestamp);
-        }
+        params: params::RestoreStorage,
+    ) -> Result<results::RestoreStorage> {
+        let user_id = params.user_id;
+        let limit = params.limit as usize;
+        let row = self
+            .sql(
+                "SELECT expiry > CURRENT_TIMESTAMP(), state, CURRENT_TIMESTAMP()
+                   FROM deleted_storage
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid",
+            )?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+            })
+            .execute_async(&self.conn)?
+            .one_or_none()
+            .await?;
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let state = row[1]
+            .get_string_value()
+            .parse::<i32>()
+            .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+        let timestamp = SyncTimestamp::from_rfc3339(row[2].get_string_value())?;
+        self.set_timestamp(timestamp);
 
         let sqlparams = params! {
             "fxa_uid" => user_id.fxa_uid.clone(),
             "fxa_kid" => user_id.fxa_kid.clone(),
-            "collection_id" => collection_id.to_string(),
             "modified" => timestamp.as_rfc3339()?,
         };
-        let sql_types = param_types! {
+        let sqltypes = param_types! {
             "modified" => TypeCode::TIMESTAMP,
         };
-        let result = self
-            .sql(
-                "SELECT 1
-                   FROM user_collections
-                  WHERE fxa_uid = @fxa_uid
-                    AND fxa_kid = @fxa_kid
-                    AND collection_id = @collection_id",
-            )?
-            .params(sqlparams.clone())
-            .execute_async(&self.conn)?
-            .one_or_none()
-            .await?;
-        if result.is_some() {
-            let mut tags = Tags::default();
-            tags.tags
-                .insert("collection".to_owned(), collection.to_owned());
-            let sql = "UPDATE user_collections
-                    SET modified = @modified
-                  WHERE fxa_uid = @fxa_uid
-                    AND fxa_kid = @fxa_kid
-                    AND collection_id = @collection_id";
-            self.sql(sql)?
-                .params(sqlparams)
-                .param_types(sql_types)
-                .execute_dml_async(&self.conn)
-                .await?;
-        } else {
-            let mut tags = Tags::default();
-            tags.tags
-                .insert("collection".to_owned(), collection.to_owned());
-            self.metrics
-                .clone()
-                .start_timer("storage.quota.init_totals", Some(tags));
-            let update_sql = if self.quota_enabled {
-                "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified, count, total_bytes)
-                VALUES (@fxa_uid, @fxa_kid, @collection_id, @modified, 0, 0)"
-            } else {
-                "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified)
-                VALUES (@fxa_uid, @fxa_kid, @collection_id, @modified)"
-            };
-            self.sql(update_sql)?
-                .params(sqlparams)
-                .param_types(sql_types)
+        match state {
+            // The first chunk replaces the user's storage
+            DELETED_STORAGE_KEPT if row[0].get_bool_value() => {
+                self.delete_storage_async(user_id.clone()).await?;
+                // The bsos' parent user_collections rows first
+                self.sql(
+                    "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified)
+                     SELECT DISTINCT fxa_uid, fxa_kid, collection_id, @modified
+                       FROM deleted_bsos
+                      WHERE fxa_uid = @fxa_uid
+                        AND fxa_kid = @fxa_kid",
+                )?
+                .params(sqlparams.clone())
+                .param_types(sqltypes.clone())
                 .execute_dml_async(&self.conn)
                 .await?;
+                self.set_deleted_storage_state_async(&user_id, DELETED_STORAGE_RESTORING)
+                    .await?;
+            }
+            DELETED_STORAGE_RESTORING => (),
+            _ => return Ok(None),
         }
-        self.session.borrow_mut().updated_collection = true;
-        Ok(timestamp)
-    }
 
-    pub async fn delete_bso_async(&self, params: params::DeleteBso) -> Result<results::DeleteBso> {
-        let collection_id = self.get_collection_id_async(&params.collection).await?;
-        let user_id = params.user_id.clone();
-        let affected_rows = self
+        // Records written by the user since are kept
+        let mut chunk_params = sqlparams;
+        chunk_params.insert("limit".to_owned(), as_value(params.limit.to_string()));
+        let mut chunk_types = sqltypes;
+        chunk_types.insert("limit".to_owned(), as_type(TypeCode::INT64));
+        let restored = self
             .sql(
-                "DELETE FROM bsos
+                "INSERT INTO bsos
+                        (fxa_uid, fxa_kid, collection_id, bso_id, sortindex, payload,
+                         payload_size, modified, expiry)
+                 SELECT fxa_uid, fxa_kid, collection_id, bso_id, sortindex, payload,
+                        payload_size, @modified, expiry
+                   FROM deleted_bsos d
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
-                    AND collection_id = @collection_id
-                    AND bso_id = @bso_id",
+                    AND NOT EXISTS (
+                        SELECT 1
+                          FROM bsos b
+                         WHERE b.fxa_uid = d.fxa_uid
+                           AND b.fxa_kid = d.fxa_kid
+                           AND b.collection_id = d.collection_id
+                           AND b.bso_id = d.bso_id)
+                  ORDER BY collection_id, bso_id
+                  LIMIT @limit",
             )?
-            .params(params! {
-                "fxa_uid" => params.user_id.fxa_uid,
-                "fxa_kid" => params.user_id.fxa_kid,
-                "collection_id" => collection_id.to_string(),
-                "bso_id" => params.id,
-            })
+            .params(chunk_params)
+            .param_types(chunk_types)
             .execute_dml_async(&self.conn)
-            .await?;
-        if affected_rows == 0 {
-            Err(DbErrorKind::BsoNotFound)?
-        } else {
-            self.metrics.incr("storage.spanner.delete_bso");
-            Ok(self
-                .update_user_collection_quotas(&user_id, collection_id)
-                .await?)
-        }
-    }
-
-    pub async fn delete_bsos_async(
-        &self,
-        params: params::DeleteBsos,
-    ) -> Result<results::DeleteBsos> {
-        let user_id = params.user_id.clone();
-        let collection_id = self.get_collection_id_async(&params.collection).await?;
-
-        let mut sqlparams = params! {
-            "fxa_uid" => user_id.fxa_uid,
-            "fxa_kid" => user_id.fxa_kid,
-            "collection_id" => collection_id.to_string(),
-        };
-        sqlparams.insert("ids".to_owned(), as_list_value(params.ids.into_iter()));
+            .await? as usize;
         self.sql(
-            "DELETE FROM bsos
+            "DELETE FROM deleted_bsos d
               WHERE fxa_uid = @fxa_uid
                 AND fxa_kid = @fxa_kid
-                AND collection_id = @collection_id
-                AND bso_id IN UNNEST(@ids)",
+                AND EXISTS (
+                    SELECT 1
+                      FROM bsos b
+                     WHERE b.fxa_uid = d.fxa_uid
+                       AND b.fxa_kid = d.fxa_kid
+                       AND b.collection_id = d.collection_id
+                       AND b.bso_id = d.bso_id)",
         )?
-        .params(sqlparams)
-        .execute_dml_async(&self.conn)
-        .await?;
+        .params(params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+
+        if restored < limit {
+            // Done: the collections are modified as of the last chunk
+            let mut streaming = self
+                .sql(
+                    "SELECT collection_id
+                       FROM user_collections
+                      WHERE fxa_uid = @fxa_uid
+                        AND fxa_kid = @fxa_kid",
+                )?
+                .params(params! {
+                    "fxa_uid" => user_id.fxa_uid.clone(),
+                    "fxa_kid" => user_id.fxa_kid.clone(),
+                })
+                .execute_async(&self.conn)?;
+            let mut collection_ids = vec![];
+            while let Some(row) = streaming.next_async().await {
+                let row = row?;
+                collection_ids.push(
+                    row[0]
+                        .get_string_value()
+                        .parse::<i32>()
+                        .map_err(|e| DbErrorKind::Integrity(e.to_string()))?,
+                );
+            }
+            for collection_id in collection_ids {
+                self.update_user_collection_quotas(&user_id, collection_id)
+                    .await?;
+            }
+            self.delete_deleted_storage_async(&user_id).await?;
+        }
+        Ok(Some(results::RestoredStorage {
+            restored,
+            modified: timestamp,
+        }))
+    }
+
+    pub async fn purge_deleted_storage_async(
+        &self,
+        params: params::PurgeDeletedStorage,
+    ) -> Result<results::PurgeDeletedStorage> {
+        let limit = params.limit as usize;
+        let mut streaming = self
+            .sql(
+                "SELECT fxa_uid, fxa_kid
+                   FROM deleted_storage
+                  WHERE expiry <= CURRENT_TIMESTAMP()
+                  LIMIT @limit",
+            )?
+            .params(params! {
+                "limit" => params.limit.to_string(),
+            })
+            .param_types(param_types! {
+                "limit" => TypeCode::INT64,
+            })
+            .execute_async(&self.conn)?;
+        let mut user_ids = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let row = row?;
+            user_ids.push(HawkIdentifier {
+                legacy_id: 0,
+                fxa_uid: row[0].get_string_value().to_owned(),
+                fxa_kid: row[1].get_string_value().to_owned(),
+            });
+        }
+        let mut purged = 0;
+        for user_id in &user_ids {
+            let deleted = self
+                .delete_deleted_bsos_async(user_id, limit - purged)
+                .await?;
+            purged += deleted;
+            if purged == limit {
+                break;
+            }
+            self.delete_deleted_storage_async(user_id).await?;
+            purged += 1;
+            if purged == limit {
+                break;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Delete up to `limit` records of the user's deleted storage
+    async fn delete_deleted_bsos_async(
+        &self,
+        user_id: &HawkIdentifier,
+        limit: usize,
+    ) -> Result<usize> {
+        let mut streaming = self
+            .sql(
+                "SELECT collection_id, bso_id
+                   FROM deleted_bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                  LIMIT @limit",
+            )?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+                "limit" => limit.to_string(),
+            })
+            .param_types(param_types! {
+                "limit" => TypeCode::INT64,
+            })
+            .execute_async(&self.conn)?;
+        let mut ids: HashMap<String, Vec<String>> = HashMap::new();
+        let mut deleted = 0;
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            ids.entry(row[0].take_string_value())
+                .or_default()
+                .push(row[1].take_string_value());
+            deleted += 1;
+        }
+        for (collection_id, bso_ids) in ids {
+            let mut sqlparams = params! {
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+                "collection_id" => collection_id,
+            };
+            sqlparams.insert("ids".to_owned(), as_list_value(bso_ids.into_iter()));
+            self.sql(
+                "DELETE FROM deleted_bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND bso_id IN UNNEST(@ids)",
+            )?
+            .params(sqlparams)
+            .execute_dml_async(&self.conn)
+            .await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn set_deleted_storage_state_async(
+        &self,
+        user_id: &HawkIdentifier,
+        state: i32,
+    ) -> Result<()> {
+        self.sql(
+            "UPDATE deleted_storage
+                SET state = @state
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid",
+        )?
+        .params(params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "state" => state.to_string(),
+        })
+        .param_types(param_types! {
+            "state" => TypeCode::INT64,
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Delete the copy of the user's deleted storage once its records are
+    /// deleted
+    async fn delete_deleted_storage_async(&self, user_id: &HawkIdentifier) -> Result<()> {
+        self.sql(
+            "DELETE FROM deleted_storage
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid",
+        )?
+        .params(params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn record_audit_async(&self, params: params::RecordAudit) -> Result<()> {
+        let timestamp = params.timestamp;
+        self.sql(
+            "DELETE FROM audit_log
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND expiry <= @timestamp",
+        )?
+        .params(params! {
+            "fxa_uid" => params.user_id.fxa_uid.clone(),
+            "fxa_kid" => params.user_id.fxa_kid.clone(),
+            "timestamp" => timestamp.as_rfc3339()?,
+        })
+        .param_types(param_types! {
+            "timestamp" => TypeCode::TIMESTAMP,
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+
+        let mut sqlparams = params! {
+            "fxa_uid" => params.user_id.fxa_uid,
+            "fxa_kid" => params.user_id.fxa_kid,
+            "recorded" => timestamp.as_rfc3339()?,
+            "entry_id" => Uuid::new_v4().to_simple().to_string(),
+            "op" => params.op.as_str().to_owned(),
+            "count" => params.count.to_string(),
+            "user_agent" => params.user_agent,
+            "device_id" => params.device_id,
+            "expiry" => to_rfc3339(timestamp.as_i64() + i64::from(params.retention) * 1000)?,
+        };
+        sqlparams.insert(
+            "collection".to_owned(),
+            params.collection.map(as_value).unwrap_or_else(null_value),
+        );
+        self.sql(
+            "INSERT INTO audit_log
+                    (fxa_uid, fxa_kid, recorded, entry_id, op, collection, count, user_agent,
+                     device_id, expiry)
+             VALUES (@fxa_uid, @fxa_kid, @recorded, @entry_id, @op, @collection, @count,
+                     @user_agent, @device_id, @expiry)",
+        )?
+        .params(sqlparams)
+        .param_types(param_types! {
+            "recorded" => TypeCode::TIMESTAMP,
+            "collection" => TypeCode::STRING,
+            "count" => TypeCode::INT64,
+            "expiry" => TypeCode::TIMESTAMP,
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_audit_log_async(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::GetAuditLog> {
+        let mut streaming = self
+            .sql(
+                "SELECT collection, op, count, recorded, user_agent, device_id
+                   FROM audit_log
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND expiry > CURRENT_TIMESTAMP()
+                  ORDER BY recorded DESC",
+            )?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid,
+                "fxa_kid" => user_id.fxa_kid,
+            })
+            .execute_async(&self.conn)?;
+        let mut entries = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            entries.push(results::AuditEntry {
+                collection: if row[0].has_null_value() {
+                    None
+                } else {
+                    Some(row[0].take_string_value())
+                },
+                op: row[1].get_string_value().parse::<AuditOp>()?,
+                count: row[2]
+                    .get_string_value()
+                    .parse::<i64>()
+                    .map_err(|e| DbErrorKind::Integrity(e.to_string()))?,
+                timestamp: SyncTimestamp::from_rfc3339(row[3].get_string_value())?,
+                user_agent: row[4].take_string_value(),
+                device_id: row[5].take_string_value(),
+            });
+        }
+        Ok(entries)
+    }
+
+    pub async fn get_revocations_async(
+        &self,
+        _: params::GetRevocations,
+    ) -> Result<results::GetRevocations> {
+        let mut streaming = self
+            .sql(
+                "SELECT key_type, key_value, issued_before
+                   FROM revocations",
+            )?
+            .execute_async(&self.conn)?;
+        let mut revocations = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            let key_type = row[0].take_string_value();
+            let key = RevocationKey::from_parts(&key_type, row[1].take_string_value()).ok_or_else(
+                || DbErrorKind::Integrity(format!("Invalid revocation key type {}", key_type)),
+            )?;
+            let issued_before = if row[2].has_null_value() {
+                None
+            } else {
+                Some(
+                    row[2]
+                        .get_string_value()
+                        .parse::<u64>()
+                        .map_err(|e| DbErrorKind::Integrity(e.to_string()))?,
+                )
+            };
+            revocations.push(Revocation { key, issued_before });
+        }
+        Ok(revocations)
+    }
+
+    pub async fn put_revocation_async(
+        &self,
+        params: params::PutRevocation,
+    ) -> Result<results::PutRevocation> {
+        self.sql(
+            "DELETE FROM revocations
+              WHERE issued_before <= @expired_before",
+        )?
+        .params(params! {
+            "expired_before" => params.expired_before.to_string(),
+        })
+        .param_types(param_types! {
+            "expired_before" => TypeCode::INT64,
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        let revocation = params.revocation;
+        let (key_type, key_value) = revocation.key.to_parts();
+        let mut sqlparams = params! {
+            "key_type" => key_type.to_owned(),
+            "key_value" => key_value,
+        };
+        sqlparams.insert(
+            "issued_before".to_owned(),
+            revocation
+                .issued_before
+                .map(|issued_before| as_value(issued_before.to_string()))
+                .unwrap_or_else(null_value),
+        );
+        self.sql(
+            "INSERT OR UPDATE INTO revocations (key_type, key_value, issued_before)
+             VALUES (@key_type, @key_value, @issued_before)",
+        )?
+        .params(sqlparams)
+        .param_types(param_types! {
+            "issued_before" => TypeCode::INT64,
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_revocation_async(
+        &self,
+        key: params::DeleteRevocation,
+    ) -> Result<results::DeleteRevocation> {
+        let (key_type, key_value) = key.to_parts();
+        let count = self
+            .sql(
+                "DELETE FROM revocations
+                  WHERE key_type = @key_type
+                    AND key_value = @key_value",
+            )?
+            .params(params! {
+                "key_type" => key_type.to_owned(),
+                "key_value" => key_value,
+            })
+            .execute_dml_async(&self.conn)
+            .await?;
+        Ok(count > 0)
+    }
+
+    /// Overrides are kept by fxa_uid alone, so they survive key rotation
+    pub async fn get_user_limits_async(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::GetUserLimits> {
+        let row = self
+            .sql(
+                "SELECT max_post_bytes, max_post_records, max_record_payload_bytes,
+                        max_total_bytes, max_total_records, max_quota_limit
+                   FROM user_limits
+                  WHERE fxa_uid = @fxa_uid",
+            )?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid,
+            })
+            .execute_async(&self.conn)?
+            .one_or_none()
+            .await?;
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let limit = |value: &Value| {
+            if value.has_null_value() {
+                return Ok(None);
+            }
+            value
+                .get_string_value()
+                .parse::<u32>()
+                .map(Some)
+                .map_err(|e| DbError::from(DbErrorKind::Integrity(e.to_string())))
+        };
+        Ok(Some(LimitOverrides {
+            max_post_bytes: limit(&row[0])?,
+            max_post_records: limit(&row[1])?,
+            max_record_payload_bytes: limit(&row[2])?,
+            max_total_bytes: limit(&row[3])?,
+            max_total_records: limit(&row[4])?,
+            max_quota_limit: limit(&row[5])?,
+        }))
+    }
+
+    pub async fn put_user_limits_async(
+        &self,
+        params: params::PutUserLimits,
+    ) -> Result<results::PutUserLimits> {
+        let limits = params.limits;
+        let limit = |value: Option<u32>| {
+            value
+                .map(|value| as_value(value.to_string()))
+                .unwrap_or_else(null_value)
+        };
+        let mut sqlparams = params! {
+            "fxa_uid" => params.user_id.fxa_uid,
+        };
+        let mut sqltypes = HashMap::new();
+        for &(name, value) in &[
+            ("max_post_bytes", limits.max_post_bytes),
+            ("max_post_records", limits.max_post_records),
+            ("max_record_payload_bytes", limits.max_record_payload_bytes),
+            ("max_total_bytes", limits.max_total_bytes),
+            ("max_total_records", limits.max_total_records),
+            ("max_quota_limit", limits.max_quota_limit),
+        ] {
+            sqlparams.insert(name.to_owned(), limit(value));
+            sqltypes.insert(name.to_owned(), as_type(TypeCode::INT64));
+        }
+        self.sql(
+            "INSERT OR UPDATE INTO user_limits
+                    (fxa_uid, max_post_bytes, max_post_records, max_record_payload_bytes,
+                     max_total_bytes, max_total_records, max_quota_limit)
+             VALUES (@fxa_uid, @max_post_bytes, @max_post_records, @max_record_payload_bytes,
+                     @max_total_bytes, @max_total_records, @max_quota_limit)",
+        )?
+        .params(sqlparams)
+        .param_types(sqltypes)
+        .execute_dml_async(&self.conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_user_limits_async(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::DeleteUserLimits> {
+        let count = self
+            .sql(
+                "DELETE FROM user_limits
+                  WHERE fxa_uid = @fxa_uid",
+            )?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid,
+            })
+            .execute_dml_async(&self.conn)
+            .await?;
+        Ok(count > 0)
+    }
+
+    /// Rewrite the user's payloads not encrypted under the primary key. Each
+    /// row's only rewritten if its payload's unchanged since it was read (a
+    /// concurrent write wins): such rows are left for the next run
+    pub async fn reencrypt_payloads_async(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::ReencryptPayloads> {
+        let cipher = match self.payload_codec.cipher() {
+            Some(cipher) => cipher,
+            None => return Ok(0),
+        };
+        let mut count = 0;
+        for &(table, keys, filter) in &[
+            ("bsos", &["collection_id", "bso_id"][..], ""),
+            (
+                "batch_bsos",
+                &["collection_id", "batch_bso_id", "batch_id"][..],
+                "",
+            ),
+            ("deleted_bsos", &["collection_id", "bso_id"][..], ""),
+            (
+                "bso_history",
+                &["collection_id", "bso_id", "modified"][..],
+                "AND NOT deleted",
+            ),
+        ] {
+            count += self
+                .reencrypt_table_async(cipher, &user_id, table, keys, filter)
+                .await?;
+        }
+        Ok(count)
+    }
+
+    /// Rewrite the payloads in one of the user's tables, identifying its
+    /// rows by the `keys` columns following fxa_uid/fxa_kid: their
+    /// collection_id and BSO id first
+    async fn reencrypt_table_async(
+        &self,
+        cipher: &PayloadCipher,
+        user_id: &HawkIdentifier,
+        table: &str,
+        keys: &[&str],
+        filter: &str,
+    ) -> Result<u64> {
+        let mut streaming = self
+            .sql(&format!(
+                "SELECT {}, payload
+                   FROM {}
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND payload IS NOT NULL
+                    {}",
+                keys.join(", "),
+                table,
+                filter
+            ))?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+            })
+            .execute_async(&self.conn)?;
+        let mut rows = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            let payload = row.pop().unwrap_or_default().take_string_value();
+            if cipher.needs_reencryption(&payload) {
+                rows.push((row, payload));
+            }
+        }
+
+        let conditions: Vec<_> = keys.iter().map(|key| format!("{0} = @{0}", key)).collect();
+        let sql = format!(
+            "UPDATE {}
+                SET payload = @payload
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND {}
+                AND payload = @old_payload",
+            table,
+            conditions.join(" AND ")
+        );
+        let mut count = 0;
+        for (row, old) in rows {
+            let collection_id = row[0]
+                .get_string_value()
+                .parse::<i32>()
+                .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+            let context = payload_context(user_id, collection_id, row[1].get_string_value());
+            let payload = cipher.encrypt(&cipher.decrypt(old.clone(), &context)?, &context)?;
+            let mut sqlparams = params! {
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+                "payload" => payload,
+                "old_payload" => old,
+            };
+            for (key, value) in keys.iter().zip(row) {
+                sqlparams.insert((*key).to_owned(), value);
+            }
+            let mut sqltypes = HashMap::new();
+            if keys.contains(&"modified") {
+                sqltypes.insert("modified".to_owned(), as_type(TypeCode::TIMESTAMP));
+            }
+            count += self
+                .sql(&sql)?
+                .params(sqlparams)
+                .param_types(sqltypes)
+                .execute_dml_async(&self.conn)
+                .await? as u64;
+        }
+        Ok(count)
+    }
+
+    pub fn timestamp(&self) -> Result<SyncTimestamp> {
+        self.session
+            .borrow()
+            .timestamp
+            .ok_or_else(|| DbError::internal("CURRENT_TIMESTAMP() not read yet"))
+    }
+
+    /// Encode a payload for storage
+    pub(super) fn encode_payload(
+        &self,
+        payload: String,
+        user_id: &HawkIdentifier,
+        collection_id: i32,
+        id: &str,
+    ) -> Result<String> {
+        let context = payload_context(user_id, collection_id, id);
+        self.payload_codec.encode(payload, &context, &self.metrics)
+    }
+
+    /// Decode a stored payload
+    fn decode_payload(
+        &self,
+        stored: String,
+        user_id: &HawkIdentifier,
+        collection_id: i32,
+        id: &str,
+    ) -> Result<String> {
+        let context = payload_context(user_id, collection_id, id);
+        self.payload_codec.decode(stored, &context)
+    }
+
+    pub async fn delete_collection_async(
+        &self,
+        params: params::DeleteCollection,
+    ) -> Result<results::DeleteCollection> {
+        // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
+        // user_collections ON DELETE CASCADE)
+        let collection_id = self.get_collection_id_async(&params.collection).await?;
+        // Its history's kept for restoring it
+        if self.bso_history.contains_key(&params.collection) {
+            let mut streaming = self
+                .sql(
+                    "SELECT bso_id
+                       FROM bsos
+                      WHERE fxa_uid = @fxa_uid
+                        AND fxa_kid = @fxa_kid
+                        AND collection_id = @collection_id",
+                )?
+                .params(params! {
+                    "fxa_uid" => params.user_id.fxa_uid.clone(),
+                    "fxa_kid" => params.user_id.fxa_kid.clone(),
+                    "collection_id" => collection_id.to_string(),
+                })
+                .execute_async(&self.conn)?;
+            let mut ids = vec![];
+            while let Some(row) = streaming.next_async().await {
+                ids.push(row?[0].take_string_value());
+            }
+            self.record_revisions_async(
+                &params.user_id,
+                &params.collection,
+                collection_id,
+                ids,
+                self.timestamp()?,
+                true,
+            )
+            .await?;
+        }
+        let collection_id = collection_id.to_string();
+        let affected_rows = self
+            .sql(
+                "DELETE FROM user_collections
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND modified > @pretouch_ts",
+            )?
+            .params(params! {
+                "fxa_uid" => params.user_id.fxa_uid.clone(),
+                "fxa_kid" => params.user_id.fxa_kid.clone(),
+                "collection_id" => collection_id.clone(),
+                "pretouch_ts" => PRETOUCH_TS.to_owned(),
+            })
+            .param_types(param_types! {
+                "pretouch_ts" => TypeCode::TIMESTAMP,
+            })
+            .execute_dml_async(&self.conn)
+            .await?;
+        if affected_rows > 0 {
+            let mut tags = Tags::default();
+            tags.tags
+                .insert("collection".to_string(), params.collection);
+            self.metrics
+                .incr_with_tags("storage.spanner.delete_collection", Some(tags));
+            self.erect_tombstone(&params.user_id).await
+        } else {
+            self.get_storage_timestamp(params.user_id).await
+        }
+    }
+
+    pub(super) async fn update_collection_async(
+        &self,
+        user_id: &HawkIdentifier,
+        collection_id: i32,
+        collection: &str,
+    ) -> Result<SyncTimestamp> {
+        // NOTE: Spanner supports upserts via its InsertOrUpdate mutation but
+        // lacks a SQL equivalent. This call could be 1 InsertOrUpdate instead
+        // of 2 queries but would require put/post_bsos to also use mutations.
+        // Due to case of when no parent row exists (in user_collections)
+        // before writing to bsos. Spanner requires a parent table row exist
+        // before child table rows are written.
+        // Mutations don't run in the same order as ExecuteSql calls, they are
+        // buffered on the client side and only issued to Spanner in the final
+        // transaction Commit.
+        let timestamp = self.timestamp()?;
+        if !cfg!(test) && self.session.borrow().updated_collection {
+            // No need to touch it again (except during tests where we
+            // currently reuse Dbs for multiple requests)
+            return Ok(timestamp);
+        }
+
+        let sqlparams = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+            "modified" => timestamp.as_rfc3339()?,
+        };
+        let sql_types = param_types! {
+            "modified" => TypeCode::TIMESTAMP,
+        };
+        let result = self
+            .sql(
+                "SELECT 1
+                   FROM user_collections
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id",
+            )?
+            .params(sqlparams.clone())
+            .execute_async(&self.conn)?
+            .one_or_none()
+            .await?;
+        if result.is_some() {
+            let mut tags = Tags::default();
+            tags.tags
+                .insert("collection".to_owned(), collection.to_owned());
+            let sql = "UPDATE user_collections
+                    SET modified = @modified
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id";
+            self.sql(sql)?
+                .params(sqlparams)
+                .param_types(sql_types)
+                .execute_dml_async(&self.conn)
+                .await?;
+        } else {
+            let mut tags = Tags::default();
+            tags.tags
+                .insert("collection".to_owned(), collection.to_owned());
+            self.metrics
+                .clone()
+                .start_timer("storage.quota.init_totals", Some(tags));
+            let update_sql = if self.quota_enabled {
+                "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified, count, total_bytes)
+                VALUES (@fxa_uid, @fxa_kid, @collection_id, @modified, 0, 0)"
+            } else {
+                "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified)
+                VALUES (@fxa_uid, @fxa_kid, @collection_id, @modified)"
+            };
+            self.sql(update_sql)?
+                .params(sqlparams)
+                .param_types(sql_types)
+                .execute_dml_async(&self.conn)
+                .await?;
+        }
+        self.session.borrow_mut().updated_collection = true;
+        Ok(timestamp)
+    }
+
+    pub async fn delete_bso_async(&self, params: params::DeleteBso) -> Result<results::DeleteBso> {
+        let collection_id = self.get_collection_id_async(&params.collection).await?;
+        let user_id = params.user_id.clone();
+        self.record_revisions_async(
+            &user_id,
+            &params.collection,
+            collection_id,
+            vec![params.id.clone()],
+            self.timestamp()?,
+            true,
+        )
+        .await?;
+        let affected_rows = self
+            .sql(
+                "DELETE FROM bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND bso_id = @bso_id",
+            )?
+            .params(params! {
+                "fxa_uid" => params.user_id.fxa_uid,
+                "fxa_kid" => params.user_id.fxa_kid,
+                "collection_id" => collection_id.to_string(),
+                "bso_id" => params.id.clone(),
+            })
+            .execute_dml_async(&self.conn)
+            .await?;
+        if affected_rows == 0 {
+            Err(DbErrorKind::BsoNotFound)?
+        } else {
+            self.metrics.incr("storage.spanner.delete_bso");
+            self.record_deletions(&user_id, collection_id, vec![params.id])?;
+            Ok(self
+                .update_user_collection_quotas(&user_id, collection_id)
+                .await?)
+        }
+    }
+
+    pub async fn delete_bsos_async(
+        &self,
+        params: params::DeleteBsos,
+    ) -> Result<results::DeleteBsos> {
+        let user_id = params.user_id.clone();
+        let collection_id = self.get_collection_id_async(&params.collection).await?;
+
+        let mut sqlparams = params! {
+            "fxa_uid" => user_id.fxa_uid,
+            "fxa_kid" => user_id.fxa_kid,
+            "collection_id" => collection_id.to_string(),
+        };
+        sqlparams.insert("ids".to_owned(), as_list_value(params.ids.into_iter()));
+        let mut streaming = self
+            .sql(
+                "SELECT bso_id
+                   FROM bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND bso_id IN UNNEST(@ids)",
+            )?
+            .params(sqlparams.clone())
+            .execute_async(&self.conn)?;
+        let mut ids = vec![];
+        while let Some(row) = streaming.next_async().await {
+            ids.push(row?[0].get_string_value().to_owned());
+        }
+        self.record_revisions_async(
+            &params.user_id,
+            &params.collection,
+            collection_id,
+            ids.clone(),
+            self.timestamp()?,
+            true,
+        )
+        .await?;
+        self.sql(
+            "DELETE FROM bsos
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND bso_id IN UNNEST(@ids)",
+        )?
+        .params(sqlparams)
+        .execute_dml_async(&self.conn)
+        .await?;
+        self.record_deletions(&params.user_id, collection_id, ids)?;
         let mut tags = Tags::default();
         tags.tags
             .insert("collection".to_string(), params.collection.clone());
@@ -1227,8 +2243,7 @@ impl SpannerDb {
             sqlparams.insert("ids".to_owned(), as_list_value(ids.into_iter()));
         }
 
-        // issue559: Dead code (timestamp always None)
-        /*
+        // Resume keyset pagination from the offset's timestamp
         if let Some(timestamp) = offset.clone().unwrap_or_default().timestamp {
             query = match sort {
                 Sorting::Newest => {
@@ -1244,7 +2259,6 @@ impl SpannerDb {
                 _ => query,
             };
         }
-        */
         if let Some(older) = older {
             query = format!("{} AND modified < @older", query);
             sqlparams.insert("older".to_string(), as_value(older.as_rfc3339()?));
@@ -1256,17 +2270,9 @@ impl SpannerDb {
             sqltypes.insert("newer".to_string(), as_type(TypeCode::TIMESTAMP));
         }
         query = match sort {
-            // issue559: Revert to previous sorting
-            /*
-            Sorting::Index => format!("{} ORDER BY sortindex DESC, bso_id DESC", query),
-            Sorting::Newest | Sorting::None => {
-                format!("{} ORDER BY modified DESC, bso_id DESC", query)
-            }
-            Sorting::Oldest => format!("{} ORDER BY modified ASC, bso_id ASC", query),
-            */
             Sorting::Index => format!("{} ORDER BY sortindex DESC", query),
-            Sorting::Newest => format!("{} ORDER BY modified DESC", query),
-            Sorting::Oldest => format!("{} ORDER BY modified ASC", query),
+            Sorting::Newest => format!("{} ORDER BY modified DESC, bso_id DESC", query),
+            Sorting::Oldest => format!("{} ORDER BY modified ASC, bso_id ASC", query),
             _ => query,
         };
 
@@ -1296,59 +2302,6 @@ impl SpannerDb {
             .execute_async(&self.conn)
     }
 
-    pub fn encode_next_offset(
-        &self,
-        _sort: Sorting,
-        offset: u64,
-        _timestamp: Option<i64>,
-        modifieds: Vec<i64>,
-    ) -> Option<String> {
-        // issue559: Use a simple numeric offset everwhere as previously for
-        // now: was previously a value of "limit + offset", modifieds.len()
-        // always equals limit
-        Some(
-            Offset {
-                offset: offset + modifieds.len() as u64,
-                timestamp: None,
-            }
-            .to_string(),
-        )
-        /*
-        let mut calc_offset = 1;
-        let mut i = (modifieds.len() as i64) - 2;
-
-        let prev_bound = match sort {
-            Sorting::Index => {
-                // Use a simple numeric offset for sortindex ordering.
-                return Some(
-                    Offset {
-                        offset: offset + modifieds.len() as u64,
-                        timestamp: None,
-                    }
-                    .to_string(),
-                );
-            }
-            Sorting::None => timestamp,
-            Sorting::Newest => timestamp,
-            Sorting::Oldest => timestamp,
-        };
-        // Find an appropriate upper bound for faster timestamp ordering.
-        let bound = *modifieds.last().unwrap_or(&0);
-        // Count how many previous items have that same timestamp, and hence
-        // will need to be skipped over.  The number of matches here is limited
-        // by upload batch size.
-        while i >= 0 && modifieds[i as usize] == bound {
-            calc_offset += 1;
-            i -= 1;
-        }
-        if i < 0 && prev_bound.is_some() && prev_bound.unwrap() == bound {
-            calc_offset += offset;
-        }
-
-        Some(format!("{}:{}", bound, calc_offset))
-        */
-    }
-
     pub async fn get_bsos_async(&self, params: params::GetBsos) -> Result<results::GetBsos> {
         let query = "\
             SELECT bso_id, sortindex, payload, modified, expiry
@@ -1361,11 +2314,14 @@ impl SpannerDb {
         let Offset { offset, timestamp } = params.params.offset.clone().unwrap_or_default();
         let sort = params.params.sort;
 
+        let user_id = params.user_id.clone();
+        let collection_id = self.get_collection_id_async(&params.collection).await?;
         let mut streaming = self.bsos_query_async(query, params).await?;
         let mut bsos = vec![];
         while let Some(row) = streaming.next_async().await {
-            let row = row?;
-            bsos.push(bso_from_row(row)?);
+            let mut bso = bso_from_row(row?)?;
+            bso.payload = self.decode_payload(bso.payload, &user_id, collection_id, &bso.id)?;
+            bsos.push(bso);
         }
 
         // NOTE: when bsos.len() == 0, server-syncstorage (the Python impl)
@@ -1378,7 +2334,12 @@ impl SpannerDb {
         let next_offset = if limit >= 0 && bsos.len() > limit as usize {
             bsos.pop();
             let modifieds: Vec<i64> = bsos.iter().map(|r| r.modified.as_i64()).collect();
-            self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+            Some(encode_next_offset(
+                sort,
+                offset,
+                timestamp.map(|t| t.as_i64()),
+                &modifieds,
+            ))
         } else {
             None
         };
@@ -1420,7 +2381,12 @@ impl SpannerDb {
         let next_offset = if limit >= 0 && ids.len() > limit as usize {
             ids.pop();
             modifieds.pop();
-            self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+            Some(encode_next_offset(
+                sort,
+                offset,
+                timestamp.map(|t| t.as_i64()),
+                &modifieds,
+            ))
         } else {
             None
         };
@@ -1433,6 +2399,7 @@ impl SpannerDb {
 
     pub async fn get_bso_async(&self, params: params::GetBso) -> Result<Option<results::GetBso>> {
         let collection_id = self.get_collection_id_async(&params.collection).await?;
+        let user_id = params.user_id.clone();
         self.sql(
             "SELECT bso_id, sortindex, payload, modified, expiry
                FROM bsos
@@ -1451,10 +2418,436 @@ impl SpannerDb {
         .execute_async(&self.conn)?
         .one_or_none()
         .await?
-        .map(bso_from_row)
+        .map(|row| {
+            let bso = bso_from_row(row)?;
+            Ok(results::GetBso {
+                payload: self.decode_payload(bso.payload, &user_id, collection_id, &bso.id)?,
+                ..bso
+            })
+        })
         .transpose()
     }
 
+    /// Remember the ids of deleted BSOs for the deleted record feed. They're
+    /// removed by the purge_ttl job once they expire
+    fn record_deletions(
+        &self,
+        user_id: &HawkIdentifier,
+        collection_id: i32,
+        ids: Vec<String>,
+    ) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let timestamp = self.timestamp()?;
+        let deleted = timestamp.as_rfc3339()?;
+        let expiry = to_rfc3339(timestamp.as_i64() + i64::from(self.deleted_bso_retention) * 1000)?;
+        let rows = ids
+            .into_iter()
+            .map(|id| {
+                let mut row = ListValue::new();
+                row.set_values(RepeatedField::from_vec(vec![
+                    as_value(user_id.fxa_uid.clone()),
+                    as_value(user_id.fxa_kid.clone()),
+                    as_value(collection_id.to_string()),
+                    as_value(id),
+                    as_value(deleted.clone()),
+                    as_value(expiry.clone()),
+                ]));
+                row
+            })
+            .collect();
+        self.insert_or_update(
+            "bso_deletions",
+            &[
+                "fxa_uid",
+                "fxa_kid",
+                "collection_id",
+                "bso_id",
+                "deleted",
+                "expiry",
+            ],
+            rows,
+        );
+        Ok(())
+    }
+
+    /// Keep the current revisions of the BSOs about to be rewritten, or
+    /// deleted (followed by a tombstone), and tombstones for those about to be
+    /// created, when the collection's revisions are kept, pruning those kept
+    /// long enough
+    pub(super) async fn record_revisions_async(
+        &self,
+        user_id: &HawkIdentifier,
+        collection: &str,
+        collection_id: i32,
+        ids: Vec<String>,
+        timestamp: SyncTimestamp,
+        deleted: bool,
+    ) -> Result<()> {
+        let policy = match self.bso_history.get(collection) {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        let mut sqlparams = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+        };
+        sqlparams.insert("ids".to_owned(), as_list_value(ids.into_iter()));
+        let mut insert_params = sqlparams.clone();
+        insert_params.insert("timestamp".to_owned(), as_value(timestamp.as_rfc3339()?));
+        self.sql(
+            "INSERT INTO bso_history
+                    (fxa_uid, fxa_kid, collection_id, bso_id, modified, sortindex, payload,
+                     expiry, deleted)
+             SELECT fxa_uid, fxa_kid, collection_id, bso_id, modified, sortindex, payload,
+                    expiry, FALSE
+               FROM bsos AS b
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND bso_id IN UNNEST(@ids)
+                AND expiry > @timestamp
+                AND NOT EXISTS (
+                    SELECT 1
+                      FROM bso_history AS h
+                     WHERE h.fxa_uid = b.fxa_uid
+                       AND h.fxa_kid = b.fxa_kid
+                       AND h.collection_id = b.collection_id
+                       AND h.bso_id = b.bso_id
+                       AND h.modified = b.modified)",
+        )?
+        .params(insert_params.clone())
+        .param_types(param_types! {
+            "timestamp" => TypeCode::TIMESTAMP,
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        if !deleted {
+            // Tombstones for the BSOs created now, as of just before, so
+            // restoring an earlier time removes them
+            let mut created_params = insert_params.clone();
+            created_params.insert(
+                "expiry".to_owned(),
+                as_value(to_rfc3339(
+                    timestamp.as_i64() + i64::from(DEFAULT_BSO_TTL) * 1000,
+                )?),
+            );
+            self.sql(
+                "INSERT OR UPDATE INTO bso_history
+                        (fxa_uid, fxa_kid, collection_id, bso_id, modified, sortindex, payload,
+                         expiry, deleted)
+                 SELECT @fxa_uid, @fxa_kid, @collection_id, id,
+                        TIMESTAMP_SUB(@timestamp, INTERVAL 1 MILLISECOND), NULL, '', @expiry,
+                        TRUE
+                   FROM UNNEST(@ids) AS id
+                  WHERE NOT EXISTS (
+                        SELECT 1
+                          FROM bsos
+                         WHERE fxa_uid = @fxa_uid
+                           AND fxa_kid = @fxa_kid
+                           AND collection_id = @collection_id
+                           AND bso_id = id
+                           AND expiry > @timestamp)",
+            )?
+            .params(created_params)
+            .param_types(param_types! {
+                "timestamp" => TypeCode::TIMESTAMP,
+                "expiry" => TypeCode::TIMESTAMP,
+            })
+            .execute_dml_async(&self.conn)
+            .await?;
+        }
+        if deleted {
+            // Replacing any revision written earlier in this transaction
+            self.sql(
+                "INSERT OR UPDATE INTO bso_history
+                        (fxa_uid, fxa_kid, collection_id, bso_id, modified, sortindex, payload,
+                         expiry, deleted)
+                 SELECT fxa_uid, fxa_kid, collection_id, bso_id, @timestamp, NULL, '',
+                        expiry, TRUE
+                   FROM bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND bso_id IN UNNEST(@ids)
+                    AND expiry > @timestamp",
+            )?
+            .params(insert_params)
+            .param_types(param_types! {
+                "timestamp" => TypeCode::TIMESTAMP,
+            })
+            .execute_dml_async(&self.conn)
+            .await?;
+        }
+
+        self.sql(
+            "DELETE FROM bso_history
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND (expiry <= @timestamp OR modified < @cutoff)",
+        )?
+        .params(params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+            "timestamp" => timestamp.as_rfc3339()?,
+            "cutoff" => to_rfc3339(policy.max_age_cutoff(timestamp.as_i64()).unwrap_or(0))?,
+        })
+        .param_types(param_types! {
+            "timestamp" => TypeCode::TIMESTAMP,
+            "cutoff" => TypeCode::TIMESTAMP,
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        if policy.max_revisions.is_none() {
+            return Ok(());
+        }
+
+        let mut streaming = self
+            .sql(
+                "SELECT bso_id, modified
+                   FROM bso_history
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND bso_id IN UNNEST(@ids)
+                  ORDER BY modified DESC",
+            )?
+            .params(sqlparams)
+            .execute_async(&self.conn)?;
+        let mut kept: HashMap<String, Vec<String>> = HashMap::new();
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            kept.entry(row[0].take_string_value())
+                .or_default()
+                .push(row[1].take_string_value());
+        }
+        for (id, revisions) in kept {
+            if let Some(modified) = policy.first_pruned(&revisions) {
+                self.sql(
+                    "DELETE FROM bso_history
+                      WHERE fxa_uid = @fxa_uid
+                        AND fxa_kid = @fxa_kid
+                        AND collection_id = @collection_id
+                        AND bso_id = @bso_id
+                        AND modified <= @modified",
+                )?
+                .params(params! {
+                    "fxa_uid" => user_id.fxa_uid.clone(),
+                    "fxa_kid" => user_id.fxa_kid.clone(),
+                    "collection_id" => collection_id.to_string(),
+                    "bso_id" => id,
+                    "modified" => modified.clone(),
+                })
+                .param_types(param_types! {
+                    "modified" => TypeCode::TIMESTAMP,
+                })
+                .execute_dml_async(&self.conn)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The ids of the BSOs deleted after `since`: both those deleted by
+    /// clients (unless they've since been rewritten) and those that expired
+    pub async fn get_deleted_bsos_async(
+        &self,
+        params: params::GetDeletedBsos,
+    ) -> Result<results::GetDeletedBsos> {
+        let collection_id = self.get_collection_id_async(&params.collection).await?;
+        let limit = params.limit.map(i64::from);
+        let mut streaming = self
+            .sql(
+                "SELECT d.bso_id, d.deleted
+                   FROM bso_deletions AS d
+                  WHERE d.fxa_uid = @fxa_uid
+                    AND d.fxa_kid = @fxa_kid
+                    AND d.collection_id = @collection_id
+                    AND d.deleted > @since
+                    AND d.expiry > CURRENT_TIMESTAMP()
+                    AND NOT EXISTS (
+                        SELECT 1
+                          FROM bsos AS b
+                         WHERE b.fxa_uid = d.fxa_uid
+                           AND b.fxa_kid = d.fxa_kid
+                           AND b.collection_id = d.collection_id
+                           AND b.bso_id = d.bso_id)
+                  UNION ALL
+                 SELECT bso_id, expiry
+                   FROM bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND expiry > @since
+                    AND expiry <= CURRENT_TIMESTAMP()
+                  ORDER BY 2, 1
+                  LIMIT @limit
+                 OFFSET @offset",
+            )?
+            .params(params! {
+                "fxa_uid" => params.user_id.fxa_uid,
+                "fxa_kid" => params.user_id.fxa_kid,
+                "collection_id" => collection_id.to_string(),
+                "since" => params.since.as_rfc3339()?,
+                // fetch an extra row to detect if there are more rows
+                "limit" => limit.map_or(i64::MAX, |limit| limit + 1).to_string(),
+                "offset" => params.offset.to_string(),
+            })
+            .param_types(param_types! {
+                "since" => TypeCode::TIMESTAMP,
+                "limit" => TypeCode::INT64,
+                "offset" => TypeCode::INT64,
+            })
+            .execute_async(&self.conn)?;
+        let mut items = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let row = row?;
+            items.push(results::DeletedBso {
+                id: row[0].get_string_value().to_owned(),
+                deleted: SyncTimestamp::from_rfc3339(row[1].get_string_value())?,
+            });
+        }
+        let offset = match limit {
+            Some(limit) if items.len() > limit as usize => {
+                items.pop();
+                Some((params.offset + items.len() as u64).to_string())
+            }
+            _ => None,
+        };
+        Ok(results::GetDeletedBsos { items, offset })
+    }
+
+    /// For each BSO modified or deleted after `at`, its latest kept revision
+    /// as of `at`, or a tombstone when it didn't exist then: when its earliest
+    /// kept revision since is one
+    pub async fn get_bso_revisions_async(
+        &self,
+        params: params::GetBsoRevisions,
+    ) -> Result<results::GetBsoRevisions> {
+        let collection_id = self.get_collection_id_async(&params.collection).await?;
+        let user_id = params.user_id.clone();
+        let mut streaming = self
+            .sql(
+                "SELECT c.bso_id, h.sortindex, h.payload, h.modified, h.expiry, h.deleted
+                   FROM (
+                        SELECT u.bso_id,
+                               COALESCE(
+                                   (SELECT MAX(modified)
+                                      FROM bso_history
+                                     WHERE fxa_uid = @fxa_uid
+                                       AND fxa_kid = @fxa_kid
+                                       AND collection_id = @collection_id
+                                       AND bso_id = u.bso_id
+                                       AND modified <= @at),
+                                   (SELECT MIN(modified)
+                                      FROM bso_history
+                                     WHERE fxa_uid = @fxa_uid
+                                       AND fxa_kid = @fxa_kid
+                                       AND collection_id = @collection_id
+                                       AND bso_id = u.bso_id
+                                       AND modified > @at)) AS at_modified
+                          FROM (SELECT bso_id
+                                  FROM bsos
+                                 WHERE fxa_uid = @fxa_uid
+                                   AND fxa_kid = @fxa_kid
+                                   AND collection_id = @collection_id
+                                   AND modified > @at
+                                 UNION DISTINCT
+                                SELECT bso_id
+                                  FROM bso_history
+                                 WHERE fxa_uid = @fxa_uid
+                                   AND fxa_kid = @fxa_kid
+                                   AND collection_id = @collection_id
+                                   AND modified > @at) AS u) AS c
+                   JOIN bso_history AS h
+                     ON h.fxa_uid = @fxa_uid
+                    AND h.fxa_kid = @fxa_kid
+                    AND h.collection_id = @collection_id
+                    AND h.bso_id = c.bso_id
+                    AND h.modified = c.at_modified
+                    AND (h.modified <= @at OR h.deleted)
+                    AND h.expiry > CURRENT_TIMESTAMP()
+                  ORDER BY c.bso_id",
+            )?
+            .params(params! {
+                "fxa_uid" => params.user_id.fxa_uid,
+                "fxa_kid" => params.user_id.fxa_kid,
+                "collection_id" => collection_id.to_string(),
+                "at" => params.at.as_rfc3339()?,
+            })
+            .param_types(param_types! {
+                "at" => TypeCode::TIMESTAMP,
+            })
+            .execute_async(&self.conn)?;
+        let mut revisions = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let row = row?;
+            if row[5].get_bool_value() {
+                revisions.push(results::BsoRevision {
+                    id: row[0].get_string_value().to_owned(),
+                    deleted: true,
+                    ..Default::default()
+                });
+                continue;
+            }
+            let bso = bso_from_row(row)?;
+            revisions.push(results::BsoRevision {
+                payload: self.decode_payload(bso.payload, &user_id, collection_id, &bso.id)?,
+                id: bso.id,
+                modified: bso.modified,
+                sortindex: bso.sortindex,
+                expiry: bso.expiry,
+                deleted: false,
+            });
+        }
+        Ok(revisions)
+    }
+
+    pub async fn get_inactive_users_async(
+        &self,
+        params: params::GetInactiveUsers,
+    ) -> Result<results::GetInactiveUsers> {
+        let after = params.after.unwrap_or_default();
+        let mut streaming = self
+            .sql(&format!(
+                "SELECT fxa_uid, fxa_kid, MAX(modified)
+                   FROM user_collections
+                  WHERE fxa_uid > @fxa_uid
+                     OR (fxa_uid = @fxa_uid AND fxa_kid > @fxa_kid)
+                  GROUP BY fxa_uid, fxa_kid
+                 HAVING MAX(modified) < @before
+                  ORDER BY fxa_uid, fxa_kid
+                  LIMIT {}",
+                params.limit
+            ))?
+            .params(params! {
+                "fxa_uid" => after.fxa_uid,
+                "fxa_kid" => after.fxa_kid,
+                "before" => params.before.as_rfc3339()?,
+            })
+            .param_types(param_types! {
+                "before" => TypeCode::TIMESTAMP,
+            })
+            .execute_async(&self.conn)?;
+        let mut users = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let row = row?;
+            users.push(results::InactiveUser {
+                user_id: HawkIdentifier {
+                    legacy_id: 0,
+                    fxa_uid: row[0].get_string_value().to_owned(),
+                    fxa_kid: row[1].get_string_value().to_owned(),
+                },
+                modified: SyncTimestamp::from_rfc3339(row[2].get_string_value())?,
+            });
+        }
+        Ok(users)
+    }
+
     pub async fn get_bso_timestamp_async(
         &self,
         params: params::GetBsoTimestamp,
@@ -1494,6 +2887,7 @@ impl SpannerDb {
             sortindex: params.sortindex,
             payload: params.payload,
             ttl: params.ttl,
+            modified: None,
         }];
         let result = self
             .post_bsos_async(params::PostBsos {
@@ -1512,6 +2906,8 @@ impl SpannerDb {
         let collection_id = self
             .get_or_create_collection_id_async(&params.collection)
             .await?;
+        self.check_collection_policy(&user_id, collection_id, &params.collection)
+            .await?;
 
         self.check_quota(&user_id, &params.collection, collection_id)
             .await?;
@@ -1533,7 +2929,7 @@ impl SpannerDb {
         );
         let mut streaming = self
             .sql(
-                "SELECT bso_id
+                "SELECT bso_id, UNIX_MILLIS(modified), expiry > CURRENT_TIMESTAMP()
                    FROM bsos
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
@@ -1543,22 +2939,77 @@ impl SpannerDb {
             .params(sqlparams)
             .execute_async(&self.conn)?;
         let mut existing = HashSet::new();
+        // The modified times of the unexpired ones
+        let mut stored = HashMap::new();
         while let Some(row) = streaming.next_async().await {
             let mut row = row?;
-            existing.insert(row[0].take_string_value());
+            let id = row[0].take_string_value();
+            if row[2].get_bool_value() {
+                let modified = row[1]
+                    .get_string_value()
+                    .parse::<i64>()
+                    .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+                stored.insert(id.clone(), modified);
+            }
+            existing.insert(id);
+        }
+        let collection = &params.collection;
+        let mut bsos: Vec<_> = params
+            .bsos
+            .into_iter()
+            .map(|bso| params::PostCollectionBso {
+                ttl: self.collection_policy.ttl(collection, bso.ttl),
+                ..bso
+            })
+            .collect();
+        let mut failed = params.failed;
+        if self.expected_modified {
+            bsos.retain(|bso| match bso.modified {
+                Some(expected)
+                    if stored.get(&bso.id).copied().unwrap_or(0) != expected.as_i64() =>
+                {
+                    failed.insert(bso.id.clone(), "conflict".to_owned());
+                    false
+                }
+                _ => true,
+            });
         }
+        // Only writes of a payload or sortindex make a new revision
+        let written = bsos
+            .iter()
+            .filter(|bso| bso.payload.is_some() || bso.sortindex.is_some())
+            .map(|bso| bso.id.clone())
+            .collect();
+        self.record_revisions_async(
+            &user_id,
+            &params.collection,
+            collection_id,
+            written,
+            timestamp,
+            false,
+        )
+        .await?;
         let mut inserts = vec![];
         let mut updates = HashMap::new();
         let mut success = vec![];
         let mut load_size: usize = 0;
-        for bso in params.bsos {
+        for bso in bsos {
             success.push(bso.id.clone());
+            let payload_size = bso.payload.as_ref().map(String::len);
+            let id = &bso.id;
+            let payload = bso
+                .payload
+                .map(|p| self.encode_payload(p, &user_id, collection_id, id))
+                .transpose()?;
+            let bso = params::PostCollectionBso { payload, ..bso };
             if existing.contains(&bso.id) {
-                let (columns, values) = bso_to_update_row(&user_id, collection_id, bso, timestamp)?;
+                let (columns, values) =
+                    bso_to_update_row(&user_id, collection_id, bso, payload_size, timestamp)?;
                 load_size += values.compute_size() as usize;
                 updates.entry(columns).or_insert_with(Vec::new).push(values);
             } else {
-                let values = bso_to_insert_row(&user_id, collection_id, bso, timestamp)?;
+                let values =
+                    bso_to_insert_row(&user_id, collection_id, bso, payload_size, timestamp)?;
                 load_size += values.compute_size() as usize;
                 inserts.push(values);
             }
@@ -1587,6 +3038,7 @@ impl SpannerDb {
                     "bso_id",
                     "sortindex",
                     "payload",
+                    "payload_size",
                     "modified",
                     "expiry",
                 ],
@@ -1604,7 +3056,7 @@ impl SpannerDb {
         let result = results::PostBsos {
             modified: timestamp,
             success,
-            failed: params.failed,
+            failed,
         };
         Ok(result)
     }
@@ -1655,10 +3107,22 @@ impl SpannerDb {
     // see above for the non-tests version
     #[cfg(test)]
     pub async fn put_bso_async_test(&self, bso: params::PutBso) -> Result<results::PutBso> {
-        use crate::db::util::to_rfc3339;
+        let payload_size = bso.payload.as_ref().map_or(0, String::len).to_string();
         let collection_id = self
             .get_or_create_collection_id_async(&bso.collection)
             .await?;
+        self.check_collection_policy(&bso.user_id, collection_id, &bso.collection)
+            .await?;
+        let bso = params::PutBso {
+            ttl: self.collection_policy.ttl(&bso.collection, bso.ttl),
+            ..bso
+        };
+        let (user_id, id) = (&bso.user_id, &bso.id);
+        let payload = bso
+            .payload
+            .map(|p| self.encode_payload(p, user_id, collection_id, id))
+            .transpose()?;
+        let bso = params::PutBso { payload, ..bso };
 
         self.check_quota(&bso.user_id, &bso.collection, collection_id)
             .await?;
@@ -1692,6 +3156,17 @@ impl SpannerDb {
             .one_or_none()
             .await?;
         let exists = result.is_some();
+        if bso.payload.is_some() || bso.sortindex.is_some() {
+            self.record_revisions_async(
+                &bso.user_id,
+                &bso.collection,
+                collection_id,
+                vec![bso.id.clone()],
+                timestamp,
+                false,
+            )
+            .await?;
+        }
 
         let sql = if exists {
             let mut q = "".to_string();
@@ -1740,7 +3215,13 @@ impl SpannerDb {
                 q,
                 if let Some(payload) = bso.payload {
                     sqlparams.insert("payload".to_string(), as_value(payload));
-                    format!("{}{}", comma(&q), "payload = @payload")
+                    sqlparams.insert("payload_size".to_string(), as_value(payload_size));
+                    sqltypes.insert("payload_size".to_string(), as_type(TypeCode::INT64));
+                    format!(
+                        "{}{}",
+                        comma(&q),
+                        "payload = @payload, payload_size = @payload_size"
+                    )
                 } else {
                     "".to_string()
                 }
@@ -1767,20 +3248,19 @@ impl SpannerDb {
                 != "NULL";
             let sql = if use_sortindex {
                 "INSERT INTO bsos
-                        (fxa_uid, fxa_kid, collection_id, bso_id, sortindex, payload, modified,
-                         expiry)
+                        (fxa_uid, fxa_kid, collection_id, bso_id, sortindex, payload,
+                         payload_size, modified, expiry)
                  VALUES
                         (@fxa_uid, @fxa_kid, @collection_id, @bso_id, @sortindex, @payload,
-                         @modified, @expiry)"
+                         @payload_size, @modified, @expiry)"
             } else {
-                "INSERT INTO bsos (fxa_uid, fxa_kid, collection_id, bso_id, payload, modified,
-                                   expiry)
-                 VALUES (@fxa_uid, @fxa_kid, @collection_id, @bso_id, @payload, @modified,
-                         @expiry)"
+                "INSERT INTO bsos (fxa_uid, fxa_kid, collection_id, bso_id, payload,
+                                   payload_size, modified, expiry)
+                 VALUES (@fxa_uid, @fxa_kid, @collection_id, @bso_id, @payload,
+                         @payload_size, @modified, @expiry)"
             };
 
             if use_sortindex {
-                use super::support::null_value;
                 let sortindex = bso
                     .sortindex
                     .map(|sortindex| as_value(sortindex.to_string()))
@@ -1792,6 +3272,8 @@ impl SpannerDb {
                 "payload".to_string(),
                 as_value(bso.payload.unwrap_or_else(|| "".to_owned())),
             );
+            sqlparams.insert("payload_size".to_string(), as_value(payload_size));
+            sqltypes.insert("payload_size".to_string(), as_type(TypeCode::INT64));
             let now_millis = timestamp.as_i64();
             let ttl = bso.ttl.map_or(i64::from(DEFAULT_BSO_TTL), |ttl| {
                 ttl.try_into()
@@ -1972,6 +3454,97 @@ impl<'a> Db<'a> for SpannerDb {
         Box::pin(async move { db.delete_storage_async(param).map_err(Into::into).await })
     }
 
+    fn archive_storage(
+        &self,
+        param: params::ArchiveStorage,
+    ) -> DbFuture<'_, results::ArchiveStorage> {
+        let db = self.clone();
+        Box::pin(async move { db.archive_storage_async(param).map_err(Into::into).await })
+    }
+
+    fn restore_storage(
+        &self,
+        param: params::RestoreStorage,
+    ) -> DbFuture<'_, results::RestoreStorage> {
+        let db = self.clone();
+        Box::pin(async move { db.restore_storage_async(param).map_err(Into::into).await })
+    }
+
+    fn purge_deleted_storage(
+        &self,
+        param: params::PurgeDeletedStorage,
+    ) -> DbFuture<'_, results::PurgeDeletedStorage> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.purge_deleted_storage_async(param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
+    fn record_audit(&self, param: params::RecordAudit) -> DbFuture<'_, results::RecordAudit> {
+        let db = self.clone();
+        Box::pin(async move { db.record_audit_async(param).map_err(Into::into).await })
+    }
+
+    fn get_audit_log(&self, param: params::GetAuditLog) -> DbFuture<'_, results::GetAuditLog> {
+        let db = self.clone();
+        Box::pin(async move { db.get_audit_log_async(param).map_err(Into::into).await })
+    }
+
+    fn reencrypt_payloads(
+        &self,
+        param: params::ReencryptPayloads,
+    ) -> DbFuture<'_, results::ReencryptPayloads> {
+        let db = self.clone();
+        Box::pin(async move { db.reencrypt_payloads_async(param).map_err(Into::into).await })
+    }
+
+    fn get_revocations(
+        &self,
+        param: params::GetRevocations,
+    ) -> DbFuture<'_, results::GetRevocations> {
+        let db = self.clone();
+        Box::pin(async move { db.get_revocations_async(param).map_err(Into::into).await })
+    }
+
+    fn put_revocation(&self, param: params::PutRevocation) -> DbFuture<'_, results::PutRevocation> {
+        let db = self.clone();
+        Box::pin(async move { db.put_revocation_async(param).map_err(Into::into).await })
+    }
+
+    fn delete_revocation(
+        &self,
+        param: params::DeleteRevocation,
+    ) -> DbFuture<'_, results::DeleteRevocation> {
+        let db = self.clone();
+        Box::pin(async move { db.delete_revocation_async(param).map_err(Into::into).await })
+    }
+
+    fn get_user_limits(
+        &self,
+        param: params::GetUserLimits,
+    ) -> DbFuture<'_, results::GetUserLimits> {
+        let db = self.clone();
+        Box::pin(async move { db.get_user_limits_async(param).map_err(Into::into).await })
+    }
+
+    fn put_user_limits(
+        &self,
+        param: params::PutUserLimits,
+    ) -> DbFuture<'_, results::PutUserLimits> {
+        let db = self.clone();
+        Box::pin(async move { db.put_user_limits_async(param).map_err(Into::into).await })
+    }
+
+    fn delete_user_limits(
+        &self,
+        param: params::DeleteUserLimits,
+    ) -> DbFuture<'_, results::DeleteUserLimits> {
+        let db = self.clone();
+        Box::pin(async move { db.delete_user_limits_async(param).map_err(Into::into).await })
+    }
+
     fn delete_bso(&self, param: params::DeleteBso) -> DbFuture<'_, results::DeleteBso> {
         let db = self.clone();
         Box::pin(async move { db.delete_bso_async(param).map_err(Into::into).await })
@@ -1992,6 +3565,30 @@ impl<'a> Db<'a> for SpannerDb {
         Box::pin(async move { db.get_bso_ids_async(param).map_err(Into::into).await })
     }
 
+    fn get_deleted_bsos(
+        &self,
+        param: params::GetDeletedBsos,
+    ) -> DbFuture<'_, results::GetDeletedBsos> {
+        let db = self.clone();
+        Box::pin(async move { db.get_deleted_bsos_async(param).map_err(Into::into).await })
+    }
+
+    fn get_bso_revisions(
+        &self,
+        param: params::GetBsoRevisions,
+    ) -> DbFuture<'_, results::GetBsoRevisions> {
+        let db = self.clone();
+        Box::pin(async move { db.get_bso_revisions_async(param).map_err(Into::into).await })
+    }
+
+    fn get_inactive_users(
+        &self,
+        param: params::GetInactiveUsers,
+    ) -> DbFuture<'_, results::GetInactiveUsers> {
+        let db = self.clone();
+        Box::pin(async move { db.get_inactive_users_async(param).map_err(Into::into).await })
+    }
+
     fn get_bso(&self, param: params::GetBso) -> DbFuture<'_, Option<results::GetBso>> {
         let db = self.clone();
         Box::pin(async move { db.get_bso_async(param).map_err(Into::into).await })
@@ -2100,7 +3697,6 @@ impl<'a> Db<'a> for SpannerDb {
         self.coll_cache.clear();
     }
 
-    #[cfg(test)]
     fn set_quota(&mut self, enabled: bool, limit: usize) {
         self.quota_enabled = enabled;
         self.quota = limit;
@@ -8,9 +8,12 @@ use std::{
 };
 
 use super::models::Result;
-use crate::db::{error::DbError, results, Db, DbPool, STD_COLLS};
+use crate::db::{
+    codec::PayloadCodec, collections::CollectionPolicy, error::DbError, results, Db, DbPool,
+    STD_COLLS,
+};
 use crate::server::metrics::Metrics;
-use crate::settings::Settings;
+use crate::settings::{BsoHistoryPolicy, Settings};
 
 use super::manager::{SpannerSession, SpannerSessionManager};
 use super::models::SpannerDb;
@@ -39,6 +42,11 @@ pub struct SpannerDbPool {
     metrics: Metrics,
     quota: usize,
     quota_enabled: bool,
+    deleted_bso_retention: u32,
+    expected_modified: bool,
+    bso_history: Arc<HashMap<String, BsoHistoryPolicy>>,
+    payload_codec: Arc<PayloadCodec>,
+    collection_policy: Arc<CollectionPolicy>,
 }
 
 impl SpannerDbPool {
@@ -60,6 +68,11 @@ impl SpannerDbPool {
             metrics: metrics.clone(),
             quota: settings.limits.max_quota_limit as usize,
             quota_enabled: settings.enable_quota,
+            deleted_bso_retention: settings.deleted_bso_retention,
+            expected_modified: settings.enable_expected_modified,
+            bso_history: Arc::new(settings.bso_history.clone()),
+            payload_codec: Arc::new(PayloadCodec::from_settings(settings)?),
+            collection_policy: Arc::new(CollectionPolicy::from_settings(settings)),
         })
     }
 
@@ -76,6 +89,11 @@ impl SpannerDbPool {
             &self.metrics,
             self.quota,
             self.quota_enabled,
+            self.deleted_bso_retention,
+            self.expected_modified,
+            Arc::clone(&self.bso_history),
+            Arc::clone(&self.payload_codec),
+            Arc::clone(&self.collection_policy),
         ))
     }
 }
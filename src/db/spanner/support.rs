@@ -18,14 +18,21 @@ use protobuf::{
 
 use crate::{
     db::{
-        params, results, spanner::models::DEFAULT_BSO_TTL, util::to_rfc3339, util::SyncTimestamp,
-        DbError, DbErrorKind,
+        encryption, params, results, spanner::models::DEFAULT_BSO_TTL, util::to_rfc3339,
+        util::SyncTimestamp, DbError, DbErrorKind,
     },
     web::extractors::HawkIdentifier,
 };
 
 use super::{models::Result, pool::Conn};
 
+/// Identifies the record a payload belongs to (see
+/// `encryption::payload_context`)
+pub fn payload_context(user_id: &HawkIdentifier, collection_id: i32, id: &str) -> String {
+    let user = format!("{}:{}", user_id.fxa_uid, user_id.fxa_kid);
+    encryption::payload_context(&user, collection_id, id)
+}
+
 pub fn as_value(string_value: String) -> Value {
     let mut value = Value::new();
     value.set_string_value(string_value);
@@ -319,10 +326,13 @@ pub fn bso_from_row(mut row: Vec<Value>) -> Result<results::GetBso> {
     })
 }
 
+/// Build a row inserting an (encoded) BSO. `payload_size` is the logical size
+/// of its payload
 pub fn bso_to_insert_row(
     user_id: &HawkIdentifier,
     collection_id: i32,
     bso: params::PostCollectionBso,
+    payload_size: Option<usize>,
     now: SyncTimestamp,
 ) -> Result<ListValue> {
     let sortindex = bso
@@ -340,16 +350,20 @@ pub fn bso_to_insert_row(
         as_value(bso.id),
         sortindex,
         as_value(bso.payload.unwrap_or_default()),
+        as_value(payload_size.unwrap_or_default().to_string()),
         as_value(now.as_rfc3339()?),
         as_value(expiry),
     ]));
     Ok(row)
 }
 
+/// Build a row updating an (encoded) BSO. `payload_size` is the logical size
+/// of its payload
 pub fn bso_to_update_row(
     user_id: &HawkIdentifier,
     collection_id: i32,
     bso: params::PostCollectionBso,
+    payload_size: Option<usize>,
     now: SyncTimestamp,
 ) -> Result<(Vec<&'static str>, ListValue)> {
     let mut columns = vec!["fxa_uid", "fxa_kid", "collection_id", "bso_id"];
@@ -368,6 +382,8 @@ pub fn bso_to_update_row(
     if let Some(payload) = bso.payload {
         columns.push("payload");
         values.push(as_value(payload));
+        columns.push("payload_size");
+        values.push(as_value(payload_size.unwrap_or_default().to_string()));
     }
     if modified {
         columns.push("modified");
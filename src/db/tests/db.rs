@@ -5,9 +5,16 @@ use lazy_static::lazy_static;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
 use super::support::{db_pool, dbso, dbsos, gbso, gbsos, hid, pbso, postbso, test_db, Result};
-use crate::db::{mysql::models::DEFAULT_BSO_TTL, params, util::SyncTimestamp, Sorting};
-use crate::settings::test_settings;
-use crate::web::extractors::HawkIdentifier;
+use crate::db::{
+    error::DbErrorKind, mysql::models::DEFAULT_BSO_TTL, params, util::SyncTimestamp, AuditOp,
+    Sorting,
+};
+use crate::error::ApiErrorKind;
+use crate::settings::{test_settings, BsoHistoryPolicy, LimitOverrides};
+use crate::web::{
+    auth::{Revocation, RevocationKey},
+    extractors::HawkIdentifier,
+};
 
 // distant future (year 2099) timestamp for tests
 const MAX_TIMESTAMP: u64 = 4_070_937_600_000;
@@ -224,6 +231,60 @@ async fn get_bsos_limit_offset() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_bsos_offset_shared_modified() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    // "0" - "4" share a modified timestamp, "5" and "6" are newer
+    for i in 0..7 {
+        let bso = pbso(
+            uid,
+            coll,
+            &i.to_string(),
+            Some("a"),
+            None,
+            Some(DEFAULT_BSO_TTL),
+        );
+        with_delta!(&db, (i64::from(i) - 4).max(0) * 10, {
+            db.put_bso(bso).await
+        })?;
+    }
+
+    for (sort, expected) in &[
+        (Sorting::Newest, ["6", "5", "4", "3", "2", "1", "0"]),
+        (Sorting::Oldest, ["0", "1", "2", "3", "4", "5", "6"]),
+    ] {
+        let mut offset = "0".to_owned();
+        let mut ids = vec![];
+        loop {
+            let bsos = db
+                .get_bsos(gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, *sort, 2, &offset))
+                .await?;
+            let bso_ids = db
+                .get_bso_ids(gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, *sort, 2, &offset))
+                .await?;
+            assert_eq!(
+                bso_ids.items,
+                bsos.items.iter().map(|b| b.id.clone()).collect::<Vec<_>>()
+            );
+            assert_eq!(bso_ids.offset, bsos.offset);
+            ids.extend(bsos.items.into_iter().map(|b| b.id));
+            match bsos.offset {
+                Some(next) => {
+                    assert!(next.contains(':'));
+                    offset = next;
+                }
+                None => break,
+            }
+        }
+        assert_eq!(ids, expected);
+    }
+    Ok(())
+}
+
 #[tokio::test]
 async fn get_bsos_newer() -> Result<()> {
     let pool = db_pool(None).await?;
@@ -636,6 +697,16 @@ async fn get_collection_usage() -> Result<()> {
             *expected.entry(coll.to_owned()).or_insert(0) += size as i64;
         }
     }
+    // Expired BSOs don't count
+    db.put_bso(pbso(
+        uid,
+        "bookmarks",
+        "expired",
+        Some(&"x".repeat(100)),
+        None,
+        Some(0),
+    ))
+    .await?;
 
     let sizes = db.get_collection_usage(hid(uid)).await?;
     assert_eq!(sizes, expected);
@@ -997,6 +1068,100 @@ async fn delete_bsos() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_deleted_bsos() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    for bid in &["b0", "b1", "b2", "b3"] {
+        db.put_bso(pbso(uid, coll, bid, Some("payload"), None, None))
+            .await?;
+    }
+    db.delete_bso(dbso(uid, coll, "b0")).await?;
+    // only the BSOs that existed are recorded
+    db.delete_bsos(dbsos(uid, coll, &["b1", "b2", "bxi0"]))
+        .await?;
+    // rewritten BSOs are no longer deleted
+    db.put_bso(pbso(uid, coll, "b2", Some("payload"), None, None))
+        .await?;
+
+    let gdbsos = |since, limit, offset| params::GetDeletedBsos {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        since,
+        limit,
+        offset,
+    };
+    let deleted = db
+        .get_deleted_bsos(gdbsos(SyncTimestamp::from_seconds(0f64), None, 0))
+        .await?;
+    let ids: Vec<_> = deleted.items.iter().map(|bso| bso.id.as_str()).collect();
+    assert_eq!(ids, vec!["b0", "b1"]);
+    assert!(deleted
+        .items
+        .iter()
+        .all(|bso| bso.deleted == db.timestamp()));
+    assert!(deleted.offset.is_none());
+
+    // Paginated
+    let deleted = db
+        .get_deleted_bsos(gdbsos(SyncTimestamp::from_seconds(0f64), Some(1), 0))
+        .await?;
+    assert_eq!(deleted.items.len(), 1);
+    assert_eq!(deleted.items[0].id, "b0");
+    assert_eq!(deleted.offset, Some("1".to_owned()));
+    let deleted = db
+        .get_deleted_bsos(gdbsos(SyncTimestamp::from_seconds(0f64), Some(1), 1))
+        .await?;
+    assert_eq!(deleted.items.len(), 1);
+    assert_eq!(deleted.items[0].id, "b1");
+    assert!(deleted.offset.is_none());
+
+    let deleted = db.get_deleted_bsos(gdbsos(db.timestamp(), None, 0)).await?;
+    assert!(deleted.items.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_inactive_users() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    let active = db.timestamp();
+    let inactive = SyncTimestamp::from_seconds(1000f64);
+    db.set_timestamp(inactive);
+    db.put_bso(pbso(uid, coll, "b0", Some("payload"), None, None))
+        .await?;
+    let get_inactive_users = |after| {
+        db.get_inactive_users(params::GetInactiveUsers {
+            before: SyncTimestamp::from_seconds(2000f64),
+            after,
+            limit: 1000,
+        })
+    };
+
+    let users = get_inactive_users(None).await?;
+    let user = users
+        .iter()
+        .find(|user| user.modified == inactive)
+        .expect("inactive user not listed");
+    // Listing resumes after the given user
+    let users = get_inactive_users(Some(user.user_id.clone())).await?;
+    assert!(users.iter().all(|user| user.modified != inactive));
+
+    // Any write makes the user active again
+    db.set_timestamp(active);
+    db.put_bso(pbso(uid, "tabs", "b0", Some("payload"), None, None))
+        .await?;
+    let users = get_inactive_users(None).await?;
+    assert!(users.iter().all(|user| user.modified != inactive));
+    Ok(())
+}
+
 /*
 #[tokio::test]
 async fn usage_stats() -> Result<()> {
@@ -1046,6 +1211,417 @@ async fn delete_storage() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn archive_and_restore_storage() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    let archive = |grace_period, limit| {
+        db.archive_storage(params::ArchiveStorage {
+            user_id: hid(uid),
+            grace_period,
+            limit,
+        })
+    };
+    let restore = |limit| {
+        db.restore_storage(params::RestoreStorage {
+            user_id: hid(uid),
+            limit,
+        })
+    };
+    for bid in &["b0", "b1"] {
+        db.put_bso(pbso(uid, coll, bid, Some("payload"), None, None))
+            .await?;
+    }
+    // A chunk at a time
+    assert_eq!(archive(60, 1).await?, 1);
+    assert_eq!(archive(60, 1).await?, 1);
+    assert_eq!(archive(60, 1).await?, 0);
+    db.delete_storage(hid(uid)).await?;
+    assert!(db.get_bso(gbso(uid, coll, "b0")).await?.is_none());
+
+    // The copy is kept while the storage hasn't changed since
+    assert_eq!(archive(60, 1).await?, 0);
+    db.delete_storage(hid(uid)).await?;
+
+    let chunk = restore(1).await?.expect("storage not restored");
+    assert_eq!(chunk.restored, 1);
+    let chunk = restore(1).await?.expect("storage not restored");
+    assert_eq!(chunk.restored, 1);
+    let chunk = restore(1).await?.expect("storage not restored");
+    assert_eq!(chunk.restored, 0);
+    let bso = db
+        .get_bso(gbso(uid, coll, "b0"))
+        .await?
+        .expect("bso not restored");
+    assert_eq!(bso.payload, "payload");
+    assert!(db.get_bso(gbso(uid, coll, "b1")).await?.is_some());
+    assert!(db.get_bso(gbso(uid, coll, "b2")).await?.is_none());
+    let ts = db
+        .get_collection_timestamp(params::GetCollectionTimestamp {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await?;
+    assert_eq!(ts, chunk.modified);
+    // Only once
+    assert!(restore(1).await?.is_none());
+
+    // A copy outdated by later writes is replaced
+    assert_eq!(archive(60, 100).await?, 2);
+    db.delete_storage(hid(uid)).await?;
+    with_delta!(&db, 10, {
+        db.put_bso(pbso(uid, coll, "b2", Some("payload"), None, None))
+            .await
+    })?;
+    assert_eq!(archive(60, 100).await?, 1);
+    db.delete_storage(hid(uid)).await?;
+    let chunk = restore(100).await?.expect("storage not restored");
+    assert_eq!(chunk.restored, 1);
+    assert!(db.get_bso(gbso(uid, coll, "b2")).await?.is_some());
+    assert!(db.get_bso(gbso(uid, coll, "b0")).await?.is_none());
+
+    // Copies are purged after their grace period
+    assert_eq!(archive(0, 100).await?, 1);
+    let purged = db
+        .purge_deleted_storage(params::PurgeDeletedStorage { limit: 100 })
+        .await?;
+    assert!(purged >= 1);
+    assert!(restore(100).await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_bso_revisions() -> Result<()> {
+    let mut settings = test_settings();
+    settings.bso_history.insert(
+        "clients".to_owned(),
+        BsoHistoryPolicy {
+            max_revisions: Some(2),
+            max_age: None,
+        },
+    );
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    let ts = db.timestamp().as_i64();
+    for (i, payload) in ["p0", "p1", "p2", "p3"].iter().enumerate() {
+        let bso = pbso(uid, coll, "b0", Some(payload), None, None);
+        with_delta!(&db, (i as i64 - 3) * 10, { db.put_bso(bso).await })?;
+    }
+    db.put_bso(pbso(uid, coll, "b1", Some("p0"), None, None))
+        .await?;
+    // Writes to other collections keep no revisions
+    let other = "bookmarks";
+    with_delta!(&db, -30, {
+        db.put_bso(pbso(uid, other, "b0", Some("p0"), None, None))
+            .await
+    })?;
+    db.put_bso(pbso(uid, other, "b0", Some("p1"), None, None))
+        .await?;
+
+    let get_revisions = |collection: &str, at| {
+        db.get_bso_revisions(params::GetBsoRevisions {
+            user_id: hid(uid),
+            collection: collection.to_owned(),
+            at: SyncTimestamp::_from_i64(ts + at).unwrap(),
+        })
+    };
+    let revisions = get_revisions(coll, -15).await?;
+    assert_eq!(revisions.len(), 2);
+    assert_eq!(revisions[0].id, "b0");
+    assert_eq!(revisions[0].payload, "p1");
+    assert_eq!(revisions[0].modified.as_i64(), ts - 20);
+    assert!(!revisions[0].deleted);
+    // b1 was created since
+    assert_eq!(revisions[1].id, "b1");
+    assert!(revisions[1].deleted);
+    let revisions = get_revisions(coll, -5).await?;
+    assert_eq!(revisions.len(), 2);
+    assert_eq!(revisions[0].payload, "p2");
+    // Only the 2 previous revisions are kept
+    let revisions = get_revisions(coll, -25).await?;
+    assert_eq!(revisions.len(), 1);
+    assert_eq!(revisions[0].id, "b1");
+    assert!(get_revisions(other, -15).await?.is_empty());
+
+    // Deleting keeps the deleted revision followed by a tombstone
+    with_delta!(&db, 10, {
+        db.delete_bso(params::DeleteBso {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id: "b0".to_owned(),
+        })
+        .await
+    })?;
+    let revisions = get_revisions(coll, 5).await?;
+    assert_eq!(revisions.len(), 1);
+    assert_eq!(revisions[0].payload, "p3");
+    assert!(!revisions[0].deleted);
+    // As does deleting the collection
+    with_delta!(&db, 20, {
+        db.delete_collection(params::DeleteCollection {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await
+    })?;
+    let revisions = get_revisions(coll, 15).await?;
+    assert_eq!(revisions.len(), 1);
+    assert_eq!(revisions[0].id, "b1");
+    assert_eq!(revisions[0].payload, "p0");
+    Ok(())
+}
+
+#[tokio::test]
+async fn payload_encryption() -> Result<()> {
+    let key_file = std::env::temp_dir().join(format!("sync-keys-{}.json", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &key_file,
+        r#"{"primary": "k1", "keys": {"k1": "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="}}"#,
+    )?;
+    let mut settings = test_settings();
+    settings.payload_encryption_key_file = Some(key_file.to_string_lossy().into_owned());
+    let pool = db_pool(Some(settings)).await;
+    std::fs::remove_file(&key_file)?;
+    let pool = pool?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    db.put_bso(pbso(uid, coll, "b0", Some("p0"), None, None))
+        .await?;
+    db.post_bsos(params::PostBsos {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        bsos: vec![postbso("b1", Some("p1"), Some(1), None)],
+        failed: Default::default(),
+    })
+    .await?;
+    let new_batch = db
+        .create_batch(params::CreateBatch {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            bsos: vec![postbso("b2", Some("p2"), None, None)],
+        })
+        .await?;
+    let batch = db
+        .get_batch(params::GetBatch {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id: new_batch.id,
+        })
+        .await?
+        .unwrap();
+    db.commit_batch(params::CommitBatch {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        batch,
+    })
+    .await?;
+
+    let bso = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    assert_eq!(bso.payload, "p0");
+    let bsos = db
+        .get_bsos(gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::Oldest,
+            10,
+            "0",
+        ))
+        .await?;
+    let mut payloads: Vec<_> = bsos.items.iter().map(|bso| bso.payload.as_str()).collect();
+    payloads.sort();
+    assert_eq!(payloads, vec!["p0", "p1", "p2"]);
+    // Usage reflects the payloads' unencrypted size
+    let sizes = db.get_collection_usage(hid(uid)).await?;
+    assert_eq!(sizes[coll], 6);
+    // Everything's already encrypted under the primary key
+    assert_eq!(db.reencrypt_payloads(hid(uid)).await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn payload_compression() -> Result<()> {
+    let mut settings = test_settings();
+    settings.payload_compression = true;
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "history";
+    let payload = format!(r#"{{"ciphertext": "{}"}}"#, "a".repeat(500));
+    db.put_bso(pbso(uid, coll, "b0", Some(&payload), None, None))
+        .await?;
+    db.post_bsos(params::PostBsos {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        bsos: vec![postbso("b1", Some(&payload), None, None)],
+        failed: Default::default(),
+    })
+    .await?;
+
+    let bso = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    assert_eq!(bso.payload, payload);
+    let bso = db.get_bso(gbso(uid, coll, "b1")).await?.unwrap();
+    assert_eq!(bso.payload, payload);
+    // Usage reflects the payloads' uncompressed size
+    let sizes = db.get_collection_usage(hid(uid)).await?;
+    assert_eq!(sizes[coll], 2 * payload.len() as i64);
+    Ok(())
+}
+
+#[tokio::test]
+async fn audit_log() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let ts = db.timestamp().as_i64();
+    let audit = |collection: Option<&str>, op, count, delta, retention| params::RecordAudit {
+        user_id: hid(uid),
+        collection: collection.map(str::to_owned),
+        op,
+        count,
+        timestamp: SyncTimestamp::_from_i64(ts + delta).unwrap(),
+        user_agent: "Firefox/81.0".to_owned(),
+        device_id: "abc".to_owned(),
+        retention,
+    };
+    db.record_audit(audit(Some("clients"), AuditOp::DeleteBso, 1, -20, 60))
+        .await?;
+    db.record_audit(audit(Some("tabs"), AuditOp::DeleteCollection, 3, -10, 60))
+        .await?;
+    // Already expired
+    db.record_audit(audit(None, AuditOp::DeleteStorage, 4, -30, 0))
+        .await?;
+
+    let entries = db.get_audit_log(hid(uid)).await?;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].op, AuditOp::DeleteCollection);
+    assert_eq!(entries[0].collection.as_deref(), Some("tabs"));
+    assert_eq!(entries[0].count, 3);
+    assert_eq!(entries[0].timestamp.as_i64(), ts - 10);
+    assert_eq!(entries[1].op, AuditOp::DeleteBso);
+    assert_eq!(entries[1].user_agent, "Firefox/81.0");
+    assert_eq!(entries[1].device_id, "abc");
+    // Entries outlive the user's storage
+    db.delete_storage(hid(uid)).await?;
+    assert_eq!(db.get_audit_log(hid(uid)).await?.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn user_limits() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    assert_eq!(db.get_user_limits(hid(uid)).await?, None);
+    assert!(!db.delete_user_limits(hid(uid)).await?);
+
+    let limits = LimitOverrides {
+        max_total_records: Some(1_000_000),
+        max_quota_limit: Some(4_000_000_000),
+        ..Default::default()
+    };
+    db.put_user_limits(params::PutUserLimits {
+        user_id: hid(uid),
+        limits: limits.clone(),
+    })
+    .await?;
+    assert_eq!(db.get_user_limits(hid(uid)).await?, Some(limits));
+
+    // Replaced rather than merged
+    let limits = LimitOverrides {
+        max_post_records: Some(10),
+        ..Default::default()
+    };
+    db.put_user_limits(params::PutUserLimits {
+        user_id: hid(uid),
+        limits: limits.clone(),
+    })
+    .await?;
+    assert_eq!(db.get_user_limits(hid(uid)).await?, Some(limits));
+
+    assert!(db.delete_user_limits(hid(uid)).await?);
+    assert_eq!(db.get_user_limits(hid(uid)).await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn revocations() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = u64::from(*UID);
+    let device_id = format!("device{}", uid);
+    let key = RevocationKey::Uid(uid);
+    let device_key = RevocationKey::HashedDeviceId(device_id);
+    let find = |revocations: Vec<Revocation>, key: &RevocationKey| {
+        revocations
+            .into_iter()
+            .find(|revocation| &revocation.key == key)
+    };
+    assert!(!db.delete_revocation(key.clone()).await?);
+
+    let revocation = Revocation {
+        key: key.clone(),
+        issued_before: Some(2_000),
+    };
+    db.put_revocation(params::PutRevocation {
+        revocation: revocation.clone(),
+        expired_before: 0,
+    })
+    .await?;
+    assert_eq!(find(db.get_revocations(()).await?, &key), Some(revocation));
+
+    // Replaced, keeping any others
+    let revocation = Revocation {
+        key: key.clone(),
+        issued_before: None,
+    };
+    db.put_revocation(params::PutRevocation {
+        revocation: revocation.clone(),
+        expired_before: 0,
+    })
+    .await?;
+    let device_revocation = Revocation {
+        key: device_key.clone(),
+        issued_before: Some(1_000),
+    };
+    db.put_revocation(params::PutRevocation {
+        revocation: device_revocation.clone(),
+        expired_before: 0,
+    })
+    .await?;
+    let revocations = db.get_revocations(()).await?;
+    assert_eq!(find(revocations.clone(), &key), Some(revocation.clone()));
+    assert_eq!(find(revocations, &device_key), Some(device_revocation));
+
+    // Revocations whose cutoffs have expired are pruned
+    db.put_revocation(params::PutRevocation {
+        revocation: revocation.clone(),
+        expired_before: 1_500,
+    })
+    .await?;
+    let revocations = db.get_revocations(()).await?;
+    assert_eq!(find(revocations.clone(), &key), Some(revocation));
+    assert_eq!(find(revocations, &device_key), None);
+
+    assert!(db.delete_revocation(key.clone()).await?);
+    assert_eq!(find(db.get_revocations(()).await?, &key), None);
+    Ok(())
+}
+
 #[tokio::test]
 async fn collection_cache() -> Result<()> {
     let pool = db_pool(None).await?;
@@ -1103,6 +1679,159 @@ async fn lock_for_write() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn collection_policy() -> Result<()> {
+    let mut settings = test_settings();
+    settings.max_custom_collections = 2;
+    settings.collection_allowlist = Some("custom1, custom2,custom3".to_owned());
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let write = |coll: &'static str| {
+        let db = &db;
+        async move {
+            let result = async {
+                db.lock_for_write(params::LockCollection {
+                    user_id: hid(uid),
+                    collection: coll.to_owned(),
+                })
+                .await?;
+                db.put_bso(pbso(uid, coll, "b0", Some("p0"), None, None))
+                    .await
+            }
+            .await;
+            if let Err(e) = result {
+                db.rollback().await?;
+                return Err(e);
+            }
+            db.commit().await
+        }
+    };
+    let kind = |result: Result<()>| match result.unwrap_err().kind() {
+        ApiErrorKind::Db(dbe) => dbe.kind().to_string(),
+        kind => panic!("Expected a DbError: {:?}", kind),
+    };
+
+    write("custom1").await?;
+    write("custom2").await?;
+    // Standard collections don't count
+    write("bookmarks").await?;
+    write("custom1").await?;
+    assert_eq!(
+        kind(write("custom3").await),
+        DbErrorKind::CollectionLimit.to_string()
+    );
+    assert_eq!(
+        kind(write("other").await),
+        DbErrorKind::CollectionNotAllowed("other".to_owned()).to_string()
+    );
+    // Deletes never create a collection
+    db.lock_for_write(params::LockCollection {
+        user_id: hid(uid),
+        collection: "other".to_owned(),
+    })
+    .await?;
+    let result = db
+        .delete_collection(params::DeleteCollection {
+            user_id: hid(uid),
+            collection: "other".to_owned(),
+        })
+        .await;
+    db.rollback().await?;
+    assert_eq!(
+        kind(result.map(|_| ())),
+        DbErrorKind::CollectionNotFound.to_string()
+    );
+
+    db.delete_collection(params::DeleteCollection {
+        user_id: hid(uid),
+        collection: "custom2".to_owned(),
+    })
+    .await?;
+    write("custom3").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn collection_ttl() -> Result<()> {
+    let mut settings = test_settings();
+    settings
+        .limits
+        .max_collection_ttls
+        .insert("tabs".to_owned(), 1000);
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "tabs";
+    let ts = db.timestamp().as_i64();
+    // The collection's maximum TTL is also its default
+    db.put_bso(pbso(uid, coll, "b0", Some("p0"), None, None))
+        .await?;
+    db.put_bso(pbso(uid, coll, "b1", Some("p0"), None, Some(2000)))
+        .await?;
+    db.post_bsos(params::PostBsos {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        bsos: vec![
+            postbso("b2", Some("p0"), None, Some(10)),
+            postbso("b3", Some("p0"), None, Some(2000)),
+        ],
+        failed: HashMap::new(),
+    })
+    .await?;
+    // Batches included
+    let batch = db
+        .create_batch(params::CreateBatch {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            bsos: vec![postbso("b4", Some("p0"), None, Some(2000))],
+        })
+        .await?;
+    db.append_to_batch(params::AppendToBatch {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        batch: batch.clone(),
+        bsos: vec![postbso("b5", Some("p0"), None, None)],
+    })
+    .await?;
+    let batch = db
+        .get_batch(params::GetBatch {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id: batch.id,
+        })
+        .await?
+        .expect("batch not found");
+    db.commit_batch(params::CommitBatch {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        batch,
+    })
+    .await?;
+    // Other collections are unaffected
+    db.put_bso(pbso(uid, "bookmarks", "b0", Some("p0"), None, Some(2000)))
+        .await?;
+
+    for (coll, id, ttl) in &[
+        (coll, "b0", 1000),
+        (coll, "b1", 1000),
+        (coll, "b2", 10),
+        (coll, "b3", 1000),
+        (coll, "b4", 1000),
+        (coll, "b5", 1000),
+        ("bookmarks", "b0", 2000),
+    ] {
+        let bso = db
+            .get_bso(gbso(uid, coll, id))
+            .await?
+            .expect("bso not stored");
+        assert_eq!(bso.expiry, ts + ttl * 1000, "{}/{}", coll, id);
+    }
+    Ok(())
+}
+
 #[tokio::test]
 async fn heartbeat() -> Result<()> {
     let pool = db_pool(None).await?;
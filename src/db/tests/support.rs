@@ -79,6 +79,7 @@ pub fn postbso(
         payload: payload.map(&str::to_owned),
         sortindex,
         ttl,
+        modified: None,
     }
 }
 
@@ -112,6 +113,8 @@ pub fn gbsos(
             limit: Some(limit as u32),
             offset: Some(Offset::from_str(offset).unwrap_or_default()),
             full: true,
+            deleted_since: None,
+            ..Default::default()
         },
     }
 }
@@ -1,6 +1,9 @@
+use crate::db::util::{etag, ms_since_epoch, SyncTimestamp};
 use crate::db::{params, Db, DbPool};
 use crate::error::{ApiError, ApiErrorKind};
+use crate::server::events::{EventPublisher, Written};
 use crate::server::metrics::Metrics;
+use crate::server::notifications::{Broker, Changed, CollectionChange};
 use crate::server::ServerState;
 use crate::web::extractors::{
     BsoParam, CollectionParam, HawkIdentifier, PreConditionHeader, PreConditionHeaderOpt,
@@ -15,8 +18,10 @@ use actix_web::http::header;
 use actix_web::web::Data;
 use actix_web::{FromRequest, HttpRequest, HttpResponse};
 use futures::future::LocalBoxFuture;
-use futures::FutureExt;
+use futures::{FutureExt, StreamExt};
 use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct DbTransactionPool {
@@ -27,6 +32,8 @@ pub struct DbTransactionPool {
     collection: Option<String>,
     bso_opt: Option<String>,
     precondition: PreConditionHeaderOpt,
+    broker: Arc<dyn Broker>,
+    events: Option<Arc<EventPublisher>>,
 }
 
 impl DbTransactionPool {
@@ -76,6 +83,28 @@ impl DbTransactionPool {
         Ok(self.pool.clone())
     }
 
+    /// Long-poll: wait, for at most `timeout`, until the user's storage is
+    /// modified after the request's `X-If-Modified-Since`.
+    ///
+    /// Only local writes end the wait early (see `Broker`). No DB connection
+    /// is held while waiting.
+    pub async fn wait_for_change(&self, timeout: Duration) -> Result<(), Error> {
+        let since = match self.precondition.opt {
+            Some(PreConditionHeader::IfModifiedSince(since)) => since,
+            _ => return Ok(()),
+        };
+        // Subscribe first so that no write after the check below is missed
+        let mut changes = self.broker.subscribe(&self.user_id);
+        let user_id = self.user_id.clone();
+        let modified = self
+            .transaction(|db| async move { Ok(db.get_storage_timestamp(user_id).await?) })
+            .await?;
+        if modified <= since {
+            let _ = actix_rt::time::timeout(timeout, changes.next()).await;
+        }
+        Ok(())
+    }
+
     /// Perform an action inside of a DB transaction.
     pub async fn transaction<'a, A: 'a, R, F>(&'a self, action: A) -> Result<R, Error>
     where
@@ -91,6 +120,9 @@ impl DbTransactionPool {
 
     /// Perform an action inside of a DB transaction. This method will rollback
     /// if the HTTP response is an error.
+    ///
+    /// Writes mark their responses as `Changed` and `Written`: their changes
+    /// and write events are published once committed.
     pub async fn transaction_http<'a, A: 'a, F>(&'a self, action: A) -> Result<HttpResponse, Error>
     where
         A: FnOnce(Box<dyn Db<'a>>) -> F,
@@ -118,29 +150,65 @@ impl DbTransactionPool {
                         {
                             StatusCode::PRECONDITION_FAILED
                         }
+                        PreConditionHeader::IfNoneMatch(etags) if etags.matches(resource_ts) => {
+                            if self.is_read {
+                                StatusCode::NOT_MODIFIED
+                            } else {
+                                StatusCode::PRECONDITION_FAILED
+                            }
+                        }
+                        PreConditionHeader::IfMatch(etags) if !etags.matches(resource_ts) => {
+                            StatusCode::PRECONDITION_FAILED
+                        }
                         _ => StatusCode::OK,
                     };
                     if status != StatusCode::OK {
-                        return Ok(HttpResponse::build(status)
-                            .content_type("application/json")
+                        let mut resp = HttpResponse::build(status);
+                        resp.content_type("application/json")
                             .header(X_LAST_MODIFIED, resource_ts.as_header())
-                            .body("".to_owned())
-                            .into_body());
+                            .header(header::VARY, "Accept-Encoding");
+                        if resource_ts.as_i64() != 0 {
+                            resp.header(header::ETAG, resource_ts.as_etag());
+                        }
+                        return Ok(resp.body("".to_owned()).into_body());
                     };
                 }
 
                 let mut resp = action(db).await?;
 
-                if resp.headers().contains_key(X_LAST_MODIFIED) {
-                    return Ok(resp);
+                if !resp.headers().contains_key(X_LAST_MODIFIED) {
+                    // See if we already extracted one and use that if possible
+                    if let Ok(ts_header) = header::HeaderValue::from_str(&resource_ts.as_header()) {
+                        debug!("📝 Setting X-Last-Modfied {:?}", ts_header);
+                        resp.headers_mut()
+                            .insert(header::HeaderName::from_static(X_LAST_MODIFIED), ts_header);
+                    }
                 }
 
-                // See if we already extracted one and use that if possible
-                if let Ok(ts_header) = header::HeaderValue::from_str(&resource_ts.as_header()) {
-                    debug!("📝 Setting X-Last-Modfied {:?}", ts_header);
-                    resp.headers_mut()
-                        .insert(header::HeaderName::from_static(X_LAST_MODIFIED), ts_header);
+                // Derive the ETag from X-Last-Modified, which writes update
+                // to the new modified time
+                if resp.status().is_success() && !resp.headers().contains_key(header::ETAG) {
+                    let etag = resp
+                        .headers()
+                        .get(X_LAST_MODIFIED)
+                        .and_then(|ts_header| ts_header.to_str().ok())
+                        .filter(|ts_header| {
+                            SyncTimestamp::from_header(ts_header)
+                                .map(|ts| ts.as_i64() != 0)
+                                .unwrap_or(false)
+                        })
+                        .map(etag)
+                        .and_then(|etag| header::HeaderValue::from_str(&etag).ok());
+                    if let Some(etag) = etag {
+                        resp.headers_mut().insert(header::ETAG, etag);
+                    }
                 }
+                // The body (and so the validity of a cached copy) varies with
+                // the negotiated content coding
+                resp.headers_mut().append(
+                    header::VARY,
+                    header::HeaderValue::from_static("Accept-Encoding"),
+                );
 
                 Ok(resp)
             }
@@ -151,8 +219,34 @@ impl DbTransactionPool {
         // HttpResponse can contain an internal error
         match resp.error() {
             None => db.commit().await?,
-            Some(_) => db.rollback().await?,
+            Some(_) => {
+                db.rollback().await?;
+                return Ok(resp);
+            }
         };
+        if !resp.status().is_success() {
+            return Ok(resp);
+        }
+        let changed = resp.extensions().get::<Changed>().copied();
+        if let Some(Changed(modified)) = changed {
+            self.broker.publish(
+                &self.user_id,
+                CollectionChange {
+                    collection: self.collection.clone(),
+                    modified,
+                },
+            );
+        }
+        let written = resp.extensions().get::<Written>().copied();
+        if let (Some(events), Some(written)) = (&self.events, written) {
+            // Writes that don't modify the collection (batch appends) are
+            // stamped with when they were committed
+            let timestamp = changed.map_or_else(
+                || SyncTimestamp::from_milliseconds(ms_since_epoch() as u64),
+                |Changed(ts)| ts,
+            );
+            events.publish(&self.user_id, self.collection.clone(), written, timestamp);
+        }
         Ok(resp)
     }
 
@@ -234,6 +328,8 @@ impl FromRequest for DbTransactionPool {
                 collection,
                 bso_opt,
                 precondition,
+                broker: Arc::clone(&state.broker),
+                events: state.events.clone(),
             };
 
             req.extensions_mut().insert(pool.clone());
@@ -34,6 +34,12 @@ impl SyncTimestamp {
         format_ts(self.0)
     }
 
+    /// Create a (weak) HTTP `ETag` value for a resource last modified at this
+    /// timestamp
+    pub fn as_etag(self) -> String {
+        etag(&self.as_header())
+    }
+
     /// Create a `SyncTimestamp` from a string header
     ///
     /// Assumes the string represents the seconds since epoch with two decimal places of precision.
@@ -169,6 +175,15 @@ where
     precise.serialize(s)
 }
 
+/// Format a Sync timestamp header value as an HTTP `ETag`
+///
+/// The tag identifies a version of the resource rather than the bytes of a
+/// representation of it (which vary with the query, content coding and
+/// expired records), so it's weak.
+pub fn etag(header: &str) -> String {
+    format!("W/\"{}\"", header)
+}
+
 /// Render a timestamp (as an i64 milliseconds since epoch) as an RFC 3339 and ISO 8601
 /// date and time string such as 1996-12-19T16:39:57-08:00
 pub fn to_rfc3339(val: i64) -> Result<String, DbError> {
@@ -40,6 +40,8 @@ enum WeaveError {
     MalformedJson = 6,
     /// Invalid Weave Basic Object
     InvalidWbo = 8,
+    /// Invalid collection
+    InvalidCollection = 13,
     /// User over quota
     OverQuota = 14,
     /// Size limit exceeded
@@ -122,12 +124,19 @@ impl ApiError {
         // Should we report this error to sentry?
         match self.kind() {
             ApiErrorKind::Db(dbe) => match dbe.kind() {
-                DbErrorKind::Conflict => return false,
+                DbErrorKind::Conflict
+                | DbErrorKind::CollectionNotAllowed(_)
+                | DbErrorKind::CollectionLimit => return false,
                 _ => (),
             },
             ApiErrorKind::Hawk(hawke) => match hawke.kind() {
                 HawkErrorKind::MissingHeader => return false,
                 HawkErrorKind::InvalidHeader => return false,
+                HawkErrorKind::Replayed => return false,
+                HawkErrorKind::Revoked => return false,
+                HawkErrorKind::StaleTimestamp(_) => return false,
+                HawkErrorKind::InvalidPayloadHash => return false,
+                HawkErrorKind::MissingPayloadHash => return false,
                 _ => (),
             },
             _ => (),
@@ -135,6 +144,19 @@ impl ApiError {
         self.metric_label.is_none()
     }
 
+    /// The `WWW-Authenticate` header value to include in the response, if any.
+    pub fn www_authenticate(&self) -> Option<&str> {
+        match self.kind() {
+            ApiErrorKind::Hawk(hawke) => match hawke.kind() {
+                HawkErrorKind::StaleTimestamp(header) => Some(header),
+                HawkErrorKind::InvalidPayloadHash => Some("Hawk error=\"Payload hash mismatch\""),
+                HawkErrorKind::MissingPayloadHash => Some("Hawk error=\"Missing payload hash\""),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     pub fn on_response(&self, state: &ServerState) {
         if self.is_conflict() {
             Metrics::from(state).incr("storage.confict")
@@ -177,6 +199,11 @@ impl ApiError {
                     }
                 }
             },
+            ApiErrorKind::Db(dbe) => match dbe.kind() {
+                DbErrorKind::CollectionNotAllowed(_) => WeaveError::InvalidCollection,
+                DbErrorKind::CollectionLimit => WeaveError::OverQuota,
+                _ => WeaveError::UnknownError,
+            },
             _ => WeaveError::UnknownError,
         }
     }
@@ -252,6 +279,9 @@ impl ResponseError for ApiError {
             .if_true(self.is_conflict(), |resp| {
                 resp.header("Retry-After", RETRY_AFTER.to_string());
             })
+            .if_some(self.www_authenticate(), |header, resp| {
+                resp.header("WWW-Authenticate", header);
+            })
             .json(self.weave_error_code() as i32)
     }
 }
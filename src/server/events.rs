@@ -0,0 +1,411 @@
+//! Write events
+//!
+//! A summary of every committed write (never its payload) is published to an
+//! `EventSink`, e.g. for analytics and backup systems. Events are buffered,
+//! up to a limit, and sent from a background task: when the sink can't keep
+//! up they're dropped rather than slowing down requests.
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use actix_web::{client::Client, http::header::AUTHORIZATION, web};
+use futures::{
+    channel::mpsc,
+    future::{self, LocalBoxFuture},
+    FutureExt, StreamExt,
+};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::db::util::SyncTimestamp;
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::server::metrics::Metrics;
+use crate::settings::Secrets;
+use crate::web::{auth::hkdf_expand_32, extractors::HawkIdentifier};
+
+/// Most events sent to a sink at once
+const MAX_BATCH_SIZE: usize = 100;
+
+/// The kind of write
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteOp {
+    Put,
+    Post,
+    BatchAppend,
+    BatchCommit,
+    Delete,
+    DeleteBsos,
+    DeleteCollection,
+    DeleteAll,
+    RestoreStorage,
+    RestoreCollection,
+}
+
+/// Marks a response as the result of a write, to be published once it's
+/// committed.
+#[derive(Clone, Copy, Debug)]
+pub struct Written {
+    pub op: WriteOp,
+    /// The number of records written or deleted by the request, when known
+    pub records: usize,
+    /// The size of the payloads written by the request
+    pub bytes: usize,
+}
+
+/// A committed write
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WriteEvent {
+    /// A keyed hash of the user's id
+    pub uid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    pub op: WriteOp,
+    pub records: usize,
+    pub bytes: usize,
+    /// The collection's new modified time, or when the write was committed
+    /// for writes not modifying it (batch appends)
+    pub timestamp: SyncTimestamp,
+}
+
+/// Where write events are sent.
+///
+/// Sinks are driven by a single background task, so they may be `!Send`.
+pub trait EventSink {
+    fn send<'a>(&'a self, events: &'a [WriteEvent]) -> LocalBoxFuture<'a, ApiResult<()>>;
+}
+
+/// Writes events as lines of JSON, on the blocking thread pool.
+pub struct WriterSink<W: Write + Send + 'static> {
+    writer: Arc<Mutex<W>>,
+}
+
+impl<W: Write + Send + 'static> WriterSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> EventSink for WriterSink<W> {
+    fn send<'a>(&'a self, events: &'a [WriteEvent]) -> LocalBoxFuture<'a, ApiResult<()>> {
+        let mut lines = vec![];
+        for event in events {
+            if let Err(e) = serde_json::to_writer(&mut lines, event) {
+                let err = ApiErrorKind::Internal(format!("Event encoding error: {}", e));
+                return future::ready(Err(err.into())).boxed_local();
+            }
+            lines.push(b'\n');
+        }
+        let writer = Arc::clone(&self.writer);
+        async move {
+            web::block(move || -> io::Result<()> {
+                let mut writer = match writer.lock() {
+                    Ok(writer) => writer,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                writer.write_all(&lines)?;
+                writer.flush()
+            })
+            .await
+            .map_err(|e| ApiErrorKind::Internal(format!("Event sink error: {}", e)))?;
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// POSTs batches of events to a URL, in the body format of a Google Cloud
+/// Pub/Sub `topics.publish` request: `{"messages": [{"data": <base64 JSON
+/// event>}, ...]}`.
+pub struct HttpSink {
+    client: Client,
+    url: String,
+    authorization: Option<String>,
+}
+
+impl HttpSink {
+    /// A sink POSTing to `url`, with the `authorization` header if any.
+    pub fn new(url: &str, authorization: Option<&str>) -> Self {
+        Self {
+            client: Client::default(),
+            url: url.to_owned(),
+            authorization: authorization.map(ToOwned::to_owned),
+        }
+    }
+}
+
+impl EventSink for HttpSink {
+    fn send<'a>(&'a self, events: &'a [WriteEvent]) -> LocalBoxFuture<'a, ApiResult<()>> {
+        async move {
+            let messages = events
+                .iter()
+                .map(|event| {
+                    let data = serde_json::to_vec(event)?;
+                    Ok(json!({ "data": base64::encode(&data) }))
+                })
+                .collect::<Result<Vec<_>, serde_json::Error>>()
+                .map_err(|e| ApiErrorKind::Internal(format!("Event encoding error: {}", e)))?;
+            let mut request = self.client.post(&self.url);
+            if let Some(authorization) = &self.authorization {
+                request = request.header(AUTHORIZATION, authorization.as_str());
+            }
+            let response = request
+                .send_json(&json!({ "messages": messages }))
+                .await
+                .map_err(|e| ApiErrorKind::Internal(format!("Event sink error: {}", e)))?;
+            if !response.status().is_success() {
+                Err(ApiErrorKind::Internal(format!(
+                    "Event sink error: {}",
+                    response.status()
+                )))?;
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// Create the sink described by the `write_events_sink` setting: `stderr`
+/// (stdout carries the logs), `file:<path>` or an `http(s)://` URL, sent the
+/// `authorization` header.
+pub fn sink_from_settings(
+    sink: &str,
+    authorization: Option<&str>,
+) -> ApiResult<Box<dyn EventSink>> {
+    if sink == "stderr" {
+        Ok(Box::new(WriterSink::new(io::stderr())))
+    } else if let Some(path) = sink.strip_prefix("file:") {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Box::new(WriterSink::new(file)))
+    } else if sink.starts_with("http://") || sink.starts_with("https://") {
+        Ok(Box::new(HttpSink::new(sink, authorization)))
+    } else {
+        Err(ApiErrorKind::Internal(format!("Invalid write_events_sink: {:?}", sink)).into())
+    }
+}
+
+/// Publishes write events to a sink without blocking.
+pub struct EventPublisher {
+    sender: Mutex<mpsc::Sender<WriteEvent>>,
+    uid_key: [u8; 32],
+    metrics: Metrics,
+}
+
+impl EventPublisher {
+    /// Start sending events to the sink from a background task, buffering
+    /// up to `buffer_size` of them.
+    pub fn spawn(
+        sink: Box<dyn EventSink>,
+        buffer_size: usize,
+        secrets: &Secrets,
+        metrics: Metrics,
+    ) -> ApiResult<Self> {
+        let (sender, mut receiver) = mpsc::channel::<WriteEvent>(buffer_size);
+        let task_metrics = metrics.clone();
+        actix_rt::spawn(async move {
+            while let Some(event) = receiver.next().await {
+                let mut events = vec![event];
+                while events.len() < MAX_BATCH_SIZE {
+                    match receiver.try_next() {
+                        Ok(Some(event)) => events.push(event),
+                        _ => break,
+                    }
+                }
+                if let Err(e) = sink.send(&events).await {
+                    warn!("⚠️ Couldn't send {} write events: {}", events.len(), e);
+                    task_metrics.incr("events.send_error");
+                }
+            }
+        });
+        Ok(Self {
+            sender: Mutex::new(sender),
+            uid_key: hkdf_expand_32(
+                b"services.mozilla.com/syncstorage/events",
+                None,
+                &secrets.master_secret,
+            )?,
+            metrics,
+        })
+    }
+
+    /// Queue a write's event, dropping it when the buffer's full.
+    pub fn publish(
+        &self,
+        user_id: &HawkIdentifier,
+        collection: Option<String>,
+        written: Written,
+        timestamp: SyncTimestamp,
+    ) {
+        let event = WriteEvent {
+            uid: self.hash_uid(user_id),
+            collection,
+            op: written.op,
+            records: written.records,
+            bytes: written.bytes,
+            timestamp,
+        };
+        let mut sender = match self.sender.lock() {
+            Ok(sender) => sender,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if sender.try_send(event).is_err() {
+            self.metrics.incr("events.dropped");
+        }
+    }
+
+    /// Hash the user's id so events can be correlated, but not traced back
+    /// to the user without the master secret
+    fn hash_uid(&self, user_id: &HawkIdentifier) -> String {
+        let uid = if user_id.fxa_uid.is_empty() {
+            user_id.legacy_id.to_string()
+        } else {
+            user_id.fxa_uid.clone()
+        };
+        // HMAC takes keys of any size
+        let mut hmac = Hmac::<Sha256>::new_varkey(&self.uid_key).expect("HMAC key error");
+        hmac.update(uid.as_bytes());
+        base64::encode_config(hmac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{test, App, HttpRequest, HttpResponse};
+    use serde_json::Value;
+
+    use super::*;
+
+    fn written(op: WriteOp, records: usize, bytes: usize) -> Written {
+        Written { op, records, bytes }
+    }
+
+    /// A `Write` recording what's written
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_writer_sink() {
+        let output = Shared::default();
+        let publisher = EventPublisher::spawn(
+            Box::new(WriterSink::new(output.clone())),
+            10,
+            &Secrets::new("foo").unwrap(),
+            Metrics::noop(),
+        )
+        .unwrap();
+        let user_id = HawkIdentifier::new_legacy(1);
+        publisher.publish(
+            &user_id,
+            Some("bookmarks".to_owned()),
+            written(WriteOp::Post, 2, 100),
+            SyncTimestamp::from_milliseconds(1230),
+        );
+        publisher.publish(
+            &user_id,
+            None,
+            written(WriteOp::DeleteAll, 0, 0),
+            SyncTimestamp::from_milliseconds(4560),
+        );
+        actix_rt::time::delay_for(Duration::from_millis(50)).await;
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        let uid = publisher.hash_uid(&user_id);
+        assert_ne!(uid, "1");
+        assert_eq!(
+            lines[0],
+            json!({
+                "uid": uid,
+                "collection": "bookmarks",
+                "op": "post",
+                "records": 2,
+                "bytes": 100,
+                "timestamp": 1.23,
+            })
+        );
+        assert_eq!(
+            lines[1],
+            json!({
+                "uid": uid,
+                "op": "delete_all",
+                "records": 0,
+                "bytes": 0,
+                "timestamp": 4.56,
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_http_sink() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let stand_in = {
+            let received = Arc::clone(&received);
+            test::start(move || {
+                let received = Arc::clone(&received);
+                App::new().route(
+                    "/publish",
+                    web::post().to(move |req: HttpRequest, body: web::Json<Value>| {
+                        let authorization = req
+                            .headers()
+                            .get(AUTHORIZATION)
+                            .map(|value| value.to_str().unwrap().to_owned());
+                        received
+                            .lock()
+                            .unwrap()
+                            .push((authorization, body.into_inner()));
+                        HttpResponse::Ok().json(json!({ "messageIds": ["1"] }))
+                    }),
+                )
+            })
+        };
+
+        let sink = HttpSink::new(&stand_in.url("/publish"), Some("Bearer xyz"));
+        let event = WriteEvent {
+            uid: "xyz".to_owned(),
+            collection: Some("tabs".to_owned()),
+            op: WriteOp::Put,
+            records: 1,
+            bytes: 10,
+            timestamp: SyncTimestamp::from_milliseconds(1000),
+        };
+        sink.send(std::slice::from_ref(&event)).await.unwrap();
+
+        let (authorization, body) = received.lock().unwrap().pop().unwrap();
+        assert_eq!(authorization.as_deref(), Some("Bearer xyz"));
+        let data = body["messages"][0]["data"].as_str().unwrap();
+        let data: Value = serde_json::from_slice(&base64::decode(data).unwrap()).unwrap();
+        assert_eq!(data, serde_json::to_value(&event).unwrap());
+
+        // Failures are reported
+        let sink = HttpSink::new(&stand_in.url("/nowhere"), None);
+        assert!(sink.send(&[event]).await.is_err());
+    }
+
+    #[test]
+    fn test_sink_from_settings() {
+        assert!(sink_from_settings("stderr", None).is_ok());
+        assert!(sink_from_settings("https://pubsub.example.com/publish", Some("Bearer x")).is_ok());
+        assert!(sink_from_settings("stdout", None).is_err());
+        assert!(sink_from_settings("carrier-pigeon", None).is_err());
+    }
+}
@@ -3,7 +3,8 @@ use std::time::Instant;
 
 use actix_web::{error::ErrorInternalServerError, web::Data, Error, HttpRequest};
 use cadence::{
-    BufferedUdpMetricSink, Counted, Metric, NopMetricSink, QueuingMetricSink, StatsdClient, Timed,
+    BufferedUdpMetricSink, Counted, Histogrammed, Metric, MetricBuilder, NopMetricSink,
+    QueuingMetricSink, StatsdClient, Timed,
 };
 
 use crate::error::ApiError;
@@ -127,25 +128,43 @@ impl Metrics {
 
     pub fn incr_with_tags(&self, label: &str, tags: Option<Tags>) {
         if let Some(client) = self.client.as_ref() {
-            let mut tagged = client.incr_with_tags(label);
-            let mut mtags = self.tags.clone().unwrap_or_default();
-            if let Some(tags) = tags {
-                mtags.extend(tags.tags);
-            }
-            for key in mtags.tags.keys().clone() {
-                if let Some(val) = mtags.tags.get(key) {
-                    tagged = tagged.with_tag(&key, val.as_ref());
-                }
-            }
-            // Include any "hard coded" tags.
-            // incr = incr.with_tag("version", env!("CARGO_PKG_VERSION"));
-            match tagged.try_send() {
-                Err(e) => {
-                    // eat the metric, but log the error
-                    warn!("⚠️ Metric {} error: {:?} ", label, e; mtags);
-                }
-                Ok(v) => trace!("☑️ {:?}", v.as_metric_str()),
+            self.send_with_tags(label, client.incr_with_tags(label), tags);
+        }
+    }
+
+    pub fn count_with_tags(&self, label: &str, value: i64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            self.send_with_tags(label, client.count_with_tags(label, value), tags);
+        }
+    }
+
+    pub fn histogram_with_tags(&self, label: &str, value: u64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            self.send_with_tags(label, client.histogram_with_tags(label, value), tags);
+        }
+    }
+
+    /// Send a metric tagged with these metrics' tags and any given ones
+    fn send_with_tags<T>(&self, label: &str, tagged: MetricBuilder<'_, '_, T>, tags: Option<Tags>)
+    where
+        T: Metric + From<String>,
+    {
+        let mut mtags = self.tags.clone().unwrap_or_default();
+        if let Some(tags) = tags {
+            mtags.extend(tags.tags);
+        }
+        let mut tagged = tagged;
+        for (key, val) in mtags.tags.iter() {
+            tagged = tagged.with_tag(key, val);
+        }
+        // Include any "hard coded" tags.
+        // tagged = tagged.with_tag("version", env!("CARGO_PKG_VERSION"));
+        match tagged.try_send() {
+            Err(e) => {
+                // eat the metric, but log the error
+                warn!("⚠️ Metric {} error: {:?} ", label, e; mtags);
             }
+            Ok(v) => trace!("☑️ {:?}", v.as_metric_str()),
         }
     }
 }
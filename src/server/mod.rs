@@ -9,21 +9,33 @@ use actix_web::{
 };
 use cadence::StatsdClient;
 
-use crate::db::{pool_from_settings, spawn_pool_periodic_reporter, DbPool};
+use crate::db::{
+    pool_from_settings, spawn_deleted_storage_purger, spawn_pool_periodic_reporter, DbPool,
+};
 use crate::error::ApiError;
+use crate::server::events::{sink_from_settings, EventPublisher};
 use crate::server::metrics::Metrics;
-use crate::settings::{Secrets, ServerLimits, Settings};
-use crate::web::{handlers, middleware, tokenserver};
+use crate::server::notifications::{Broker, LocalBroker};
+use crate::server::user_limits::UserLimitsCache;
+use crate::settings::{PayloadHashMode, Secrets, ServerLimits, Settings};
+use crate::web::{
+    admin,
+    auth::{NonceCache, RevocationStore},
+    handlers, middleware, tokenserver,
+};
 
 pub const BSO_ID_REGEX: &str = r"[ -~]{1,64}";
 pub const COLLECTION_ID_REGEX: &str = r"[a-zA-Z0-9._-]{1,32}";
 const MYSQL_UID_REGEX: &str = r"[0-9]{1,10}";
 const SYNC_VERSION_PATH: &str = "1.5";
 
+pub mod events;
 pub mod metrics;
+pub mod notifications;
 #[cfg(test)]
 mod test;
 pub mod user_agent;
+pub mod user_limits;
 
 /// This is the global HTTP state object that will be made available to all
 /// HTTP API calls.
@@ -36,15 +48,61 @@ pub struct ServerState {
     /// limits rendered as JSON
     pub limits_json: String,
 
+    /// Users' limits, with their overrides applied.
+    pub user_limits: Arc<UserLimitsCache>,
+
     /// Secrets used during Hawk authentication.
     pub secrets: Arc<Secrets>,
 
+    /// Maximum allowed Hawk timestamp clock skew.
+    pub hawk_timestamp_skew: Duration,
+
+    /// Recently seen Hawk nonces, shared between workers.
+    pub nonce_cache: Arc<NonceCache>,
+
+    /// How the Hawk payload hash of write requests is verified.
+    pub hawk_payload_hash: PayloadHashMode,
+
+    /// Secret used to verify Bearer JWTs, if enabled.
+    pub jwt_secret: Option<Arc<Vec<u8>>>,
+
+    /// Revoked tokens, shared between workers.
+    pub revocations: Arc<RevocationStore>,
+
+    /// Secret required by the admin API, if enabled.
+    pub admin_secret: Option<String>,
+
     /// Metric reporting
     pub metrics: Box<StatsdClient>,
 
     pub port: u16,
 
     pub quota_enabled: bool,
+
+    /// Whether the payloads of the standard encrypted collections must be
+    /// Sync crypto envelopes.
+    pub strict_payload_envelopes: bool,
+
+    /// How long, in seconds, deleted BSO ids are available to
+    /// `deleted_since` queries.
+    pub deleted_bso_retention: u32,
+
+    /// How long, in seconds, deleted storage can be restored (0 when it's
+    /// deleted immediately).
+    pub deleted_storage_grace_period: u32,
+
+    /// How long, in seconds, audit log entries are kept (0 when destructive
+    /// operations aren't recorded).
+    pub audit_log_retention: u32,
+
+    /// Fans out collection changes to subscribed clients.
+    pub broker: Arc<dyn Broker>,
+
+    /// Longest time a long-polling `/info/collections` request is held open.
+    pub max_info_collections_wait: Duration,
+
+    /// Publishes write events, if enabled.
+    pub events: Option<Arc<EventPublisher>>,
 }
 
 pub fn cfg_path(path: &str) -> String {
@@ -72,6 +130,7 @@ macro_rules! build_app {
             .wrap(middleware::weave::WeaveTimestamp::new())
             .wrap(middleware::sentry::SentryWrapper::new())
             .wrap(middleware::rejectua::RejectUA::default())
+            .wrap(middleware::compression::Compression::default())
             // Followed by the "official middleware" so they run first.
             .wrap(Cors::default())
             .service(
@@ -93,6 +152,10 @@ macro_rules! build_app {
             .service(
                 web::resource(&cfg_path("/info/quota")).route(web::get().to(handlers::get_quota)),
             )
+            .service(
+                web::resource(&cfg_path("/notifications"))
+                    .route(web::get().to(handlers::get_notifications)),
+            )
             .service(web::resource(&cfg_path("")).route(web::delete().to(handlers::delete_all)))
             .service(
                 web::resource(&cfg_path("/storage")).route(web::delete().to(handlers::delete_all)),
@@ -152,6 +215,31 @@ macro_rules! build_app {
                 })),
             )
             .service(web::resource("/__error__").route(web::get().to(handlers::test_error)))
+            // Admin
+            .service(
+                web::resource("/__admin__/revocations")
+                    .route(web::get().to(admin::get_revocations))
+                    .route(web::post().to(admin::add_revocation))
+                    .route(web::delete().to(admin::remove_revocation)),
+            )
+            .service(
+                web::resource("/__admin__/storage/audit")
+                    .route(web::get().to(admin::get_audit_log)),
+            )
+            .service(
+                web::resource("/__admin__/storage/restore")
+                    .route(web::post().to(admin::restore_storage)),
+            )
+            .service(
+                web::resource("/__admin__/storage/collection/restore")
+                    .route(web::post().to(admin::restore_collection)),
+            )
+            .service(
+                web::resource("/__admin__/storage/limits")
+                    .route(web::get().to(admin::get_user_limits))
+                    .route(web::put().to(admin::put_user_limits))
+                    .route(web::delete().to(admin::delete_user_limits)),
+            )
     };
 }
 
@@ -162,11 +250,59 @@ impl Server {
         let limits = Arc::new(settings.limits);
         let limits_json =
             serde_json::to_string(&*limits).expect("ServerLimits failed to serialize");
+        let user_limits = Arc::new(UserLimitsCache::new(Duration::from_secs(
+            settings.user_limits_cache_ttl.into(),
+        )));
         let secrets = Arc::new(settings.master_secret);
+        let hawk_timestamp_skew = Duration::from_secs(settings.hawk_timestamp_skew.into());
+        let nonce_cache = Arc::new(NonceCache::new(
+            settings.hawk_nonce_cache_size as usize,
+            hawk_timestamp_skew,
+        ));
+        let hawk_payload_hash = settings.hawk_payload_hash;
+        let jwt_secret = settings
+            .jwt_secret
+            .as_ref()
+            .map(|secret| Arc::new(secret.as_bytes().to_vec()));
+        let revocations = Arc::new(RevocationStore::new(Duration::from_secs(
+            settings.hawk_token_duration.into(),
+        )));
+        let admin_secret = settings.admin_secret.clone();
         let port = settings.port;
         let quota_enabled = settings.enable_quota;
+        let strict_payload_envelopes = settings.strict_payload_envelopes;
+        let deleted_bso_retention = settings.deleted_bso_retention;
+        let deleted_storage_grace_period = settings.deleted_storage_grace_period;
+        let audit_log_retention = settings.audit_log_retention;
+        let broker: Arc<dyn Broker> = Arc::new(LocalBroker::new());
+        let max_info_collections_wait =
+            Duration::from_secs(settings.max_info_collections_wait.into());
+        let events = match &settings.write_events_sink {
+            Some(sink) => Some(Arc::new(EventPublisher::spawn(
+                sink_from_settings(sink, settings.write_events_sink_authorization.as_deref())?,
+                settings.write_events_buffer_size as usize,
+                &secrets,
+                Metrics::from(&metrics),
+            )?)),
+            None => None,
+        };
 
         spawn_pool_periodic_reporter(Duration::from_secs(10), metrics.clone(), db_pool.clone())?;
+        if let Err(e) = revocations.refresh(db_pool.as_ref()).await {
+            warn!("⚠️ Couldn't load token revocations: {}", e);
+        }
+        RevocationStore::spawn_refresher(
+            Arc::clone(&revocations),
+            Duration::from_secs(settings.revocations_refresh_interval.into()),
+            db_pool.clone(),
+        );
+        if deleted_storage_grace_period > 0 {
+            spawn_deleted_storage_purger(
+                Duration::from_secs(60 * 60),
+                metrics.clone(),
+                db_pool.clone(),
+            );
+        }
 
         let mut server = HttpServer::new(move || {
             // Setup the server state
@@ -174,10 +310,24 @@ impl Server {
                 db_pool: db_pool.clone(),
                 limits: Arc::clone(&limits),
                 limits_json: limits_json.clone(),
+                user_limits: Arc::clone(&user_limits),
                 secrets: Arc::clone(&secrets),
+                hawk_timestamp_skew,
+                nonce_cache: Arc::clone(&nonce_cache),
+                hawk_payload_hash,
+                jwt_secret: jwt_secret.clone(),
+                revocations: Arc::clone(&revocations),
+                admin_secret: admin_secret.clone(),
                 metrics: Box::new(metrics.clone()),
                 port,
                 quota_enabled,
+                strict_payload_envelopes,
+                deleted_bso_retention,
+                deleted_storage_grace_period,
+                audit_log_retention,
+                broker: Arc::clone(&broker),
+                max_info_collections_wait,
+                events: events.clone(),
             };
 
             build_app!(state, limits)
@@ -0,0 +1,296 @@
+//! Collection change notifications
+//!
+//! Once a write is committed, the collection it changed and the new
+//! timestamp are published to a `Broker`, which fans them out to the clients
+//! subscribed to the user (as server-sent events, see
+//! `handlers::get_notifications`).
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_rt::time::{delay_for, interval, Delay, Interval};
+use bytes::Bytes;
+use futures::{channel::mpsc, stream::BoxStream, FutureExt, Stream, StreamExt};
+use serde::Serialize;
+
+use crate::db::util::SyncTimestamp;
+use crate::web::extractors::HawkIdentifier;
+
+/// How many changes a subscriber may fall behind by before it's
+/// disconnected
+const SUBSCRIBER_BUFFER: usize = 16;
+
+/// How often an idle event stream sends a comment to keep its connection
+/// open through proxies
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A committed change to a user's storage
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CollectionChange {
+    /// The collection changed: None when all of the user's storage was
+    /// deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    pub modified: SyncTimestamp,
+}
+
+/// Marks a response as the result of a write that changed the collection
+/// (at this timestamp), to be published once it's committed.
+#[derive(Clone, Copy, Debug)]
+pub struct Changed(pub SyncTimestamp);
+
+/// A subscriber's stream of changes. It ends if the subscriber falls behind.
+pub type Subscription = BoxStream<'static, CollectionChange>;
+
+/// Fans out the changes to a user's storage to its subscribers.
+///
+/// The `LocalBroker` only reaches subscribers connected to this process:
+/// multi-node deployments need a `Broker` relaying changes between nodes.
+pub trait Broker: Send + Sync {
+    /// Publish a change to the user's current subscribers. Must not block.
+    fn publish(&self, user_id: &HawkIdentifier, change: CollectionChange);
+
+    /// Subscribe to the user's changes.
+    fn subscribe(&self, user_id: &HawkIdentifier) -> Subscription;
+}
+
+/// The users' subscribers
+type Subscribers = Arc<Mutex<HashMap<HawkIdentifier, Vec<mpsc::Sender<CollectionChange>>>>>;
+
+/// An in-process `Broker`.
+#[derive(Debug, Default)]
+pub struct LocalBroker {
+    inner: Subscribers,
+}
+
+impl LocalBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Broker for LocalBroker {
+    fn publish(&self, user_id: &HawkIdentifier, change: CollectionChange) {
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let senders = match inner.get_mut(user_id) {
+            Some(senders) => senders,
+            None => return,
+        };
+        // Drop the subscribers that have gone or fallen behind: ending
+        // their streams tells them to resync
+        *senders = senders
+            .drain(..)
+            .filter_map(|mut sender| sender.try_send(change.clone()).ok().map(|_| sender))
+            .collect();
+        if senders.is_empty() {
+            inner.remove(user_id);
+        }
+    }
+
+    fn subscribe(&self, user_id: &HawkIdentifier) -> Subscription {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let senders = inner.entry(user_id.clone()).or_insert_with(Vec::new);
+        senders.retain(|sender| !sender.is_closed());
+        senders.push(sender);
+        LocalSubscription {
+            receiver,
+            user_id: user_id.clone(),
+            subscribers: Arc::clone(&self.inner),
+        }
+        .boxed()
+    }
+}
+
+/// A `LocalBroker` subscription, forgetting its user once their last
+/// subscriber is dropped.
+struct LocalSubscription {
+    receiver: mpsc::Receiver<CollectionChange>,
+    user_id: HawkIdentifier,
+    subscribers: Subscribers,
+}
+
+impl Stream for LocalSubscription {
+    type Item = CollectionChange;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for LocalSubscription {
+    fn drop(&mut self) {
+        // Closing the receiver closes its sender
+        self.receiver.close();
+        let mut subscribers = match self.subscribers.lock() {
+            Ok(subscribers) => subscribers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(senders) = subscribers.get_mut(&self.user_id) {
+            senders.retain(|sender| !sender.is_closed());
+            if senders.is_empty() {
+                subscribers.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+/// Renders a `Subscription` as a `text/event-stream` body.
+///
+/// The stream ends when the token the subscriber authenticated with expires
+/// or is revoked (checked as each event is sent), after which it must
+/// reconnect with a new one.
+pub struct EventStream {
+    subscription: Subscription,
+    keepalive: Interval,
+    expiry: Delay,
+    revoked: Box<dyn Fn() -> bool>,
+}
+
+impl EventStream {
+    /// A stream of `subscription`'s changes, ending after `expires_in` or
+    /// once `revoked` returns true.
+    pub fn new(
+        subscription: Subscription,
+        expires_in: Duration,
+        revoked: impl Fn() -> bool + 'static,
+    ) -> Self {
+        Self {
+            subscription,
+            keepalive: interval(KEEPALIVE_INTERVAL),
+            expiry: delay_for(expires_in),
+            revoked: Box::new(revoked),
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.expiry.poll_unpin(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        match self.subscription.poll_next_unpin(cx) {
+            Poll::Ready(Some(_)) if (self.revoked)() => return Poll::Ready(None),
+            Poll::Ready(Some(change)) => {
+                let data = serde_json::to_string(&change).unwrap_or_default();
+                return Poll::Ready(Some(Ok(Bytes::from(format!(
+                    "event: change\ndata: {}\n\n",
+                    data
+                )))));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => (),
+        }
+        match self.keepalive.poll_next_unpin(cx) {
+            Poll::Ready(Some(_)) if (self.revoked)() => Poll::Ready(None),
+            Poll::Ready(Some(_)) => Poll::Ready(Some(Ok(Bytes::from_static(b": keepalive\n\n")))),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn change(collection: &str, modified: u64) -> CollectionChange {
+        CollectionChange {
+            collection: Some(collection.to_owned()),
+            modified: SyncTimestamp::from_milliseconds(modified),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_local_broker() {
+        let broker = LocalBroker::new();
+        let user1 = HawkIdentifier::new_legacy(1);
+        let user2 = HawkIdentifier::new_legacy(2);
+        let mut sub1 = broker.subscribe(&user1);
+        let mut sub2 = broker.subscribe(&user2);
+
+        broker.publish(&user1, change("bookmarks", 1000));
+        broker.publish(&user2, change("history", 2000));
+        assert_eq!(sub1.next().await, Some(change("bookmarks", 1000)));
+        assert_eq!(sub2.next().await, Some(change("history", 2000)));
+
+        // Subscribers that fall behind are disconnected, after receiving
+        // what was buffered
+        let published = SUBSCRIBER_BUFFER as u64 * 2;
+        for i in 0..published {
+            broker.publish(&user1, change("bookmarks", i));
+        }
+        let received: Vec<_> = sub1.collect().await;
+        assert!(received.len() > SUBSCRIBER_BUFFER && received.len() < published as usize);
+        assert_eq!(received[0], change("bookmarks", 0));
+
+        // The departed are forgotten, once the user's last one leaves
+        let sub3 = broker.subscribe(&user2);
+        drop(sub2);
+        assert_eq!(broker.inner.lock().unwrap()[&user2].len(), 1);
+        drop(sub3);
+        assert!(broker.inner.lock().unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_event_stream() {
+        let broker = LocalBroker::new();
+        let user_id = HawkIdentifier::new_legacy(1);
+        let mut events = EventStream::new(
+            broker.subscribe(&user_id),
+            Duration::from_secs(3600),
+            || false,
+        );
+        // The first keepalive is sent immediately, flushing the headers
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            Bytes::from_static(b": keepalive\n\n")
+        );
+
+        broker.publish(&user_id, change("bookmarks", 1230));
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            Bytes::from_static(
+                b"event: change\ndata: {\"collection\":\"bookmarks\",\"modified\":1.23}\n\n"
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_event_stream_ends() {
+        let broker = LocalBroker::new();
+        let user_id = HawkIdentifier::new_legacy(1);
+
+        // At the token's expiry
+        let mut events = EventStream::new(
+            broker.subscribe(&user_id),
+            Duration::from_millis(10),
+            || false,
+        );
+        assert!(events.next().await.is_some());
+        assert!(events.next().await.is_none());
+
+        // Once it's revoked
+        let revoked = Rc::new(Cell::new(false));
+        let mut events = EventStream::new(broker.subscribe(&user_id), Duration::from_secs(3600), {
+            let revoked = Rc::clone(&revoked);
+            move || revoked.get()
+        });
+        assert!(events.next().await.is_some());
+        revoked.set(true);
+        broker.publish(&user_id, change("bookmarks", 1230));
+        assert!(events.next().await.is_none());
+    }
+}
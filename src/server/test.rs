@@ -7,6 +7,7 @@ use actix_web::{
 };
 use bytes::Bytes;
 use chrono::offset::Utc;
+use futures::StreamExt;
 use hawk::{self, Credentials, Key, RequestBuilder};
 use hkdf::Hkdf;
 use hmac::{Hmac, Mac, NewMac};
@@ -18,11 +19,12 @@ use std::str::FromStr;
 
 use super::*;
 use crate::build_app;
+use crate::db::mock::MockDbPool;
 use crate::db::params;
 use crate::db::pool_from_settings;
 use crate::db::results::{DeleteBso, GetBso, PostBsos, PutBso};
 use crate::db::util::SyncTimestamp;
-use crate::settings::{test_settings, Secrets, ServerLimits};
+use crate::settings::{test_settings, BsoHistoryPolicy, LimitOverrides, Secrets, ServerLimits};
 use crate::web::{auth::HawkPayload, extractors::BsoBody, X_LAST_MODIFIED};
 
 lazy_static! {
@@ -62,10 +64,34 @@ async fn get_test_state(settings: &Settings) -> ServerState {
             .expect("Could not get db_pool in get_test_state"),
         limits: Arc::clone(&SERVER_LIMITS),
         limits_json: serde_json::to_string(&**SERVER_LIMITS).unwrap(),
+        user_limits: Arc::new(UserLimitsCache::new(Duration::from_secs(
+            settings.user_limits_cache_ttl.into(),
+        ))),
         secrets: Arc::clone(&SECRETS),
+        hawk_timestamp_skew: Duration::from_secs(settings.hawk_timestamp_skew.into()),
+        nonce_cache: Arc::new(NonceCache::new(
+            settings.hawk_nonce_cache_size as usize,
+            Duration::from_secs(settings.hawk_timestamp_skew.into()),
+        )),
+        hawk_payload_hash: settings.hawk_payload_hash,
+        jwt_secret: settings
+            .jwt_secret
+            .as_ref()
+            .map(|secret| Arc::new(secret.as_bytes().to_vec())),
+        revocations: Arc::new(RevocationStore::new(Duration::from_secs(
+            settings.hawk_token_duration.into(),
+        ))),
+        admin_secret: settings.admin_secret.clone(),
         metrics: Box::new(metrics),
         port: settings.port,
         quota_enabled: settings.enable_quota,
+        strict_payload_envelopes: settings.strict_payload_envelopes,
+        deleted_bso_retention: settings.deleted_bso_retention,
+        deleted_storage_grace_period: settings.deleted_storage_grace_period,
+        audit_log_retention: settings.audit_log_retention,
+        broker: Arc::new(LocalBroker::new()),
+        max_info_collections_wait: Duration::from_secs(settings.max_info_collections_wait.into()),
+        events: None,
     }
 }
 
@@ -299,6 +325,173 @@ async fn delete_all() {
     test_endpoint(http::Method::DELETE, "/1.5/42/storage", None, Some("null")).await;
 }
 
+#[actix_rt::test]
+async fn delete_all_restore() {
+    let mut settings = get_test_settings();
+    settings.deleted_storage_grace_period = 60;
+    settings.admin_secret = Some("admin".to_owned());
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let bsos = json!([{"id": "restored", "payload": "bar"}]);
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/forms",
+        None,
+        Some(bsos),
+    )
+    .to_request();
+    assert_eq!(app.call(req).await.unwrap().status(), StatusCode::OK);
+    let restore = |token: &str| {
+        test::TestRequest::with_uri("/__admin__/storage/restore")
+            .method(http::Method::POST)
+            .header("Authorization", format!("Bearer {}", token))
+            .set_json(&json!({"legacy_id": 42, "fxa_uid": "xxx_test", "fxa_kid": "xxx_test"}))
+            .to_request()
+    };
+
+    // A deletion failing its precondition leaves no copy
+    let mut headers = HashMap::new();
+    headers.insert("X-If-Unmodified-Since", "1.00".to_owned());
+    let req =
+        create_request(http::Method::DELETE, "/1.5/42/storage", Some(headers), None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::PRECONDITION_FAILED);
+    let sresp = app.call(restore("admin")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::NOT_FOUND);
+
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    assert_eq!(app.call(req).await.unwrap().status(), StatusCode::OK);
+    let get_bso = || {
+        create_request(
+            http::Method::GET,
+            "/1.5/42/storage/forms/restored",
+            None,
+            None,
+        )
+        .to_request()
+    };
+    let sresp = app.call(get_bso()).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::NOT_FOUND);
+
+    let sresp = app.call(restore("wrong")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::UNAUTHORIZED);
+    let sresp = app.call(restore("admin")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    assert!(body["modified"].is_number());
+
+    let sresp = app.call(get_bso()).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let bso: serde_json::Value = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    assert_eq!(bso["payload"], "bar");
+    // Only once
+    let sresp = app.call(restore("admin")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn delete_all_audit() {
+    let mut settings = get_test_settings();
+    settings.audit_log_retention = 60;
+    settings.admin_secret = Some("admin".to_owned());
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let bsos = json!([{"id": "b0", "payload": "p0"}, {"id": "b1", "payload": "p1"}]);
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/forms",
+        None,
+        Some(bsos),
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let result: PostBsos = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    assert_eq!(app.call(req).await.unwrap().status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri(
+        "/__admin__/storage/audit?legacy_id=42&fxa_uid=xxx_test&fxa_kid=xxx_test",
+    )
+    .header("Authorization", "Bearer admin")
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let entries: serde_json::Value = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    let entry = &entries[0];
+    assert_eq!(entry["op"], "delete_storage");
+    assert_eq!(entry["count"], 2);
+    // The deletion's timestamp
+    let timestamp = SyncTimestamp::from_seconds(entry["timestamp"].as_f64().unwrap());
+    assert!(timestamp >= result.modified);
+}
+
+#[actix_rt::test]
+async fn restore_collection() {
+    let mut settings = get_test_settings();
+    settings.bso_history.insert(
+        "clients".to_owned(),
+        BsoHistoryPolicy {
+            max_revisions: None,
+            max_age: None,
+        },
+    );
+    settings.admin_secret = Some("admin".to_owned());
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let put_bso = |id: &str, payload: &str| {
+        create_request(
+            http::Method::PUT,
+            &format!("/1.5/42/storage/clients/{}", id),
+            None,
+            Some(json!({ "payload": payload })),
+        )
+        .to_request()
+    };
+    let sresp = app.call(put_bso("kept", "before")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let at: serde_json::Value = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    actix_rt::time::delay_for(Duration::from_millis(50)).await;
+    let sresp = app.call(put_bso("kept", "after")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let sresp = app.call(put_bso("created", "after")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/__admin__/storage/collection/restore")
+        .method(http::Method::POST)
+        .header("Authorization", "Bearer admin")
+        .set_json(&json!({
+            "legacy_id": 42,
+            "fxa_uid": "xxx_test",
+            "fxa_kid": "xxx_test",
+            "collection": "clients",
+            "at": at,
+        }))
+        .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+
+    let get_bso = |id: &str| {
+        create_request(
+            http::Method::GET,
+            &format!("/1.5/42/storage/clients/{}", id),
+            None,
+            None,
+        )
+        .to_request()
+    };
+    let sresp = app.call(get_bso("kept")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let bso: serde_json::Value = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    assert_eq!(bso["payload"], "before");
+    // Created since
+    let sresp = app.call(get_bso("created")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::NOT_FOUND);
+}
+
 #[actix_rt::test]
 async fn delete_collection() {
     let start = SyncTimestamp::default();
@@ -365,6 +558,7 @@ async fn post_collection() {
         sortindex: Some(0),
         payload: Some("bar".to_string()),
         ttl: Some(31_536_000),
+        modified: None,
     }]);
     let bytes =
         test_endpoint_with_body(http::Method::POST, "/1.5/42/storage/bookmarks", res_body).await;
@@ -375,6 +569,86 @@ async fn post_collection() {
     assert_eq!(result.failed.len(), 0);
 }
 
+#[actix_rt::test]
+async fn post_collection_expected_modified() {
+    let mut settings = get_test_settings();
+    settings.enable_expected_modified = true;
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let bsos = json!([
+        {"id": "new", "payload": "bar", "modified": 0},
+        {"id": "stale", "payload": "bar", "modified": 1234.56},
+        {"id": "unconditional", "payload": "bar"},
+    ]);
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/bookmarks",
+        None,
+        Some(bsos),
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let result: PostBsos = serde_json::from_slice(&test::read_body(sresp).await)
+        .expect("Could not get result in post_collection_expected_modified");
+    assert_eq!(result.success, vec!["new", "unconditional"]);
+    assert_eq!(result.failed.len(), 1);
+    assert_eq!(result.failed["stale"], "conflict");
+
+    // Batched records are checked on commit
+    let bsos = json!([
+        {"id": "stale", "payload": "bar", "modified": 1234.56},
+        {"id": "fresh", "payload": "bar", "modified": 0},
+    ]);
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/bookmarks?batch=true",
+        None,
+        Some(bsos),
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::ACCEPTED);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    let batch = body["batch"].as_str().unwrap().to_owned();
+
+    let req = create_request(
+        http::Method::POST,
+        &format!("/1.5/42/storage/bookmarks?batch={}&commit=true", batch),
+        None,
+        Some(json!([])),
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    assert_eq!(body["failed"], json!({"stale": "conflict"}));
+
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/bookmarks?ids=stale,fresh",
+        None,
+        None,
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    let ids: Vec<String> = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    assert_eq!(ids, vec!["fresh"]);
+}
+
+#[actix_rt::test]
+async fn post_collection_expected_modified_disabled() {
+    // Without enable_expected_modified, "modified" is ignored
+    let bsos = json!([{"id": "stale", "payload": "bar", "modified": 1234.56}]);
+    let bytes =
+        test_endpoint_with_body(http::Method::POST, "/1.5/42/storage/bookmarks", bsos).await;
+    let result: PostBsos = serde_json::from_slice(&bytes.to_vec())
+        .expect("Could not get result in post_collection_expected_modified_disabled");
+    assert_eq!(result.success, vec!["stale"]);
+    assert!(result.failed.is_empty());
+}
+
 #[actix_rt::test]
 async fn delete_bso() {
     test_endpoint(
@@ -432,6 +706,170 @@ async fn bsos_can_have_a_collection_field() {
     assert!(result2 >= start);
 }
 
+#[actix_rt::test]
+async fn etag_preconditions() {
+    let mut app = init_app!().await;
+    let path = "/1.5/42/storage/bookmarks/etagged";
+    let put = |headers: Option<HashMap<&'static str, String>>| {
+        create_request(
+            http::Method::PUT,
+            path,
+            headers,
+            Some(json!({"payload": "wibble"})),
+        )
+        .to_request()
+    };
+    let if_header = |name: &'static str, etag: &str| {
+        let mut headers = HashMap::new();
+        headers.insert(name, etag.to_owned());
+        Some(headers)
+    };
+
+    let response = app.call(put(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response
+        .headers()
+        .get("ETag")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+    assert!(etag.starts_with("W/\""));
+    assert_eq!(response.headers().get("Vary").unwrap(), "Accept-Encoding");
+
+    // Unchanged since it was fetched
+    let req = create_request(
+        http::Method::GET,
+        path,
+        if_header("If-None-Match", &etag),
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers().get("ETag").unwrap(), etag.as_str());
+    assert_eq!(response.headers().get("Vary").unwrap(), "Accept-Encoding");
+
+    // A lost update
+    let response = app
+        .call(put(if_header("If-Match", "W/\"1.00\"")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let response = app.call(put(if_header("If-Match", &etag))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers().get("ETag").unwrap(), etag.as_str());
+
+    // Changed since it was fetched
+    let req = create_request(
+        http::Method::GET,
+        path,
+        if_header("If-None-Match", &etag),
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn compressed_bodies() {
+    use flate2::{read::GzDecoder, write::GzEncoder};
+    use std::io::{Read, Write};
+
+    let mut app = init_app!().await;
+    let bsos = json!([{"id": "foo", "payload": "bar".repeat(1000)}]).to_string();
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(bsos.as_bytes()).unwrap();
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type", "application/json".to_owned());
+    headers.insert("Content-Encoding", "gzip".to_owned());
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/bookmarks",
+        Some(headers),
+        None,
+    )
+    .set_payload(encoder.finish().unwrap())
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let result: PostBsos = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(result.success, vec!["foo"]);
+
+    let mut headers = HashMap::new();
+    headers.insert("Accept-Encoding", "gzip".to_owned());
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/bookmarks?full=1",
+        Some(headers),
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("Content-Encoding").unwrap(), "gzip");
+    let mut body = String::new();
+    GzDecoder::new(&test::read_body(response).await[..])
+        .read_to_string(&mut body)
+        .unwrap();
+    assert!(body.contains(&"bar".repeat(1000)));
+}
+
+/// Collects the metrics sent to it.
+#[derive(Clone, Default)]
+struct CollectingSink(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl cadence::MetricSink for CollectingSink {
+    fn emit(&self, metric: &str) -> std::io::Result<usize> {
+        self.0.lock().unwrap().push(metric.to_owned());
+        Ok(metric.len())
+    }
+}
+
+#[actix_rt::test]
+async fn compressed_response_metrics() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let sink = CollectingSink::default();
+    let mut state = get_test_state(&settings).await;
+    state.metrics = Box::new(cadence::StatsdClient::from_sink("", sink.clone()));
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let mut headers = HashMap::new();
+    headers.insert("Accept-Encoding", "gzip".to_owned());
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/info/configuration",
+        Some(headers),
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.headers().get("Content-Encoding").unwrap(), "gzip");
+    test::read_body(response).await;
+    let ratios = |sink: &CollectingSink| {
+        sink.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|metric| metric.starts_with("response.compression_ratio:"))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let recorded = ratios(&sink);
+    assert_eq!(recorded.len(), 1);
+    assert!(recorded[0].contains("|h"));
+    assert!(recorded[0].contains("encoding:gzip"));
+
+    // Nothing's recorded for uncompressed responses
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/configuration", None, None).to_request();
+    test::read_body(app.call(req).await.unwrap()).await;
+    assert_eq!(ratios(&sink).len(), 1);
+}
+
 #[actix_rt::test]
 async fn invalid_content_type() {
     let path = "/1.5/42/storage/bookmarks/wibble";
@@ -598,6 +1036,153 @@ async fn reject_old_ios() {
     assert_eq!(body, "0");
 }
 
+/// A `Write` recording what's written
+#[derive(Clone, Default)]
+struct SharedWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[actix_rt::test]
+async fn write_events() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut state = get_test_state(&settings).await;
+    let output = SharedWriter::default();
+    state.events = Some(Arc::new(
+        events::EventPublisher::spawn(
+            Box::new(events::WriterSink::new(output.clone())),
+            10,
+            &settings.master_secret,
+            Metrics::noop(),
+        )
+        .unwrap(),
+    ));
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let start = SyncTimestamp::from_milliseconds(crate::db::util::ms_since_epoch() as u64);
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/bookmarks?batch=true",
+        None,
+        Some(json!([{"id": "1", "payload": "xxx"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    actix_rt::time::delay_for(Duration::from_millis(50)).await;
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let event: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+    assert_eq!(event["op"], "batch_append");
+    assert_eq!(event["records"], 1);
+    assert_eq!(event["bytes"], 3);
+    // Appends don't modify the collection, so carry their commit time
+    assert!(event["timestamp"].as_f64().unwrap() >= start.as_seconds());
+}
+
+#[actix_rt::test]
+async fn notifications() {
+    let mut app = init_app!().await;
+    let req = create_request(http::Method::GET, "/1.5/42/notifications", None, None).to_request();
+    let mut response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut events = response.take_body();
+    // The first keepalive flushes the headers
+    let event = events.next().await.unwrap().unwrap();
+    assert_eq!(event, Bytes::from_static(b": keepalive\n\n"));
+
+    // A committed write reaches the subscriber
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/bookmarks",
+        None,
+        Some(json!([{"id": "1", "payload": "x"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let result: PostBsos = serde_json::from_slice(&test::read_body(response).await).unwrap();
+
+    let event = events.next().await.unwrap().unwrap();
+    let expected = format!(
+        "event: change\ndata: {}\n\n",
+        json!({"collection": "bookmarks", "modified": result.modified})
+    );
+    assert_eq!(event, Bytes::from(expected));
+
+    // The stream ends when the (short lived test) token expires
+    assert!(events.next().await.is_none());
+}
+
+#[actix_rt::test]
+async fn info_collections_wait() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut state = get_test_state(&settings).await;
+    // Waits are capped to this
+    let max_wait = Duration::from_millis(50);
+    state.max_info_collections_wait = max_wait;
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let mut headers = HashMap::new();
+    headers.insert("X-If-Modified-Since", SyncTimestamp::default().as_header());
+    let start = std::time::Instant::now();
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/info/collections?wait=10",
+        Some(headers),
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= max_wait && elapsed < Duration::from_secs(10));
+
+    // Waiting requires an X-If-Modified-Since to wait for changes after
+    for headers in [
+        None,
+        Some(vec![("If-None-Match", "*".to_owned())]),
+        Some(vec![
+            ("X-If-Modified-Since", SyncTimestamp::default().as_header()),
+            ("If-None-Match", "*".to_owned()),
+        ]),
+    ]
+    .iter()
+    {
+        let headers = headers
+            .as_ref()
+            .map(|headers| headers.iter().cloned().collect());
+        let req = create_request(
+            http::Method::GET,
+            "/1.5/42/info/collections?wait=10",
+            headers,
+            None,
+        )
+        .to_request();
+        let response = app.call(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/info/collections?wait=soon",
+        None,
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
 #[actix_rt::test]
 async fn info_configuration_xlm() {
     let mut app = init_app!().await;
@@ -614,3 +1199,182 @@ async fn info_configuration_xlm() {
         "0.00"
     );
 }
+
+#[actix_rt::test]
+async fn info_configuration_user_limits() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut state = get_test_state(&settings).await;
+    state.db_pool = Box::new(MockDbPool::with_user_limits(Some(Some(LimitOverrides {
+        max_post_records: Some(1),
+        ..Default::default()
+    }))));
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/configuration", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    assert_eq!(body["max_post_records"], 1);
+    assert_eq!(body["max_post_bytes"], SERVER_LIMITS.max_post_bytes);
+}
+
+#[actix_rt::test]
+async fn info_configuration_user_limits_unavailable() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut state = get_test_state(&settings).await;
+    // Loading the user's overrides fails
+    state.db_pool = Box::new(MockDbPool::with_user_limits(None));
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/configuration", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(sresp).await).unwrap();
+    assert_eq!(body, serde_json::to_value(&**SERVER_LIMITS).unwrap());
+}
+
+#[actix_rt::test]
+async fn put_user_limits_exceeding_request_bytes() {
+    let mut settings = get_test_settings();
+    settings.admin_secret = Some("admin".to_owned());
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let put_limits = |max_post_bytes: u32| {
+        test::TestRequest::with_uri("/__admin__/storage/limits")
+            .method(http::Method::PUT)
+            .header("Authorization", "Bearer admin")
+            .set_json(&json!({
+                "legacy_id": 42,
+                "limits": {"max_post_bytes": max_post_bytes},
+            }))
+            .to_request()
+    };
+    let response = app
+        .call(put_limits(SERVER_LIMITS.max_request_bytes + 1))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app
+        .call(put_limits(SERVER_LIMITS.max_request_bytes))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let req = test::TestRequest::with_uri("/__admin__/storage/limits")
+        .method(http::Method::DELETE)
+        .header("Authorization", "Bearer admin")
+        .set_json(&json!({"legacy_id": 42}))
+        .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn collection_policy() {
+    let request = |method: http::Method, path: &str| {
+        let payload = if method == http::Method::PUT {
+            Some(json!({"payload": "x"}))
+        } else {
+            None
+        };
+        create_request(method, &format!("/1.5/42/storage/{}", path), None, payload).to_request()
+    };
+
+    let mut settings = get_test_settings();
+    settings.max_custom_collections = 1;
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+    app.call(request(http::Method::DELETE, "policy_custom1"))
+        .await
+        .unwrap();
+
+    let sresp = app
+        .call(request(http::Method::PUT, "policy_custom1/b0"))
+        .await
+        .unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let sresp = app
+        .call(request(http::Method::PUT, "policy_custom2/b0"))
+        .await
+        .unwrap();
+    assert_eq!(sresp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::read_body(sresp).await, "14");
+    // Deletes never add a collection, so aren't capped
+    let sresp = app
+        .call(request(http::Method::DELETE, "policy_custom2"))
+        .await
+        .unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let sresp = app
+        .call(request(http::Method::DELETE, "policy_custom1"))
+        .await
+        .unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+
+    let mut settings = get_test_settings();
+    settings.collection_allowlist = Some("policy_custom1".to_owned());
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+    let sresp = app
+        .call(request(http::Method::PUT, "policy_other/b0"))
+        .await
+        .unwrap();
+    assert_eq!(sresp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(test::read_body(sresp).await, "13");
+    let sresp = app
+        .call(request(http::Method::DELETE, "policy_other"))
+        .await
+        .unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn revoked_token() {
+    let mut settings = get_test_settings();
+    settings.admin_secret = Some("admin".to_owned());
+    let limits = Arc::new(settings.limits.clone());
+    let state = get_test_state(&settings).await;
+    let db_pool = state.db_pool.clone();
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let admin_request = |method: http::Method, token: &str| {
+        test::TestRequest::with_uri("/__admin__/revocations")
+            .method(method)
+            .header("Authorization", format!("Bearer {}", token))
+            .set_json(&json!({"uid": 42}))
+            .to_request()
+    };
+    let info_collections =
+        || create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+
+    let response = app
+        .call(admin_request(http::Method::POST, "wrong"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app
+        .call(admin_request(http::Method::POST, "admin"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(info_collections()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    // The revocation's persisted for other server processes
+    let revocations = RevocationStore::new(Duration::from_secs(3600));
+    revocations.refresh(db_pool.as_ref()).await.unwrap();
+    assert!(revocations.is_revoked(42, "", "", None));
+
+    let response = app
+        .call(admin_request(http::Method::DELETE, "admin"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(info_collections()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,127 @@
+//! Per-user overrides of the server limits
+//!
+//! Overrides are stored in the database (see `Db::put_user_limits`) and
+//! users' effective limits cached in memory for `user_limits_cache_ttl`.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::db::DbPool;
+use crate::error::ApiResult;
+use crate::settings::ServerLimits;
+use crate::web::extractors::HawkIdentifier;
+
+/// Most users whose limits are cached at once
+const MAX_CACHED_USERS: usize = 100_000;
+
+/// Users' effective limits and when they were loaded
+type Entries = HashMap<HawkIdentifier, (Instant, Arc<ServerLimits>)>;
+
+/// Users' effective limits: the server limits with their overrides applied
+#[derive(Debug)]
+pub struct UserLimitsCache {
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl UserLimitsCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The user's effective limits, loading their overrides when they're
+    /// not cached
+    pub async fn get(
+        &self,
+        limits: &Arc<ServerLimits>,
+        db_pool: &dyn DbPool,
+        user_id: &HawkIdentifier,
+    ) -> ApiResult<Arc<ServerLimits>> {
+        if let Some(cached) = self.cached(user_id) {
+            return Ok(cached);
+        }
+        let db = db_pool.get().await?;
+        db.begin(false).await?;
+        let overrides = db.get_user_limits(user_id.clone()).await;
+        db.commit().await?;
+        let effective = match overrides? {
+            Some(overrides) => Arc::new(limits.with_overrides(&overrides)),
+            None => Arc::clone(limits),
+        };
+        self.insert(user_id.clone(), Arc::clone(&effective));
+        Ok(effective)
+    }
+
+    /// Forget every user's cached limits, after some user's overrides
+    /// changed
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn cached(&self, user_id: &HawkIdentifier) -> Option<Arc<ServerLimits>> {
+        self.lock()
+            .get(user_id)
+            .filter(|(loaded, _)| loaded.elapsed() < self.ttl)
+            .map(|(_, limits)| Arc::clone(limits))
+    }
+
+    fn insert(&self, user_id: HawkIdentifier, limits: Arc<ServerLimits>) {
+        if self.ttl == Duration::from_secs(0) {
+            return;
+        }
+        let mut entries = self.lock();
+        if entries.len() >= MAX_CACHED_USERS {
+            let ttl = self.ttl;
+            entries.retain(|_, (loaded, _)| loaded.elapsed() < ttl);
+            if entries.len() >= MAX_CACHED_USERS {
+                entries.clear();
+            }
+        }
+        entries.insert(user_id, (Instant::now(), limits));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::mock::MockDbPool;
+    use crate::settings::LimitOverrides;
+
+    #[actix_rt::test]
+    async fn cached_limits() {
+        let cache = UserLimitsCache::new(Duration::from_secs(60));
+        let pool = MockDbPool::new();
+        let limits = Arc::new(ServerLimits::default());
+        let user_id = HawkIdentifier::new_legacy(1);
+
+        // No overrides
+        let effective = cache.get(&limits, &pool, &user_id).await.unwrap();
+        assert!(Arc::ptr_eq(&effective, &limits));
+
+        let overridden = Arc::new(limits.with_overrides(&LimitOverrides {
+            max_post_records: Some(1),
+            ..Default::default()
+        }));
+        assert_eq!(overridden.max_post_records, 1);
+        assert_eq!(overridden.max_post_bytes, limits.max_post_bytes);
+        cache.insert(user_id.clone(), Arc::clone(&overridden));
+        let effective = cache.get(&limits, &pool, &user_id).await.unwrap();
+        assert!(Arc::ptr_eq(&effective, &overridden));
+
+        cache.clear();
+        let effective = cache.get(&limits, &pool, &user_id).await.unwrap();
+        assert!(Arc::ptr_eq(&effective, &limits));
+    }
+}
@@ -1,5 +1,5 @@
 //! Application settings objects and initialization
-use std::{cmp::min, env};
+use std::{cmp::min, collections::HashMap, env};
 
 use config::{Config, ConfigError, Environment, File};
 use serde::{de::Deserializer, Deserialize, Serialize};
@@ -23,6 +23,17 @@ static DEFAULT_MAX_TOTAL_RECORDS: u32 = 100 * DEFAULT_MAX_POST_RECORDS;
 // Hard spanner limit is 4GB per split (items under a unique index).
 // This gives us more than a bit of wiggle room.
 static DEFAULT_MAX_QUOTA_LIMIT: u32 = 2 * GIGABYTE;
+// Allow plenty of leeway for clock skew by default, because client
+// timestamps tend to be all over the shop (52 weeks).
+static DEFAULT_HAWK_TIMESTAMP_SKEW: u32 = 52 * 7 * 24 * 60 * 60;
+static DEFAULT_HAWK_NONCE_CACHE_SIZE: u32 = 100_000;
+// Matches the tokenserver's default token duration (1 hour).
+static DEFAULT_HAWK_TOKEN_DURATION: u32 = 60 * 60;
+static DEFAULT_REVOCATIONS_REFRESH_INTERVAL: u32 = 60;
+static DEFAULT_DELETED_BSO_RETENTION: u32 = 30 * 24 * 60 * 60;
+static DEFAULT_MAX_INFO_COLLECTIONS_WAIT: u32 = 60;
+static DEFAULT_USER_LIMITS_CACHE_TTL: u32 = 5 * 60;
+static DEFAULT_WRITE_EVENTS_BUFFER_SIZE: u32 = 10_000;
 static PREFIX: &str = "sync";
 
 #[derive(Clone, Debug, Deserialize)]
@@ -46,6 +57,101 @@ pub struct Settings {
     /// the signing secret and token secret
     /// that are used during Hawk authentication.
     pub master_secret: Secrets,
+
+    /// Maximum difference, in seconds, allowed between a Hawk request's
+    /// timestamp and the server's clock.
+    pub hawk_timestamp_skew: u32,
+
+    /// Number of recently seen Hawk nonces remembered for replay
+    /// detection (0 disables the check).
+    pub hawk_nonce_cache_size: u32,
+
+    /// Whether the Hawk `hash` attribute is checked against the body of
+    /// write requests.
+    pub hawk_payload_hash: PayloadHashMode,
+
+    /// Secret used to verify HS256-signed `Authorization: Bearer` JWTs,
+    /// accepted alongside Hawk. Bearer authentication is disabled when unset.
+    pub jwt_secret: Option<String>,
+
+    /// Lifetime, in seconds, of the tokens issued by the tokenserver. Used
+    /// to estimate when a Hawk token was issued for token revocation.
+    pub hawk_token_duration: u32,
+
+    /// How often, in seconds, token revocations are reloaded from the
+    /// database. Revocations made via the admin API of another server
+    /// process apply after at most this long.
+    pub revocations_refresh_interval: u32,
+
+    /// Secret that admin API requests must supply as a Bearer token. The
+    /// admin API is disabled when unset.
+    pub admin_secret: Option<String>,
+
+    /// How long, in seconds, the ids of deleted BSOs are remembered for the
+    /// `deleted_since` collection query.
+    pub deleted_bso_retention: u32,
+
+    /// How long, in seconds, a user's deleted storage (`DELETE /storage`)
+    /// can be restored via the admin API. Storage is deleted immediately
+    /// when 0.
+    pub deleted_storage_grace_period: u32,
+
+    /// How long, in seconds, users' destructive operations are kept in the
+    /// audit log. They're not recorded when 0.
+    pub audit_log_retention: u32,
+
+    /// Policies for keeping the previous revisions of the BSOs of the named
+    /// collections, from which a collection can be restored to an earlier
+    /// point in time. No revisions are kept for other collections.
+    #[serde(default)]
+    pub bso_history: HashMap<String, BsoHistoryPolicy>,
+
+    /// Path of a JSON key file (`{"primary": <key id>, "keys": {<key id>:
+    /// <base64 AES-256 key>}}`) used to encrypt stored BSO payloads. Payloads
+    /// are stored as sent when unset.
+    pub payload_encryption_key_file: Option<String>,
+
+    /// Whether stored BSO payloads are compressed.
+    pub payload_compression: bool,
+
+    /// Whether the payloads of the standard encrypted collections must be
+    /// well formed Sync crypto envelopes. Other records are rejected.
+    pub strict_payload_envelopes: bool,
+
+    /// Whether a POSTed (or batched) BSO's `modified` is its expected stored
+    /// timestamp, failing the BSO with a conflict when it differs. `modified`
+    /// is ignored otherwise.
+    pub enable_expected_modified: bool,
+
+    /// Most non-standard collections a user may store. Unlimited when 0.
+    pub max_custom_collections: u32,
+
+    /// Comma separated names of the only non-standard collections users may
+    /// write to. Any name matching `COLLECTION_ID_REGEX` is allowed when
+    /// unset.
+    pub collection_allowlist: Option<String>,
+
+    /// Longest time, in seconds, a long-polling `/info/collections` request
+    /// (`?wait=`) is held open.
+    pub max_info_collections_wait: u32,
+
+    /// How long, in seconds, users' limit overrides are cached. Changes
+    /// made via the admin API on another instance apply after at most this
+    /// long.
+    pub user_limits_cache_ttl: u32,
+
+    /// Where events summarizing each write are published: `stderr`,
+    /// `file:<path>` or an `http(s)://` URL. Disabled when unset.
+    pub write_events_sink: Option<String>,
+
+    /// The `Authorization` header sent to an `http(s)://` write events sink,
+    /// if any.
+    pub write_events_sink_authorization: Option<String>,
+
+    /// Number of write events buffered for the sink before further events
+    /// are dropped.
+    pub write_events_buffer_size: u32,
+
     pub human_logs: bool,
 
     pub statsd_host: Option<String>,
@@ -69,6 +175,28 @@ impl Default for Settings {
             actix_keep_alive: None,
             limits: ServerLimits::default(),
             master_secret: Secrets::default(),
+            hawk_timestamp_skew: DEFAULT_HAWK_TIMESTAMP_SKEW,
+            hawk_nonce_cache_size: DEFAULT_HAWK_NONCE_CACHE_SIZE,
+            hawk_payload_hash: PayloadHashMode::default(),
+            jwt_secret: None,
+            hawk_token_duration: DEFAULT_HAWK_TOKEN_DURATION,
+            revocations_refresh_interval: DEFAULT_REVOCATIONS_REFRESH_INTERVAL,
+            admin_secret: None,
+            deleted_bso_retention: DEFAULT_DELETED_BSO_RETENTION,
+            deleted_storage_grace_period: 0,
+            audit_log_retention: 0,
+            bso_history: HashMap::new(),
+            payload_encryption_key_file: None,
+            payload_compression: false,
+            strict_payload_envelopes: false,
+            enable_expected_modified: false,
+            max_custom_collections: 0,
+            collection_allowlist: None,
+            max_info_collections_wait: DEFAULT_MAX_INFO_COLLECTIONS_WAIT,
+            user_limits_cache_ttl: DEFAULT_USER_LIMITS_CACHE_TTL,
+            write_events_sink: None,
+            write_events_sink_authorization: None,
+            write_events_buffer_size: DEFAULT_WRITE_EVENTS_BUFFER_SIZE,
             statsd_host: None,
             statsd_port: 8125,
             statsd_label: "syncstorage".to_string(),
@@ -91,6 +219,45 @@ impl Settings {
         #[cfg(test)]
         s.set_default("database_use_test_transactions", false)?;
         s.set_default("master_secret", "")?;
+        s.set_default(
+            "hawk_timestamp_skew",
+            i64::from(DEFAULT_HAWK_TIMESTAMP_SKEW),
+        )?;
+        s.set_default(
+            "hawk_nonce_cache_size",
+            i64::from(DEFAULT_HAWK_NONCE_CACHE_SIZE),
+        )?;
+        s.set_default("hawk_payload_hash", "ignored")?;
+        s.set_default(
+            "hawk_token_duration",
+            i64::from(DEFAULT_HAWK_TOKEN_DURATION),
+        )?;
+        s.set_default(
+            "revocations_refresh_interval",
+            i64::from(DEFAULT_REVOCATIONS_REFRESH_INTERVAL),
+        )?;
+        s.set_default(
+            "deleted_bso_retention",
+            i64::from(DEFAULT_DELETED_BSO_RETENTION),
+        )?;
+        s.set_default("deleted_storage_grace_period", 0)?;
+        s.set_default("payload_compression", false)?;
+        s.set_default("strict_payload_envelopes", false)?;
+        s.set_default("enable_expected_modified", false)?;
+        s.set_default("max_custom_collections", 0)?;
+        s.set_default("audit_log_retention", 0)?;
+        s.set_default(
+            "max_info_collections_wait",
+            i64::from(DEFAULT_MAX_INFO_COLLECTIONS_WAIT),
+        )?;
+        s.set_default(
+            "user_limits_cache_ttl",
+            i64::from(DEFAULT_USER_LIMITS_CACHE_TTL),
+        )?;
+        s.set_default(
+            "write_events_buffer_size",
+            i64::from(DEFAULT_WRITE_EVENTS_BUFFER_SIZE),
+        )?;
         s.set_default("limits.max_post_bytes", i64::from(DEFAULT_MAX_POST_BYTES))?;
         s.set_default(
             "limits.max_post_records",
@@ -227,6 +394,11 @@ pub struct ServerLimits {
     /// Maximum BSO count across a batch upload.
     pub max_total_records: u32,
     pub max_quota_limit: u32,
+
+    /// Retention policies: the maximum TTL, in seconds, of the BSOs of the
+    /// named collections, which is also their default TTL.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub max_collection_ttls: HashMap<String, u32>,
 }
 
 impl Default for ServerLimits {
@@ -240,10 +412,96 @@ impl Default for ServerLimits {
             max_total_bytes: DEFAULT_MAX_TOTAL_BYTES,
             max_total_records: DEFAULT_MAX_TOTAL_RECORDS,
             max_quota_limit: DEFAULT_MAX_QUOTA_LIMIT,
+            max_collection_ttls: HashMap::new(),
+        }
+    }
+}
+
+impl ServerLimits {
+    /// These limits with a user's overrides applied.
+    pub fn with_overrides(&self, overrides: &LimitOverrides) -> Self {
+        Self {
+            max_post_bytes: overrides.max_post_bytes.unwrap_or(self.max_post_bytes),
+            max_post_records: overrides.max_post_records.unwrap_or(self.max_post_records),
+            max_record_payload_bytes: overrides
+                .max_record_payload_bytes
+                .unwrap_or(self.max_record_payload_bytes),
+            max_total_bytes: overrides.max_total_bytes.unwrap_or(self.max_total_bytes),
+            max_total_records: overrides
+                .max_total_records
+                .unwrap_or(self.max_total_records),
+            max_quota_limit: overrides.max_quota_limit.unwrap_or(self.max_quota_limit),
+            ..self.clone()
         }
     }
 }
 
+/// A user's overrides of the `ServerLimits`, stored in the database. Unset
+/// limits keep their configured value.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LimitOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_post_bytes: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_post_records: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_record_payload_bytes: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_total_bytes: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_total_records: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_quota_limit: Option<u32>,
+}
+
+/// How long a collection's previous BSO revisions are kept: revisions are
+/// pruned once either limit is exceeded, and kept indefinitely when neither
+/// is set.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BsoHistoryPolicy {
+    /// Most revisions kept per BSO.
+    #[serde(default)]
+    pub max_revisions: Option<u32>,
+
+    /// Longest time, in seconds, a revision is kept after it was written.
+    #[serde(default)]
+    pub max_age: Option<u32>,
+}
+
+impl BsoHistoryPolicy {
+    /// Of a BSO's revisions (newest first), the newest exceeding
+    /// `max_revisions`: it and every older revision are pruned.
+    pub fn first_pruned<'a, T>(&self, revisions: &'a [T]) -> Option<&'a T> {
+        revisions.get(self.max_revisions? as usize)
+    }
+
+    /// Revisions modified before this time, in milliseconds since epoch, are
+    /// pruned.
+    pub fn max_age_cutoff(&self, now: i64) -> Option<i64> {
+        self.max_age.map(|max_age| now - i64::from(max_age) * 1000)
+    }
+}
+
+/// How the Hawk payload hash of a write request is verified.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadHashMode {
+    /// Every write request must include a matching `hash` attribute.
+    Required,
+
+    /// The `hash` attribute is verified when present.
+    Optional,
+
+    /// The `hash` attribute is not verified.
+    Ignored,
+}
+
+impl Default for PayloadHashMode {
+    fn default() -> Self {
+        PayloadHashMode::Ignored
+    }
+}
+
 /// Secrets used during Hawk authentication.
 #[derive(Clone, Debug)]
 pub struct Secrets {
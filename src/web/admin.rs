@@ -0,0 +1,392 @@
+//! Admin API handlers
+use actix_web::{
+    web::{Data, Json, Query},
+    Error, HttpResponse,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::db::{
+    self, params,
+    util::{ms_since_epoch, SyncTimestamp},
+};
+use crate::error::ApiResult;
+use crate::server::{
+    events::{WriteOp, Written},
+    notifications::CollectionChange,
+    ServerState,
+};
+use crate::settings::LimitOverrides;
+use crate::web::{
+    auth::{Revocation, RevocationKey},
+    error::ValidationErrorKind,
+    extractors::{AdminRequest, HawkIdentifier, RequestErrorLocation},
+};
+
+/// Identifies a user: by `legacy_id` for MySQL, by `fxa_uid` and `fxa_kid`
+/// for Spanner.
+#[derive(Debug, Deserialize)]
+pub struct StorageUser {
+    #[serde(default)]
+    pub legacy_id: u64,
+    #[serde(default)]
+    pub fxa_uid: String,
+    #[serde(default)]
+    pub fxa_kid: String,
+}
+
+impl From<StorageUser> for HawkIdentifier {
+    fn from(user: StorageUser) -> Self {
+        HawkIdentifier {
+            legacy_id: user.legacy_id,
+            fxa_uid: user.fxa_uid,
+            fxa_kid: user.fxa_kid,
+        }
+    }
+}
+
+/// A user's collection to restore to its state at an earlier time
+#[derive(Debug, Deserialize)]
+pub struct CollectionRestore {
+    #[serde(default)]
+    pub legacy_id: u64,
+    #[serde(default)]
+    pub fxa_uid: String,
+    #[serde(default)]
+    pub fxa_kid: String,
+    pub collection: String,
+    /// In seconds since epoch
+    pub at: SyncTimestamp,
+}
+
+/// A user's overrides of the server limits
+#[derive(Debug, Deserialize)]
+pub struct UserLimitsUpdate {
+    #[serde(default)]
+    pub legacy_id: u64,
+    #[serde(default)]
+    pub fxa_uid: String,
+    #[serde(default)]
+    pub fxa_kid: String,
+    pub limits: LimitOverrides,
+}
+
+pub async fn get_revocations(
+    admin: AdminRequest,
+    state: Data<ServerState>,
+) -> Result<HttpResponse, Error> {
+    admin.metrics.incr("request.admin.get_revocations");
+    state.revocations.refresh(state.db_pool.as_ref()).await?;
+    Ok(HttpResponse::Ok().json(state.revocations.list()))
+}
+
+pub async fn add_revocation(
+    admin: AdminRequest,
+    state: Data<ServerState>,
+    revocation: Json<Revocation>,
+) -> Result<HttpResponse, Error> {
+    admin.metrics.incr("request.admin.add_revocation");
+    info!("Adding token revocation: {:?}", revocation);
+    let db = state.db_pool.get().await?;
+    db.begin(true).await?;
+    let result: ApiResult<_> = async {
+        db.put_revocation(params::PutRevocation {
+            revocation: revocation.into_inner(),
+            expired_before: state.revocations.expired_before(),
+        })
+        .await?;
+        db.get_revocations(()).await
+    }
+    .await;
+    let revocations = match result {
+        Ok(revocations) => revocations,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e.into());
+        }
+    };
+    db.commit().await?;
+    state.revocations.replace(revocations);
+    Ok(HttpResponse::Ok().json(state.revocations.list()))
+}
+
+pub async fn remove_revocation(
+    admin: AdminRequest,
+    state: Data<ServerState>,
+    key: Json<RevocationKey>,
+) -> Result<HttpResponse, Error> {
+    admin.metrics.incr("request.admin.remove_revocation");
+    info!("Removing token revocation: {:?}", key);
+    let db = state.db_pool.get().await?;
+    db.begin(true).await?;
+    let result: ApiResult<_> = async {
+        let removed = db.delete_revocation(key.into_inner()).await?;
+        Ok((removed, db.get_revocations(()).await?))
+    }
+    .await;
+    let (removed, revocations) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e.into());
+        }
+    };
+    db.commit().await?;
+    state.revocations.replace(revocations);
+    if !removed {
+        return Ok(HttpResponse::NotFound().json(state.revocations.list()));
+    }
+    Ok(HttpResponse::Ok().json(state.revocations.list()))
+}
+
+/// A user's audit log of destructive operations, newest first
+pub async fn get_audit_log(
+    admin: AdminRequest,
+    state: Data<ServerState>,
+    user: Query<StorageUser>,
+) -> Result<HttpResponse, Error> {
+    admin.metrics.incr("request.admin.get_audit_log");
+    let user_id = HawkIdentifier::from(user.into_inner());
+    let db = state.db_pool.get().await?;
+    db.begin(false).await?;
+    let entries = db.get_audit_log(user_id).await;
+    db.commit().await?;
+    Ok(HttpResponse::Ok().json(entries?))
+}
+
+/// Restore a user's deleted storage, if still within its grace period
+pub async fn restore_storage(
+    admin: AdminRequest,
+    state: Data<ServerState>,
+    user: Json<StorageUser>,
+) -> Result<HttpResponse, Error> {
+    admin.metrics.incr("request.admin.restore_storage");
+    let user_id = HawkIdentifier::from(user.into_inner());
+    info!("Restoring deleted storage: {:?}", user_id);
+    let restored = db::restore_storage(state.db_pool.as_ref(), &user_id).await?;
+
+    let modified = match restored {
+        Some(modified) => modified,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    state.broker.publish(
+        &user_id,
+        CollectionChange {
+            collection: None,
+            modified,
+        },
+    );
+    if let Some(events) = &state.events {
+        let written = Written {
+            op: WriteOp::RestoreStorage,
+            records: 0,
+            bytes: 0,
+        };
+        events.publish(&user_id, None, written, modified);
+    }
+    Ok(HttpResponse::Ok().json(json!({ "modified": modified })))
+}
+
+/// Restore a user's collection to its state at an earlier time from the
+/// previous revisions of its records, as far as they're kept: records created
+/// since are deleted. The collection's TTL policy and the quota apply to the
+/// restored records
+pub async fn restore_collection(
+    admin: AdminRequest,
+    state: Data<ServerState>,
+    restore: Json<CollectionRestore>,
+) -> Result<HttpResponse, Error> {
+    admin.metrics.incr("request.admin.restore_collection");
+    let restore = restore.into_inner();
+    let user_id = HawkIdentifier {
+        legacy_id: restore.legacy_id,
+        fxa_uid: restore.fxa_uid,
+        fxa_kid: restore.fxa_kid,
+    };
+    let (collection, at) = (restore.collection, restore.at);
+    info!(
+        "Restoring collection {} of {:?} as of {}",
+        collection,
+        user_id,
+        at.as_header()
+    );
+    let db = state.db_pool.get().await?;
+    db.begin(true).await?;
+    let result: ApiResult<_> = async {
+        db.lock_for_write(params::LockCollection {
+            user_id: user_id.clone(),
+            collection: collection.clone(),
+        })
+        .await?;
+        let revisions = db
+            .get_bso_revisions(params::GetBsoRevisions {
+                user_id: user_id.clone(),
+                collection: collection.clone(),
+                at,
+            })
+            .await?;
+        if revisions.is_empty() {
+            return Ok(None);
+        }
+        let now = ms_since_epoch();
+        let (deleted, revisions): (Vec<_>, Vec<_>) =
+            revisions.into_iter().partition(|bso| bso.deleted);
+        let records = revisions.len();
+        let bytes = revisions.iter().map(|bso| bso.payload.len()).sum();
+        let mut modified = None;
+        if !deleted.is_empty() {
+            modified = Some(
+                db.delete_bsos(params::DeleteBsos {
+                    user_id: user_id.clone(),
+                    collection: collection.clone(),
+                    ids: deleted.into_iter().map(|bso| bso.id).collect(),
+                })
+                .await?,
+            );
+        }
+        // Written one at a time so that any failure (e.g. the quota) rolls
+        // back the entire restore
+        for bso in revisions {
+            modified = Some(
+                db.put_bso(params::PutBso {
+                    user_id: user_id.clone(),
+                    collection: collection.clone(),
+                    id: bso.id,
+                    sortindex: bso.sortindex,
+                    payload: Some(bso.payload),
+                    ttl: Some(((bso.expiry - now) / 1000).max(1) as u32),
+                })
+                .await?,
+            );
+        }
+        Ok(modified.map(|modified| (modified, records, bytes)))
+    }
+    .await;
+    let restored = match result {
+        Ok(restored) => restored,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e.into());
+        }
+    };
+    db.commit().await?;
+
+    let (modified, records, bytes) = match restored {
+        Some(restored) => restored,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    state.broker.publish(
+        &user_id,
+        CollectionChange {
+            collection: Some(collection.clone()),
+            modified,
+        },
+    );
+    if let Some(events) = &state.events {
+        let written = Written {
+            op: WriteOp::RestoreCollection,
+            records,
+            bytes,
+        };
+        events.publish(&user_id, Some(collection), written, modified);
+    }
+    Ok(HttpResponse::Ok().json(json!({ "modified": modified })))
+}
+
+/// A user's overrides of the server limits
+pub async fn get_user_limits(
+    admin: AdminRequest,
+    state: Data<ServerState>,
+    user: Query<StorageUser>,
+) -> Result<HttpResponse, Error> {
+    admin.metrics.incr("request.admin.get_user_limits");
+    let user_id = HawkIdentifier::from(user.into_inner());
+    let db = state.db_pool.get().await?;
+    db.begin(false).await?;
+    let limits = db.get_user_limits(user_id).await;
+    db.commit().await?;
+    Ok(match limits? {
+        Some(limits) => HttpResponse::Ok().json(limits),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+/// Replace a user's overrides of the server limits
+pub async fn put_user_limits(
+    admin: AdminRequest,
+    state: Data<ServerState>,
+    update: Json<UserLimitsUpdate>,
+) -> Result<HttpResponse, Error> {
+    admin.metrics.incr("request.admin.put_user_limits");
+    let UserLimitsUpdate {
+        legacy_id,
+        fxa_uid,
+        fxa_kid,
+        limits,
+    } = update.into_inner();
+    let user_id = HawkIdentifier {
+        legacy_id,
+        fxa_uid,
+        fxa_kid,
+    };
+    // A POST's records must fit in a single request
+    let effective = state.limits.with_overrides(&limits);
+    if effective.max_post_bytes > effective.max_request_bytes {
+        return Err(ValidationErrorKind::FromDetails(
+            format!(
+                "max_post_bytes must not exceed max_request_bytes ({})",
+                effective.max_request_bytes
+            ),
+            RequestErrorLocation::Body,
+            Some("max_post_bytes".to_owned()),
+            None,
+            None,
+        )
+        .into());
+    }
+    info!("Overriding limits of {:?}: {:?}", user_id, limits);
+    let db = state.db_pool.get().await?;
+    db.begin(true).await?;
+    let result = db
+        .put_user_limits(params::PutUserLimits {
+            user_id,
+            limits: limits.clone(),
+        })
+        .await;
+    match result {
+        Ok(_) => db.commit().await?,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e.into());
+        }
+    }
+    state.user_limits.clear();
+    Ok(HttpResponse::Ok().json(limits))
+}
+
+/// Remove a user's overrides of the server limits
+pub async fn delete_user_limits(
+    admin: AdminRequest,
+    state: Data<ServerState>,
+    user: Json<StorageUser>,
+) -> Result<HttpResponse, Error> {
+    admin.metrics.incr("request.admin.delete_user_limits");
+    let user_id = HawkIdentifier::from(user.into_inner());
+    info!("Removing limit overrides of {:?}", user_id);
+    let db = state.db_pool.get().await?;
+    db.begin(true).await?;
+    let deleted = match db.delete_user_limits(user_id).await {
+        Ok(deleted) => deleted,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e.into());
+        }
+    };
+    db.commit().await?;
+    state.user_limits.clear();
+    Ok(if deleted {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    })
+}
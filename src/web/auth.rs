@@ -1,4 +1,4 @@
-//! Types for parsing and authenticating HAWK headers.
+//! Types for parsing and authenticating HAWK (and Bearer JWT) headers.
 //! Matches the [Python logic](https://github.com/mozilla-services/tokenlib).
 //! We may want to extract this to its own repo/crate in due course.
 #![cfg_attr(
@@ -6,15 +6,16 @@
     allow(dead_code, unused_imports, unused_variables)
 )]
 
-use std::convert::TryInto;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use chrono::offset::Utc;
-use hawk::{self, Header as HawkHeader, Key, RequestBuilder};
+use hawk::{self, Header as HawkHeader, Key, PayloadHasher, RequestBuilder};
 use hkdf::Hkdf;
 use hmac::{Hmac, Mac, NewMac};
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
-use time::Duration;
+use sha2::{Digest, Sha256};
 
 use actix_web::dev::ConnectionInfo;
 use actix_web::http::Uri;
@@ -24,8 +25,10 @@ use super::{
     error::{HawkErrorKind, ValidationErrorKind},
     extractors::RequestErrorLocation,
 };
+use crate::db::DbPool;
 use crate::error::{ApiErrorKind, ApiResult};
-use crate::settings::Secrets;
+use crate::server::ServerState;
+use crate::settings::{PayloadHashMode, Secrets};
 
 /// A parsed and authenticated JSON payload
 /// extracted from the signed `id` property
@@ -64,6 +67,11 @@ impl HawkPayload {
     ///
     /// Assumes that the header string
     /// includes the `Hawk ` prefix.
+    ///
+    /// The header timestamp must be within `ts_skew` of the server clock
+    /// and, if a `NonceCache` is supplied, its `(id, nonce, ts)` must not
+    /// have been seen before.
+    #[allow(clippy::too_many_arguments)]
     fn new(
         header: &str,
         method: &str,
@@ -72,6 +80,8 @@ impl HawkPayload {
         port: u16,
         secrets: &Secrets,
         expiry: u64,
+        ts_skew: Duration,
+        nonces: Option<&NonceCache>,
     ) -> ApiResult<HawkPayload> {
         if header.len() < 5 || &header[0..5] != "Hawk " {
             Err(HawkErrorKind::MissingPrefix)?;
@@ -82,12 +92,7 @@ impl HawkPayload {
 
         let payload = HawkPayload::extract_and_validate(id, secrets, expiry)?;
 
-        let token_secret = hkdf_expand_32(
-            format!("services.mozilla.com/tokenlib/v1/derive/{}", id).as_bytes(),
-            Some(payload.salt.as_bytes()),
-            &secrets.master_secret,
-        )?;
-        let token_secret = base64::encode_config(&token_secret, base64::URL_SAFE);
+        let token_secret = derive_token_secret(id, &payload.salt, secrets)?;
 
         let request = RequestBuilder::new(method, host, port, path).request();
 
@@ -98,24 +103,39 @@ impl HawkPayload {
 
         #[cfg(not(feature = "no_auth"))]
         {
-            let mut duration: std::time::Duration = Duration::weeks(52)
-                .try_into()
-                .map_err(|_| ApiErrorKind::Internal("Duration::weeks".to_owned()))?;
+            let mut ts_skew = ts_skew;
             if cfg!(test) {
                 // test cases are valid until 3018. Add millenia as required.
-                duration *= 1000;
+                ts_skew *= 1000;
             }
-            if request.validate_header(
+            // Verify the MAC with an unbounded skew first: the timestamp is
+            // checked separately so a stale (but authentic) request can be
+            // told the server's time
+            if !request.validate_header(
                 &header,
                 &Key::new(token_secret.as_bytes(), hawk::DigestAlgorithm::Sha256)?,
-                // Allow plenty of leeway for clock skew, because
-                // client timestamps tend to be all over the shop
-                duration,
+                Duration::from_secs(u64::MAX),
             ) {
-                Ok(payload)
-            } else {
-                Err(HawkErrorKind::InvalidHeader)?
+                Err(HawkErrorKind::InvalidHeader)?;
+            }
+
+            let ts = header.ts.ok_or(HawkErrorKind::InvalidHeader)?;
+            let now = SystemTime::now();
+            let skew = now.duration_since(ts).unwrap_or_else(|e| e.duration());
+            if skew > ts_skew {
+                Err(HawkErrorKind::StaleTimestamp(www_authenticate(
+                    token_secret.as_bytes(),
+                    now,
+                )?))?;
+            }
+
+            if let Some(nonces) = nonces {
+                let nonce = header.nonce.as_ref().ok_or(HawkErrorKind::InvalidHeader)?;
+                if !nonces.insert(id, nonce, ts) {
+                    Err(HawkErrorKind::Replayed)?;
+                }
             }
+            Ok(payload)
         }
     }
 
@@ -143,6 +163,18 @@ impl HawkPayload {
         }
     }
 
+    /// Serialize and sign the payload as the `id` property of a Hawk header.
+    ///
+    /// This is the inverse of `extract_and_validate`.
+    pub fn make_token(&self, secrets: &Secrets) -> ApiResult<String> {
+        let mut token = serde_json::to_vec(self)
+            .map_err(|e| ApiErrorKind::Internal(format!("Unserializable payload: {}", e)))?;
+        let mut hmac = Hmac::<Sha256>::new_varkey(&secrets.signing_secret)?;
+        hmac.update(&token);
+        token.extend_from_slice(&hmac.finalize().into_bytes());
+        Ok(base64::encode_config(&token, base64::URL_SAFE))
+    }
+
     #[cfg(test)]
     pub fn test_default(user_id: u64) -> Self {
         HawkPayload {
@@ -161,7 +193,7 @@ impl HawkPayload {
     pub fn extrude(
         header: &str,
         method: &str,
-        secrets: &Secrets,
+        state: &ServerState,
         ci: &ConnectionInfo,
         uri: &Uri,
         tags: Option<Tags>,
@@ -190,10 +222,489 @@ impl HawkPayload {
             Utc::now().timestamp() as u64
         };
 
-        HawkPayload::new(header, method, path.as_str(), host, port, &secrets, expiry)
+        HawkPayload::new(
+            header,
+            method,
+            path.as_str(),
+            host,
+            port,
+            &state.secrets,
+            expiry,
+            state.hawk_timestamp_skew,
+            Some(&state.nonce_cache),
+        )
+    }
+}
+
+/// The authenticated claims of a Bearer JWT,
+/// accepted as an alternative to Hawk authentication
+/// when a `jwt_secret` is configured.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct JwtClaims {
+    /// Expiry time for the token, in seconds.
+    pub exp: u64,
+
+    /// Issue time for the token, in seconds, checked against token
+    /// revocations.
+    #[serde(default)]
+    pub iat: Option<u64>,
+
+    /// User identifier.
+    pub uid: u64,
+
+    #[serde(default)]
+    pub fxa_uid: String,
+
+    #[serde(default)]
+    pub fxa_kid: String,
+}
+
+/// The JOSE header of a JWT.
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+impl JwtClaims {
+    /// Parse and authenticate the claims of an HS256-signed JWT.
+    ///
+    /// Assumes that the header string
+    /// includes the `Bearer ` prefix.
+    fn new(header: &str, secret: &[u8], now: u64) -> ApiResult<JwtClaims> {
+        if header.len() < 7 || &header[0..7] != "Bearer " {
+            Err(HawkErrorKind::MissingPrefix)?;
+        }
+
+        let token = header[7..].trim();
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            Err(HawkErrorKind::InvalidJwt)?;
+        }
+
+        let jwt_header: JwtHeader =
+            serde_json::from_slice(&base64::decode_config(parts[0], base64::URL_SAFE_NO_PAD)?)?;
+        if jwt_header.alg != "HS256" {
+            Err(HawkErrorKind::InvalidJwt)?;
+        }
+
+        let signing_input = &token[0..parts[0].len() + 1 + parts[1].len()];
+        let signature = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD)?;
+
+        #[cfg(not(feature = "no_auth"))]
+        verify_hmac(signing_input.as_bytes(), secret, &signature)?;
+
+        let claims: JwtClaims =
+            serde_json::from_slice(&base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD)?)?;
+
+        if claims.exp > now {
+            Ok(claims)
+        } else {
+            Err(HawkErrorKind::Expired)?
+        }
+    }
+
+    pub fn extrude(header: &str, state: &ServerState) -> ApiResult<Self> {
+        // Bearer authentication is disabled unless a secret is configured
+        let secret = state
+            .jwt_secret
+            .as_ref()
+            .ok_or(HawkErrorKind::MissingPrefix)?;
+        JwtClaims::new(header, secret, Utc::now().timestamp() as u64)
+    }
+}
+
+/// Verify a request body against the `hash` attribute of its Hawk
+/// `Authorization` header.
+///
+/// The hash is computed over the raw body and its (parameter-less)
+/// content type, as per the Hawk spec. The header's MAC covers the `hash`
+/// attribute, so this must only be called once the header itself has been
+/// authenticated.
+pub fn verify_payload_hash(
+    header: Option<&str>,
+    content_type: &str,
+    body: &[u8],
+    mode: PayloadHashMode,
+) -> ApiResult<()> {
+    if let Some(expected) = expected_payload_hash(header, mode)? {
+        let hash = PayloadHasher::hash(content_type, hawk::DigestAlgorithm::Sha256, body)?;
+        if hash != expected {
+            Err(HawkErrorKind::InvalidPayloadHash)?;
+        }
+    }
+    Ok(())
+}
+
+/// The `hash` attribute of a request's Hawk `Authorization` header that its
+/// body must be verified against, if any.
+///
+/// For bodies that are hashed incrementally (via a `PayloadHasher`) rather
+/// than with `verify_payload_hash`.
+pub fn expected_payload_hash(
+    header: Option<&str>,
+    mode: PayloadHashMode,
+) -> ApiResult<Option<Vec<u8>>> {
+    if cfg!(feature = "no_auth") || mode == PayloadHashMode::Ignored {
+        return Ok(None);
+    }
+
+    let header = header.ok_or(HawkErrorKind::MissingHeader)?;
+    if header.starts_with("Bearer ") {
+        // Bearer tokens don't sign the request: there's no hash to verify
+        return Ok(None);
+    }
+    if header.len() < 5 || &header[0..5] != "Hawk " {
+        Err(HawkErrorKind::MissingPrefix)?;
+    }
+    let header: HawkHeader = header[5..].parse()?;
+
+    match header.hash {
+        Some(expected) => Ok(Some(expected)),
+        None if mode == PayloadHashMode::Required => Err(HawkErrorKind::MissingPayloadHash.into()),
+        None => Ok(None),
+    }
+}
+
+/// A bounded cache of recently seen Hawk `(id, nonce, ts)` triples.
+///
+/// Used to reject replayed requests. Entries whose timestamp has fallen
+/// outside of the timestamp skew window are evicted first (those requests
+/// would be rejected as stale anyway), then the oldest entries once the
+/// cache reaches its maximum size. A request can't be checked against an
+/// entry evicted while still within the window, so requests timestamped no
+/// later than the newest such entry are rejected too.
+#[derive(Debug)]
+pub struct NonceCache {
+    max_size: usize,
+    ts_skew: Duration,
+    inner: Mutex<NonceCacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct NonceCacheInner {
+    seen: HashSet<[u8; 32]>,
+    order: VecDeque<([u8; 32], SystemTime)>,
+    /// The newest timestamp of the entries evicted for space
+    evicted: Option<SystemTime>,
+}
+
+impl NonceCache {
+    pub fn new(max_size: usize, ts_skew: Duration) -> Self {
+        Self {
+            max_size,
+            ts_skew,
+            inner: Mutex::new(NonceCacheInner::default()),
+        }
+    }
+
+    /// Record a `(id, nonce, ts)` triple, returning false if it was
+    /// already present (or may have been, before being evicted).
+    ///
+    /// A cache with a `max_size` of 0 is disabled and always returns true.
+    pub fn insert(&self, id: &str, nonce: &str, ts: SystemTime) -> bool {
+        if self.max_size == 0 {
+            return true;
+        }
+        let secs = ts
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        // The token id is large: only keep a digest of the triple
+        let key: [u8; 32] = Sha256::new()
+            .chain(id.as_bytes())
+            .chain(b"\n")
+            .chain(nonce.as_bytes())
+            .chain(b"\n")
+            .chain(secs.to_string().as_bytes())
+            .finalize()
+            .into();
+
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if inner.seen.contains(&key) || inner.evicted.map_or(false, |evicted| ts <= evicted) {
+            return false;
+        }
+
+        let cutoff = SystemTime::now()
+            .checked_sub(self.ts_skew)
+            .unwrap_or(UNIX_EPOCH);
+        while let Some((old_key, old_ts)) = inner.order.front().cloned() {
+            if old_ts >= cutoff && inner.order.len() < self.max_size {
+                break;
+            }
+            inner.order.pop_front();
+            inner.seen.remove(&old_key);
+            if old_ts >= cutoff && inner.evicted.map_or(true, |evicted| old_ts > evicted) {
+                inner.evicted = Some(old_ts);
+            }
+        }
+        inner.seen.insert(key);
+        inner.order.push_back((key, ts));
+        true
     }
 }
 
+/// What a token revocation applies to.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevocationKey {
+    Uid(u64),
+    FxaUid(String),
+    HashedDeviceId(String),
+}
+
+impl RevocationKey {
+    /// The key's type and value, as stored in the database.
+    pub fn to_parts(&self) -> (&'static str, String) {
+        match self {
+            RevocationKey::Uid(uid) => ("uid", uid.to_string()),
+            RevocationKey::FxaUid(fxa_uid) => ("fxa_uid", fxa_uid.clone()),
+            RevocationKey::HashedDeviceId(device_id) => ("hashed_device_id", device_id.clone()),
+        }
+    }
+
+    /// A key from its type and value, as stored in the database.
+    pub fn from_parts(kind: &str, value: String) -> Option<Self> {
+        match kind {
+            "uid" => value.parse().ok().map(RevocationKey::Uid),
+            "fxa_uid" => Some(RevocationKey::FxaUid(value)),
+            "hashed_device_id" => Some(RevocationKey::HashedDeviceId(value)),
+            _ => None,
+        }
+    }
+}
+
+/// A token revocation, as managed via the admin API.
+///
+/// Serialized as e.g. `{"uid": 123, "issued_before": 1600000000}`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Revocation {
+    #[serde(flatten)]
+    pub key: RevocationKey,
+
+    /// Only revoke tokens issued before this time, in seconds. All of the
+    /// matching tokens are revoked when unset.
+    #[serde(default)]
+    pub issued_before: Option<u64>,
+}
+
+/// Revoked tokens, checked during every authenticated request.
+///
+/// Revocations are stored in the database (see `Db::put_revocation`) and
+/// held in memory, reloaded every `revocations_refresh_interval` so ones
+/// made via the admin API of another server process apply after at most
+/// that long.
+///
+/// Hawk tokens don't record when they were issued, so this is estimated as
+/// their expiry time less `token_duration`. Once every token a revocation's
+/// `issued_before` cutoff could match has expired, it's dropped.
+#[derive(Debug)]
+pub struct RevocationStore {
+    token_duration: u64,
+    inner: RwLock<HashMap<RevocationKey, Option<u64>>>,
+}
+
+impl RevocationStore {
+    pub fn new(token_duration: Duration) -> Self {
+        Self {
+            token_duration: token_duration.as_secs(),
+            inner: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Replace the revocations with those loaded from the database.
+    pub fn replace(&self, revocations: Vec<Revocation>) {
+        let mut inner = match self.inner.write() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *inner = revocations
+            .into_iter()
+            .map(|revocation| (revocation.key, revocation.issued_before))
+            .collect();
+        self.prune(&mut inner);
+    }
+
+    /// Reload the revocations from the database.
+    pub async fn refresh(&self, db_pool: &dyn DbPool) -> ApiResult<()> {
+        let db = db_pool.get().await?;
+        db.begin(false).await?;
+        let revocations = db.get_revocations(()).await;
+        db.commit().await?;
+        self.replace(revocations?);
+        Ok(())
+    }
+
+    /// Periodically reload the revocations from the database.
+    pub fn spawn_refresher(store: Arc<Self>, interval: Duration, db_pool: Box<dyn DbPool>) {
+        actix_rt::spawn(async move {
+            loop {
+                actix_rt::time::delay_for(interval).await;
+                if let Err(e) = store.refresh(db_pool.as_ref()).await {
+                    warn!("⚠️ Couldn't reload token revocations: {}", e);
+                }
+            }
+        });
+    }
+
+    /// The earliest time, in seconds, revocations' `issued_before` cutoffs
+    /// must be after to match any unexpired token. Older revocations can be
+    /// dropped.
+    pub fn expired_before(&self) -> u64 {
+        (Utc::now().timestamp() as u64).saturating_sub(self.token_duration)
+    }
+
+    /// When a Hawk token expiring at `expires` (in seconds) was issued.
+    pub fn hawk_issued(&self, expires: u64) -> u64 {
+        expires.saturating_sub(self.token_duration)
+    }
+
+    /// List the current revocations.
+    pub fn list(&self) -> Vec<Revocation> {
+        let mut inner = match self.inner.write() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        self.prune(&mut inner);
+        inner
+            .iter()
+            .map(|(key, issued_before)| Revocation {
+                key: key.clone(),
+                issued_before: *issued_before,
+            })
+            .collect()
+    }
+
+    /// Whether a token issued at `issued` (in seconds) has been revoked.
+    ///
+    /// Tokens without a known issue time are revoked by any matching
+    /// revocation.
+    pub fn is_revoked(
+        &self,
+        uid: u64,
+        fxa_uid: &str,
+        device_id: &str,
+        issued: Option<u64>,
+    ) -> bool {
+        let inner = match self.inner.read() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if inner.is_empty() {
+            return false;
+        }
+
+        let mut keys = vec![RevocationKey::Uid(uid)];
+        if !fxa_uid.is_empty() {
+            keys.push(RevocationKey::FxaUid(fxa_uid.to_owned()));
+        }
+        if !device_id.is_empty() {
+            keys.push(RevocationKey::HashedDeviceId(device_id.to_owned()));
+        }
+        keys.iter().any(|key| match (inner.get(key), issued) {
+            (Some(Some(issued_before)), Some(issued)) => issued < *issued_before,
+            (Some(_), _) => true,
+            (None, _) => false,
+        })
+    }
+
+    /// Drop the revocations that can no longer match an unexpired token.
+    fn prune(&self, inner: &mut HashMap<RevocationKey, Option<u64>>) {
+        let expired_before = self.expired_before();
+        inner.retain(|_, issued_before| match issued_before {
+            Some(issued_before) => *issued_before > expired_before,
+            None => true,
+        });
+    }
+}
+
+/// Verify the `Authorization: Bearer` token of an admin request against the
+/// configured admin secret.
+pub fn verify_admin_token(header: Option<&str>, secret: &str) -> ApiResult<()> {
+    let header = header.ok_or(HawkErrorKind::MissingHeader)?;
+    if header.len() < 7 || &header[0..7] != "Bearer " {
+        Err(HawkErrorKind::InvalidAdminToken)?;
+    }
+    // Compare MACs of the two, rather than the values themselves, for a
+    // constant time comparison
+    let info = b"services.mozilla.com/syncstorage/admin";
+    let mut hmac = Hmac::<Sha256>::new_varkey(secret.as_bytes())?;
+    hmac.update(info);
+    verify_hmac(
+        info,
+        header[7..].trim().as_bytes(),
+        &hmac.finalize().into_bytes(),
+    )
+    .map_err(|_| HawkErrorKind::InvalidAdminToken.into())
+}
+
+/// Sign a raw pagination `offset` as an opaque continuation token, bound to
+/// the `shape` of the query that produced it.
+pub fn sign_offset(shape: &str, offset: &str, secrets: &Secrets) -> ApiResult<String> {
+    let mac = offset_hmac(shape, offset, secrets)?.finalize().into_bytes();
+    Ok(format!(
+        "{}.{}",
+        base64::encode_config(offset, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(mac, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Verify a continuation token issued by `sign_offset` for a query of the
+/// same `shape`, returning its raw offset.
+pub fn verify_offset(shape: &str, token: &str, secrets: &Secrets) -> Option<String> {
+    let mut parts = token.splitn(2, '.');
+    let offset = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let offset = String::from_utf8(offset).ok()?;
+    let mac = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    offset_hmac(shape, &offset, secrets)
+        .ok()?
+        .verify(&mac)
+        .ok()?;
+    Some(offset)
+}
+
+fn offset_hmac(shape: &str, offset: &str, secrets: &Secrets) -> ApiResult<Hmac<Sha256>> {
+    let key = hkdf_expand_32(
+        b"services.mozilla.com/syncstorage/offset",
+        None,
+        &secrets.master_secret,
+    )?;
+    let mut hmac = Hmac::<Sha256>::new_varkey(&key)?;
+    hmac.update(format!("{}\n{}", shape, offset).as_bytes());
+    Ok(hmac)
+}
+
+/// Build the `WWW-Authenticate` header value returned for a stale timestamp.
+///
+/// Per the Hawk spec this carries the server's current time (`ts`) and a MAC
+/// of it (`tsm`) so the client can safely resynchronise its clock.
+fn www_authenticate(key: &[u8], now: SystemTime) -> ApiResult<String> {
+    let ts = now
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| ApiErrorKind::Internal(format!("System clock error: {}", e)))?
+        .as_secs();
+    let mut hmac = Hmac::<Sha256>::new_varkey(key)?;
+    hmac.update(format!("hawk.1.ts\n{}\n", ts).as_bytes());
+    let tsm = base64::encode(hmac.finalize().into_bytes());
+    Ok(format!(
+        "Hawk ts=\"{}\", tsm=\"{}\", error=\"Stale timestamp\"",
+        ts, tsm
+    ))
+}
+
+/// Derive the secret key for a Hawk token `id` (the per-token key that
+/// clients sign their requests with), encoded as URL-safe base64.
+pub fn derive_token_secret(id: &str, salt: &str, secrets: &Secrets) -> ApiResult<String> {
+    let token_secret = hkdf_expand_32(
+        format!("services.mozilla.com/tokenlib/v1/derive/{}", id).as_bytes(),
+        Some(salt.as_bytes()),
+        &secrets.master_secret,
+    )?;
+    Ok(base64::encode_config(&token_secret, base64::URL_SAFE))
+}
+
 /// Helper function for [HKDF](https://tools.ietf.org/html/rfc5869) expansion to 32 bytes.
 pub fn hkdf_expand_32(info: &[u8], salt: Option<&[u8]>, key: &[u8]) -> ApiResult<[u8; 32]> {
     let mut result = [0u8; 32];
@@ -213,8 +724,23 @@ fn verify_hmac(info: &[u8], key: &[u8], expected: &[u8]) -> ApiResult<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::{HawkPayload, Secrets};
-    use crate::settings::Settings;
+    use std::time::{Duration, SystemTime};
+
+    use hawk::{Credentials, DigestAlgorithm, Key, PayloadHasher, RequestBuilder};
+
+    use hmac::{Hmac, Mac, NewMac};
+    use serde_json::json;
+    use sha2::Sha256;
+
+    use super::{
+        derive_token_secret, sign_offset, verify_admin_token, verify_offset, verify_payload_hash,
+        HawkPayload, JwtClaims, NonceCache, Revocation, RevocationKey, RevocationStore, Secrets,
+    };
+    use crate::error::ApiErrorKind;
+    use crate::settings::{PayloadHashMode, Settings};
+    use crate::web::error::HawkErrorKind;
+
+    const TS_SKEW: Duration = Duration::from_secs(52 * 7 * 24 * 60 * 60);
 
     #[test]
     fn valid_header() {
@@ -228,6 +754,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_ok());
@@ -256,6 +784,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_ok());
@@ -276,6 +806,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -293,6 +825,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -310,6 +844,8 @@ mod tests {
             fixture.request.port,
             &Secrets::new("wibble").unwrap(),
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -332,6 +868,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -349,6 +887,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -367,6 +907,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -385,6 +927,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -403,6 +947,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -421,6 +967,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -442,6 +990,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -460,6 +1010,8 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
@@ -478,11 +1030,322 @@ mod tests {
             fixture.request.port,
             &fixture.settings.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
         );
 
         assert!(result.is_err());
     }
 
+    #[test]
+    fn make_token() {
+        let fixture = TestFixture::new();
+        let secrets = &fixture.settings.master_secret;
+
+        let id = fixture.expected.make_token(secrets).unwrap();
+        let token_secret = derive_token_secret(&id, &fixture.expected.salt, secrets).unwrap();
+        let credentials = Credentials {
+            id,
+            key: Key::new(token_secret.as_bytes(), DigestAlgorithm::Sha256).unwrap(),
+        };
+        let request = RequestBuilder::new(
+            &fixture.request.method,
+            &fixture.request.host,
+            fixture.request.port,
+            &fixture.request.path,
+        )
+        .request();
+        let header = format!("Hawk {}", request.make_header(&credentials).unwrap());
+
+        let result = HawkPayload::new(
+            &header,
+            &fixture.request.method,
+            &fixture.request.path,
+            &fixture.request.host,
+            fixture.request.port,
+            secrets,
+            fixture.expected.expires.round() as u64 - 1,
+            TS_SKEW,
+            None,
+        );
+
+        assert_eq!(result.unwrap(), fixture.expected);
+    }
+
+    #[test]
+    fn replayed_nonce() {
+        let fixture = TestFixture::new();
+        let nonces = NonceCache::new(10, TS_SKEW);
+
+        let validate = || {
+            HawkPayload::new(
+                &fixture.header.to_string(),
+                &fixture.request.method,
+                &fixture.request.path,
+                &fixture.request.host,
+                fixture.request.port,
+                &fixture.settings.master_secret,
+                fixture.expected.expires.round() as u64 - 1,
+                TS_SKEW,
+                Some(&nonces),
+            )
+        };
+
+        assert!(validate().is_ok());
+        let err = validate().unwrap_err();
+        match err.kind() {
+            ApiErrorKind::Hawk(e) => assert!(matches!(e.kind(), HawkErrorKind::Replayed)),
+            _ => panic!("Expected a Hawk error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn stale_timestamp() {
+        let fixture = TestFixture::new();
+
+        let result = HawkPayload::new(
+            &fixture.header.to_string(),
+            &fixture.request.method,
+            &fixture.request.path,
+            &fixture.request.host,
+            fixture.request.port,
+            &fixture.settings.master_secret,
+            fixture.expected.expires.round() as u64 - 1,
+            Duration::from_secs(60),
+            None,
+        );
+
+        let err = result.unwrap_err();
+        let header = err
+            .www_authenticate()
+            .expect("Expected a WWW-Authenticate header");
+        assert!(header.starts_with("Hawk ts=\""));
+        assert!(header.contains("tsm=\""));
+        assert!(header.ends_with("error=\"Stale timestamp\""));
+    }
+
+    #[test]
+    fn nonce_cache_bounded() {
+        let nonces = NonceCache::new(2, TS_SKEW);
+        let now = SystemTime::now();
+        let earlier = now - Duration::from_secs(2);
+
+        assert!(nonces.insert("id", "a", earlier));
+        assert!(nonces.insert("id", "b", now));
+        assert!(!nonces.insert("id", "b", now));
+        // The token id is part of the key
+        assert!(nonces.insert("other", "b", now));
+        // "a" was evicted to make room, but still can't be replayed
+        assert!(!nonces.insert("id", "a", earlier));
+        assert!(!nonces.insert("id", "c", earlier));
+        assert!(nonces.insert("id", "c", now));
+
+        let disabled = NonceCache::new(0, TS_SKEW);
+        assert!(disabled.insert("id", "a", now));
+        assert!(disabled.insert("id", "a", now));
+    }
+
+    #[test]
+    fn payload_hash() {
+        use PayloadHashMode::{Ignored, Optional, Required};
+
+        let fixture = TestFixture::new();
+        let body = br#"[{"id": "foo", "payload": "bar"}]"#;
+        let hash =
+            PayloadHasher::hash("application/json", DigestAlgorithm::Sha256, &body[..]).unwrap();
+        let unhashed = fixture.header.to_string();
+        let hashed = format!("{}, hash=\"{}\"", unhashed, base64::encode(&hash));
+
+        let verify = |header: &str, body: &[u8], mode| {
+            verify_payload_hash(Some(header), "application/json", body, mode)
+        };
+        let hawk_error = |result: crate::error::ApiResult<()>| {
+            let err = result.unwrap_err();
+            match err.kind() {
+                ApiErrorKind::Hawk(e) => e.kind().to_string(),
+                _ => panic!("Expected a Hawk error: {:?}", err),
+            }
+        };
+
+        assert!(verify(&hashed, body, Required).is_ok());
+        assert!(verify(&hashed, body, Optional).is_ok());
+        assert!(verify(&unhashed, body, Optional).is_ok());
+        assert!(verify(&hashed, b"[]", Ignored).is_ok());
+        assert!(verify(&unhashed, body, Ignored).is_ok());
+
+        assert_eq!(
+            hawk_error(verify(&hashed, b"[]", Optional)),
+            "payload hash mismatch"
+        );
+        assert_eq!(
+            hawk_error(verify(&unhashed, body, Required)),
+            "missing payload hash"
+        );
+        // The content type is covered by the hash
+        assert_eq!(
+            hawk_error(verify_payload_hash(
+                Some(&hashed),
+                "text/plain",
+                body,
+                Optional
+            )),
+            "payload hash mismatch"
+        );
+    }
+
+    #[test]
+    fn valid_jwt() {
+        let claims = json!({
+            "exp": 2_000,
+            "iat": 500,
+            "uid": 1,
+            "fxa_uid": "foo",
+            "fxa_kid": "bar"
+        });
+        let token = make_jwt("HS256", &claims, b"secret");
+
+        let result = JwtClaims::new(&format!("Bearer {}", token), b"secret", 1_000).unwrap();
+        assert_eq!(
+            result,
+            JwtClaims {
+                exp: 2_000,
+                iat: Some(500),
+                uid: 1,
+                fxa_uid: "foo".to_owned(),
+                fxa_kid: "bar".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_jwt() {
+        let claims = json!({"exp": 2_000, "uid": 1});
+        let token = make_jwt("HS256", &claims, b"secret");
+
+        let validate = |header: &str, now| {
+            JwtClaims::new(header, b"secret", now)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(validate(&format!("Bearer {}", token), 2_000).ends_with("expired payload"));
+        let token = make_jwt("HS256", &claims, b"wrong secret");
+        assert!(validate(&format!("Bearer {}", token), 1_000).ends_with("failed MAC verification"));
+        let token = make_jwt("none", &claims, b"secret");
+        assert!(validate(&format!("Bearer {}", token), 1_000).ends_with("invalid JWT"));
+        assert!(validate("Bearer foo.bar", 1_000).ends_with("invalid JWT"));
+    }
+
+    fn make_jwt(alg: &str, claims: &serde_json::Value, secret: &[u8]) -> String {
+        let encode = |value: &serde_json::Value| {
+            base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+        };
+        let signing_input = format!("{}.{}", encode(&json!({ "alg": alg })), encode(claims));
+        let mut hmac = Hmac::<Sha256>::new_varkey(secret).unwrap();
+        hmac.update(signing_input.as_bytes());
+        let signature =
+            base64::encode_config(hmac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        format!("{}.{}", signing_input, signature)
+    }
+
+    #[test]
+    fn revocations() {
+        let revocations = RevocationStore::new(Duration::from_secs(3600));
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // A Hawk token issued an hour ago, and one issued now
+        let (old, new) = (
+            Some(revocations.hawk_issued(now)),
+            Some(revocations.hawk_issued(now + 3600)),
+        );
+        assert_eq!(old, Some(now - 3600));
+
+        assert!(!revocations.is_revoked(1, "fxa", "device", old));
+
+        let fxa_revocation = Revocation {
+            key: RevocationKey::FxaUid("fxa".to_owned()),
+            issued_before: Some(now - 60),
+        };
+        revocations.replace(vec![fxa_revocation.clone()]);
+        assert!(revocations.is_revoked(1, "fxa", "device", old));
+        assert!(!revocations.is_revoked(1, "fxa", "device", new));
+        assert!(!revocations.is_revoked(1, "other", "device", old));
+        // Tokens with an unknown issue time can't be told apart
+        assert!(revocations.is_revoked(1, "fxa", "device", None));
+
+        revocations.replace(vec![
+            fxa_revocation.clone(),
+            Revocation {
+                key: RevocationKey::HashedDeviceId("device".to_owned()),
+                issued_before: None,
+            },
+        ]);
+        assert!(revocations.is_revoked(2, "", "device", new));
+        assert!(!revocations.is_revoked(2, "", "", new));
+        assert_eq!(revocations.list().len(), 2);
+
+        revocations.replace(vec![fxa_revocation.clone()]);
+        assert!(!revocations.is_revoked(2, "", "device", new));
+
+        // Revocations are dropped once the tokens they match have expired
+        revocations.replace(vec![
+            fxa_revocation.clone(),
+            Revocation {
+                key: RevocationKey::Uid(3),
+                issued_before: Some(now - 7200),
+            },
+        ]);
+        assert_eq!(revocations.list(), vec![fxa_revocation]);
+    }
+
+    #[test]
+    fn revocation_json() {
+        let revocation: Revocation =
+            serde_json::from_str(r#"{"uid": 123, "issued_before": 1600000000}"#).unwrap();
+        assert_eq!(revocation.key, RevocationKey::Uid(123));
+        assert_eq!(revocation.issued_before, Some(1_600_000_000));
+        let revocation: Revocation =
+            serde_json::from_str(r#"{"hashed_device_id": "abc"}"#).unwrap();
+        assert_eq!(
+            revocation.key,
+            RevocationKey::HashedDeviceId("abc".to_owned())
+        );
+        assert_eq!(revocation.issued_before, None);
+    }
+
+    #[test]
+    fn admin_token() {
+        assert!(verify_admin_token(Some("Bearer admin"), "admin").is_ok());
+        assert!(verify_admin_token(Some("Bearer wrong"), "admin").is_err());
+        assert!(verify_admin_token(Some("admin"), "admin").is_err());
+        assert!(verify_admin_token(None, "admin").is_err());
+    }
+
+    #[test]
+    fn offset_token() {
+        let secrets = Secrets::new("Ted Koppel is a robot").unwrap();
+        let token = sign_offset("Newest\n\n\n", "1600000000000:2", &secrets).unwrap();
+        assert_eq!(
+            verify_offset("Newest\n\n\n", &token, &secrets),
+            Some("1600000000000:2".to_owned())
+        );
+        // Reused against a different query
+        assert_eq!(verify_offset("Oldest\n\n\n", &token, &secrets), None);
+        // Signed with a different secret
+        let other = Secrets::new("Ted Koppel is not a robot").unwrap();
+        assert_eq!(verify_offset("Newest\n\n\n", &token, &other), None);
+        // Tampered with
+        let forged = format!(
+            "{}{}",
+            base64::encode_config("0", base64::URL_SAFE_NO_PAD),
+            &token[token.find('.').unwrap()..]
+        );
+        assert_eq!(verify_offset("Newest\n\n\n", &forged, &secrets), None);
+        assert_eq!(verify_offset("Newest\n\n\n", "10", &secrets), None);
+    }
+
     #[derive(Debug)]
     struct TestFixture {
         pub header: HawkHeader,
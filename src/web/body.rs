@@ -0,0 +1,317 @@
+//! Request bodies, decoded from their `Content-Encoding` as they're read.
+//!
+//! Decoding is done by actix's `Decompress`: this limits both the encoded and
+//! decoded sizes (so a small "zip bomb" can't expand into an unbounded body),
+//! and computes the Hawk payload hash, which covers the body as it was sent
+//! (i.e. its encoded bytes).
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{Decompress, Payload},
+    error::PayloadError,
+    http::header::CONTENT_ENCODING,
+    web::{Bytes, BytesMut},
+    HttpRequest,
+};
+use futures::{ready, Stream, StreamExt};
+use hawk::{DigestAlgorithm, PayloadHasher};
+
+use crate::error::{ApiError, ApiResult};
+use crate::server::metrics::Metrics;
+use crate::web::{
+    error::{HawkErrorKind, ValidationErrorKind},
+    extractors::RequestErrorLocation,
+    tags::Tags,
+};
+
+/// The supported (non-identity) content codings.
+const ENCODINGS: [&str; 3] = ["gzip", "deflate", "br"];
+
+/// Encoded bodies are decoded this many bytes at a time, bounding how far a
+/// single chunk can expand before the decoded size is checked.
+const DECODE_CHUNK_BYTES: usize = 1024;
+
+/// The body as it was sent.
+#[derive(Default)]
+struct Wire {
+    size: usize,
+    hasher: Option<PayloadHasher>,
+}
+
+/// The body as it's read off the wire: size limited, hashed and split into
+/// chunks small enough to decode.
+struct WirePayload {
+    payload: Payload,
+    chunk: Bytes,
+    max_bytes: usize,
+    wire: Rc<RefCell<Wire>>,
+}
+
+impl Stream for WirePayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.chunk.is_empty() {
+            let chunk = match ready!(Pin::new(&mut this.payload).poll_next(cx)) {
+                Some(Ok(chunk)) => chunk,
+                other => return Poll::Ready(other),
+            };
+            let mut wire = this.wire.borrow_mut();
+            wire.size += chunk.len();
+            if wire.size > this.max_bytes {
+                return Poll::Ready(Some(Err(PayloadError::Overflow)));
+            }
+            if let Some(hasher) = wire.hasher.as_mut() {
+                if let Err(e) = hasher.update(&chunk) {
+                    let e = io::Error::new(io::ErrorKind::Other, e.to_string());
+                    return Poll::Ready(Some(Err(PayloadError::Io(e))));
+                }
+            }
+            this.chunk = chunk;
+        }
+        let size = this.chunk.len().min(DECODE_CHUNK_BYTES);
+        Poll::Ready(Some(Ok(this.chunk.split_to(size))))
+    }
+}
+
+/// A request body, decoded as it's read.
+pub struct RequestBody {
+    stream: Decompress<WirePayload>,
+    wire: Rc<RefCell<Wire>>,
+    expected_hash: Option<Vec<u8>>,
+    encoding: Option<String>,
+    size: usize,
+    max_bytes: usize,
+    metrics: Metrics,
+    tags: Tags,
+}
+
+impl RequestBody {
+    /// Decode a request's body per its `Content-Encoding`, limiting both its
+    /// encoded and decoded size to `max_bytes` and verifying it against the
+    /// Hawk `expected_hash` (of the body's `content_type`), if any.
+    pub fn new(
+        req: &HttpRequest,
+        payload: Payload,
+        max_bytes: usize,
+        content_type: &str,
+        expected_hash: Option<Vec<u8>>,
+    ) -> ApiResult<Self> {
+        let tags = Tags::from_request_head(req.head());
+        let content_encoding = req
+            .headers()
+            .get(CONTENT_ENCODING)
+            .map(|header| header.to_str().unwrap_or("invalid").trim().to_lowercase());
+        let encoding = match content_encoding {
+            None => None,
+            Some(encoding) if encoding.is_empty() || encoding == "identity" => None,
+            Some(encoding) if ENCODINGS.contains(&encoding.as_str()) => Some(encoding),
+            Some(encoding) => {
+                return Err(ValidationErrorKind::FromDetails(
+                    format!("Unsupported Content-Encoding {:?}", encoding),
+                    RequestErrorLocation::Header,
+                    Some("Content-Encoding".to_owned()),
+                    Some(tags),
+                    label!("request.validate.bad_content_encoding"),
+                )
+                .into());
+            }
+        };
+        let hasher = match expected_hash {
+            Some(_) => Some(PayloadHasher::new(content_type, DigestAlgorithm::Sha256)?),
+            None => None,
+        };
+        let wire = Rc::new(RefCell::new(Wire { size: 0, hasher }));
+        let payload = WirePayload {
+            payload,
+            chunk: Bytes::new(),
+            max_bytes,
+            wire: wire.clone(),
+        };
+        Ok(Self {
+            stream: Decompress::from_headers(payload, req.headers()),
+            wire,
+            expected_hash,
+            encoding,
+            size: 0,
+            max_bytes,
+            metrics: Metrics::from(req),
+            tags,
+        })
+    }
+
+    /// The next chunk of the decoded body.
+    pub async fn next(&mut self) -> Option<ApiResult<Bytes>> {
+        let chunk = match self.stream.next().await? {
+            Ok(chunk) => chunk,
+            Err(PayloadError::Overflow) => return Some(Err(self.too_large())),
+            Err(e) => return Some(Err(self.read_error(e))),
+        };
+        self.size += chunk.len();
+        if self.size > self.max_bytes {
+            return Some(Err(self.too_large()));
+        }
+        Some(Ok(chunk))
+    }
+
+    /// Verify the Hawk payload hash of the fully read body.
+    pub fn finish(self) -> ApiResult<()> {
+        if let Some(encoding) = self.encoding {
+            let mut tags = HashMap::new();
+            tags.insert("encoding".to_owned(), encoding);
+            self.metrics.histogram_with_tags(
+                "request.compression_ratio",
+                (self.wire.borrow().size * 100 / self.size.max(1)) as u64,
+                Some(Tags::with_tags(tags)),
+            );
+        }
+        let hasher = self.wire.borrow_mut().hasher.take();
+        if let (Some(expected), Some(hasher)) = (self.expected_hash, hasher) {
+            if hasher.finish()? != expected {
+                Err(HawkErrorKind::InvalidPayloadHash)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read and verify the entire decoded body.
+    pub async fn read(mut self) -> ApiResult<Bytes> {
+        let mut body = BytesMut::new();
+        while let Some(chunk) = self.next().await {
+            body.extend_from_slice(&chunk?);
+        }
+        self.finish()?;
+        Ok(body.freeze())
+    }
+
+    fn too_large(&self) -> ApiError {
+        self.metrics
+            .incr_with_tags("request.error.payload_too_large", Some(self.tags.clone()));
+        ValidationErrorKind::FromDetails(
+            "size-limit-exceeded".to_owned(),
+            RequestErrorLocation::Body,
+            None,
+            Some(self.tags.clone()),
+            label!("request.validate.payload_too_large"),
+        )
+        .into()
+    }
+
+    fn read_error(&self, e: PayloadError) -> ApiError {
+        if self.encoding.is_some() {
+            warn!("⚠️ Payload decode error: {:?}", e);
+            return ValidationErrorKind::FromDetails(
+                "Invalid compressed request body".to_owned(),
+                RequestErrorLocation::Body,
+                None,
+                Some(self.tags.clone()),
+                label!("request.validate.invalid_content_encoding"),
+            )
+            .into();
+        }
+        warn!("⚠️ Payload read error: {:?}", e);
+        ValidationErrorKind::FromDetails(
+            "Mimetype/encoding/content-length error".to_owned(),
+            RequestErrorLocation::Header,
+            None,
+            None,
+            None,
+        )
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use actix_web::{http::StatusCode, test::TestRequest, HttpResponse};
+    use brotli2::write::BrotliEncoder;
+    use flate2::write::GzEncoder;
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn encode(body: &[u8], encoding: &str) -> Vec<u8> {
+        match encoding {
+            "gzip" => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => {
+                let mut encoder = BrotliEncoder::new(Vec::new(), 3);
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    fn read(
+        content_encoding: &str,
+        body: &[u8],
+        max_bytes: usize,
+        expected_hash: Option<Vec<u8>>,
+    ) -> ApiResult<Bytes> {
+        let (req, payload) = TestRequest::default()
+            .header(CONTENT_ENCODING, content_encoding)
+            .set_payload(body.to_vec())
+            .to_http_parts();
+        let body = RequestBody::new(&req, payload, max_bytes, "application/json", expected_hash)?;
+        block_on(body.read())
+    }
+
+    #[test]
+    fn test_decode() {
+        let body = b"[{\"id\": \"1\", \"payload\": \"x\"}]".repeat(100);
+        for encoding in &["gzip", "br"] {
+            let compressed = encode(&body, encoding);
+            assert_eq!(read(encoding, &compressed, body.len(), None).unwrap(), body);
+
+            // Decompressed size limit
+            let err = read(encoding, &compressed, body.len() - 1, None).unwrap_err();
+            assert_eq!(err.to_string(), "size-limit-exceeded");
+
+            // Compressed size limit
+            assert!(read(encoding, &compressed, compressed.len() - 1, None).is_err());
+
+            // Truncated/corrupt
+            let truncated = &compressed[..compressed.len() / 2];
+            assert!(read(encoding, truncated, body.len(), None).is_err());
+        }
+
+        assert_eq!(read("identity", &body, body.len(), None).unwrap(), body);
+
+        let err = read("compress", &body, body.len(), None).unwrap_err();
+        let resp: HttpResponse = err.into();
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_zip_bomb() {
+        let compressed = encode(&vec![0; 10_000_000], "gzip");
+        assert!(compressed.len() < 100_000);
+        let err = read("gzip", &compressed, 100_000, None).unwrap_err();
+        assert_eq!(err.to_string(), "size-limit-exceeded");
+    }
+
+    #[test]
+    fn test_payload_hash() {
+        // The hash covers the body as sent, not as decoded
+        let body = b"[{\"id\": \"1\", \"payload\": \"x\"}]";
+        let compressed = encode(body, "gzip");
+        let hash = |body: &[u8]| {
+            PayloadHasher::hash("application/json", DigestAlgorithm::Sha256, body).unwrap()
+        };
+        assert!(read("gzip", &compressed, 1000, Some(hash(&compressed))).is_ok());
+        let err = read("gzip", &compressed, 1000, Some(hash(body))).unwrap_err();
+        let resp: HttpResponse = err.into();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -45,12 +45,21 @@ pub enum HawkErrorKind {
     #[fail(display = "{}", _0)]
     Hmac(MacError),
 
+    #[fail(display = "invalid admin token")]
+    InvalidAdminToken,
+
     #[fail(display = "validation failed")]
     InvalidHeader,
 
+    #[fail(display = "invalid JWT")]
+    InvalidJwt,
+
     #[fail(display = "{}", _0)]
     InvalidKeyLength(InvalidKeyLength),
 
+    #[fail(display = "payload hash mismatch")]
+    InvalidPayloadHash,
+
     #[fail(display = "{}", _0)]
     Json(#[cause] JsonError),
 
@@ -63,12 +72,26 @@ pub enum HawkErrorKind {
     #[fail(display = "missing path")]
     MissingPath,
 
+    #[fail(display = "missing payload hash")]
+    MissingPayloadHash,
+
     #[fail(display = "missing \"Hawk \" prefix")]
     MissingPrefix,
 
     #[fail(display = "{}", _0)]
     Parse(ParseError),
 
+    #[fail(display = "nonce already used")]
+    Replayed,
+
+    #[fail(display = "token revoked")]
+    Revoked,
+
+    /// The request timestamp is outside of the allowed clock skew. Carries
+    /// the `WWW-Authenticate` header value to return to the client.
+    #[fail(display = "stale timestamp")]
+    StaleTimestamp(String),
+
     #[fail(display = "id property is too short")]
     TruncatedId,
 }
@@ -135,7 +158,7 @@ impl From<Context<ValidationErrorKind>> for ValidationError {
             ) if *location == RequestErrorLocation::Header => {
                 match name.to_ascii_lowercase().as_str() {
                     "accept" => StatusCode::NOT_ACCEPTABLE,
-                    "content-type" => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "content-type" | "content-encoding" => StatusCode::UNSUPPORTED_MEDIA_TYPE,
                     _ => StatusCode::BAD_REQUEST,
                 }
             }
@@ -2,15 +2,21 @@
 //!
 //! Handles ensuring the header's, body, and query parameters are correct, extraction to
 //! relevant types, and failing correctly with the appropriate errors if issues arise.
-use std::{self, collections::HashMap, num::ParseIntError, str::FromStr};
+use std::{
+    self,
+    collections::{HashMap, HashSet},
+    num::ParseIntError,
+    str::FromStr,
+    sync::Arc,
+};
 
 use actix_web::{
     dev::{ConnectionInfo, Extensions, Payload, RequestHead},
     http::{
-        header::{qitem, Accept, ContentType, Header, HeaderMap},
+        header::{qitem, Accept, ContentType, Header, HeaderMap, IF_MATCH, IF_NONE_MATCH},
         Uri,
     },
-    web::{Data, Json, Query},
+    web::{Data, Query},
     Error, FromRequest, HttpMessage, HttpRequest,
 };
 
@@ -27,12 +33,16 @@ use serde_json::Value;
 use validator::{Validate, ValidationError};
 
 use crate::db::transaction::DbTransactionPool;
-use crate::db::{util::SyncTimestamp, DbPool, Sorting};
-use crate::error::{ApiError, ApiErrorKind};
+use crate::db::{params, util::SyncTimestamp, AuditOp, DbPool, Sorting};
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::server::{metrics, ServerState, BSO_ID_REGEX, COLLECTION_ID_REGEX};
-use crate::settings::Secrets;
+use crate::settings::{Secrets, ServerLimits};
 use crate::web::{
-    auth::HawkPayload,
+    auth::{
+        expected_payload_hash, sign_offset, verify_admin_token, verify_offset, HawkPayload,
+        JwtClaims,
+    },
+    body::RequestBody,
     error::{HawkErrorKind, ValidationErrorKind},
     tags::Tags,
     X_WEAVE_RECORDS,
@@ -48,6 +58,26 @@ const BSO_MIN_SORTINDEX_VALUE: i32 = -999_999_999;
 const ACCEPTED_CONTENT_TYPES: [&str; 3] =
     ["application/json", "text/plain", "application/newlines"];
 
+/// The standard collections whose payloads clients encrypt (`meta` holds the
+/// plaintext `meta/global`), checked by `strict_payload_envelopes`
+const ENVELOPE_COLLECTIONS: [&str; 11] = [
+    "clients",
+    "crypto",
+    "forms",
+    "history",
+    "bookmarks",
+    "prefs",
+    "tabs",
+    "passwords",
+    "addons",
+    "addresses",
+    "creditcards",
+];
+/// Size of a crypto envelope's IV, and of its ciphertext's (AES) blocks
+const ENVELOPE_BLOCK_SIZE: usize = 16;
+/// Length of a crypto envelope's hex HMAC-SHA256
+const ENVELOPE_HMAC_LEN: usize = 64;
+
 lazy_static! {
     static ref KNOWN_BAD_PAYLOAD_REGEX: Regex =
         Regex::new(r#"IV":\s*"AAAAAAAAAAAAAAAAAAAAAA=="#).unwrap();
@@ -80,13 +110,18 @@ pub struct BatchBsoBody {
     pub payload: Option<String>,
     #[validate(custom = "validate_body_bso_ttl")]
     pub ttl: Option<u32>,
+    /// The record's expected stored timestamp (0 when it shouldn't exist
+    /// yet). With `enable_expected_modified` the record fails with a conflict
+    /// when it differs, otherwise it's ignored
+    #[validate(custom = "validate_body_bso_modified")]
+    pub modified: Option<f64>,
 }
 
 impl BatchBsoBody {
     /// Function to convert valid raw JSON BSO body to a BatchBsoBody
     fn from_raw_bso(val: &Value) -> Result<BatchBsoBody, String> {
         let map = val.as_object().ok_or("invalid json")?;
-        // Verify all the keys are valid. modified/collection are allowed but ignored
+        // Verify all the keys are valid. collection is allowed but ignored
         let valid_keys = [
             "id",
             "sortindex",
@@ -138,12 +173,119 @@ fn get_accepted(req: &HttpRequest, accepted: &[&str], default: &'static str) ->
     "invalid".to_string()
 }
 
+/// The raw `Authorization` header of a request, if it has a readable one.
+fn auth_header(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("authorization")
+        .and_then(|header| header.to_str().ok())
+        .map(str::to_owned)
+}
+
 #[derive(Clone, Default, Deserialize)]
 pub struct BsoBodies {
     pub valid: Vec<BatchBsoBody>,
     pub invalid: HashMap<String, String>,
 }
 
+/// Accumulates the records of a POST body as they're read, validating each
+/// one and applying the post limits along the way.
+struct BsoBodiesBuilder {
+    bodies: BsoBodies,
+    bso_ids: HashSet<String>,
+    total_payload_size: usize,
+    lines: usize,
+    max_payload_size: usize,
+    max_post_bytes: usize,
+    max_post_records: usize,
+    tags: Tags,
+}
+
+impl BsoBodiesBuilder {
+    fn new(limits: &ServerLimits, tags: Tags) -> Self {
+        Self {
+            bodies: BsoBodies::default(),
+            bso_ids: HashSet::new(),
+            total_payload_size: 0,
+            lines: 0,
+            max_payload_size: limits.max_record_payload_bytes as usize,
+            max_post_bytes: limits.max_post_bytes as usize,
+            max_post_records: limits.max_post_records as usize,
+            tags,
+        }
+    }
+
+    /// Add a BSO (a JSON mapping) with the given id.
+    ///
+    /// Duplicate ids fail the entire request, any other invalid BSO (or one
+    /// beyond the post limits) is moved to the invalid list.
+    fn push(&mut self, bso_id: String, bso: &Value) -> Result<(), Error> {
+        if !self.bso_ids.insert(bso_id.clone()) {
+            return Err(ValidationErrorKind::FromDetails(
+                "Input BSO has duplicate ID".to_owned(),
+                RequestErrorLocation::Body,
+                Some("bsos".to_owned()),
+                Some(self.tags.clone()),
+                label!("request.store.duplicate_bso_id"),
+            )
+            .into());
+        }
+        match BatchBsoBody::from_raw_bso(bso) {
+            Ok(b) => {
+                // Is this record too large? Deny if it is.
+                let payload_size = b
+                    .payload
+                    .as_ref()
+                    .map(std::string::String::len)
+                    .unwrap_or_default();
+                self.total_payload_size += payload_size;
+                if payload_size > self.max_payload_size
+                    || self.total_payload_size > self.max_post_bytes
+                {
+                    self.bodies.invalid.insert(b.id, "retry bytes".to_string());
+                } else if self.bodies.valid.len() >= self.max_post_records {
+                    self.bodies.invalid.insert(b.id, "retry bso".to_string());
+                } else {
+                    self.bodies.valid.push(b);
+                }
+            }
+            Err(e) => {
+                self.bodies.invalid.insert(bso_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a line of an `application/newlines` body.
+    ///
+    /// Lines that aren't a BSO with an id are reported as invalid, keyed by
+    /// their line number.
+    fn push_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        self.lines += 1;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+        let line_key = format!("line {}", self.lines);
+        let bso = match serde_json::from_slice::<Value>(line) {
+            Ok(bso) if bso.is_object() => bso,
+            _ => {
+                self.bodies
+                    .invalid
+                    .insert(line_key, "invalid json".to_owned());
+                return Ok(());
+            }
+        };
+        match bso.get("id").and_then(Value::as_str) {
+            Some(id) => self.push(id.to_owned(), &bso),
+            None => {
+                self.bodies
+                    .invalid
+                    .insert(line_key, "missing id".to_owned());
+                Ok(())
+            }
+        }
+    }
+}
+
 impl FromRequest for BsoBodies {
     type Config = ();
     type Error = Error;
@@ -156,6 +298,10 @@ impl FromRequest for BsoBodies {
     ///   - All BSO's deserialize from the request correctly
     ///   - Request content-type is a valid value
     ///   - Valid BSO's include a BSO id
+    ///   - Valid BSO's do not exceed the post limits
+    ///
+    /// `application/newlines` bodies are parsed as they're streamed in, with
+    /// unparseable lines reported as invalid rather than failing the request.
     ///
     /// No collection id is used, so payload checks are not done here.
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
@@ -194,19 +340,6 @@ impl FromRequest for BsoBodies {
             ));
         }
 
-        // Load the entire request into a String
-        let fut = <String>::from_request(req, payload).map_err(|e| {
-            warn!("⚠️ Payload read error: {:?}", e);
-            ValidationErrorKind::FromDetails(
-                "Mimetype/encoding/content-length error".to_owned(),
-                RequestErrorLocation::Header,
-                None,
-                None,
-                None,
-            )
-            .into()
-        });
-
         // Avoid duplicating by defining our error func now, doesn't need the box wrapper
         fn make_error(tags: Option<Tags>, metrics: metrics::Metrics) -> Error {
             metrics.incr_with_tags("request.error.invalid_json", tags.clone());
@@ -242,104 +375,80 @@ impl FromRequest for BsoBodies {
             }
         };
 
-        let max_payload_size = state.limits.max_record_payload_bytes as usize;
-        let max_post_bytes = state.limits.max_post_bytes as usize;
+        let payload_hash = state.hawk_payload_hash;
+        let auth_header = auth_header(req);
+        let req = req.clone();
+        let payload = payload.take();
 
-        let fut = fut.and_then(move |body| {
-            // Get all the raw / values
-            let bsos: Vec<Value> = if newlines {
-                let mut bsos = Vec::new();
-                for item in body.lines() {
-                    // Check that its a valid JSON map like we expect
-                    if let Ok(raw_json) = serde_json::from_str::<Value>(&item) {
-                        bsos.push(raw_json);
-                    } else {
-                        // Per Python version, BSO's must json deserialize
-                        return future::err(make_error(None, metrics));
+        Box::pin(async move {
+            let limits = UserLimits::from_request(&req, &mut Payload::None).await?.0;
+            let mut builder = BsoBodiesBuilder::new(&limits, tags.clone());
+            let max_request_bytes = limits.max_request_bytes as usize;
+            let expected_hash = expected_payload_hash(auth_header.as_deref(), payload_hash)?;
+            let mut body = RequestBody::new(
+                &req,
+                payload,
+                max_request_bytes,
+                &content_type,
+                expected_hash,
+            )?;
+            if newlines {
+                // Parse each line as soon as it's complete, only buffering
+                // the remainder
+                let mut buf = Vec::new();
+                while let Some(chunk) = body.next().await {
+                    let chunk = chunk?;
+                    // Only search the new data for line endings
+                    let mut searched = buf.len();
+                    buf.extend_from_slice(&chunk);
+                    let mut start = 0;
+                    while let Some(end) = buf[searched..].iter().position(|&b| b == b'\n') {
+                        let end = searched + end;
+                        builder.push_line(&buf[start..end])?;
+                        start = end + 1;
+                        searched = start;
                     }
+                    buf.drain(..start);
                 }
-                bsos
-            } else if let Ok(json_vals) = serde_json::from_str::<Vec<Value>>(&body) {
-                json_vals
-            } else {
-                // Per Python version, BSO's must json deserialize
-                return future::err(make_error(None, metrics));
-            };
-
-            // Validate all the BSO's, move invalid to our other list. Assume they'll all make
-            // it with our pre-allocation
-            let mut valid: Vec<BatchBsoBody> = Vec::with_capacity(bsos.len());
-
-            // Invalid BSO's are any BSO that can deserialize despite how wrong the contents are
-            // per the way the Python version works.
-            let mut invalid: HashMap<String, String> = HashMap::new();
+                builder.push_line(&buf)?;
+                body.finish()?;
+                return Ok(builder.bodies);
+            }
 
-            // Keep track of our total payload size
-            let mut total_payload_size = 0;
+            // Load the entire (decoded) request
+            let body = body.read().await?;
 
-            // Temporarily track the bso id's for dupe detection
-            let mut bso_ids: Vec<String> = Vec::with_capacity(bsos.len());
+            // Get all the raw / values
+            let bsos = match serde_json::from_slice::<Vec<Value>>(&body) {
+                Ok(json_vals) => json_vals,
+                // Per Python version, BSO's must json deserialize
+                Err(_) => return Err(make_error(None, metrics)),
+            };
 
+            // Validate all the BSO's, move invalid to our other list.
             for bso in bsos {
                 // Error out if its not a JSON mapping type
                 if !bso.is_object() {
-                    return future::err(make_error(None, metrics));
+                    return Err(make_error(None, metrics));
                 }
-                // Save all id's we get, check for missing id, or duplicate.
-                let bso_id = if let Some(id) = bso.get("id").and_then(serde_json::Value::as_str) {
-                    let id = id.to_string();
-                    if bso_ids.contains(&id) {
-                        return future::err(
-                            ValidationErrorKind::FromDetails(
-                                "Input BSO has duplicate ID".to_owned(),
-                                RequestErrorLocation::Body,
-                                Some("bsos".to_owned()),
-                                Some(tags),
-                                label!("request.store.duplicate_bso_id"),
-                            )
-                            .into(),
-                        );
-                    } else {
-                        bso_ids.push(id.clone());
-                        id
-                    }
-                } else {
-                    return future::err(
-                        ValidationErrorKind::FromDetails(
+                // Check for missing id (or duplicate, when added)
+                let bso_id = match bso.get("id").and_then(Value::as_str) {
+                    Some(id) => id.to_owned(),
+                    None => {
+                        return Err(ValidationErrorKind::FromDetails(
                             "Input BSO has no ID".to_owned(),
                             RequestErrorLocation::Body,
                             Some("bsos".to_owned()),
                             Some(tags),
                             label!("request.store.missing_bso_id"),
                         )
-                        .into(),
-                    );
-                };
-                match BatchBsoBody::from_raw_bso(&bso) {
-                    Ok(b) => {
-                        // Is this record too large? Deny if it is.
-                        let payload_size = b
-                            .payload
-                            .as_ref()
-                            .map(std::string::String::len)
-                            .unwrap_or_default();
-                        total_payload_size += payload_size;
-                        if payload_size <= max_payload_size && total_payload_size <= max_post_bytes
-                        {
-                            valid.push(b);
-                        } else {
-                            invalid.insert(b.id, "retry bytes".to_string());
-                        }
-                    }
-                    Err(e) => {
-                        invalid.insert(bso_id, e);
+                        .into());
                     }
-                }
+                };
+                builder.push(bso_id, &bso)?;
             }
-            future::ok(BsoBodies { valid, invalid })
-        });
-
-        Box::pin(fut)
+            Ok(builder.bodies)
+        })
     }
 }
 
@@ -415,54 +524,69 @@ impl FromRequest for BsoBody {
             }
         };
 
-        let max_payload_size = state.limits.max_record_payload_bytes as usize;
+        let payload_hash = state.hawk_payload_hash;
+        let auth_header = auth_header(req);
 
-        let fut = <Json<BsoBody>>::from_request(&req, payload)
-            .map_err(|e| {
-                warn!("⚠️ Could not parse BSO Body: {:?}", e);
-                let err: ApiError = ValidationErrorKind::FromDetails(
-                    e.to_string(),
-                    RequestErrorLocation::Body,
-                    Some("bso".to_owned()),
-                    Some(tags),
-                    label!("request.validate.bad_bso_body"),
-                )
-                .into();
-                err.into()
-            })
-            .and_then(move |bso: Json<BsoBody>| {
-                // Check the max payload size manually with our desired limit
-                if bso
-                    .payload
-                    .as_ref()
-                    .map(std::string::String::len)
-                    .unwrap_or_default()
-                    > max_payload_size
-                {
-                    let err: ApiError = ValidationErrorKind::FromDetails(
-                        "payload too large".to_owned(),
+        // Read the raw body (rather than using the `Json` extractor) so its
+        // Hawk payload hash can be verified
+        let req = req.clone();
+        let payload = payload.take();
+        Box::pin(async move {
+            let limits = UserLimits::from_request(&req, &mut Payload::None).await?.0;
+            let max_payload_size = limits.max_record_payload_bytes as usize;
+            let max_request_bytes = limits.max_request_bytes as usize;
+            let expected_hash = expected_payload_hash(auth_header.as_deref(), payload_hash)?;
+            let body = RequestBody::new(
+                &req,
+                payload,
+                max_request_bytes,
+                &content_type,
+                expected_hash,
+            )?
+            .read()
+            .await?;
+            let bso: BsoBody = match serde_json::from_slice(&body) {
+                Ok(bso) => bso,
+                Err(e) => {
+                    warn!("⚠️ Could not parse BSO Body: {:?}", e);
+                    return Err(ValidationErrorKind::FromDetails(
+                        e.to_string(),
                         RequestErrorLocation::Body,
                         Some("bso".to_owned()),
-                        Some(ftags),
-                        label!("request.validate.payload_too_large"),
-                    )
-                    .into();
-                    return future::err(err.into());
-                }
-                if let Err(e) = bso.validate() {
-                    let err: ApiError = ValidationErrorKind::FromValidationErrors(
-                        e,
-                        RequestErrorLocation::Body,
-                        Some(fftags),
-                        None,
+                        Some(tags),
+                        label!("request.validate.bad_bso_body"),
                     )
-                    .into();
-                    return future::err(err.into());
+                    .into());
                 }
-                future::ok(bso.into_inner())
-            });
-
-        Box::pin(fut)
+            };
+            // Check the max payload size manually with our desired limit
+            if bso
+                .payload
+                .as_ref()
+                .map(std::string::String::len)
+                .unwrap_or_default()
+                > max_payload_size
+            {
+                return Err(ValidationErrorKind::FromDetails(
+                    "payload too large".to_owned(),
+                    RequestErrorLocation::Body,
+                    Some("bso".to_owned()),
+                    Some(ftags),
+                    label!("request.validate.payload_too_large"),
+                )
+                .into());
+            }
+            if let Err(e) = bso.validate() {
+                return Err(ValidationErrorKind::FromValidationErrors(
+                    e,
+                    RequestErrorLocation::Body,
+                    Some(fftags),
+                    None,
+                )
+                .into());
+            }
+            Ok(bso)
+        })
     }
 }
 
@@ -659,6 +783,8 @@ impl FromRequest for CollectionParam {
 /// requests: https://mozilla-services.readthedocs.io/en/latest/storage/apis-1.5.html#general-info
 pub struct MetaRequest {
     pub user_id: HawkIdentifier,
+    /// The token the request was authenticated with
+    pub token: TokenInfo,
     pub metrics: metrics::Metrics,
     pub tags: Tags,
 }
@@ -681,9 +807,15 @@ impl FromRequest for MetaRequest {
                 }
             };
             let user_id = HawkIdentifier::from_request(&req, &mut payload).await?;
+            let token = req
+                .extensions()
+                .get::<TokenInfo>()
+                .cloned()
+                .unwrap_or_default();
 
             Ok(MetaRequest {
                 user_id,
+                token,
                 metrics: metrics::Metrics::from(&req),
                 tags,
             })
@@ -692,6 +824,198 @@ impl FromRequest for MetaRequest {
     }
 }
 
+/// The authenticated user's limits: the server limits with their overrides
+/// applied.
+///
+/// They're cached in the request's extensions once extracted, as the
+/// `BsoBodies` and `BsoBody` extractors also extract them.
+#[derive(Clone, Debug)]
+pub struct UserLimits(pub Arc<ServerLimits>);
+
+impl FromRequest for UserLimits {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = Payload::None;
+        Box::pin(async move {
+            if let Some(limits) = req.extensions().get::<UserLimits>() {
+                return Ok(limits.clone());
+            }
+            let user_id = HawkIdentifier::from_request(&req, &mut payload).await?;
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("state".to_owned()),
+                        None,
+                        None,
+                    )
+                    .into());
+                }
+            };
+            let limits = state
+                .user_limits
+                .get(&state.limits, &*state.db_pool, &user_id)
+                .await?;
+            let limits = UserLimits(limits);
+            req.extensions_mut().insert(limits.clone());
+            Ok(limits)
+        })
+    }
+}
+
+/// The token a request was authenticated with
+#[derive(Clone, Debug, Default)]
+pub struct TokenInfo {
+    /// The hashed device id of a Hawk token (empty for Bearer JWTs)
+    pub device_id: String,
+    /// When the token was issued, in seconds (estimated for Hawk tokens)
+    pub issued: Option<u64>,
+    /// When the token expires, in seconds
+    pub expires: u64,
+}
+
+/// The client making a request, for the audit log of destructive operations
+#[derive(Clone, Debug, Default)]
+pub struct RequestOrigin {
+    pub user_agent: String,
+    /// The hashed device id of the request's Hawk token (empty for Bearer
+    /// JWTs)
+    pub device_id: String,
+    /// How long, in seconds, audit log entries are kept (0 when destructive
+    /// operations aren't recorded)
+    pub audit_log_retention: u32,
+}
+
+impl RequestOrigin {
+    /// Whether destructive operations are recorded in the audit log
+    pub fn audited(&self) -> bool {
+        self.audit_log_retention > 0
+    }
+
+    /// The audit log entry of a destructive operation made by this request
+    pub fn audit(
+        &self,
+        user_id: HawkIdentifier,
+        collection: Option<String>,
+        op: AuditOp,
+        count: i64,
+        timestamp: SyncTimestamp,
+    ) -> params::RecordAudit {
+        params::RecordAudit {
+            user_id,
+            collection,
+            op,
+            count,
+            timestamp,
+            user_agent: self.user_agent.clone(),
+            device_id: self.device_id.clone(),
+            retention: self.audit_log_retention,
+        }
+    }
+}
+
+impl FromRequest for RequestOrigin {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = Payload::None;
+        Box::pin(async move {
+            let tags = Tags::from_request(&req, &mut payload).await?;
+            // Authenticates the request if it hasn't been already, recording
+            // its device id
+            HawkIdentifier::from_request(&req, &mut payload).await?;
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("state".to_owned()),
+                        Some(tags),
+                        None,
+                    )
+                    .into());
+                }
+            };
+            let device_id = req
+                .extensions()
+                .get::<TokenInfo>()
+                .map(|token| token.device_id.clone())
+                .unwrap_or_default();
+            Ok(RequestOrigin {
+                user_agent: tags.extra.get("ua").cloned().unwrap_or_default(),
+                device_id,
+                audit_log_retention: state.audit_log_retention,
+            })
+        })
+    }
+}
+
+/// `/info/collections` query parameters
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct InfoCollectionsQueryParams {
+    /// long-poll: the seconds to wait, before responding, for the storage to
+    /// be modified after `X-If-Modified-Since` (integer). Only valid with an
+    /// `X-If-Modified-Since` header (and no `If-Match`/`If-None-Match`, which
+    /// take precedence over it)
+    pub wait: Option<u32>,
+}
+
+impl FromRequest for InfoCollectionsQueryParams {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = Payload::None;
+        Box::pin(async move {
+            let tags = Tags::from_request(&req, &mut payload).await?;
+            let params = Query::<InfoCollectionsQueryParams>::from_request(&req, &mut payload)
+                .map_err(|e| {
+                    ValidationErrorKind::FromDetails(
+                        e.to_string(),
+                        RequestErrorLocation::QueryString,
+                        Some("wait".to_owned()),
+                        Some(tags.clone()),
+                        label!("request.validate.info_collections.wait"),
+                    )
+                })
+                .await?
+                .into_inner();
+            if params.wait.is_some() {
+                let precondition = PreConditionHeaderOpt::extrude(req.headers(), None)?;
+                if !matches!(
+                    precondition.opt,
+                    Some(PreConditionHeader::IfModifiedSince(_))
+                ) {
+                    return Err(ValidationErrorKind::FromDetails(
+                        "wait requires X-If-Modified-Since".to_owned(),
+                        RequestErrorLocation::QueryString,
+                        Some("wait".to_owned()),
+                        Some(tags),
+                        label!("request.validate.info_collections.wait"),
+                    )
+                    .into());
+                }
+            }
+            Ok(params)
+        })
+    }
+}
+
 /// Desired reply format for a Collection Get request
 #[derive(Copy, Clone, Debug)]
 pub enum ReplyFormat {
@@ -749,6 +1073,38 @@ impl FromRequest for CollectionRequest {
                 }
             };
 
+            if let Some(deleted_since) = query.deleted_since {
+                let retention = match req.app_data::<Data<ServerState>>() {
+                    Some(state) => state.deleted_bso_retention,
+                    None => {
+                        error!("⚠️ Could not load the app state");
+                        return Err(ValidationErrorKind::FromDetails(
+                            "Internal error".to_owned(),
+                            RequestErrorLocation::Unknown,
+                            Some("app_data".to_owned()),
+                            Some(tags),
+                            None,
+                        )
+                        .into());
+                    }
+                };
+                // Deletions older than the retention period may have been
+                // forgotten: the client must fully resync instead
+                let horizon = SyncTimestamp::default()
+                    .as_i64()
+                    .saturating_sub(i64::from(retention) * 1000);
+                if deleted_since.as_i64() < horizon {
+                    return Err(ValidationErrorKind::FromDetails(
+                        "deleted_since is older than the deletion retention period".to_owned(),
+                        RequestErrorLocation::QueryString,
+                        Some("deleted_since".to_owned()),
+                        Some(tags),
+                        label!("request.validate.bso_query.deleted_since"),
+                    )
+                    .into());
+                }
+            }
+
             Ok(CollectionRequest {
                 collection,
                 user_id,
@@ -773,6 +1129,7 @@ pub struct CollectionPostRequest {
     pub batch: Option<BatchRequest>,
     pub metrics: metrics::Metrics,
     pub quota_enabled: bool,
+    pub limits: Arc<ServerLimits>,
 }
 
 impl FromRequest for CollectionPostRequest {
@@ -782,10 +1139,12 @@ impl FromRequest for CollectionPostRequest {
 
     /// Extractor for Collection Posts (Batch BSO upload)
     ///
-    /// Utilizes the `BsoBodies` for parsing, and add's two validation steps not
+    /// Utilizes the `BsoBodies` for parsing, and add's validation steps not
     /// done previously:
     ///   - If the collection is 'crypto', known bad payloads are checked for
-    ///   - Any valid BSO's beyond `BATCH_MAX_RECORDS` are moved to invalid
+    ///   - With `strict_payload_envelopes`, the payloads of encrypted
+    ///     collections that aren't crypto envelopes are moved to the invalid
+    ///     list
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
         let req = req.clone();
         let mut payload = payload.take();
@@ -809,11 +1168,10 @@ impl FromRequest for CollectionPostRequest {
                 }
             };
 
-            let max_post_records = i64::from(state.limits.max_post_records);
-
             let user_id = HawkIdentifier::from_request(&req, &mut payload).await?;
             let collection = CollectionParam::from_request(&req, &mut payload).await?;
             let query = BsoQueryParams::from_request(&req, &mut payload).await?;
+            let limits = UserLimits::from_request(&req, &mut payload).await?.0;
             let mut bsos = BsoBodies::from_request(&req, &mut payload).await?;
 
             let collection = collection.collection;
@@ -834,13 +1192,16 @@ impl FromRequest for CollectionPostRequest {
                     }
                 }
             }
-
-            // Trim the excess BSO's to be under the batch size
-            let overage: i64 = (bsos.valid.len() as i64) - max_post_records;
-            if overage > 0 {
-                for _ in 1..=overage {
-                    if let Some(last) = bsos.valid.pop() {
-                        bsos.invalid.insert(last.id, "retry bso".to_string());
+            if state.strict_payload_envelopes && ENVELOPE_COLLECTIONS.contains(&collection.as_str())
+            {
+                let metrics = metrics::Metrics::from(&req);
+                for bso in std::mem::take(&mut bsos.valid) {
+                    match bso.payload.as_deref().map(validate_payload_envelope) {
+                        Some(Err(reason)) => {
+                            metrics.incr("request.error.invalid_payload_envelope");
+                            bsos.invalid.insert(bso.id, reason);
+                        }
+                        _ => bsos.valid.push(bso),
                     }
                 }
             }
@@ -855,6 +1216,7 @@ impl FromRequest for CollectionPostRequest {
                 batch: batch.opt,
                 metrics: metrics::Metrics::from(&req),
                 quota_enabled: state.quota_enabled,
+                limits,
             })
         })
     }
@@ -909,6 +1271,8 @@ pub struct BsoPutRequest {
     pub bso: String,
     pub body: BsoBody,
     pub metrics: metrics::Metrics,
+    pub quota_enabled: bool,
+    pub limits: Arc<ServerLimits>,
 }
 
 impl FromRequest for BsoPutRequest {
@@ -926,8 +1290,23 @@ impl FromRequest for BsoPutRequest {
             let collection = CollectionParam::from_request(&req, &mut payload).await?;
             let query = BsoQueryParams::from_request(&req, &mut payload).await?;
             let bso = BsoParam::from_request(&req, &mut payload).await?;
+            let limits = UserLimits::from_request(&req, &mut payload).await?.0;
             let body = BsoBody::from_request(&req, &mut payload).await?;
             let tags = Tags::from_request(&req, &mut payload).await?;
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("app_data".to_owned()),
+                        Some(tags),
+                        None,
+                    )
+                    .into());
+                }
+            };
 
             let collection = collection.collection;
             if collection == "crypto" {
@@ -945,6 +1324,20 @@ impl FromRequest for BsoPutRequest {
                     }
                 }
             }
+            if state.strict_payload_envelopes && ENVELOPE_COLLECTIONS.contains(&collection.as_str())
+            {
+                if let Some(Err(reason)) = body.payload.as_deref().map(validate_payload_envelope) {
+                    metrics.incr("request.error.invalid_payload_envelope");
+                    return Err(ValidationErrorKind::FromDetails(
+                        reason,
+                        RequestErrorLocation::Body,
+                        Some("bso".to_owned()),
+                        Some(tags),
+                        label!("request.process.invalid_payload_envelope"),
+                    )
+                    .into());
+                }
+            }
             Ok(BsoPutRequest {
                 collection,
                 user_id,
@@ -952,6 +1345,8 @@ impl FromRequest for BsoPutRequest {
                 bso: bso.bso,
                 body,
                 metrics,
+                quota_enabled: state.quota_enabled,
+                limits,
             })
         }
         .boxed_local()
@@ -1031,6 +1426,51 @@ impl FromRequest for TestErrorRequest {
     }
 }
 
+/// An authenticated admin API request.
+///
+/// The admin API is disabled unless an `admin_secret` is configured, which
+/// requests must supply as an `Authorization: Bearer` token.
+#[derive(Debug)]
+pub struct AdminRequest {
+    pub metrics: metrics::Metrics,
+}
+
+impl FromRequest for AdminRequest {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let metrics = metrics::Metrics::from(req);
+        let state = match req.app_data::<Data<ServerState>>() {
+            Some(s) => s,
+            None => {
+                error!("⚠️ Could not load the app state");
+                return Box::pin(future::err(
+                    ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("state".to_owned()),
+                        None,
+                        None,
+                    )
+                    .into(),
+                ));
+            }
+        };
+        let result = match &state.admin_secret {
+            Some(secret) => verify_admin_token(auth_header(req).as_deref(), secret),
+            None => Err(HawkErrorKind::InvalidAdminToken.into()),
+        };
+        if let Err(e) = result {
+            warn!("⚠️ Rejected admin request: {}", e);
+            metrics.incr("request.error.admin_auth");
+            return Box::pin(future::err(e.into()));
+        }
+        Box::pin(future::ok(AdminRequest { metrics }))
+    }
+}
+
 /// Extract a user-identifier from the authentication token and validate against the URL
 ///
 /// This token should be adapted as needed for the storage system to store data
@@ -1125,24 +1565,65 @@ impl HawkIdentifier {
             .ok_or_else(|| -> ApiError { HawkErrorKind::MissingHeader.into() })?
             .to_str()
             .map_err(|e| -> ApiError { HawkErrorKind::Header(e).into() })?;
-        let identifier = Self::generate(&state.secrets, method, auth_header, ci, uri, tags)?;
+        let (identifier, token) = Self::generate(state, method, auth_header, ci, uri, tags)?;
         msg.extensions_mut().insert(identifier.clone());
+        msg.extensions_mut().insert(token);
         Ok(identifier)
     }
 
+    /// Authenticate the request, returning the user's id and the token they
+    /// authenticated with
     pub fn generate(
-        secrets: &Secrets,
+        state: &ServerState,
         method: &str,
         header: &str,
         connection_info: &ConnectionInfo,
         uri: &Uri,
         tags: Option<Tags>,
-    ) -> Result<Self, Error> {
-        let payload =
-            HawkPayload::extrude(header, method, secrets, connection_info, uri, tags.clone())?;
+    ) -> Result<(Self, TokenInfo), Error> {
+        let (user_id, token) = if header.starts_with("Bearer ") {
+            let claims = JwtClaims::extrude(header, state)?;
+            let user_id = HawkIdentifier {
+                legacy_id: claims.uid,
+                fxa_uid: claims.fxa_uid,
+                fxa_kid: claims.fxa_kid,
+            };
+            let token = TokenInfo {
+                device_id: String::new(),
+                issued: claims.iat,
+                expires: claims.exp,
+            };
+            (user_id, token)
+        } else {
+            let payload =
+                HawkPayload::extrude(header, method, state, connection_info, uri, tags.clone())?;
+            let user_id = HawkIdentifier {
+                legacy_id: payload.user_id,
+                fxa_uid: payload.fxa_uid,
+                fxa_kid: payload.fxa_kid,
+            };
+            let expires = payload.expires.round() as u64;
+            let token = TokenInfo {
+                device_id: payload.device_id,
+                issued: Some(state.revocations.hawk_issued(expires)),
+                expires,
+            };
+            (user_id, token)
+        };
+        if state.revocations.is_revoked(
+            user_id.legacy_id,
+            &user_id.fxa_uid,
+            &token.device_id,
+            token.issued,
+        ) {
+            warn!("⚠️ Revoked token: {:?}", user_id);
+            metrics::Metrics::from(state)
+                .incr_with_tags("request.error.revoked_token", tags.clone());
+            return Err(ApiError::from(HawkErrorKind::Revoked).into());
+        }
         let puid = Self::uid_from_path(&uri, tags.clone())?;
-        if payload.user_id != puid {
-            warn!("⚠️ Hawk UID not in URI: {:?} {:?}", payload.user_id, uri);
+        if user_id.legacy_id != puid {
+            warn!("⚠️ Hawk UID not in URI: {:?} {:?}", user_id.legacy_id, uri);
             Err(ValidationErrorKind::FromDetails(
                 "conflicts with payload".to_owned(),
                 RequestErrorLocation::Path,
@@ -1151,13 +1632,7 @@ impl HawkIdentifier {
                 label!("request.validate.hawk.uri_missing_uid"),
             ))?;
         }
-
-        let user_id = HawkIdentifier {
-            legacy_id: payload.user_id,
-            fxa_uid: payload.fxa_uid,
-            fxa_kid: payload.fxa_kid,
-        };
-        Ok(user_id)
+        Ok((user_id, token))
     }
 }
 
@@ -1224,13 +1699,6 @@ impl ToString for Offset {
 impl FromStr for Offset {
     type Err = ParseIntError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // issue559: Disable ':' support for now: simply parse as i64 as
-        // previously (it was u64 previously but i64's close enough)
-        let result = Offset {
-            timestamp: None,
-            offset: s.parse::<u64>()?,
-        };
-        /*
         let result = match s.chars().position(|c| c == ':') {
             None => Offset {
                 timestamp: None,
@@ -1247,7 +1715,6 @@ impl FromStr for Offset {
                 }
             }
         };
-        */
         Ok(result)
     }
 }
@@ -1274,8 +1741,9 @@ pub struct BsoQueryParams {
     /// maximum number of items to return (integer)
     pub limit: Option<u32>,
 
-    /// position at which to restart search (string)
-    #[serde(deserialize_with = "deserialize_offset")]
+    /// position at which to restart search (decoded from the opaque `offset`
+    /// token issued for the previous page)
+    #[serde(skip_deserializing)]
     pub offset: Option<Offset>,
 
     /// a comma-separated list of BSO ids (list of strings)
@@ -1286,6 +1754,43 @@ pub struct BsoQueryParams {
     // flag, whether to include full bodies (bool)
     #[serde(deserialize_with = "deserialize_present_value")]
     pub full: bool,
+
+    /// list the ids of BSOs deleted or expired after this time instead
+    #[serde(deserialize_with = "deserialize_sync_timestamp")]
+    pub deleted_since: Option<SyncTimestamp>,
+
+    /// the user and collection the query is against (from the path)
+    #[serde(skip_deserializing)]
+    pub scope: String,
+}
+
+/// The opaque pagination token of a `BsoQueryParams` query string.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct OffsetParam {
+    offset: Option<String>,
+}
+
+impl BsoQueryParams {
+    /// The parameters a pagination token is bound to: a token may only be
+    /// used to continue the query that issued it.
+    fn shape(&self) -> String {
+        let as_ms = |ts: Option<SyncTimestamp>| ts.map(|ts| ts.as_i64().to_string());
+        format!(
+            "{}\n{:?}\n{}\n{}\n{}\n{}",
+            self.scope,
+            self.sort,
+            as_ms(self.newer).unwrap_or_default(),
+            as_ms(self.older).unwrap_or_default(),
+            self.ids.join(","),
+            as_ms(self.deleted_since).unwrap_or_default()
+        )
+    }
+
+    /// Sign the next `offset` of this query as an opaque pagination token.
+    pub fn offset_token(&self, offset: &str, secrets: &Secrets) -> ApiResult<String> {
+        sign_offset(&self.shape(), offset, secrets)
+    }
 }
 
 impl FromRequest for BsoQueryParams {
@@ -1300,7 +1805,7 @@ impl FromRequest for BsoQueryParams {
         Box::pin(async move {
             let tags = Tags::from_request(&req, &mut payload).await?;
 
-            let params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
+            let mut params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
                 .map_err(|e| {
                     ValidationErrorKind::FromDetails(
                         e.to_string(),
@@ -1312,6 +1817,12 @@ impl FromRequest for BsoQueryParams {
                 })
                 .await?
                 .into_inner();
+            let path = req.match_info();
+            params.scope = format!(
+                "{}\n{}",
+                path.get("uid").unwrap_or_default(),
+                path.get("collection").unwrap_or_default()
+            );
             params.validate().map_err(|e| {
                 ValidationErrorKind::FromValidationErrors(
                     e,
@@ -1320,36 +1831,79 @@ impl FromRequest for BsoQueryParams {
                     None,
                 )
             })?;
-            // issue559: Dead code (timestamp always None)
-            /*
-               if params.sort != Sorting::Index {
-                   if let Some(timestamp) = params.offset.as_ref().and_then(|offset| offset.timestamp)
-                   {
-                       let bound = timestamp.as_i64();
-                       if let Some(newer) = params.newer {
-                           if bound < newer.as_i64() {
-                               return Err(ValidationErrorKind::FromDetails(
-                                   format!("Invalid Offset {} {}", bound, newer.as_i64()),
-                                   RequestErrorLocation::QueryString,
-                                   Some("newer".to_owned()),
-                                   None,
-                               )
-                               .into());
-                           }
-                       } else if let Some(older) = params.older {
-                           if bound > older.as_i64() {
-                               return Err(ValidationErrorKind::FromDetails(
-                                   "Invalid Offset".to_owned(),
-                                   RequestErrorLocation::QueryString,
-                                   Some("older".to_owned()),
-                                   None,
-                               )
-                               .into());
-                           }
-                       }
-                   }
-               }
-            */
+            let token = Query::<OffsetParam>::from_request(&req, &mut payload)
+                .await
+                .map(|param| param.into_inner().offset)
+                .unwrap_or_default();
+            if let Some(token) = token {
+                let state = match req.app_data::<Data<ServerState>>() {
+                    Some(s) => s,
+                    None => {
+                        error!("⚠️ Could not load the app state");
+                        return Err(ValidationErrorKind::FromDetails(
+                            "Internal error".to_owned(),
+                            RequestErrorLocation::Unknown,
+                            Some("state".to_owned()),
+                            Some(tags),
+                            None,
+                        )
+                        .into());
+                    }
+                };
+                // Reject tokens that weren't issued by us for this query
+                let offset = verify_offset(&params.shape(), &token, &state.secrets)
+                    .and_then(|offset| Offset::from_str(&offset).ok());
+                if offset.is_none() {
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Invalid Offset".to_owned(),
+                        RequestErrorLocation::QueryString,
+                        Some("offset".to_owned()),
+                        Some(tags),
+                        label!("request.validate.bso_query.invalid_offset"),
+                    )
+                    .into());
+                }
+                params.offset = offset;
+            }
+            if let Some(timestamp) = params.offset.as_ref().and_then(|offset| offset.timestamp) {
+                // Timestamp offsets are only issued for (and only meaningful
+                // with) modified time ordering
+                if ![Sorting::Newest, Sorting::Oldest].contains(&params.sort) {
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Invalid Offset".to_owned(),
+                        RequestErrorLocation::QueryString,
+                        Some("offset".to_owned()),
+                        Some(tags),
+                        label!("request.validate.bso_query.invalid_offset"),
+                    )
+                    .into());
+                }
+                let bound = timestamp.as_i64();
+                if let Some(newer) = params.newer {
+                    if bound < newer.as_i64() {
+                        return Err(ValidationErrorKind::FromDetails(
+                            format!("Invalid Offset {} {}", bound, newer.as_i64()),
+                            RequestErrorLocation::QueryString,
+                            Some("newer".to_owned()),
+                            Some(tags),
+                            label!("request.validate.bso_query.newer"),
+                        )
+                        .into());
+                    }
+                }
+                if let Some(older) = params.older {
+                    if bound > older.as_i64() {
+                        return Err(ValidationErrorKind::FromDetails(
+                            "Invalid Offset".to_owned(),
+                            RequestErrorLocation::QueryString,
+                            Some("older".to_owned()),
+                            Some(tags),
+                            label!("request.validate.bso_query.older"),
+                        )
+                        .into());
+                    }
+                }
+            }
             Ok(params)
         })
     }
@@ -1398,22 +1952,7 @@ impl FromRequest for BatchRequestOpt {
                 })
                 .await?
                 .into_inner();
-            let state = match req.app_data::<Data<ServerState>>() {
-                Some(s) => s,
-                None => {
-                    error!("⚠️ Could not load the app state");
-                    return Err(ValidationErrorKind::FromDetails(
-                        "Internal error".to_owned(),
-                        RequestErrorLocation::Unknown,
-                        Some("state".to_owned()),
-                        Some(tags),
-                        None,
-                    )
-                    .into());
-                }
-            };
-
-            let limits = &state.limits;
+            let limits = UserLimits::from_request(&req, &mut payload).await?.0;
 
             let checks = [
                 (X_WEAVE_RECORDS, limits.max_post_records),
@@ -1519,25 +2058,120 @@ impl FromRequest for BatchRequestOpt {
 /// PreCondition Header
 ///
 /// It's valid to include a X-If-Modified-Since or X-If-Unmodified-Since header but not
-/// both.
+/// both. Likewise for the standard If-Match and If-None-Match headers, which take
+/// precedence over the former.
 ///
 /// Used with Option<PreConditionHeader> to extract a possible PreConditionHeader.
 #[derive(Debug, Clone, PartialEq)]
 pub enum PreConditionHeader {
     IfModifiedSince(SyncTimestamp),
     IfUnmodifiedSince(SyncTimestamp),
+    IfMatch(ETagMatch),
+    IfNoneMatch(ETagMatch),
     NoHeader,
 }
 
+/// The entity tags listed in an If-Match or If-None-Match header
+#[derive(Debug, Clone, PartialEq)]
+pub enum ETagMatch {
+    /// `*`, matching any existing resource
+    Any,
+    Tags(Vec<String>),
+}
+
+impl ETagMatch {
+    fn parse(value: &str) -> Self {
+        if value.trim() == "*" {
+            return ETagMatch::Any;
+        }
+        ETagMatch::Tags(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(ToOwned::to_owned)
+                .collect(),
+        )
+    }
+
+    /// Whether the resource last modified at `resource_ts` (0 when it doesn't
+    /// exist) matches.
+    ///
+    /// Our (weak) tags name a version of the resource, so they're compared
+    /// with the weak comparison function for If-Match too: the strong one
+    /// would never match them, leaving no lost-update protection.
+    pub fn matches(&self, resource_ts: SyncTimestamp) -> bool {
+        if resource_ts.as_i64() == 0 {
+            return false;
+        }
+        let etag = resource_ts.as_etag();
+        let opaque = |tag: &str| tag.trim_start_matches("W/").to_owned();
+        match self {
+            ETagMatch::Any => true,
+            ETagMatch::Tags(tags) => tags.iter().any(|tag| opaque(tag) == opaque(&etag)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PreConditionHeaderOpt {
     pub opt: Option<PreConditionHeader>,
 }
 
-impl PreConditionHeaderOpt {
-    pub fn extrude(headers: &HeaderMap, tags: Option<Tags>) -> Result<Self, Error> {
-        let modified = headers.get("X-If-Modified-Since");
-        let unmodified = headers.get("X-If-Unmodified-Since");
+impl PreConditionHeaderOpt {
+    pub fn extrude(headers: &HeaderMap, tags: Option<Tags>) -> Result<Self, Error> {
+        let if_match = headers.get(IF_MATCH);
+        let if_none_match = headers.get(IF_NONE_MATCH);
+        if if_match.is_some() && if_none_match.is_some() {
+            return Err(ValidationErrorKind::FromDetails(
+                "conflicts with If-Match".to_owned(),
+                RequestErrorLocation::Header,
+                Some("If-None-Match".to_owned()),
+                tags,
+                label!("request.validate.etag_header.conflict"),
+            )
+            .into());
+        }
+        let modified = headers.get("X-If-Modified-Since");
+        let unmodified = headers.get("X-If-Unmodified-Since");
+        if let Some(field_name) = if_match
+            .map(|_| "If-Match")
+            .or_else(|| if_none_match.map(|_| "If-None-Match"))
+        {
+            if modified.is_some() || unmodified.is_some() {
+                return Err(ValidationErrorKind::FromDetails(
+                    "conflicts with X-If-Modified-Since/X-If-Unmodified-Since".to_owned(),
+                    RequestErrorLocation::Header,
+                    Some(field_name.to_owned()),
+                    tags,
+                    label!("request.validate.etag_header.conflict"),
+                )
+                .into());
+            }
+        }
+        if let Some((value, field_name)) = if_match
+            .map(|value| (value, "If-Match"))
+            .or_else(|| if_none_match.map(|value| (value, "If-None-Match")))
+        {
+            let etags = value.to_str().map(ETagMatch::parse).map_err(|e| {
+                let err: ApiError = ValidationErrorKind::FromDetails(
+                    e.to_string(),
+                    RequestErrorLocation::Header,
+                    Some(field_name.to_owned()),
+                    tags.clone(),
+                    label!("request.validate.etag_header.invalid"),
+                )
+                .into();
+                err
+            })?;
+            let header = if field_name == "If-Match" {
+                PreConditionHeader::IfMatch(etags)
+            } else {
+                PreConditionHeader::IfNoneMatch(etags)
+            };
+            return Ok(Self { opt: Some(header) });
+        }
+
         if modified.is_some() && unmodified.is_some() {
             // TODO: See following error,
             return Err(ValidationErrorKind::FromDetails(
@@ -1700,6 +2334,49 @@ fn validate_body_bso_ttl(ttl: u32) -> Result<(), ValidationError> {
     Ok(())
 }
 
+/// Validate the expected modified timestamp of a BSO in a POST
+fn validate_body_bso_modified(modified: f64) -> Result<(), ValidationError> {
+    if modified < 0.0 {
+        return Err(request_error(
+            "Invalid modified timestamp",
+            RequestErrorLocation::Body,
+        ));
+    }
+    Ok(())
+}
+
+/// Verifies a payload is a Sync crypto envelope: a JSON object with a base64
+/// AES-256-CBC `ciphertext` and `IV`, and the hex HMAC-SHA256 `hmac` of the
+/// ciphertext. Returns the reason it isn't
+fn validate_payload_envelope(payload: &str) -> Result<(), String> {
+    let envelope: Value =
+        serde_json::from_str(payload).map_err(|_| "invalid payload: not json".to_owned())?;
+    let field = |name: &str| {
+        envelope
+            .get(name)
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("invalid payload: missing {}", name))
+    };
+    let base64_field = |name: &str| {
+        field(name).and_then(|value| {
+            base64::decode(value).map_err(|_| format!("invalid payload: {} isn't base64", name))
+        })
+    };
+
+    let ciphertext = base64_field("ciphertext")?;
+    if ciphertext.is_empty() || ciphertext.len() % ENVELOPE_BLOCK_SIZE != 0 {
+        return Err("invalid payload: invalid ciphertext length".to_owned());
+    }
+    if base64_field("IV")?.len() != ENVELOPE_BLOCK_SIZE {
+        return Err("invalid payload: invalid IV length".to_owned());
+    }
+    let hmac = field("hmac")?;
+    if hmac.len() != ENVELOPE_HMAC_LEN || !hmac.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("invalid payload: hmac isn't a hex SHA-256".to_owned());
+    }
+    Ok(())
+}
+
 /// Deserialize a comma separated string
 fn deserialize_comma_sep_string<'de, D, E>(deserializer: D) -> Result<Vec<E>, D::Error>
 where
@@ -1745,17 +2422,6 @@ where
     }
 }
 
-fn deserialize_offset<'de, D>(deserializer: D) -> Result<Option<Offset>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let maybe_str: Option<String> = Deserialize::deserialize(deserializer)?;
-    if let Some(val) = maybe_str {
-        return Ok(Some(Offset::from_str(&val).map_err(SerdeError::custom)?));
-    }
-    Ok(None)
-}
-
 // Tokenserver extractor
 #[derive(Debug, Default, Clone, Deserialize)]
 pub struct TokenServerRequest {
@@ -1776,11 +2442,13 @@ impl FromRequest for TokenServerRequest {
 #[cfg(test)]
 mod tests {
     use actix_http::h1;
+    use chrono::offset::Utc;
     use futures::executor::block_on;
 
     use super::*;
 
     use std::sync::Arc;
+    use std::time::Duration;
 
     use actix_web::{
         dev::ServiceResponse,
@@ -1801,10 +2469,12 @@ mod tests {
         mock::{MockDb, MockDbPool},
         Db,
     };
-    use crate::server::{metrics, ServerState};
-    use crate::settings::{Secrets, ServerLimits, Settings};
+    use crate::server::{
+        metrics, notifications::LocalBroker, user_limits::UserLimitsCache, ServerState,
+    };
+    use crate::settings::{LimitOverrides, PayloadHashMode, Secrets, ServerLimits, Settings};
 
-    use crate::web::auth::{hkdf_expand_32, HawkPayload};
+    use crate::web::auth::{hkdf_expand_32, HawkPayload, NonceCache, RevocationStore};
 
     lazy_static! {
         static ref SERVER_LIMITS: Arc<ServerLimits> = Arc::new(ServerLimits::default());
@@ -1830,10 +2500,33 @@ mod tests {
             db_pool: Box::new(MockDbPool::new()),
             limits: Arc::clone(&SERVER_LIMITS),
             limits_json: serde_json::to_string(&**SERVER_LIMITS).unwrap(),
+            user_limits: Arc::new(UserLimitsCache::new(Duration::from_secs(
+                settings.user_limits_cache_ttl.into(),
+            ))),
             secrets: Arc::clone(&SECRETS),
+            hawk_timestamp_skew: Duration::from_secs(settings.hawk_timestamp_skew.into()),
+            nonce_cache: Arc::new(NonceCache::new(
+                settings.hawk_nonce_cache_size as usize,
+                Duration::from_secs(settings.hawk_timestamp_skew.into()),
+            )),
+            hawk_payload_hash: settings.hawk_payload_hash,
+            jwt_secret: None,
+            revocations: Arc::new(RevocationStore::new(Duration::from_secs(
+                settings.hawk_token_duration.into(),
+            ))),
+            admin_secret: None,
             port: 8000,
             metrics: Box::new(metrics::metrics_from_opts(&settings).unwrap()),
             quota_enabled: settings.enable_quota,
+            strict_payload_envelopes: settings.strict_payload_envelopes,
+            deleted_bso_retention: settings.deleted_bso_retention,
+            deleted_storage_grace_period: settings.deleted_storage_grace_period,
+            audit_log_retention: settings.audit_log_retention,
+            broker: Arc::new(LocalBroker::new()),
+            max_info_collections_wait: Duration::from_secs(
+                settings.max_info_collections_wait.into(),
+            ),
+            events: None,
         }
     }
 
@@ -1874,26 +2567,52 @@ mod tests {
         format!("Hawk {}", request.make_header(&credentials).unwrap())
     }
 
+    fn create_valid_jwt(claims: &serde_json::Value, secret: &[u8]) -> String {
+        let header = base64::encode_config(r#"{"alg":"HS256"}"#, base64::URL_SAFE_NO_PAD);
+        let claims = base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD);
+        let signing_input = format!("{}.{}", header, claims);
+        let mut hmac = Hmac::<Sha256>::new_varkey(secret).unwrap();
+        hmac.update(signing_input.as_bytes());
+        let signature =
+            base64::encode_config(hmac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        format!("{}.{}", signing_input, signature)
+    }
+
     async fn post_collection(
         qs: &str,
         body: &serde_json::Value,
+    ) -> Result<CollectionPostRequest, Error> {
+        post_collection_body(qs, "application/json; charset=UTF-8", &body.to_string()).await
+    }
+
+    async fn post_collection_body(
+        qs: &str,
+        content_type: &str,
+        bod_str: &str,
+    ) -> Result<CollectionPostRequest, Error> {
+        post_collection_with_state(make_state(), qs, content_type, bod_str).await
+    }
+
+    async fn post_collection_with_state(
+        state: ServerState,
+        qs: &str,
+        content_type: &str,
+        bod_str: &str,
     ) -> Result<CollectionPostRequest, Error> {
         let payload = HawkPayload::test_default(*USER_ID);
-        let state = make_state();
         let path = format!(
             "/1.5/{}/storage/tabs{}{}",
             *USER_ID,
             if !qs.is_empty() { "?" } else { "" },
             qs
         );
-        let bod_str = body.to_string();
         let header =
             create_valid_hawk_header(&payload, &state, "POST", &path, TEST_HOST, TEST_PORT);
         let req = TestRequest::with_uri(&format!("http://{}:{}{}", TEST_HOST, TEST_PORT, path))
             .data(state)
             .method(Method::POST)
             .header("authorization", header)
-            .header("content-type", "application/json; charset=UTF-8")
+            .header("content-type", content_type)
             .header("accept", "application/json;q=0.9,/;q=0.2")
             .set_payload(bod_str.to_owned())
             .param("uid", &USER_ID_STR)
@@ -1901,11 +2620,50 @@ mod tests {
             .to_http_request();
         req.extensions_mut().insert(make_db());
 
-        // Not sure why but sending req through *::extract loses the body.
-        // Compose a payload here and call the *::from_request
-        let (_sender, mut payload) = h1::Payload::create(true);
-        payload.unread_data(bytes::Bytes::from(bod_str.to_owned()));
-        CollectionPostRequest::from_request(&req, &mut payload.into()).await
+        CollectionPostRequest::from_request(&req, &mut make_payload(bod_str)).await
+    }
+
+    /// Not sure why but sending req through *::extract loses the body.
+    /// Compose a payload here (fed in small chunks, so records span
+    /// chunks) for calling the *::from_request
+    fn make_payload(bod_str: &str) -> Payload {
+        let (mut sender, payload) = h1::Payload::create(false);
+        for chunk in bod_str.as_bytes().chunks(7) {
+            sender.feed_data(bytes::Bytes::copy_from_slice(chunk));
+        }
+        sender.feed_eof();
+        payload.into()
+    }
+
+    /// A request to store into the user's tabs collection, for a server
+    /// whose database has the given overrides of the user's limits
+    fn make_store_request(
+        method: Method,
+        path: &str,
+        content_type: &str,
+        overrides: Option<LimitOverrides>,
+    ) -> HttpRequest {
+        let mut state = make_state();
+        state.db_pool = Box::new(MockDbPool::with_user_limits(Some(overrides)));
+        let payload = HawkPayload::test_default(*USER_ID);
+        let header = create_valid_hawk_header(
+            &payload,
+            &state,
+            method.as_str(),
+            path,
+            TEST_HOST,
+            TEST_PORT,
+        );
+        let req = TestRequest::with_uri(&format!("http://{}:{}{}", TEST_HOST, TEST_PORT, path))
+            .data(state)
+            .method(method)
+            .header("authorization", header)
+            .header("content-type", content_type)
+            .param("uid", &USER_ID_STR)
+            .param("collection", "tabs")
+            .to_http_request();
+        req.extensions_mut().insert(make_db());
+        req
     }
 
     #[test]
@@ -1984,6 +2742,105 @@ mod tests {
         assert_eq!(result.full, true);
     }
 
+    #[test]
+    fn test_offset_query_args() {
+        // Tokens are signed for the shape of the query they continue
+        let query = |uri: &str, offset: &str| {
+            let req = TestRequest::with_uri(uri)
+                .data(make_state())
+                .to_http_request();
+            let params = block_on(BsoQueryParams::extract(&req)).unwrap();
+            let token = params.offset_token(offset, &SECRETS).unwrap();
+            let uri = format!("{}&offset={}", uri, token);
+            let req = TestRequest::with_uri(&uri)
+                .data(make_state())
+                .to_http_request();
+            block_on(BsoQueryParams::extract(&req))
+        };
+
+        let result = query("/?sort=newest&newer=1.5", "2000:3").unwrap();
+        let offset = result.offset.unwrap();
+        assert_eq!(offset.timestamp, Some(SyncTimestamp::from_seconds(2.0)));
+        assert_eq!(offset.offset, 3);
+        assert_eq!(offset.to_string(), "2000:3");
+
+        let result = query("/?sort=index&ids=1,2", "10").unwrap();
+        assert_eq!(result.offset.unwrap().offset, 10);
+
+        for (uri, offset) in &[
+            ("/?sort=index", "2000:3"),
+            ("/?full=1", "2000:3"),
+            ("/?sort=newest&newer=2.5", "2000:3"),
+            ("/?sort=oldest&older=1.5", "2000:3"),
+        ] {
+            let result = query(uri, offset);
+            assert!(result.is_err(), "{}", uri);
+            let response: HttpResponse = result.err().unwrap().into();
+            assert_eq!(response.status(), 400);
+        }
+    }
+
+    #[test]
+    fn test_invalid_offset_token() {
+        let req = TestRequest::with_uri("/?sort=newest")
+            .data(make_state())
+            .to_http_request();
+        let params = block_on(BsoQueryParams::extract(&req)).unwrap();
+        let token = params.offset_token("2000:3", &SECRETS).unwrap();
+
+        for uri in &[
+            // Raw offsets
+            "/?sort=newest&offset=2000:3".to_owned(),
+            "/?sort=newest&offset=10".to_owned(),
+            // Reused against a different query
+            format!("/?sort=oldest&offset={}", token),
+            format!("/?sort=newest&newer=1.5&offset={}", token),
+            format!("/?sort=newest&ids=1&offset={}", token),
+            // Tampered with
+            format!("/?sort=newest&offset=MTA{}", token),
+        ] {
+            let req = TestRequest::with_uri(uri)
+                .data(make_state())
+                .to_http_request();
+            let result = block_on(BsoQueryParams::extract(&req));
+            assert!(result.is_err(), "{}", uri);
+            let response: HttpResponse = result.err().unwrap().into();
+            assert_eq!(response.status(), 400);
+        }
+
+        let req = TestRequest::with_uri(&format!("/?sort=newest&limit=5&offset={}", token))
+            .data(make_state())
+            .to_http_request();
+        let result = block_on(BsoQueryParams::extract(&req)).unwrap();
+        assert_eq!(result.offset.unwrap().to_string(), "2000:3");
+    }
+
+    #[test]
+    fn test_replayed_offset_token() {
+        let extract = |uid: &'static str, collection: &'static str, uri: &str| {
+            let req = TestRequest::with_uri(uri)
+                .data(make_state())
+                .param("uid", uid)
+                .param("collection", collection)
+                .to_http_request();
+            block_on(BsoQueryParams::extract(&req))
+        };
+        let params = extract(&USER_ID_STR, "tabs", "/?sort=newest").unwrap();
+        let token = params.offset_token("2000:3", &SECRETS).unwrap();
+        let uri = format!("/?sort=newest&offset={}", token);
+
+        // Replayed against another user's or collection's query
+        for (uid, collection) in &[("1", "tabs"), (USER_ID_STR.as_str(), "bookmarks")] {
+            let result = extract(uid, collection, &uri);
+            assert!(result.is_err(), "{} {}", uid, collection);
+            let response: HttpResponse = result.err().unwrap().into();
+            assert_eq!(response.status(), 400);
+        }
+
+        let result = extract(&USER_ID_STR, "tabs", &uri).unwrap();
+        assert_eq!(result.offset.unwrap().to_string(), "2000:3");
+    }
+
     #[test]
     fn test_valid_bso_request() {
         let payload = HawkPayload::test_default(*USER_ID);
@@ -2070,6 +2927,42 @@ mod tests {
         assert_eq!(result.body.payload, Some("x".to_string()));
     }
 
+    #[test]
+    fn test_bso_post_body_payload_hash_mismatch() {
+        let payload = HawkPayload::test_default(*USER_ID);
+        let mut state = make_state();
+        state.hawk_payload_hash = PayloadHashMode::Optional;
+        let uri = format!("/1.5/{}/storage/tabs/asdf", *USER_ID);
+        // The header's hash attribute doesn't match the body
+        let header = create_valid_hawk_header(&payload, &state, "POST", &uri, TEST_HOST, TEST_PORT);
+        let bso_body = json!({
+            "id": "128", "payload": "x"
+        });
+        let req = TestRequest::with_uri(&uri)
+            .data(state)
+            .header("authorization", header)
+            .header("content-type", "application/json")
+            .method(Method::POST)
+            .set_payload(bso_body.to_string())
+            .param("uid", &USER_ID_STR)
+            .param("collection", "tabs")
+            .param("bso", "asdf")
+            .to_http_request();
+        req.extensions_mut().insert(make_db());
+        let (_sender, mut payload) = h1::Payload::create(true);
+        payload.unread_data(bytes::Bytes::from(bso_body.to_string()));
+        let result = block_on(BsoPutRequest::from_request(&req, &mut payload.into()));
+        let response: HttpResponse = result
+            .err()
+            .expect("Could not get response in test_bso_post_body_payload_hash_mismatch")
+            .into();
+        assert_eq!(response.status(), 401);
+        assert_eq!(
+            response.headers().get("WWW-Authenticate").unwrap(),
+            "Hawk error=\"Payload hash mismatch\""
+        );
+    }
+
     #[test]
     fn test_invalid_bso_post_body() {
         let payload = HawkPayload::test_default(*USER_ID);
@@ -2129,6 +3022,55 @@ mod tests {
         assert_eq!(&result.collection, "tabs");
     }
 
+    #[test]
+    fn test_bearer_collection_request() {
+        let bearer_state = || {
+            let mut state = make_state();
+            state.jwt_secret = Some(Arc::new(b"jwt secret".to_vec()));
+            state
+        };
+        let uri = format!("/1.5/{}/storage/tabs", *USER_ID);
+        let make_request = |state: ServerState, claims: serde_json::Value| {
+            let header = format!("Bearer {}", create_valid_jwt(&claims, b"jwt secret"));
+            let req = TestRequest::with_uri(&uri)
+                .data(state)
+                .header("authorization", header)
+                .method(Method::GET)
+                .param("uid", &USER_ID_STR)
+                .param("collection", "tabs")
+                .to_http_request();
+            req.extensions_mut().insert(make_db());
+            req
+        };
+        let exp = Utc::now().timestamp() + 300;
+
+        let req = make_request(
+            bearer_state(),
+            json!({"exp": exp, "uid": *USER_ID, "fxa_uid": "foo", "fxa_kid": "bar"}),
+        );
+        let result = block_on(CollectionRequest::extract(&req))
+            .expect("Could not get result in test_bearer_collection_request");
+        assert_eq!(result.user_id.legacy_id, *USER_ID);
+        assert_eq!(&result.user_id.fxa_uid, "foo");
+        assert_eq!(&result.user_id.fxa_kid, "bar");
+
+        // The uid in the path is checked against the claims
+        let req = make_request(bearer_state(), json!({"exp": exp, "uid": *USER_ID + 1}));
+        let response: HttpResponse = block_on(CollectionRequest::extract(&req))
+            .err()
+            .unwrap()
+            .into();
+        assert_eq!(response.status(), 400);
+
+        // Bearer tokens are rejected unless enabled
+        let req = make_request(make_state(), json!({"exp": exp, "uid": *USER_ID}));
+        let response: HttpResponse = block_on(CollectionRequest::extract(&req))
+            .err()
+            .unwrap()
+            .into();
+        assert_eq!(response.status(), 401);
+    }
+
     #[test]
     fn test_quoted_bso() {
         let payload = HawkPayload::test_default(*USER_ID);
@@ -2220,6 +3162,113 @@ mod tests {
         assert_eq!(result.bsos.invalid.len(), 2);
     }
 
+    #[actix_rt::test]
+    async fn test_newlines_collection_post_request() {
+        let bso_body = [
+            r#"{"id": "123", "payload": "xxx", "sortindex": 23}"#,
+            "not json",
+            "",
+            "[1]",
+            r#"{"payload": "xxx"}"#,
+            r#"{"id": "456", "sortindex": "high"}"#,
+            r#"{"id": "789", "payload": "xxxasdf"}"#,
+        ]
+        .join("\n");
+        let result = post_collection_body("", "application/newlines", &bso_body)
+            .await
+            .expect("Could not get result in test_newlines_collection_post_request");
+        let ids: Vec<_> = result.bsos.valid.iter().map(|b| b.id.as_str()).collect();
+        assert_eq!(ids, vec!["123", "789"]);
+        assert_eq!(result.bsos.invalid.len(), 4);
+        assert_eq!(result.bsos.invalid["line 2"], "invalid json");
+        assert_eq!(result.bsos.invalid["line 4"], "invalid json");
+        assert_eq!(result.bsos.invalid["line 5"], "missing id");
+        assert!(result.bsos.invalid.contains_key("456"));
+
+        // Duplicate ids fail the entire request
+        let bso_body = "{\"id\": \"1\"}\n{\"id\": \"1\"}\n";
+        assert!(post_collection_body("", "application/newlines", bso_body)
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_newlines_collection_post_request_limits() {
+        let max_post_records = SERVER_LIMITS.max_post_records as usize;
+        let bso_body: String = (0..=max_post_records)
+            .map(|i| format!("{{\"id\": \"{}\", \"payload\": \"x\"}}\n", i))
+            .collect();
+        let result = post_collection_body("", "application/newlines", &bso_body)
+            .await
+            .expect("Could not get result in test_newlines_collection_post_request_limits");
+        assert_eq!(result.bsos.valid.len(), max_post_records);
+        assert_eq!(result.bsos.invalid.len(), 1);
+        assert_eq!(
+            result.bsos.invalid[&max_post_records.to_string()],
+            "retry bso"
+        );
+
+        let payload = "x".repeat(SERVER_LIMITS.max_record_payload_bytes as usize + 1);
+        let bso_body = format!(
+            "{{\"id\": \"1\", \"payload\": \"x\"}}\n{{\"id\": \"2\", \"payload\": \"{}\"}}",
+            payload
+        );
+        let result = post_collection_body("", "application/newlines", &bso_body)
+            .await
+            .expect("Could not get result in test_newlines_collection_post_request_limits");
+        assert_eq!(result.bsos.valid.len(), 1);
+        assert_eq!(result.bsos.invalid["2"], "retry bytes");
+    }
+
+    #[actix_rt::test]
+    async fn test_bso_bodies_user_limits() {
+        let path = format!("/1.5/{}/storage/tabs", *USER_ID);
+        let bso_body =
+            json!([{"id": "1", "payload": "x"}, {"id": "2", "payload": "x"}]).to_string();
+        let overrides = LimitOverrides {
+            max_post_records: Some(1),
+            ..Default::default()
+        };
+
+        let req = make_store_request(Method::POST, &path, "application/json", None);
+        let bsos = BsoBodies::from_request(&req, &mut make_payload(&bso_body))
+            .await
+            .expect("Could not get result in test_bso_bodies_user_limits");
+        assert_eq!(bsos.valid.len(), 2);
+
+        let req = make_store_request(Method::POST, &path, "application/json", Some(overrides));
+        let bsos = BsoBodies::from_request(&req, &mut make_payload(&bso_body))
+            .await
+            .expect("Could not get result in test_bso_bodies_user_limits");
+        assert_eq!(bsos.valid.len(), 1);
+        assert_eq!(bsos.invalid["2"], "retry bso");
+    }
+
+    #[actix_rt::test]
+    async fn test_bso_body_user_limits() {
+        let path = format!("/1.5/{}/storage/tabs/asdf", *USER_ID);
+        let bso_body = json!({"payload": "xxx"}).to_string();
+        let overrides = LimitOverrides {
+            max_record_payload_bytes: Some(2),
+            ..Default::default()
+        };
+
+        let req = make_store_request(Method::PUT, &path, "application/json", None);
+        let bso = BsoBody::from_request(&req, &mut make_payload(&bso_body))
+            .await
+            .expect("Could not get result in test_bso_body_user_limits");
+        assert_eq!(bso.payload.as_deref(), Some("xxx"));
+
+        let req = make_store_request(Method::PUT, &path, "application/json", Some(overrides));
+        let response: HttpResponse = BsoBody::from_request(&req, &mut make_payload(&bso_body))
+            .await
+            .expect_err("Could not get response in test_bso_body_user_limits")
+            .into();
+        assert_eq!(response.status(), 400);
+        let body = test::read_body(ServiceResponse::new(req, response)).await;
+        assert_eq!(body, "8");
+    }
+
     #[actix_rt::test]
     async fn test_valid_collection_batch_post_request() {
         // If the "batch" parameter is has no value or has a value of "true"
@@ -2316,6 +3365,32 @@ mod tests {
             .header("X-If-Modified-Since", "-32.1")
             .to_http_request();
         assert_invalid_header(req, "X-If-Modified-Since", "Invalid value");
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("If-Match", "\"32.10\"")
+            .header("If-None-Match", "*")
+            .to_http_request();
+        assert_invalid_header(req, "If-None-Match", "conflicts with If-Match");
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("If-Match", "\"32.10\"")
+            .header("X-If-Unmodified-Since", "32.10")
+            .to_http_request();
+        assert_invalid_header(
+            req,
+            "If-Match",
+            "conflicts with X-If-Modified-Since/X-If-Unmodified-Since",
+        );
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("If-None-Match", "*")
+            .header("X-If-Modified-Since", "32.10")
+            .to_http_request();
+        assert_invalid_header(
+            req,
+            "If-None-Match",
+            "conflicts with X-If-Modified-Since/X-If-Unmodified-Since",
+        );
     }
 
     #[test]
@@ -2344,6 +3419,50 @@ mod tests {
             result,
             PreConditionHeader::IfUnmodifiedSince(SyncTimestamp::from_seconds(32.14))
         );
+        // If-None-Match takes precedence over X-If-Modified-Since
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("X-If-Modified-Since", "32.1")
+            .header("If-None-Match", "\"32.10\", W/\"32.14\"")
+            .to_http_request();
+        let result = PreConditionHeaderOpt::extrude(&req.headers(), None)
+            .unwrap()
+            .opt
+            .unwrap();
+        assert_eq!(
+            result,
+            PreConditionHeader::IfNoneMatch(ETagMatch::Tags(vec![
+                "\"32.10\"".to_owned(),
+                "W/\"32.14\"".to_owned()
+            ]))
+        );
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("If-Match", "*")
+            .to_http_request();
+        let result = PreConditionHeaderOpt::extrude(&req.headers(), None)
+            .unwrap()
+            .opt
+            .unwrap();
+        assert_eq!(result, PreConditionHeader::IfMatch(ETagMatch::Any));
+    }
+
+    #[test]
+    fn test_etag_match() {
+        let ts = SyncTimestamp::from_seconds(32.14);
+        assert_eq!(ts.as_etag(), "W/\"32.14\"");
+        let missing = SyncTimestamp::from_seconds(0.0);
+
+        assert!(ETagMatch::Any.matches(ts));
+        assert!(!ETagMatch::Any.matches(missing));
+
+        let etags = ETagMatch::parse("\"32.10\", W/\"32.14\"");
+        assert!(etags.matches(ts));
+        assert!(!etags.matches(SyncTimestamp::from_seconds(32.2)));
+        assert!(!etags.matches(missing));
+
+        // Compared by the version they name
+        assert!(ETagMatch::parse("\"32.14\"").matches(ts));
     }
 
     #[test]
@@ -2414,4 +3533,102 @@ mod tests {
         assert_eq!(result.bsos.invalid.len(), 1);
         assert!(result.bsos.invalid.contains_key("789"));
     }
+
+    #[test]
+    fn test_validate_payload_envelope() {
+        let envelope = |ciphertext: &str, iv: &str, hmac: &str| {
+            json!({"ciphertext": ciphertext, "IV": iv, "hmac": hmac}).to_string()
+        };
+        let ciphertext = base64::encode([0u8; 32]);
+        let iv = base64::encode([0u8; 16]);
+        let hmac = "0123456789abcdef".repeat(4);
+        assert!(validate_payload_envelope(&envelope(&ciphertext, &iv, &hmac)).is_ok());
+
+        let invalid = |payload: &str| validate_payload_envelope(payload).unwrap_err();
+        assert_eq!(invalid("plaintext"), "invalid payload: not json");
+        assert_eq!(
+            invalid(r#"{"title": "plaintext"}"#),
+            "invalid payload: missing ciphertext"
+        );
+        assert_eq!(
+            invalid(&envelope("not base64!", &iv, &hmac)),
+            "invalid payload: ciphertext isn't base64"
+        );
+        assert_eq!(
+            invalid(&envelope(&base64::encode([0u8; 20]), &iv, &hmac)),
+            "invalid payload: invalid ciphertext length"
+        );
+        assert_eq!(
+            invalid(&envelope(&ciphertext, &ciphertext, &hmac)),
+            "invalid payload: invalid IV length"
+        );
+        assert_eq!(
+            invalid(&envelope(&ciphertext, &iv, &hmac[1..])),
+            "invalid payload: hmac isn't a hex SHA-256"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_strict_payload_envelopes() {
+        let envelope = json!({
+            "ciphertext": base64::encode([0u8; 32]),
+            "IV": base64::encode([0u8; 16]),
+            "hmac": "0".repeat(64),
+        })
+        .to_string();
+        let bso_body = json!([
+            {"id": "123", "payload": envelope},
+            {"id": "456", "payload": "{\"title\": \"plaintext\"}"},
+            {"id": "789", "sortindex": 23}
+        ])
+        .to_string();
+        let content_type = "application/json";
+
+        // Not checked by default
+        let result = post_collection_body("", content_type, &bso_body)
+            .await
+            .expect("Could not get result in test_strict_payload_envelopes");
+        assert_eq!(result.bsos.valid.len(), 3);
+
+        let mut state = make_state();
+        state.strict_payload_envelopes = true;
+        let result = post_collection_with_state(state, "", content_type, &bso_body)
+            .await
+            .expect("Could not get result in test_strict_payload_envelopes");
+        let ids: Vec<_> = result.bsos.valid.iter().map(|b| b.id.as_str()).collect();
+        assert_eq!(ids, vec!["123", "789"]);
+        assert_eq!(
+            result.bsos.invalid["456"],
+            "invalid payload: missing ciphertext"
+        );
+    }
+
+    #[test]
+    fn test_strict_payload_envelopes_put() {
+        let payload = HawkPayload::test_default(*USER_ID);
+        let mut state = make_state();
+        state.strict_payload_envelopes = true;
+        let uri = format!("/1.5/{}/storage/tabs/asdf", *USER_ID);
+        let header = create_valid_hawk_header(&payload, &state, "PUT", &uri, TEST_HOST, TEST_PORT);
+        let bso_body = json!({"payload": "{\"title\": \"plaintext\"}"});
+        let req = TestRequest::with_uri(&uri)
+            .data(state)
+            .header("authorization", header)
+            .header("content-type", "application/json")
+            .method(Method::PUT)
+            .set_payload(bso_body.to_string())
+            .param("uid", &USER_ID_STR)
+            .param("collection", "tabs")
+            .param("bso", "asdf")
+            .to_http_request();
+        req.extensions_mut().insert(make_db());
+        let result = block_on(BsoPutRequest::extract(&req));
+        let response: HttpResponse = result
+            .err()
+            .expect("Could not get response in test_strict_payload_envelopes_put")
+            .into();
+        assert_eq!(response.status(), 400);
+        let body = extract_body_as_str(ServiceResponse::new(req, response));
+        assert_eq!(body, "8");
+    }
 }
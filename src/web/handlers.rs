@@ -1,24 +1,38 @@
 //! API Handlers
 use std::collections::HashMap;
-
-use actix_web::{http::StatusCode, web::Data, Error, HttpRequest, HttpResponse};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{
+    dev::BodyEncoding,
+    http::{header, ContentEncoding, StatusCode},
+    web::Data,
+    Error, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
 use serde::Serialize;
 use serde_json::{json, Value};
 
 use crate::{
     db::{
-        params,
+        archive_storage, params,
         results::{CreateBatch, Paginated},
         transaction::DbTransactionPool,
         util::SyncTimestamp,
-        Db, DbError, DbErrorKind,
+        AuditOp, Db, DbError, DbErrorKind,
     },
     error::{ApiError, ApiErrorKind, ApiResult},
-    server::ServerState,
+    server::{
+        events::{WriteOp, Written},
+        notifications::{Changed, EventStream},
+        ServerState,
+    },
+    settings::Secrets,
     web::{
         extractors::{
-            BsoPutRequest, BsoRequest, CollectionPostRequest, CollectionRequest, HeartbeatRequest,
-            MetaRequest, ReplyFormat, TestErrorRequest,
+            BatchBsoBody, BsoPutRequest, BsoQueryParams, BsoRequest, CollectionPostRequest,
+            CollectionRequest, HeartbeatRequest, InfoCollectionsQueryParams, MetaRequest,
+            ReplyFormat, RequestOrigin, TestErrorRequest, UserLimits,
         },
         X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET, X_WEAVE_RECORDS,
     },
@@ -28,8 +42,15 @@ pub const ONE_KB: f64 = 1024.0;
 
 pub async fn get_collections(
     meta: MetaRequest,
+    query: InfoCollectionsQueryParams,
     db_pool: DbTransactionPool,
+    state: Data<ServerState>,
 ) -> Result<HttpResponse, Error> {
+    if let Some(wait) = query.wait {
+        meta.metrics.incr("request.get_collections.wait");
+        let wait = Duration::from_secs(wait.into()).min(state.max_info_collections_wait);
+        db_pool.wait_for_change(wait).await?;
+    }
     db_pool
         .transaction_http(|db| async move {
             meta.metrics.incr("request.get_collections");
@@ -94,24 +115,78 @@ pub async fn get_quota(
 
 pub async fn delete_all(
     meta: MetaRequest,
+    origin: RequestOrigin,
     db_pool: DbTransactionPool,
+    state: Data<ServerState>,
 ) -> Result<HttpResponse, Error> {
+    let grace_period = state.deleted_storage_grace_period;
     db_pool
-        .transaction_http(|db| async move {
+        .transaction_http(move |db| async move {
             meta.metrics.incr("request.delete_all");
-            Ok(HttpResponse::Ok().json(db.delete_storage(meta.user_id).await?))
+            // The storage's copied for restoring first
+            if grace_period > 0 {
+                archive_storage(db.clone(), &meta.user_id, grace_period).await?;
+            }
+            let count = if origin.audited() {
+                db.get_collection_counts(meta.user_id.clone())
+                    .await?
+                    .values()
+                    .sum()
+            } else {
+                0
+            };
+            let timestamp = db.delete_storage(meta.user_id.clone()).await?;
+            if origin.audited() {
+                db.record_audit(origin.audit(
+                    meta.user_id,
+                    None,
+                    AuditOp::DeleteStorage,
+                    count,
+                    timestamp,
+                ))
+                .await?;
+            }
+            let resp = HttpResponse::Ok().json(());
+            Ok(changed(resp, timestamp, written(WriteOp::DeleteAll, 0, 0)))
         })
         .await
 }
 
 pub async fn delete_collection(
     coll: CollectionRequest,
+    origin: RequestOrigin,
     db_pool: DbTransactionPool,
 ) -> Result<HttpResponse, Error> {
     db_pool
         .transaction_http(|db| async move {
             let delete_bsos = !coll.query.ids.is_empty();
             let metrics = coll.metrics.clone();
+            let count = if !origin.audited() {
+                0
+            } else if delete_bsos {
+                // Only the requested ids that exist are deleted
+                let existing = db
+                    .get_bso_ids(params::GetBsos {
+                        user_id: coll.user_id.clone(),
+                        collection: coll.collection.clone(),
+                        params: BsoQueryParams {
+                            ids: coll.query.ids.clone(),
+                            ..Default::default()
+                        },
+                    })
+                    .await;
+                match existing {
+                    Ok(existing) => existing.items.len() as i64,
+                    Err(e) if e.is_collection_not_found() => 0,
+                    Err(e) => return Err(e.into()),
+                }
+            } else {
+                db.get_collection_counts(coll.user_id.clone())
+                    .await?
+                    .get(&coll.collection)
+                    .copied()
+                    .unwrap_or_default()
+            };
             let timestamp: ApiResult<SyncTimestamp> = if delete_bsos {
                 metrics.incr("request.delete_bsos");
                 db.delete_bsos(params::DeleteBsos {
@@ -129,22 +204,45 @@ pub async fn delete_collection(
                 .await
             };
 
-            let timestamp = match timestamp {
-                Ok(timestamp) => timestamp,
+            let (timestamp, is_changed) = match timestamp {
+                Ok(timestamp) => (timestamp, true),
                 Err(e) => {
                     if e.is_collection_not_found() || e.is_bso_not_found() {
-                        db.get_storage_timestamp(coll.user_id).await?
+                        (db.get_storage_timestamp(coll.user_id.clone()).await?, false)
                     } else {
                         return Err(e.into());
                     }
                 }
             };
+            if is_changed && origin.audited() {
+                let op = if delete_bsos {
+                    AuditOp::DeleteBsos
+                } else {
+                    AuditOp::DeleteCollection
+                };
+                db.record_audit(origin.audit(
+                    coll.user_id.clone(),
+                    Some(coll.collection.clone()),
+                    op,
+                    count,
+                    timestamp,
+                ))
+                .await?;
+            }
 
-            Ok(HttpResponse::Ok()
+            let resp = HttpResponse::Ok()
                 .if_true(delete_bsos, |resp| {
                     resp.header(X_LAST_MODIFIED, timestamp.as_header());
                 })
-                .json(timestamp))
+                .json(timestamp);
+            Ok(if !is_changed {
+                resp
+            } else if delete_bsos {
+                let records = coll.query.ids.len();
+                changed(resp, timestamp, written(WriteOp::DeleteBsos, records, 0))
+            } else {
+                changed(resp, timestamp, written(WriteOp::DeleteCollection, 0, 0))
+            })
         })
         .await
 }
@@ -152,6 +250,7 @@ pub async fn delete_collection(
 pub async fn get_collection(
     coll: CollectionRequest,
     db_pool: DbTransactionPool,
+    state: Data<ServerState>,
 ) -> Result<HttpResponse, Error> {
     db_pool
         .transaction_http(|db| async move {
@@ -162,13 +261,25 @@ pub async fn get_collection(
                 collection: coll.collection.clone(),
             };
 
-            let response = if coll.query.full {
+            let response = if let Some(since) = coll.query.deleted_since {
+                coll.metrics.clone().incr("request.get_deleted_bsos");
+                let result = db
+                    .get_deleted_bsos(params::GetDeletedBsos {
+                        user_id: coll.user_id.clone(),
+                        collection: coll.collection.clone(),
+                        since,
+                        limit: coll.query.limit,
+                        offset: coll.query.offset.as_ref().map_or(0, |offset| offset.offset),
+                    })
+                    .await;
+                finish_get_collection(&coll, db, result, &state.secrets).await?
+            } else if coll.query.full {
                 let result = db.get_bsos(params).await;
-                finish_get_collection(&coll, db, result).await?
+                finish_get_collection(&coll, db, result, &state.secrets).await?
             } else {
                 // Changed to be a Paginated list of BSOs, need to extract IDs from them.
                 let result = db.get_bso_ids(params).await;
-                finish_get_collection(&coll, db, result).await?
+                finish_get_collection(&coll, db, result, &state.secrets).await?
             };
 
             Ok(response)
@@ -180,6 +291,7 @@ async fn finish_get_collection<T>(
     coll: &CollectionRequest,
     db: Box<dyn Db<'_> + '_>,
     result: Result<Paginated<T>, ApiError>,
+    secrets: &Secrets,
 ) -> Result<HttpResponse, Error>
 where
     T: Serialize + Default + 'static,
@@ -197,12 +309,16 @@ where
     let ts = db
         .extract_resource(coll.user_id.clone(), Some(coll.collection.clone()), None)
         .await?;
+    let next_offset = match result.offset {
+        Some(offset) => Some(coll.query.offset_token(&offset, secrets)?),
+        None => None,
+    };
 
     let mut builder = HttpResponse::build(StatusCode::OK);
     let resp = builder
         .header(X_LAST_MODIFIED, ts.as_header())
         .header(X_WEAVE_RECORDS, result.items.len().to_string())
-        .if_some(result.offset, |offset, resp| {
+        .if_some(next_offset, |offset, resp| {
             resp.header(X_WEAVE_NEXT_OFFSET, offset);
         });
 
@@ -230,13 +346,20 @@ pub async fn post_collection(
     db_pool: DbTransactionPool,
 ) -> Result<HttpResponse, Error> {
     db_pool
-        .transaction_http(|db| async move {
+        .transaction_http(|mut db| async move {
             coll.metrics.clone().incr("request.post_collection");
+            db.set_quota(coll.quota_enabled, coll.limits.max_quota_limit as usize);
 
             if coll.batch.is_some() {
                 return post_collection_batch(coll, db).await;
             }
 
+            let sizes: HashMap<_, _> = coll
+                .bsos
+                .valid
+                .iter()
+                .map(|bso| (bso.id.clone(), payload_size(bso)))
+                .collect();
             let result = db
                 .post_bsos(params::PostBsos {
                     user_id: coll.user_id,
@@ -246,9 +369,16 @@ pub async fn post_collection(
                 })
                 .await?;
 
-            Ok(HttpResponse::build(StatusCode::OK)
-                .header(X_LAST_MODIFIED, result.modified.as_header())
-                .json(result))
+            let modified = result.modified;
+            let bytes = result.success.iter().filter_map(|id| sizes.get(id)).sum();
+            let written = written(WriteOp::Post, result.success.len(), bytes);
+            Ok(changed(
+                HttpResponse::build(StatusCode::OK)
+                    .header(X_LAST_MODIFIED, modified.as_header())
+                    .json(result),
+                modified,
+                written,
+            ))
         })
         .await
 }
@@ -314,9 +444,14 @@ pub async fn post_collection_batch(
 
     let mut success = vec![];
     let mut failed = coll.bsos.invalid;
-    let bso_ids: Vec<_> = coll.bsos.valid.iter().map(|bso| bso.id.clone()).collect();
-
-    let result = if commit && !coll.bsos.valid.is_empty() {
+    let bsos = coll.bsos.valid;
+    let bso_ids: Vec<_> = bsos.iter().map(|bso| bso.id.clone()).collect();
+    let sizes: HashMap<_, _> = bsos
+        .iter()
+        .map(|bso| (bso.id.clone(), payload_size(bso)))
+        .collect();
+
+    let result = if commit && !bsos.is_empty() {
         // There's pending items to append to the batch but since we're
         // committing, write them to bsos immediately. Otherwise under
         // Spanner we would pay twice the mutations for those pending
@@ -325,37 +460,40 @@ pub async fn post_collection_batch(
         db.post_bsos(params::PostBsos {
             user_id: coll.user_id.clone(),
             collection: coll.collection.clone(),
-            // XXX: why does BatchBsoBody exist (it's the same struct
-            // as PostCollectionBso)?
-            bsos: coll
-                .bsos
-                .valid
-                .into_iter()
-                .map(|batch_bso| params::PostCollectionBso {
-                    id: batch_bso.id,
-                    sortindex: batch_bso.sortindex,
-                    payload: batch_bso.payload,
-                    ttl: batch_bso.ttl,
-                })
-                .collect(),
+            bsos: bsos.into_iter().map(From::from).collect(),
             failed: Default::default(),
         })
         .await
-        .map(|_| ())
+        .map(|result| result.failed)
     } else {
         db.append_to_batch(params::AppendToBatch {
             user_id: coll.user_id.clone(),
             collection: coll.collection.clone(),
             batch: new_batch.clone(),
-            bsos: coll.bsos.valid.into_iter().map(From::from).collect(),
+            bsos: bsos.into_iter().map(From::from).collect(),
         })
         .await
+        .map(|_| HashMap::new())
     };
 
-    match result {
-        Ok(_) => success.extend(bso_ids),
+    // Events of batches only count the records of each request
+    let (records, bytes) = match result {
+        Ok(conflicts) => {
+            let ids: Vec<_> = bso_ids
+                .into_iter()
+                .filter(|id| !conflicts.contains_key(id))
+                .collect();
+            let bytes = ids.iter().filter_map(|id| sizes.get(id)).sum();
+            let records = ids.len();
+            success.extend(ids);
+            failed.extend(conflicts);
+            (records, bytes)
+        }
         Err(e) if e.is_conflict() => return Err(e.into()),
-        Err(_) => failed.extend(bso_ids.into_iter().map(|id| (id, "db error".to_owned()))),
+        Err(_) => {
+            failed.extend(bso_ids.into_iter().map(|id| (id, "db error".to_owned())));
+            (0, 0)
+        }
     };
 
     let mut resp = json!({
@@ -365,7 +503,10 @@ pub async fn post_collection_batch(
 
     if !breq.commit {
         resp["batch"] = json!(&new_batch.id);
-        return Ok(HttpResponse::Accepted().json(resp));
+        let mut resp = HttpResponse::Accepted().json(resp);
+        resp.extensions_mut()
+            .insert(written(WriteOp::BatchAppend, records, bytes));
+        return Ok(resp);
     }
 
     let batch = db
@@ -390,14 +531,23 @@ pub async fn post_collection_batch(
         return Err(ApiError::from(err).into());
     };
 
+    // Batched records whose expected modified times no longer matched
+    for (id, reason) in result.failed {
+        resp["failed"][id] = json!(reason);
+    }
     resp["modified"] = json!(result.modified);
-    Ok(HttpResponse::build(StatusCode::OK)
-        .header(X_LAST_MODIFIED, result.modified.as_header())
-        .json(resp))
+    Ok(changed(
+        HttpResponse::build(StatusCode::OK)
+            .header(X_LAST_MODIFIED, result.modified.as_header())
+            .json(resp),
+        result.modified,
+        written(WriteOp::BatchCommit, records, bytes),
+    ))
 }
 
 pub async fn delete_bso(
     bso_req: BsoRequest,
+    origin: RequestOrigin,
     db_pool: DbTransactionPool,
 ) -> Result<HttpResponse, Error> {
     db_pool
@@ -405,12 +555,26 @@ pub async fn delete_bso(
             bso_req.metrics.incr("request.delete_bso");
             let result = db
                 .delete_bso(params::DeleteBso {
-                    user_id: bso_req.user_id,
-                    collection: bso_req.collection,
+                    user_id: bso_req.user_id.clone(),
+                    collection: bso_req.collection.clone(),
                     id: bso_req.bso,
                 })
                 .await?;
-            Ok(HttpResponse::Ok().json(json!({ "modified": result })))
+            if origin.audited() {
+                db.record_audit(origin.audit(
+                    bso_req.user_id,
+                    Some(bso_req.collection),
+                    AuditOp::DeleteBso,
+                    1,
+                    result,
+                ))
+                .await?;
+            }
+            Ok(changed(
+                HttpResponse::Ok().json(json!({ "modified": result })),
+                result,
+                written(WriteOp::Delete, 1, 0),
+            ))
         })
         .await
 }
@@ -443,8 +607,13 @@ pub async fn put_bso(
     db_pool: DbTransactionPool,
 ) -> Result<HttpResponse, Error> {
     db_pool
-        .transaction_http(|db| async move {
+        .transaction_http(|mut db| async move {
             bso_req.metrics.incr("request.put_bso");
+            db.set_quota(
+                bso_req.quota_enabled,
+                bso_req.limits.max_quota_limit as usize,
+            );
+            let bytes = bso_req.body.payload.as_ref().map_or(0, String::len);
             let result = db
                 .put_bso(params::PutBso {
                     user_id: bso_req.user_id,
@@ -456,24 +625,94 @@ pub async fn put_bso(
                 })
                 .await?;
 
-            Ok(HttpResponse::build(StatusCode::OK)
-                .header(X_LAST_MODIFIED, result.as_header())
-                .json(result))
+            Ok(changed(
+                HttpResponse::build(StatusCode::OK)
+                    .header(X_LAST_MODIFIED, result.as_header())
+                    .json(result),
+                result,
+                written(WriteOp::Put, 1, bytes),
+            ))
         })
         .await
 }
 
-pub fn get_configuration(state: Data<ServerState>) -> HttpResponse {
+/// Mark a write's response with the timestamp of the change it made, and
+/// its stats, to be published once committed
+fn changed(mut resp: HttpResponse, modified: SyncTimestamp, written: Written) -> HttpResponse {
+    resp.extensions_mut().insert(Changed(modified));
+    resp.extensions_mut().insert(written);
+    resp
+}
+
+fn written(op: WriteOp, records: usize, bytes: usize) -> Written {
+    Written { op, records, bytes }
+}
+
+fn payload_size(bso: &BatchBsoBody) -> usize {
+    bso.payload.as_ref().map_or(0, String::len)
+}
+
+/// Stream the user's collection changes as server-sent events
+pub async fn get_notifications(meta: MetaRequest, state: Data<ServerState>) -> HttpResponse {
+    meta.metrics.incr("request.get_notifications");
+    let expires_in = meta
+        .token
+        .expires
+        .saturating_sub(Utc::now().timestamp() as u64);
+    let revocations = Arc::clone(&state.revocations);
+    let MetaRequest { user_id, token, .. } = meta;
+    let subscription = state.broker.subscribe(&user_id);
+    let revoked = move || {
+        revocations.is_revoked(
+            user_id.legacy_id,
+            &user_id.fxa_uid,
+            &token.device_id,
+            token.issued,
+        )
+    };
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        // Compressing would hold events back in the encoder's buffer
+        .encoding(ContentEncoding::Identity)
+        .streaming(EventStream::new(
+            subscription,
+            Duration::from_secs(expires_in),
+            revoked,
+        ))
+}
+
+/// The user's limits, with their overrides applied (or the server limits
+/// when their overrides can't be loaded)
+pub async fn get_configuration(
+    limits: Result<UserLimits, Error>,
+    state: Data<ServerState>,
+) -> Result<HttpResponse, Error> {
+    let limits = match limits {
+        Ok(limits) => limits.0,
+        Err(e) => match e.as_error::<ApiError>().map(ApiError::kind) {
+            Some(ApiErrorKind::Db(dbe)) => {
+                warn!("⚠️ Could not load the user's limits: {}", dbe);
+                Arc::clone(&state.limits)
+            }
+            _ => return Err(e),
+        },
+    };
     // With no DbConnection (via a `transaction_http` call) needed here, we
     // miss out on a couple things it does:
     // 1. Ensuring an X-Last-Modified (always 0.00) is returned
     // 2. Handling precondition checks
     // The precondition checks don't make sense against hardcoded to the
     // service limits data + a 0.00 timestamp, so just ensure #1 is handled
-    HttpResponse::Ok()
+    let body = if Arc::ptr_eq(&limits, &state.limits) {
+        state.limits_json.clone()
+    } else {
+        serde_json::to_string(&*limits).expect("ServerLimits failed to serialize")
+    };
+    Ok(HttpResponse::Ok()
         .header(X_LAST_MODIFIED, "0.00")
         .content_type("application/json")
-        .body(&state.limits_json)
+        .body(body))
 }
 
 /** Returns a status message indicating the state of the current server
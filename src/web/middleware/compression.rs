@@ -0,0 +1,171 @@
+//! Response compression.
+//!
+//! Wraps actix's `Compress` middleware, recording each compressed response's
+//! encoded size against its raw size per encoding (as
+//! `response.compression_ratio`, the counterpart of
+//! `request.compression_ratio`).
+#![allow(clippy::type_complexity)]
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_http::encoding::Encoder;
+use actix_web::{
+    body::{BodySize, MessageBody, ResponseBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::CONTENT_ENCODING,
+    middleware::Compress,
+    web::{Bytes, Data},
+    Error,
+};
+use futures::future::{LocalBoxFuture, TryFutureExt};
+
+use crate::server::{metrics::Metrics, ServerState};
+use crate::web::tags::Tags;
+
+/// The size of a response's body before compression.
+struct RawSize(usize);
+
+#[derive(Debug, Default)]
+pub struct Compression;
+
+impl<S, B> Transform<S> for Compression
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + Unpin + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<MeteredBody<Encoder<B>>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform =
+        CompressionMiddleware<<Compress as Transform<RawSizeMiddleware<S>>>::Transform>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(
+            Compress::default()
+                .new_transform(RawSizeMiddleware { service })
+                .map_ok(|service| CompressionMiddleware { service }),
+        )
+    }
+}
+
+/// Notes the size of responses before `Compress` encodes them.
+pub struct RawSizeMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RawSizeMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        Box::pin(self.service.call(sreq).map_ok(|mut resp| {
+            if let BodySize::Sized(size) = resp.response().body().size() {
+                resp.response_mut()
+                    .extensions_mut()
+                    .insert(RawSize(size as usize));
+            }
+            resp
+        }))
+    }
+}
+
+pub struct CompressionMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for CompressionMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + Unpin,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<MeteredBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let metrics = sreq
+            .app_data::<Data<ServerState>>()
+            .map(|state| Metrics::from(state.get_ref()));
+        Box::pin(self.service.call(sreq).map_ok(move |resp| {
+            let raw_size = resp
+                .response()
+                .extensions()
+                .get::<RawSize>()
+                .map(|raw_size| raw_size.0);
+            let encoding = resp
+                .headers()
+                .get(CONTENT_ENCODING)
+                .and_then(|header| header.to_str().ok())
+                .map(str::to_owned);
+            let metric = match (metrics, raw_size, encoding) {
+                (Some(metrics), Some(raw_size), Some(encoding)) if raw_size > 0 => {
+                    let mut tags = HashMap::new();
+                    tags.insert("encoding".to_owned(), encoding);
+                    Some((metrics, Tags::with_tags(tags), raw_size))
+                }
+                _ => None,
+            };
+            resp.map_body(|_, body| {
+                ResponseBody::Body(MeteredBody {
+                    body,
+                    size: 0,
+                    metric,
+                })
+            })
+        }))
+    }
+}
+
+/// A compressed response body, recording its compression ratio once it's
+/// been fully sent.
+pub struct MeteredBody<B> {
+    body: ResponseBody<B>,
+    size: usize,
+    metric: Option<(Metrics, Tags, usize)>,
+}
+
+impl<B: MessageBody + Unpin> MessageBody for MeteredBody<B> {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Error>>> {
+        let this = self.get_mut();
+        let next = Pin::new(&mut this.body).poll_next(cx);
+        match &next {
+            Poll::Ready(Some(Ok(chunk))) => this.size += chunk.len(),
+            Poll::Ready(None) => {
+                if let Some((metrics, tags, raw_size)) = this.metric.take() {
+                    metrics.histogram_with_tags(
+                        "response.compression_ratio",
+                        (this.size * 100 / raw_size) as u64,
+                        Some(tags),
+                    );
+                }
+            }
+            _ => (),
+        }
+        next
+    }
+}
@@ -1,4 +1,5 @@
 // pub mod db;
+pub mod compression;
 pub mod rejectua;
 pub mod sentry;
 pub mod weave;
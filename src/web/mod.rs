@@ -1,5 +1,7 @@
 //! Web authentication, handlers, and middleware
+pub mod admin;
 pub mod auth;
+pub mod body;
 pub mod error;
 pub mod extractors;
 pub mod handlers;
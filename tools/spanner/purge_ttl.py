@@ -94,24 +94,31 @@ def add_conditions(args, query: str, prefix: Optional[str]):
     return (query, params, types)
 
 
-def get_expiry_condition(args):
+def get_expiry_condition(args, grace: int = 0):
     """
     Get the expiry SQL WHERE condition to use
     :param args: The program arguments
+    :param grace: How long (in seconds) to keep rows after they expire
     :return: A SQL snippet to use in the WHERE clause
     """
     if args.expiry_mode == "now":
-        return 'expiry < CURRENT_TIMESTAMP()'
+        now = 'CURRENT_TIMESTAMP()'
     elif args.expiry_mode == "midnight":
-        return 'expiry < TIMESTAMP_TRUNC(CURRENT_TIMESTAMP(), DAY, "UTC")'
+        now = 'TIMESTAMP_TRUNC(CURRENT_TIMESTAMP(), DAY, "UTC")'
     else:
         raise Exception("Invalid expiry mode: {}".format(args.expiry_mode))
+    if grace:
+        return 'expiry < TIMESTAMP_SUB({}, INTERVAL {} SECOND)'.format(
+            now, int(grace))
+    return 'expiry < {}'.format(now)
 
 
 def spanner_purge(args):
     instance = client.instance(args.instance_id)
     database = instance.database(args.database_id)
     expiry_condition = get_expiry_condition(args)
+    # Expired BSOs are kept for the deleted record feed (?deleted_since=)
+    bso_expiry_condition = get_expiry_condition(args, args.deleted_retention)
     prefixes = args.uid_prefixes if args.uid_prefixes else [None]
 
     for prefix in prefixes:
@@ -139,7 +146,7 @@ def spanner_purge(args):
             # Delete BSOs
             (bso_query, params, types) = add_conditions(
                 args,
-                'DELETE FROM bsos WHERE {}'.format(expiry_condition),
+                'DELETE FROM bsos WHERE {}'.format(bso_expiry_condition),
                 prefix
             )
             deleter(
@@ -152,6 +159,22 @@ def spanner_purge(args):
                 dryrun=args.dryrun,
             )
 
+            # Delete the forgotten deleted BSO ids
+            (deletions_query, params, types) = add_conditions(
+                args,
+                'DELETE FROM bso_deletions WHERE {}'.format(expiry_condition),
+                prefix
+            )
+            deleter(
+                database,
+                name="bso_deletions",
+                query=deletions_query,
+                params=params,
+                param_types=types,
+                prefix=prefix,
+                dryrun=args.dryrun,
+            )
+
 
 def get_args():
     parser = argparse.ArgumentParser(
@@ -204,6 +227,13 @@ def get_args():
         default=os.environ.get("PURGE_EXPIRY_MODE", "midnight"),
         help="Choose the timestamp used to check if an entry is expired"
     )
+    parser.add_argument(
+        "--deleted_retention",
+        type=int,
+        default=os.environ.get("SYNC_DELETED_BSO_RETENTION", 0),
+        help="Seconds to keep expired BSOs for the deleted record feed. "
+             "Defaults to syncstorage's SYNC_DELETED_BSO_RETENTION setting"
+    )
     parser.add_argument(
         '--dryrun',
         action="store_true",